@@ -0,0 +1,125 @@
+//! API Version
+//!
+//! `ANTHROPIC_API_VERSION` overrides the `anthropic-version` header every
+//! binary sends. A malformed value is ignored with a warning instead of
+//! turning each request into an opaque 400, and API errors that look
+//! version-related get a hint naming the override.
+
+use anthropic::client::ClientBuilder;
+use anyhow::Result;
+use colored::Colorize;
+use std::env;
+
+/// `anthropic-version` sent when ANTHROPIC_API_VERSION is unset
+pub const DEFAULT_API_VERSION: &str = "2023-06-01";
+
+/// Check that `value` has the shape of an API version: a `YYYY-MM-DD` date
+pub fn validate_api_version(value: &str) -> Result<()> {
+    let parts: Vec<&str> = value.split('-').collect();
+    let numeric =
+        |part: &str, len: usize| part.len() == len && part.bytes().all(|b| b.is_ascii_digit());
+    let valid = match parts.as_slice() {
+        [year, month, day] if numeric(year, 4) && numeric(month, 2) && numeric(day, 2) => {
+            let month: u32 = month.parse().unwrap_or(0);
+            let day: u32 = day.parse().unwrap_or(0);
+            (1..=12).contains(&month) && (1..=31).contains(&day)
+        }
+        _ => false,
+    };
+    if !valid {
+        anyhow::bail!(
+            "Ignoring ANTHROPIC_API_VERSION '{}': expected a YYYY-MM-DD date such as {}; using the default",
+            value,
+            DEFAULT_API_VERSION
+        );
+    }
+    Ok(())
+}
+
+/// ANTHROPIC_API_VERSION, if set. A malformed value is an error the caller
+/// reports as a warning before falling back to the default.
+pub fn api_version_from_env() -> Result<Option<String>> {
+    match env::var("ANTHROPIC_API_VERSION") {
+        Ok(value) if !value.trim().is_empty() => {
+            let value = value.trim().to_string();
+            validate_api_version(&value)?;
+            Ok(Some(value))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// `builder` with ANTHROPIC_API_VERSION applied. A malformed version would
+/// fail every request, so it is reported and the default kept.
+pub fn apply_api_version(builder: ClientBuilder) -> ClientBuilder {
+    match api_version_from_env() {
+        Ok(Some(api_version)) => builder.api_version(api_version),
+        Ok(None) => builder,
+        Err(e) => {
+            eprintln!("{} {}", "Warning:".bright_yellow(), e);
+            builder
+        }
+    }
+}
+
+/// Whether an API error message points at the `anthropic-version` header
+pub fn is_version_error(message: &str) -> bool {
+    let message = message.to_lowercase();
+    message.contains("anthropic-version")
+        || message.contains("api version")
+        || message.contains("api-version")
+}
+
+/// Hint for a version-related API error while ANTHROPIC_API_VERSION is set
+pub fn api_version_hint(message: &str) -> Option<String> {
+    version_hint_for(message, env::var("ANTHROPIC_API_VERSION").ok().as_deref())
+}
+
+/// [`api_version_hint`] with the configured version passed in
+pub fn version_hint_for(message: &str, configured: Option<&str>) -> Option<String> {
+    let configured = configured.map(str::trim).filter(|v| !v.is_empty())?;
+    is_version_error(message).then(|| {
+        format!(
+            "Hint: API version '{}' may be invalid; unset ANTHROPIC_API_VERSION to use the default",
+            configured
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validate_api_version_format() {
+        assert!(validate_api_version("2023-06-01").is_ok());
+        assert!(validate_api_version(DEFAULT_API_VERSION).is_ok());
+        for bad in [
+            "",
+            "2023-6-1",
+            "2023-13-01",
+            "2023-06-00",
+            "v1",
+            "2023/06/01",
+            "2023-06-01x",
+        ] {
+            assert!(validate_api_version(bad).is_err(), "{}", bad);
+        }
+        assert!(validate_api_version("latest")
+            .unwrap_err()
+            .to_string()
+            .starts_with("Ignoring ANTHROPIC_API_VERSION 'latest': expected a YYYY-MM-DD date"));
+    }
+
+    #[test]
+    fn test_version_hint_only_for_version_errors() {
+        let error = "API error 400: invalid anthropic-version header";
+        assert_eq!(
+            version_hint_for(error, Some("2099-01-01")).unwrap(),
+            "Hint: API version '2099-01-01' may be invalid; unset ANTHROPIC_API_VERSION to use the default"
+        );
+        // Nothing to unset without an override
+        assert!(version_hint_for(error, None).is_none());
+        assert!(version_hint_for("API error 401: unauthorized", Some("2099-01-01")).is_none());
+    }
+}
@@ -0,0 +1,281 @@
+//! Archive-Aware Reads
+//!
+//! v4's `read_file` reads `.gz` and `.zip` files as their decompressed text
+//! and can number lines. Decoding, line limits and the output cap are the
+//! same as every other version's read; decompression itself stops at the
+//! output cap.
+
+use crate::tools::{cap_output_redacted, decode_lossy, limit_lines, safe_path, MAX_TOOL_OUTPUT};
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// Decompress at most [`MAX_TOOL_OUTPUT`] bytes, so a small archive that
+/// expands to gigabytes can't exhaust memory. The flag is set when there
+/// was more.
+fn read_capped(decoder: impl Read) -> io::Result<(Vec<u8>, bool)> {
+    let mut text = Vec::new();
+    decoder
+        .take(MAX_TOOL_OUTPUT as u64 + 1)
+        .read_to_end(&mut text)?;
+    let truncated = text.len() > MAX_TOOL_OUTPUT;
+    text.truncate(MAX_TOOL_OUTPUT);
+    Ok((text, truncated))
+}
+
+/// Transparently decompress `.gz` and `.zip` files for read_file.
+///
+/// Archives are recognized by magic bytes or extension. A zip with several
+/// entries needs `member`; without one the entries are listed instead.
+/// Anything else is returned unchanged. The flag is set when decompressed
+/// data was cut at [`MAX_TOOL_OUTPUT`] bytes.
+fn decompress_for_read(
+    path: &str,
+    bytes: Vec<u8>,
+    member: Option<&str>,
+) -> std::result::Result<(Vec<u8>, bool), String> {
+    let extension = Path::new(path)
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase());
+    let is_gzip = bytes.starts_with(GZIP_MAGIC) || extension.as_deref() == Some("gz");
+    let is_zip = bytes.starts_with(ZIP_MAGIC) || extension.as_deref() == Some("zip");
+
+    if is_gzip {
+        return read_capped(flate2::read::MultiGzDecoder::new(bytes.as_slice()))
+            .map_err(|e| format!("Error decompressing {}: {}", path, e));
+    }
+    if !is_zip {
+        return match member {
+            Some(_) => Err(format!(
+                "Error: 'member' only applies to .zip archives, not {}",
+                path
+            )),
+            None => Ok((bytes, false)),
+        };
+    }
+
+    let mut archive = zip::ZipArchive::new(io::Cursor::new(bytes))
+        .map_err(|e| format!("Error opening zip {}: {}", path, e))?;
+    let files: Vec<String> = archive
+        .file_names()
+        .filter(|name| !name.ends_with('/'))
+        .map(str::to_string)
+        .collect();
+
+    let name = match (member, files.as_slice()) {
+        (Some(member), _) => member.to_string(),
+        (None, [only]) => only.clone(),
+        (None, _) => {
+            let mut files = files;
+            files.sort();
+            return Err(format!(
+                "Error: {} is a zip archive with {} entries; pass 'member' to read one:\n{}",
+                path,
+                files.len(),
+                files
+                    .iter()
+                    .map(|f| format!("- {}", f))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ));
+        }
+    };
+
+    let entry = archive
+        .by_name(&name)
+        .map_err(|e| format!("Error reading {} from {}: {}", name, path, e))?;
+    read_capped(entry).map_err(|e| format!("Error reading {} from {}: {}", name, path, e))
+}
+
+/// Read a file for v4's read_file: `.gz` and `.zip` files are decompressed,
+/// and `line_numbers` prefixes each line with its number.
+///
+/// Otherwise as [`crate::tools::run_read`], except that output over the cap
+/// is saved through `redact`.
+pub fn run_read_with(
+    workdir: &Path,
+    path: &str,
+    limit: Option<i64>,
+    line_numbers: bool,
+    member: Option<&str>,
+    redact: &dyn Fn(&str) -> String,
+) -> String {
+    match safe_path(workdir, path) {
+        Ok(safe_path) => {
+            // Read file as raw bytes first to handle non-UTF8 content gracefully
+            match fs::read(&safe_path)
+                .map_err(|e| format!("Error reading file: {}", e))
+                .and_then(|bytes| decompress_for_read(path, bytes, member))
+            {
+                Ok((bytes, truncated)) => {
+                    let content = decode_lossy(bytes);
+
+                    // Number lines before limiting/truncating so numbers match the file
+                    let content = if line_numbers {
+                        number_lines(&content)
+                    } else {
+                        content
+                    };
+
+                    let output = cap_output_redacted(limit_lines(content, limit), redact);
+                    if truncated {
+                        format!(
+                            "{}\n... (decompression stopped at {} bytes; the rest was not read)",
+                            output, MAX_TOOL_OUTPUT
+                        )
+                    } else {
+                        output
+                    }
+                }
+                Err(e) => e,
+            }
+        }
+        Err(e) => format!("Error: {}", e),
+    }
+}
+
+/// Prefix each line with a right-aligned line number and a `|`.
+fn number_lines(content: &str) -> String {
+    let width = content.lines().count().max(1).to_string().len();
+    content
+        .lines()
+        .enumerate()
+        .map(|(i, line)| format!("{:>width$}| {}", i + 1, line, width = width))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_read_gzip_and_zip() {
+        use std::io::Write as _;
+
+        let dir = env::temp_dir().join(format!("core_read_archive_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(b"line one\nline two\nline three\n").unwrap();
+        fs::write(dir.join("app.log.gz"), gz.finish().unwrap()).unwrap();
+
+        let zip_with = |name: &str, entries: &[(&str, &str)]| {
+            let mut zip = zip::ZipWriter::new(fs::File::create(dir.join(name)).unwrap());
+            for (entry, content) in entries {
+                zip.start_file(*entry, zip::write::SimpleFileOptions::default())
+                    .unwrap();
+                zip.write_all(content.as_bytes()).unwrap();
+            }
+            zip.finish().unwrap();
+        };
+        zip_with("single.zip", &[("fixture.json", "{\"ok\": true}")]);
+        zip_with("multi.zip", &[("b.txt", "bee"), ("a.txt", "ay")]);
+
+        let gz = run_read_with(&dir, "app.log.gz", Some(2), false, None, &str::to_string);
+        let single = run_read_with(&dir, "single.zip", None, false, None, &str::to_string);
+        let listing = run_read_with(&dir, "multi.zip", None, false, None, &str::to_string);
+        let member = run_read_with(
+            &dir,
+            "multi.zip",
+            None,
+            false,
+            Some("b.txt"),
+            &str::to_string,
+        );
+        let missing = run_read_with(
+            &dir,
+            "multi.zip",
+            None,
+            false,
+            Some("c.txt"),
+            &str::to_string,
+        );
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(gz, "line one\nline two\n... (1 more lines)");
+        assert_eq!(single, "{\"ok\": true}");
+        assert_eq!(
+            listing,
+            "Error: multi.zip is a zip archive with 2 entries; pass 'member' to read one:\n- a.txt\n- b.txt"
+        );
+        assert_eq!(member, "bee");
+        assert!(missing.starts_with("Error reading c.txt from multi.zip"));
+    }
+
+    #[test]
+    fn test_decompression_bomb_is_cut() {
+        use std::io::Write as _;
+
+        let dir = env::temp_dir().join(format!("core_read_bomb_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+
+        // 8 MB of newlines compresses to a few KB
+        let expanded = vec![b'\n'; 8 * 1024 * 1024];
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+        gz.write_all(&expanded).unwrap();
+        let compressed = gz.finish().unwrap();
+        assert!(compressed.len() < 64 * 1024);
+        fs::write(dir.join("bomb.gz"), &compressed).unwrap();
+
+        let mut zip = zip::ZipWriter::new(fs::File::create(dir.join("bomb.zip")).unwrap());
+        zip.start_file("bomb.txt", zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(&expanded).unwrap();
+        zip.finish().unwrap();
+
+        let (gz_bytes, gz_cut) =
+            read_capped(flate2::read::MultiGzDecoder::new(compressed.as_slice())).unwrap();
+        let gz = run_read_with(&dir, "bomb.gz", Some(2), false, None, &str::to_string);
+        let zipped = run_read_with(&dir, "bomb.zip", Some(2), false, None, &str::to_string);
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(gz_bytes.len(), MAX_TOOL_OUTPUT);
+        assert!(gz_cut);
+        let note = format!(
+            "\n... (decompression stopped at {} bytes; the rest was not read)",
+            MAX_TOOL_OUTPUT
+        );
+        assert!(gz.ends_with(&note), "{}", gz);
+        assert!(zipped.ends_with(&note), "{}", zipped);
+
+        let (small, small_cut) = read_capped(&b"short"[..]).unwrap();
+        assert_eq!(small, b"short");
+        assert!(!small_cut);
+    }
+
+    #[test]
+    fn test_run_read_line_numbers() {
+        let dir = env::temp_dir().join(format!("core_read_numbers_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("sample.txt"), "alpha\nbeta\ngamma\n").unwrap();
+
+        let plain = run_read_with(&dir, "sample.txt", None, false, None, &str::to_string);
+        let numbered = run_read_with(&dir, "sample.txt", None, true, None, &str::to_string);
+        let limited = run_read_with(&dir, "sample.txt", Some(2), true, None, &str::to_string);
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(plain, "alpha\nbeta\ngamma\n");
+        assert_eq!(numbered, "1| alpha\n2| beta\n3| gamma");
+        assert_eq!(limited, "1| alpha\n2| beta\n... (1 more lines)");
+    }
+
+    #[test]
+    fn test_number_lines_right_aligns() {
+        let content = (1..=10)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let numbered = number_lines(&content);
+        assert!(numbered.starts_with(" 1| 1\n"));
+        assert!(numbered.ends_with("10| 10"));
+    }
+}
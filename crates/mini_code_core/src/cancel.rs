@@ -0,0 +1,239 @@
+//! Cancellable Shell Commands
+//!
+//! The `bash` tool v4 runs: the command gets its own process group, so
+//! cancelling a [`CancellationToken`] (Ctrl-C) kills it along with anything
+//! it started, and a failed command can be retried. Unlike
+//! [`crate::tools::run_bash`] the result says whether the command succeeded.
+
+use crate::tools::{cap_output_redacted, check_workdir, is_dangerous_command};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+
+/// Kill `pid` and the rest of its process group
+pub fn kill_process_group(pid: u32) {
+    #[cfg(unix)]
+    let killed = Command::new("kill")
+        .args(["-KILL", "--", &format!("-{}", pid)])
+        .stderr(Stdio::null())
+        .status();
+    #[cfg(not(unix))]
+    let killed = Command::new("taskkill")
+        .args(["/F", "/T", "/PID", &pid.to_string()])
+        .stderr(Stdio::null())
+        .status();
+    killed.ok();
+}
+
+/// Most retries a single `bash` call may ask for
+const MAX_BASH_RETRIES: u64 = 5;
+
+/// Pause between retries of a failed `bash` command
+const BASH_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Run a shell command, re-running it up to `retries` times on nonzero exit.
+/// Returns whether the last attempt succeeded, and its output.
+///
+/// Cancelling `cancel` kills the command (and anything it started) and stops
+/// further retries. Output over the cap is saved through `redact`.
+pub fn run_bash_cancellable(
+    workdir: &Path,
+    command: &str,
+    retries: u64,
+    cancel: &CancellationToken,
+    redact: &dyn Fn(&str) -> String,
+) -> (bool, String) {
+    if is_dangerous_command(command) {
+        return (false, "Error: Dangerous command blocked".to_string());
+    }
+
+    let retries = retries.min(MAX_BASH_RETRIES);
+    let mut attempt = 0;
+    loop {
+        let (success, output) = run_bash_once(workdir, command, cancel, redact);
+        if success || attempt >= retries || cancel.is_cancelled() {
+            let output = if attempt > 0 {
+                format!("{}\n(retries: {})", output, attempt)
+            } else {
+                output
+            };
+            return (success, output);
+        }
+        attempt += 1;
+        std::thread::sleep(BASH_RETRY_DELAY);
+    }
+}
+
+/// Marker `run_bash_cancellable` appends when a command exits nonzero. It is only text for
+/// the model: `is_error` comes from the exit status, which output can't fake.
+const EXIT_CODE_PREFIX: &str = "[exit code: ";
+
+/// Result of a tool call stopped (or never started) because of Ctrl-C
+pub const CANCELLED_TOOL_CALL: &str = "Error: Cancelled by the user";
+
+/// How often a running `bash` command checks for cancellation
+pub const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Run a shell command once, returning whether it exited zero and its output.
+fn run_bash_once(
+    workdir: &Path,
+    command: &str,
+    cancel: &CancellationToken,
+    redact: &dyn Fn(&str) -> String,
+) -> (bool, String) {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .current_dir(workdir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // Own process group, so cancelling also reaches whatever the shell started
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+
+    if let Err(e) = check_workdir(workdir) {
+        return (false, format!("Error: {}", e));
+    }
+    let child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => return (false, format!("Error: {}", e)),
+    };
+    let pid = child.id();
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || tx.send(child.wait_with_output()).ok());
+
+    let output = loop {
+        match rx.recv_timeout(CANCEL_POLL_INTERVAL) {
+            Ok(output) => break output,
+            Err(RecvTimeoutError::Timeout) if cancel.is_cancelled() => {
+                kill_process_group(pid);
+                return (false, CANCELLED_TOOL_CALL.to_string());
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => {
+                return (false, "Error: Lost track of the command".to_string())
+            }
+        }
+    };
+
+    match output {
+        Ok(output) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let combined = format!("{}{}", stdout, stderr).trim().to_string();
+
+            let mut text = if combined.is_empty() {
+                "(no output)".to_string()
+            } else {
+                cap_output_redacted(combined, redact)
+            };
+
+            // Only failures are labelled; a silent success stays "(no output)"
+            if !output.status.success() {
+                let code = output
+                    .status
+                    .code()
+                    .map_or_else(|| "signal".to_string(), |c| c.to_string());
+                text.push_str(&format!("\n{}{}]", EXIT_CODE_PREFIX, code));
+            }
+            (output.status.success(), text)
+        }
+        Err(e) => (false, format!("Error: {}", e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::time::Instant;
+
+    #[test]
+    fn test_cancel_kills_sleeping_bash_child() {
+        let dir = env::temp_dir().join(format!("core_bash_cancel_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cancel = CancellationToken::new();
+        let canceller = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            canceller.cancel();
+        });
+
+        let started = Instant::now();
+        let (success, output) = run_bash_cancellable(
+            &dir,
+            "sleep 30 & echo $! > sleep.pid; wait",
+            2,
+            &cancel,
+            &str::to_string,
+        );
+        let pid = fs::read_to_string(dir.join("sleep.pid")).unwrap();
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(output, CANCELLED_TOOL_CALL);
+        assert!(!success);
+        assert!(started.elapsed() < Duration::from_secs(10));
+
+        // The orphaned sleep may linger as a zombie, but must not be running
+        let running = || {
+            let ps = Command::new("ps")
+                .args(["-o", "stat=", "-p", pid.trim()])
+                .output()
+                .unwrap();
+            let stat = String::from_utf8_lossy(&ps.stdout).trim().to_string();
+            !stat.is_empty() && !stat.starts_with('Z')
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while running() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert!(!running(), "sleep {} survived cancellation", pid.trim());
+    }
+
+    #[test]
+    fn test_run_bash_retries_until_success() {
+        let dir = env::temp_dir().join(format!("core_bash_retry_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        // Fails the first time, succeeds once the marker exists
+        let script =
+            "if [ -f marker ]; then echo fetched; else touch marker; echo flaky; exit 1; fi";
+
+        let without_retry =
+            run_bash_cancellable(&dir, script, 0, &CancellationToken::new(), &str::to_string);
+        fs::remove_file(dir.join("marker")).ok();
+        let with_retry =
+            run_bash_cancellable(&dir, script, 3, &CancellationToken::new(), &str::to_string);
+        let always_fails = run_bash_cancellable(
+            &dir,
+            "echo nope; exit 2",
+            1,
+            &CancellationToken::new(),
+            &str::to_string,
+        );
+        let blocked = run_bash_cancellable(
+            &dir,
+            "sudo true",
+            3,
+            &CancellationToken::new(),
+            &str::to_string,
+        );
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(without_retry, (false, "flaky\n[exit code: 1]".to_string()));
+        assert_eq!(with_retry, (true, "fetched\n(retries: 1)".to_string()));
+        assert_eq!(
+            always_fails,
+            (false, "nope\n[exit code: 2]\n(retries: 1)".to_string())
+        );
+        assert_eq!(
+            blocked,
+            (false, "Error: Dangerous command blocked".to_string())
+        );
+    }
+}
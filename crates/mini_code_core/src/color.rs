@@ -0,0 +1,45 @@
+//! Terminal Colors
+//!
+//! Every binary takes `--no-color` and honours NO_COLOR; output piped to a
+//! file or another program is plain too.
+
+use std::env;
+use std::io::{self, IsTerminal};
+
+/// Colors are off for `--no-color`, a non-empty NO_COLOR, or piped stdout.
+pub fn should_disable_color(no_color_flag: bool, no_color_env: bool, stdout_is_tty: bool) -> bool {
+    no_color_flag || no_color_env || !stdout_is_tty
+}
+
+/// Apply the color policy globally via `colored`'s override.
+pub fn configure_colors(no_color_flag: bool) {
+    let no_color_env = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    if should_disable_color(no_color_flag, no_color_env, io::stdout().is_terminal()) {
+        colored::control::set_override(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use colored::Colorize;
+
+    #[test]
+    fn test_color_disabled_output_has_no_ansi() {
+        assert!(should_disable_color(true, false, true));
+        assert!(should_disable_color(false, true, true));
+        assert!(should_disable_color(false, false, false));
+        assert!(!should_disable_color(false, false, true));
+
+        colored::control::set_override(false);
+        let line = format!(
+            "{} {}",
+            "Error:".bright_red(),
+            "file not found".bright_black()
+        );
+        colored::control::unset_override();
+
+        assert_eq!(line, "Error: file not found");
+        assert!(!line.contains('\x1b'));
+    }
+}
@@ -0,0 +1,24 @@
+//! Shared pieces used by every mini-code binary (v0–v5)
+//!
+//! Features:
+//! - `todo`: [`todo::TodoManager`] for the binaries with a `TodoWrite` tool
+//! - `cancel`: [`cancel::run_bash_cancellable`], v4's killable `bash`
+//! - `locking`: [`locking::run_write_locked`] / [`locking::run_edit_locked`]
+//! - `archive`: [`archive::run_read_with`], v4's archive-aware `read_file`
+//!
+//! Features only add functions, so enabling one for v4 never changes what
+//! v1–v3 get from [`tools`].
+
+pub mod api;
+#[cfg(feature = "archive")]
+pub mod archive;
+#[cfg(feature = "cancel")]
+pub mod cancel;
+pub mod color;
+#[cfg(feature = "locking")]
+pub mod locking;
+pub mod model;
+#[cfg(feature = "todo")]
+pub mod todo;
+pub mod tokens;
+pub mod tools;
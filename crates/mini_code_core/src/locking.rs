@@ -0,0 +1,397 @@
+//! Locked Writes
+//!
+//! v4 runs tools concurrently, from the main agent and from subagents. Each
+//! resolved path gets a process-wide lock, and an edit only writes if the
+//! file still matches what it read, so a change made meanwhile (by a `bash`
+//! command or an editor) is reported instead of overwritten.
+
+use crate::tools::safe_path;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::SystemTime;
+
+/// Per-path advisory locks shared by every writer in the process (the main
+/// agent and any subagents), so writes to the same file serialize.
+static WRITE_LOCKS: LazyLock<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// The lock guarding writes to `path` (already resolved by safe_path)
+pub fn write_lock(path: &Path) -> Arc<Mutex<()>> {
+    WRITE_LOCKS
+        .lock()
+        .unwrap()
+        .entry(path.to_path_buf())
+        .or_default()
+        .clone()
+}
+
+/// Returned when the file moved under an edit between its read and write
+pub const CHANGED_DURING_EDIT: &str = "Error: file changed concurrently, re-read it";
+
+/// What an edit saw when it read the file
+#[derive(Debug, PartialEq)]
+pub struct ReadSnapshot {
+    pub exists: bool,
+    pub modified: Option<SystemTime>,
+    pub content: String,
+}
+
+impl ReadSnapshot {
+    pub fn take(path: &Path) -> io::Result<Self> {
+        match fs::metadata(path) {
+            Ok(meta) => Ok(Self {
+                exists: true,
+                modified: meta.modified().ok(),
+                content: fs::read_to_string(path)?,
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self {
+                exists: false,
+                modified: None,
+                content: String::new(),
+            }),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Write `new` only if the file is still exactly as the edit read it.
+///
+/// Catches writers outside the lock, e.g. a bash command or an editor. A
+/// file that existed is opened without `create`, so one deleted or renamed
+/// in the meantime is never silently recreated.
+pub fn write_if_unchanged(path: &Path, read: &ReadSnapshot, new: &str) -> Result<(), String> {
+    match ReadSnapshot::take(path) {
+        Ok(current) if current == *read => {}
+        _ => return Err(CHANGED_DURING_EDIT.to_string()),
+    }
+
+    let mut options = fs::OpenOptions::new();
+    if read.exists {
+        options.write(true).truncate(true);
+    } else {
+        options.write(true).create_new(true);
+    }
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(new.as_bytes()))
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound | io::ErrorKind::AlreadyExists => {
+                CHANGED_DURING_EDIT.to_string()
+            }
+            _ => format!("Error: {}", e),
+        })
+}
+
+/// Apply write_file's `line_ending` / `ensure_trailing_newline` options.
+///
+/// With no `line_ending` the content's own endings are kept; an added
+/// trailing newline then matches them (CRLF if the content uses any).
+fn format_line_endings(
+    content: &str,
+    line_ending: Option<&str>,
+    ensure_trailing_newline: bool,
+) -> Result<String, String> {
+    let mut content = match line_ending {
+        None => content.to_string(),
+        Some("lf") => content.replace("\r\n", "\n"),
+        Some("crlf") => content.replace("\r\n", "\n").replace('\n', "\r\n"),
+        Some(other) => {
+            return Err(format!(
+                "Error: line_ending must be \"lf\" or \"crlf\", got \"{}\"",
+                other
+            ))
+        }
+    };
+
+    if ensure_trailing_newline && !content.is_empty() && !content.ends_with('\n') {
+        let crlf =
+            line_ending == Some("crlf") || (line_ending.is_none() && content.contains("\r\n"));
+        content.push_str(if crlf { "\r\n" } else { "\n" });
+    }
+    Ok(content)
+}
+
+/// Count of (added, removed) lines between two versions of a file
+pub fn diff_stat(old: &str, new: &str) -> (usize, usize) {
+    let diff = similar::TextDiff::from_lines(old, new);
+    diff.iter_all_changes()
+        .fold((0, 0), |(added, removed), change| match change.tag() {
+            similar::ChangeTag::Insert => (added + 1, removed),
+            similar::ChangeTag::Delete => (added, removed + 1),
+            similar::ChangeTag::Equal => (added, removed),
+        })
+}
+
+/// Write content to a file under its write lock, creating parent directories.
+///
+/// `line_ending` ("lf"/"crlf") and `ensure_trailing_newline` normalize the
+/// content first; an overwrite reports how many lines changed.
+pub fn run_write_locked(
+    workdir: &Path,
+    path: &str,
+    content: &str,
+    line_ending: Option<&str>,
+    ensure_trailing_newline: bool,
+) -> String {
+    let content = match format_line_endings(content, line_ending, ensure_trailing_newline) {
+        Ok(content) => content,
+        Err(e) => return e,
+    };
+    let content = content.as_str();
+
+    match safe_path(workdir, path) {
+        Ok(safe_path) => {
+            let lock = write_lock(&safe_path);
+            let _guard = lock.lock().unwrap();
+
+            if let Some(parent) = safe_path.parent() {
+                if let Err(e) = fs::create_dir_all(parent) {
+                    return format!("Error creating parent directories: {}", e);
+                }
+            }
+
+            let previous = fs::read_to_string(&safe_path).ok();
+            match fs::write(&safe_path, content) {
+                Ok(_) => match previous {
+                    Some(previous) => {
+                        let (added, removed) = diff_stat(&previous, content);
+                        format!(
+                            "Wrote {} bytes to {} (+{}/-{} lines)",
+                            content.len(),
+                            path,
+                            added,
+                            removed
+                        )
+                    }
+                    None => format!("Wrote {} bytes to {}", content.len(), path),
+                },
+                Err(e) => format!("Error: {}", e),
+            }
+        }
+        Err(e) => format!("Error: {}", e),
+    }
+}
+
+/// Replace the first occurrence of `old_text` under the file's write lock.
+///
+/// The write only lands if the file is unchanged since it was read. An empty
+/// `old_text` creates the file, if it is missing or empty.
+pub fn run_edit_locked(workdir: &Path, path: &str, old_text: &str, new_text: &str) -> String {
+    let safe_path = match safe_path(workdir, path) {
+        Ok(p) => p,
+        Err(e) => return format!("Error: {}", e),
+    };
+    let lock = write_lock(&safe_path);
+    let _guard = lock.lock().unwrap();
+
+    if old_text.is_empty() {
+        // Empty old_text means "create": only allowed for missing or empty files
+        let existing = match ReadSnapshot::take(&safe_path) {
+            Ok(snapshot) => snapshot,
+            Err(e) => return format!("Error: {}", e),
+        };
+        if !existing.content.is_empty() {
+            return format!("Error: old_text is empty but {} already has content", path);
+        }
+
+        if let Some(parent) = safe_path.parent() {
+            if let Err(e) = fs::create_dir_all(parent) {
+                return format!("Error creating parent directories: {}", e);
+            }
+        }
+
+        return match write_if_unchanged(&safe_path, &existing, new_text) {
+            Ok(_) => format!("Created {}", path),
+            Err(e) => e,
+        };
+    }
+
+    match ReadSnapshot::take(&safe_path) {
+        Ok(read) if !read.exists => format!("Error: {} does not exist", path),
+        Ok(read) => {
+            if !read.content.contains(old_text) {
+                return format!("Error: Text not found in {}", path);
+            }
+
+            let new_content = read.content.replacen(old_text, new_text, 1);
+
+            match write_if_unchanged(&safe_path, &read, &new_content) {
+                Ok(_) => format!("Edited {}", path),
+                Err(e) => e,
+            }
+        }
+        Err(e) => format!("Error: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::thread;
+
+    #[test]
+    fn test_concurrent_edits_to_one_path_serialize() {
+        let dir = env::temp_dir().join(format!("core_write_lock_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        fs::write(dir.join("shared.txt"), "END\n").unwrap();
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let dir = dir.clone();
+                thread::spawn(move || {
+                    run_edit_locked(&dir, "shared.txt", "END", &format!("line {}\nEND", i))
+                })
+            })
+            .collect();
+        let results: Vec<String> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        let content = fs::read_to_string(dir.join("shared.txt")).unwrap();
+
+        // A stale read is rejected rather than clobbering the newer content
+        let stale_read = ReadSnapshot {
+            exists: true,
+            modified: fs::metadata(dir.join("shared.txt"))
+                .unwrap()
+                .modified()
+                .ok(),
+            content: "END\n".to_string(),
+        };
+        let stale = write_if_unchanged(&dir.join("shared.txt"), &stale_read, "oops");
+        fs::remove_dir_all(&dir).ok();
+
+        assert!(
+            results.iter().all(|r| r == "Edited shared.txt"),
+            "{:?}",
+            results
+        );
+        for i in 0..8 {
+            assert!(content.contains(&format!("line {}\n", i)), "{}", content);
+        }
+        assert_eq!(
+            stale,
+            Err("Error: file changed concurrently, re-read it".to_string())
+        );
+    }
+
+    #[test]
+    fn test_edit_rejects_file_removed_between_read_and_write() {
+        let dir = env::temp_dir().join(format!("core_edit_removed_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let deleted = dir.join("deleted.txt");
+        let renamed = dir.join("renamed.txt");
+        fs::write(&deleted, "old content\n").unwrap();
+        fs::write(&renamed, "old content\n").unwrap();
+
+        // Read as run_edit would, then lose the file to a concurrent command
+        let deleted_read = ReadSnapshot::take(&deleted).unwrap();
+        let renamed_read = ReadSnapshot::take(&renamed).unwrap();
+        fs::remove_file(&deleted).unwrap();
+        fs::rename(&renamed, dir.join("moved.txt")).unwrap();
+        let after_delete = write_if_unchanged(&deleted, &deleted_read, "new content\n");
+        let after_rename = write_if_unchanged(&renamed, &renamed_read, "new content\n");
+        let deleted_exists = deleted.exists();
+        let renamed_exists = renamed.exists();
+
+        // A file created by someone else after a "create" read is not clobbered
+        let created = dir.join("created.txt");
+        let created_read = ReadSnapshot::take(&created).unwrap();
+        fs::write(&created, "theirs\n").unwrap();
+        let after_create = write_if_unchanged(&created, &created_read, "mine\n");
+        let created_content = fs::read_to_string(&created).unwrap();
+        let missing = run_edit_locked(&dir, "deleted.txt", "old", "new");
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(after_delete, Err(CHANGED_DURING_EDIT.to_string()));
+        assert_eq!(after_rename, Err(CHANGED_DURING_EDIT.to_string()));
+        assert!(!deleted_exists && !renamed_exists);
+        assert_eq!(after_create, Err(CHANGED_DURING_EDIT.to_string()));
+        assert_eq!(created_content, "theirs\n");
+        assert_eq!(missing, "Error: deleted.txt does not exist");
+    }
+
+    #[test]
+    fn test_write_line_endings() {
+        let dir = env::temp_dir().join(format!("core_write_eol_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+
+        run_write_locked(&dir, "crlf.txt", "a\nb\r\nc", Some("crlf"), true);
+        run_write_locked(&dir, "lf.txt", "a\r\nb\r\n", Some("lf"), false);
+        run_write_locked(&dir, "kept.txt", "a\r\nb", None, true);
+        run_write_locked(&dir, "raw.txt", "a\r\nb", None, false);
+        let bad = run_write_locked(&dir, "bad.txt", "a", Some("cr"), false);
+
+        let read = |name: &str| fs::read(dir.join(name)).unwrap();
+        assert_eq!(read("crlf.txt"), b"a\r\nb\r\nc\r\n");
+        assert_eq!(read("lf.txt"), b"a\nb\n");
+        assert_eq!(read("kept.txt"), b"a\r\nb\r\n");
+        assert_eq!(read("raw.txt"), b"a\r\nb");
+        assert!(bad.starts_with("Error: line_ending"));
+        assert!(!dir.join("bad.txt").exists());
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_write_reports_diff_stat_on_overwrite() {
+        let dir = env::temp_dir().join(format!("core_write_stat_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+
+        let original: String = (1..=10).map(|i| format!("line {}\n", i)).collect();
+        let created = run_write_locked(&dir, "f.txt", &original, None, false);
+        assert_eq!(created, format!("Wrote {} bytes to f.txt", original.len()));
+
+        // Drop lines 9-10 and rewrite line 3: one added, three removed
+        let shorter: String = (1..=8)
+            .map(|i| match i {
+                3 => "line three\n".to_string(),
+                i => format!("line {}\n", i),
+            })
+            .collect();
+        let overwritten = run_write_locked(&dir, "f.txt", &shorter, None, false);
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(
+            overwritten,
+            format!("Wrote {} bytes to f.txt (+1/-3 lines)", shorter.len())
+        );
+    }
+
+    #[test]
+    fn test_run_edit_creates_file_with_empty_old_text() {
+        let dir = env::temp_dir().join(format!("core_edit_create_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+
+        let created = run_edit_locked(&dir, "nested/new.txt", "", "hello\n");
+        let content = fs::read_to_string(dir.join("nested/new.txt")).unwrap_or_default();
+        let again = run_edit_locked(&dir, "nested/new.txt", "", "clobber");
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(created, "Created nested/new.txt");
+        assert_eq!(content, "hello\n");
+        assert!(again.starts_with("Error:"));
+    }
+
+    #[test]
+    fn test_run_edit_missing_file_with_old_text() {
+        let dir = env::temp_dir().join(format!("core_edit_missing_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let result = run_edit_locked(&dir, "missing.txt", "needle", "replacement");
+        let exists = dir.join("missing.txt").exists();
+        fs::remove_dir_all(&dir).ok();
+
+        assert!(result.starts_with("Error:"));
+        assert!(!exists);
+    }
+}
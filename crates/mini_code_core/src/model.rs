@@ -0,0 +1,118 @@
+//! Model Selection
+//!
+//! Every binary resolves its model the same way:
+//!
+//! 1. `MODEL_NAME` set in the shell environment
+//! 2. `MODEL_NAME` from the config file (`.env`, or `--env-file` where
+//!    supported), which never overrides a variable already set in the shell
+//! 3. [`DEFAULT_MODEL`]
+//!
+//! Steps 1 and 2 both surface as the `MODEL_NAME` environment variable once
+//! the config file is loaded, so binaries only call [`resolve_model`].
+//!
+//! The optional sampling settings are checked here too, by
+//! [`parse_sampling_param`], and added to requests by [`apply_sampling`].
+
+use anthropic::types::MessagesRequestBuilder;
+use anyhow::Result;
+use std::env;
+
+/// Model used when `MODEL_NAME` is not configured
+pub const DEFAULT_MODEL: &str = "claude-sonnet-4-5-20250929";
+
+/// Resolve the model from `MODEL_NAME`, falling back to [`DEFAULT_MODEL`]
+pub fn resolve_model() -> String {
+    model_from(env::var("MODEL_NAME").ok())
+}
+
+/// Fallback rule behind [`resolve_model`]; a blank value counts as unset
+pub fn model_from(value: Option<String>) -> String {
+    value
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .unwrap_or_else(|| DEFAULT_MODEL.to_string())
+}
+
+/// Where the resolved model came from, for `/config`
+pub fn model_source() -> &'static str {
+    match env::var("MODEL_NAME") {
+        Ok(v) if !v.trim().is_empty() => "MODEL_NAME",
+        _ => "default",
+    }
+}
+
+/// Parse an optional sampling parameter (`MINI_CODE_TEMPERATURE`,
+/// `MINI_CODE_TOP_P`), rejecting values outside 0.0..=1.0. Blank is unset.
+pub fn parse_sampling_param(name: &str, value: Option<String>) -> Result<Option<f32>> {
+    let raw = match value {
+        Some(raw) if !raw.trim().is_empty() => raw,
+        _ => return Ok(None),
+    };
+
+    match raw.trim().parse::<f32>() {
+        Ok(v) if (0.0..=1.0).contains(&v) => Ok(Some(v)),
+        _ => anyhow::bail!(
+            "{} must be a number between 0.0 and 1.0, got '{}'",
+            name,
+            raw
+        ),
+    }
+}
+
+/// Set temperature / top_p on a request; `None` keeps the API default
+pub fn apply_sampling(
+    mut builder: MessagesRequestBuilder,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+) -> MessagesRequestBuilder {
+    if let Some(temperature) = temperature {
+        builder = builder.temperature(temperature);
+    }
+    if let Some(top_p) = top_p {
+        builder = builder.top_p(top_p);
+    }
+    builder
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_from_falls_back_to_default() {
+        assert_eq!(model_from(None), DEFAULT_MODEL);
+        assert_eq!(model_from(Some("  ".to_string())), DEFAULT_MODEL);
+        assert_eq!(
+            model_from(Some(" claude-opus-4-5-20251101 ".to_string())),
+            "claude-opus-4-5-20251101"
+        );
+    }
+
+    #[test]
+    fn test_resolve_model_without_env_uses_default() {
+        // Every binary resolves through here, so this pins them to one default
+        env::remove_var("MODEL_NAME");
+        assert_eq!(resolve_model(), DEFAULT_MODEL);
+        assert_eq!(model_source(), "default");
+    }
+
+    #[test]
+    fn test_parse_sampling_param() {
+        assert_eq!(parse_sampling_param("T", None).unwrap(), None);
+        assert_eq!(
+            parse_sampling_param("T", Some("".to_string())).unwrap(),
+            None
+        );
+        assert_eq!(
+            parse_sampling_param("T", Some("0.0".to_string())).unwrap(),
+            Some(0.0)
+        );
+        assert_eq!(
+            parse_sampling_param("T", Some(" 0.7 ".to_string())).unwrap(),
+            Some(0.7)
+        );
+        assert!(parse_sampling_param("T", Some("1.5".to_string())).is_err());
+        assert!(parse_sampling_param("T", Some("-0.1".to_string())).is_err());
+        assert!(parse_sampling_param("T", Some("hot".to_string())).is_err());
+    }
+}
@@ -0,0 +1,244 @@
+//! Todo List
+//!
+//! The structured task list behind the `TodoWrite` tool (v2 onward). The
+//! model sends the whole list on every call; [`TodoManager::update`]
+//! validates it, stores it and returns the rendered view the model sees.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+
+/// Most items a list may hold
+pub const MAX_TODOS: usize = 20;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TodoItem {
+    pub content: String,
+    pub status: TodoStatus,
+    /// Present-tense form shown while the item is in progress
+    #[serde(rename = "activeForm")]
+    pub active_form: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum TodoStatus {
+    Pending,
+    InProgress,
+    Completed,
+}
+
+/// How `TodoManager::render` marks each item (MINI_CODE_TODO_STYLE in v4)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum TodoRenderStyle {
+    /// `[x]` / `[>]` / `[ ]`
+    #[default]
+    Ascii,
+    /// ✅ / 🔄 / ⬜
+    Emoji,
+    /// `- [x]` checklist, ready to paste into a Markdown document
+    Markdown,
+}
+
+impl TodoRenderStyle {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "" | "ascii" => Ok(Self::Ascii),
+            "emoji" => Ok(Self::Emoji),
+            "markdown" | "md" => Ok(Self::Markdown),
+            other => anyhow::bail!(
+                "Invalid MINI_CODE_TODO_STYLE '{}': expected ascii, emoji or markdown",
+                other
+            ),
+        }
+    }
+
+    pub fn line(self, item: &TodoItem) -> String {
+        match (self, &item.status) {
+            (Self::Ascii, TodoStatus::Completed) => format!("[x] {}", item.content),
+            (Self::Ascii, TodoStatus::InProgress) => {
+                format!("[>] {} <- {}", item.content, item.active_form)
+            }
+            (Self::Ascii, TodoStatus::Pending) => format!("[ ] {}", item.content),
+            (Self::Emoji, TodoStatus::Completed) => format!("✅ {}", item.content),
+            (Self::Emoji, TodoStatus::InProgress) => {
+                format!("🔄 {} <- {}", item.content, item.active_form)
+            }
+            (Self::Emoji, TodoStatus::Pending) => format!("⬜ {}", item.content),
+            (Self::Markdown, TodoStatus::Completed) => format!("- [x] {}", item.content),
+            (Self::Markdown, TodoStatus::InProgress) => {
+                format!("- [ ] **{}** _({})_", item.content, item.active_form)
+            }
+            (Self::Markdown, TodoStatus::Pending) => format!("- [ ] {}", item.content),
+        }
+    }
+}
+
+/// Manages a structured task list with enforced constraints.
+///
+/// 1. Max [`MAX_TODOS`] items: prevents endless lists
+/// 2. One in_progress: forces focus on ONE thing at a time
+/// 3. Required fields: each item needs content, status and activeForm
+pub struct TodoManager {
+    items: Arc<Mutex<Vec<TodoItem>>>,
+    style: TodoRenderStyle,
+}
+
+impl Default for TodoManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TodoManager {
+    pub fn new() -> Self {
+        Self::with_style(TodoRenderStyle::Ascii)
+    }
+
+    pub fn with_style(style: TodoRenderStyle) -> Self {
+        Self {
+            items: Arc::new(Mutex::new(Vec::new())),
+            style,
+        }
+    }
+
+    /// Validate and replace the list, returning the rendered view
+    pub fn update(&self, new_items: Vec<TodoItem>) -> Result<String> {
+        let mut in_progress_count = 0;
+
+        for (i, item) in new_items.iter().enumerate() {
+            if item.content.trim().is_empty() {
+                anyhow::bail!("Item {}: content required", i);
+            }
+            if item.active_form.trim().is_empty() {
+                anyhow::bail!("Item {}: activeForm required", i);
+            }
+            if item.status == TodoStatus::InProgress {
+                in_progress_count += 1;
+            }
+        }
+
+        if new_items.len() > MAX_TODOS {
+            anyhow::bail!("Max {} todos allowed", MAX_TODOS);
+        }
+        if in_progress_count > 1 {
+            anyhow::bail!("Only one task can be in_progress at a time");
+        }
+
+        *self.items.lock().unwrap() = new_items;
+
+        Ok(self.render())
+    }
+
+    pub fn has_in_progress(&self) -> bool {
+        self.items
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|t| t.status == TodoStatus::InProgress)
+    }
+
+    /// Items that are still pending or in progress
+    pub fn incomplete(&self) -> Vec<String> {
+        self.items
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|t| t.status != TodoStatus::Completed)
+            .map(|t| t.content.clone())
+            .collect()
+    }
+
+    /// Render the list, one line per item, then `(done/total completed)`
+    pub fn render(&self) -> String {
+        let items = self.items.lock().unwrap();
+
+        if items.is_empty() {
+            return "No todos.".to_string();
+        }
+
+        let mut lines = Vec::new();
+        for item in items.iter() {
+            lines.push(self.style.line(item));
+        }
+
+        let completed = items
+            .iter()
+            .filter(|t| t.status == TodoStatus::Completed)
+            .count();
+        lines.push(format!("\n({}/{} completed)", completed, items.len()));
+
+        lines.join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(content: &str, status: TodoStatus) -> TodoItem {
+        TodoItem {
+            content: content.to_string(),
+            status,
+            active_form: format!("Doing {}", content),
+        }
+    }
+
+    #[test]
+    fn test_update_enforces_constraints() {
+        let manager = TodoManager::new();
+        assert_eq!(manager.render(), "No todos.");
+
+        let two_active = manager.update(vec![
+            item("a", TodoStatus::InProgress),
+            item("b", TodoStatus::InProgress),
+        ]);
+        assert!(two_active
+            .unwrap_err()
+            .to_string()
+            .contains("Only one task"));
+
+        let too_many = (0..=MAX_TODOS)
+            .map(|i| item(&i.to_string(), TodoStatus::Pending))
+            .collect();
+        assert!(manager.update(too_many).is_err());
+
+        let mut blank = item("a", TodoStatus::Pending);
+        blank.active_form = " ".to_string();
+        assert_eq!(
+            manager.update(vec![blank]).unwrap_err().to_string(),
+            "Item 0: activeForm required"
+        );
+
+        // Rejected updates leave the list untouched
+        assert_eq!(manager.render(), "No todos.");
+    }
+
+    #[test]
+    fn test_render_styles_and_progress() {
+        let items = vec![
+            item("parse", TodoStatus::Completed),
+            item("test", TodoStatus::InProgress),
+            item("ship", TodoStatus::Pending),
+        ];
+
+        let ascii = TodoManager::new();
+        assert_eq!(
+            ascii.update(items.clone()).unwrap(),
+            "[x] parse\n[>] test <- Doing test\n[ ] ship\n\n(1/3 completed)"
+        );
+        assert!(ascii.has_in_progress());
+        assert_eq!(ascii.incomplete(), vec!["test", "ship"]);
+
+        let markdown = TodoManager::with_style(TodoRenderStyle::Markdown);
+        assert!(markdown
+            .update(items)
+            .unwrap()
+            .starts_with("- [x] parse\n- [ ] **test** _(Doing test)_"));
+        assert_eq!(
+            TodoRenderStyle::parse("md").unwrap(),
+            TodoRenderStyle::Markdown
+        );
+        assert!(TodoRenderStyle::parse("fancy").is_err());
+    }
+}
@@ -0,0 +1,71 @@
+//! Output Token Budget
+//!
+//! How many tokens a request may ask for, and how often a truncated reply is
+//! retried. Shared by the agents that size `max_tokens` from the context
+//! they send instead of hardcoding it.
+
+use std::env;
+
+/// Claude's context window in tokens
+pub const CONTEXT_WINDOW: usize = 200_000;
+
+/// Floor for `max_tokens`, however full the context is
+pub const MIN_OUTPUT_TOKENS: u32 = 4000;
+
+/// Share of the remaining window a reply may use
+const OUTPUT_RATIO: f64 = 0.4;
+
+/// `MINI_CODE_MAX_OUTPUT_TOKENS` when unset
+pub const DEFAULT_MAX_OUTPUT_TOKENS: u32 = 160_000;
+
+/// `MINI_CODE_MAX_TRUNCATION_RETRIES` when unset
+pub const DEFAULT_MAX_TRUNCATION_RETRIES: usize = 3;
+
+/// Rough token count for `chars` bytes of text (~4 chars per token)
+pub fn estimate_tokens(chars: usize) -> usize {
+    chars / 4
+}
+
+/// `max_tokens` for a request whose context is about `context_tokens` long:
+/// 40% of what is left of the window, at least [`MIN_OUTPUT_TOKENS`] and at
+/// most `max_configured`.
+pub fn calculate_max_tokens(context_tokens: usize, max_configured: u32) -> u32 {
+    let available = CONTEXT_WINDOW.saturating_sub(context_tokens);
+    let max_output = (available as f64 * OUTPUT_RATIO) as u32;
+
+    max_output.max(MIN_OUTPUT_TOKENS).min(max_configured)
+}
+
+/// `MINI_CODE_MAX_OUTPUT_TOKENS`, clamped to 1000..=100M
+pub fn max_output_tokens() -> u32 {
+    env::var("MINI_CODE_MAX_OUTPUT_TOKENS")
+        .ok()
+        .and_then(|s| s.parse::<u32>().ok())
+        .unwrap_or(DEFAULT_MAX_OUTPUT_TOKENS)
+        .clamp(1000, 100_000_000)
+}
+
+/// `MINI_CODE_MAX_TRUNCATION_RETRIES`, clamped to 1..=10
+pub fn max_truncation_retries() -> usize {
+    env::var("MINI_CODE_MAX_TRUNCATION_RETRIES")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MAX_TRUNCATION_RETRIES)
+        .clamp(1, 10)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calculate_max_tokens_bounds() {
+        // Empty context: 40% of the window, capped by the configured maximum
+        assert_eq!(calculate_max_tokens(0, 160_000), 80_000);
+        assert_eq!(calculate_max_tokens(0, 8000), 8000);
+        // Nearly full context still leaves the floor
+        assert_eq!(calculate_max_tokens(199_000, 160_000), MIN_OUTPUT_TOKENS);
+        assert_eq!(calculate_max_tokens(500_000, 160_000), MIN_OUTPUT_TOKENS);
+        assert_eq!(calculate_max_tokens(100_000, 160_000), 40_000);
+    }
+}
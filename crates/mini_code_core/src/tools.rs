@@ -0,0 +1,523 @@
+//! Tool Implementations
+//!
+//! The file and shell tools every agent exposes (`bash`, `read_file`,
+//! `write_file`, `edit_file`) plus the helpers they share. Each tool returns
+//! the string the model sees; failures come back as `Error: ...` text rather
+//! than `Err`, so a bad call never ends the agent loop.
+//!
+//! v4's cancellable bash, locked writes and archive-aware read build on these
+//! helpers in the `cancel`, `locking` and `archive` modules (one feature each).
+
+use anyhow::Result;
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Tool output beyond this many bytes is cut off with `...`
+pub const MAX_TOOL_OUTPUT: usize = 50_000;
+
+/// Substrings that get a `bash` command refused outright
+pub const DANGEROUS_COMMANDS: &[&str] = &["rm -rf /", "sudo", "shutdown", "reboot", "> /dev/"];
+
+/// `MINI_CODE_MAX_SYMLINK_DEPTH` when unset (Linux's own limit)
+pub const DEFAULT_MAX_SYMLINK_DEPTH: usize = 40;
+
+/// `MINI_CODE_MAX_SYMLINK_DEPTH`: symlinks one path may pass through, 1..=1000
+pub fn max_symlink_depth() -> usize {
+    env::var("MINI_CODE_MAX_SYMLINK_DEPTH")
+        .ok()
+        .and_then(|s| s.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MAX_SYMLINK_DEPTH)
+        .clamp(1, 1000)
+}
+
+/// Safely truncate a string at a UTF-8 character boundary.
+///
+/// Unlike `&s[..n]` which panics if n is not at a character boundary,
+/// this function finds the largest valid boundary <= max_bytes.
+pub fn safe_truncate(s: &str, max_bytes: usize) -> &str {
+    if s.len() <= max_bytes {
+        return s;
+    }
+
+    let mut boundary = max_bytes;
+    while boundary > 0 && !s.is_char_boundary(boundary) {
+        boundary -= 1;
+    }
+
+    &s[..boundary]
+}
+
+/// `MINI_CODE_SAVE_TRUNCATED_DIR`: where cut-off tool output is kept in full
+pub fn save_truncated_dir() -> Option<PathBuf> {
+    env::var("MINI_CODE_SAVE_TRUNCATED_DIR")
+        .ok()
+        .filter(|s| !s.trim().is_empty())
+        .map(PathBuf::from)
+}
+
+/// Cut `output` to [`MAX_TOOL_OUTPUT`] bytes, marking the cut with `...`.
+///
+/// With `MINI_CODE_SAVE_TRUNCATED_DIR` set the full output is saved there
+/// first and the marker names the file, so the rest can be read on demand.
+pub fn cap_output(output: String) -> String {
+    cap_output_to(output, save_truncated_dir().as_deref(), None)
+}
+
+/// [`cap_output`] that runs the full output through `redact` before saving
+/// it, so nothing reaches disk that the model wouldn't see
+pub fn cap_output_redacted(output: String, redact: &dyn Fn(&str) -> String) -> String {
+    cap_output_to(output, save_truncated_dir().as_deref(), Some(redact))
+}
+
+/// [`cap_output`] saving the full output under `save_dir` instead of the
+/// directory from the environment, through `redact` when given
+pub fn cap_output_to(
+    output: String,
+    save_dir: Option<&Path>,
+    redact: Option<&dyn Fn(&str) -> String>,
+) -> String {
+    if output.len() <= MAX_TOOL_OUTPUT {
+        return output;
+    }
+    let head = safe_truncate(&output, MAX_TOOL_OUTPUT);
+    let saved = save_dir.map(|dir| match redact {
+        Some(redact) => save_full_output(dir, &redact(&output)),
+        None => save_full_output(dir, &output),
+    });
+    match saved {
+        Some(Ok(path)) => format!("{}... (truncated; full output at {})", head, path.display()),
+        Some(Err(e)) => format!("{}... (truncated; could not save full output: {})", head, e),
+        None => format!("{}...", head),
+    }
+}
+
+/// Write `output` to a new timestamped file in `dir`
+fn save_full_output(dir: &Path, output: &str) -> Result<PathBuf> {
+    // Tools run concurrently; the counter keeps same-millisecond saves apart
+    static SAVED: AtomicUsize = AtomicUsize::new(0);
+
+    fs::create_dir_all(dir)?;
+    let millis = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or(0);
+    let path = dir.join(format!(
+        "output-{}-{}-{}.txt",
+        millis,
+        std::process::id(),
+        SAVED.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&path, output)?;
+    Ok(path)
+}
+
+/// Fail fast if the workspace was deleted or moved while the agent runs
+pub fn check_workdir(workdir: &Path) -> Result<()> {
+    if workdir.is_dir() {
+        Ok(())
+    } else {
+        anyhow::bail!(
+            "Workspace {} no longer exists (deleted or moved?); restart in a valid directory",
+            workdir.display()
+        )
+    }
+}
+
+/// Like [`fs::canonicalize`], but fails once more than `max_links` symlinks
+/// have been followed instead of leaving long chains and loops to the OS.
+///
+/// Every component must exist.
+pub fn canonicalize_bounded(path: &Path, max_links: usize) -> Result<PathBuf> {
+    let absolute = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        env::current_dir()?.join(path)
+    };
+
+    // Components still to resolve, next one last
+    let mut pending: Vec<PathBuf> = absolute
+        .components()
+        .rev()
+        .map(|c| PathBuf::from(c.as_os_str()))
+        .collect();
+    let mut resolved = PathBuf::new();
+    let mut links = 0;
+
+    while let Some(part) = pending.pop() {
+        match part.components().next() {
+            Some(Component::Prefix(_)) => resolved = part,
+            Some(Component::RootDir) => resolved.push(part),
+            Some(Component::ParentDir) => {
+                resolved.pop();
+            }
+            Some(Component::Normal(name)) => {
+                let candidate = resolved.join(name);
+                if !fs::symlink_metadata(&candidate)?.file_type().is_symlink() {
+                    resolved = candidate;
+                    continue;
+                }
+
+                links += 1;
+                if links > max_links {
+                    anyhow::bail!(
+                        "Too many levels of symbolic links in {} (limit {}, see MINI_CODE_MAX_SYMLINK_DEPTH)",
+                        path.display(),
+                        max_links
+                    );
+                }
+                let target = fs::read_link(&candidate)?;
+                if target.is_absolute() {
+                    resolved = PathBuf::new();
+                }
+                pending.extend(
+                    target
+                        .components()
+                        .rev()
+                        .map(|c| PathBuf::from(c.as_os_str())),
+                );
+            }
+            Some(Component::CurDir) | None => {}
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Ensure path stays within workspace (security measure).
+///
+/// The deepest existing ancestor is canonicalized, so paths into
+/// not-yet-created directories still resolve and can't hide a `..`.
+/// Symlinks are followed at most [`max_symlink_depth`] deep.
+pub fn safe_path(workdir: &Path, relative_path: &str) -> Result<PathBuf> {
+    safe_path_with_depth(workdir, relative_path, max_symlink_depth())
+}
+
+/// [`safe_path`] with an explicit symlink depth limit
+pub fn safe_path_with_depth(
+    workdir: &Path,
+    relative_path: &str,
+    max_links: usize,
+) -> Result<PathBuf> {
+    check_workdir(workdir)?;
+    let path = workdir.join(relative_path);
+
+    let mut existing = path.as_path();
+    let mut missing = Vec::new();
+    // A dangling or looping symlink counts as existing, so it is resolved (and
+    // rejected) rather than treated as a file still to be created
+    while fs::symlink_metadata(existing).is_err() {
+        missing.push(
+            existing
+                .file_name()
+                .ok_or_else(|| anyhow::anyhow!("Invalid path"))?,
+        );
+        existing = existing
+            .parent()
+            .ok_or_else(|| anyhow::anyhow!("Invalid path"))?;
+    }
+
+    let mut canonical = canonicalize_bounded(existing, max_links)?;
+    for part in missing.iter().rev() {
+        canonical.push(part);
+    }
+
+    if !canonical.starts_with(workdir) {
+        anyhow::bail!("Path escapes workspace: {}", relative_path);
+    }
+
+    Ok(canonical)
+}
+
+/// True if `command` contains one of [`DANGEROUS_COMMANDS`]
+pub fn is_dangerous_command(command: &str) -> bool {
+    DANGEROUS_COMMANDS.iter().any(|d| command.contains(d))
+}
+
+/// Execute shell command with safety checks.
+///
+/// Blocks obviously dangerous commands; stdout and stderr are combined and
+/// capped at [`MAX_TOOL_OUTPUT`].
+pub fn run_bash(workdir: &Path, command: &str) -> String {
+    if is_dangerous_command(command) {
+        return "Error: Dangerous command blocked".to_string();
+    }
+
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .current_dir(workdir)
+        .output();
+
+    match output {
+        Ok(output) => {
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let combined = format!("{}{}", stdout, stderr).trim().to_string();
+
+            if combined.is_empty() {
+                "(no output)".to_string()
+            } else {
+                cap_output(combined)
+            }
+        }
+        Err(e) => format!("Error: {}", e),
+    }
+}
+
+/// File bytes as text; invalid UTF-8 (binary bits in logs) becomes U+FFFD
+/// instead of failing the whole read.
+pub fn decode_lossy(bytes: Vec<u8>) -> String {
+    match String::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(e) => String::from_utf8_lossy(e.as_bytes()).into_owned(),
+    }
+}
+
+/// Keep the first `limit` lines, noting how many were left out.
+/// A missing or non-positive limit keeps everything.
+pub fn limit_lines(content: String, limit: Option<i64>) -> String {
+    let total_lines = content.lines().count();
+    match limit {
+        Some(limit) if limit > 0 && (limit as usize) < total_lines => {
+            let kept: Vec<&str> = content.lines().take(limit as usize).collect();
+            format!(
+                "{}\n... ({} more lines)",
+                kept.join("\n"),
+                total_lines - limit as usize
+            )
+        }
+        _ => content,
+    }
+}
+
+/// Read file contents with optional line limit.
+///
+/// For large files, use limit to read just the first N lines.
+/// Output is capped at [`MAX_TOOL_OUTPUT`].
+pub fn run_read(workdir: &Path, path: &str, limit: Option<i64>) -> String {
+    let safe_path = match safe_path(workdir, path) {
+        Ok(p) => p,
+        Err(e) => return format!("Error: {}", e),
+    };
+    match fs::read(&safe_path) {
+        Ok(bytes) => cap_output(limit_lines(decode_lossy(bytes), limit)),
+        Err(e) => format!("Error reading file: {}", e),
+    }
+}
+
+/// Write content to file, creating parent directories if needed.
+///
+/// This is for complete file creation/overwrite.
+/// For partial edits, use edit_file instead.
+pub fn run_write(workdir: &Path, path: &str, content: &str) -> String {
+    match safe_path(workdir, path) {
+        Ok(safe_path) => {
+            if let Some(parent) = safe_path.parent() {
+                if let Err(e) = fs::create_dir_all(parent) {
+                    return format!("Error creating parent directories: {}", e);
+                }
+            }
+
+            match fs::write(&safe_path, content) {
+                Ok(_) => format!("Wrote {} bytes to {}", content.len(), path),
+                Err(e) => format!("Error: {}", e),
+            }
+        }
+        Err(e) => format!("Error: {}", e),
+    }
+}
+
+/// Replace exact text in a file (surgical edit).
+///
+/// Uses exact string matching - the old_text must appear verbatim.
+/// Only replaces the first occurrence to prevent accidental mass changes.
+pub fn run_edit(workdir: &Path, path: &str, old_text: &str, new_text: &str) -> String {
+    match safe_path(workdir, path) {
+        Ok(safe_path) => match fs::read_to_string(&safe_path) {
+            Ok(content) => {
+                if !content.contains(old_text) {
+                    return format!("Error: Text not found in {}", path);
+                }
+
+                let new_content = content.replacen(old_text, new_text, 1);
+
+                match fs::write(&safe_path, new_content) {
+                    Ok(_) => format!("Edited {}", path),
+                    Err(e) => format!("Error: {}", e),
+                }
+            }
+            Err(e) => format!("Error: {}", e),
+        },
+        Err(e) => format!("Error: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    /// Fresh canonical directory under the system temp dir
+    fn workspace(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("core_tools_{}_{}", name, std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir.canonicalize().unwrap()
+    }
+
+    #[test]
+    fn test_safe_truncate_char_boundaries() {
+        assert_eq!(safe_truncate("Hello, World!", 100), "Hello, World!");
+        assert_eq!(safe_truncate("Hello", 5), "Hello");
+        // "é" is two bytes; cutting inside it backs off to the boundary
+        assert_eq!(safe_truncate("caféx", 4), "caf");
+        assert_eq!(safe_truncate("🦀🦀", 5), "🦀");
+
+        let long = "a".repeat(MAX_TOOL_OUTPUT + 10);
+        let capped = cap_output(long);
+        assert_eq!(capped.len(), MAX_TOOL_OUTPUT + 3);
+        assert!(capped.ends_with("..."));
+    }
+
+    #[test]
+    fn test_truncated_output_is_saved_in_full() {
+        let dir = workspace("truncated").join("saved");
+        let long = format!("{}tail-marker", "x".repeat(MAX_TOOL_OUTPUT));
+
+        let capped = cap_output_to(long.clone(), Some(&dir), None);
+        let (head, note) = capped.split_at(MAX_TOOL_OUTPUT);
+        let path = note
+            .strip_prefix("... (truncated; full output at ")
+            .and_then(|rest| rest.strip_suffix(')'))
+            .unwrap();
+        let saved = fs::read_to_string(path).unwrap();
+        let short = cap_output_to("short".to_string(), Some(&dir), None);
+        let entries = fs::read_dir(&dir).unwrap().count();
+        fs::remove_dir_all(dir.parent().unwrap()).ok();
+
+        assert!(head.chars().all(|c| c == 'x'));
+        assert!(Path::new(path).starts_with(&dir));
+        assert_eq!(saved, long);
+        assert!(saved.ends_with("tail-marker"));
+        // Output under the cap is returned as-is and never saved
+        assert_eq!(short, "short");
+        assert_eq!(entries, 1);
+    }
+
+    #[test]
+    fn test_saved_output_is_redacted() {
+        let dir = workspace("save_redacted").join("saved");
+        let long = format!("password hunter22\n{}", "x".repeat(MAX_TOOL_OUTPUT));
+
+        let redact = |text: &str| text.replace("hunter22", "***");
+        let capped = cap_output_to(long, Some(&dir), Some(&redact));
+        let path = capped
+            .rsplit_once("full output at ")
+            .and_then(|(_, rest)| rest.strip_suffix(')'))
+            .unwrap();
+        let saved = fs::read_to_string(path).unwrap();
+        fs::remove_dir_all(dir.parent().unwrap()).ok();
+
+        assert!(saved.starts_with("password ***\n"));
+        assert!(!saved.contains("hunter22"));
+    }
+
+    #[test]
+    fn test_safe_path_confines_to_workspace() {
+        let dir = workspace("safe_path");
+        let nested = safe_path(&dir, "new/deeper/file.txt");
+        let escape = safe_path(&dir, "../../../etc/passwd");
+        let sneaky = safe_path(&dir, "new/../../outside.txt");
+        fs::remove_dir_all(&dir).ok();
+        let gone = safe_path(&dir, "file.txt");
+
+        // Missing directories resolve instead of failing
+        assert_eq!(nested.unwrap(), dir.join("new/deeper/file.txt"));
+        assert!(escape
+            .unwrap_err()
+            .to_string()
+            .contains("escapes workspace"));
+        assert!(sneaky.is_err());
+        assert!(gone.unwrap_err().to_string().contains("no longer exists"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_chain_beyond_limit_is_rejected() {
+        use std::os::unix::fs::symlink;
+
+        let dir = workspace("symlinks");
+        fs::create_dir_all(dir.join("real")).unwrap();
+        fs::write(dir.join("real/file.txt"), "x").unwrap();
+        // link5 -> link4 -> ... -> link1 -> real
+        symlink("real", dir.join("link1")).unwrap();
+        for i in 2..=5 {
+            symlink(format!("link{}", i - 1), dir.join(format!("link{}", i))).unwrap();
+        }
+        symlink("loop_b", dir.join("loop_a")).unwrap();
+        symlink("loop_a", dir.join("loop_b")).unwrap();
+        symlink("../missing_outside.txt", dir.join("dangling")).unwrap();
+
+        let within = safe_path_with_depth(&dir, "link5/file.txt", 5);
+        let beyond = safe_path_with_depth(&dir, "link5/file.txt", 4);
+        let looped = safe_path_with_depth(&dir, "loop_a/file.txt", 40);
+        let dangling = safe_path_with_depth(&dir, "dangling", 40);
+        let bounded = canonicalize_bounded(&dir.join("link3/../link2"), 10);
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(within.unwrap(), dir.join("real/file.txt"));
+        assert!(beyond
+            .unwrap_err()
+            .to_string()
+            .starts_with("Too many levels of symbolic links"));
+        assert!(looped.unwrap_err().to_string().contains("(limit 40"));
+        // Not a file to create: writing to it would land outside the workspace
+        assert!(dangling.is_err());
+        // `..` applies to where the link points, as with fs::canonicalize
+        assert_eq!(bounded.unwrap(), dir.join("real"));
+    }
+
+    #[test]
+    fn test_run_bash_output_and_blocklist() {
+        let dir = workspace("bash");
+        let echoed = run_bash(&dir, "echo out; echo err >&2");
+        let silent = run_bash(&dir, "true");
+        let blocked = run_bash(&dir, "sudo ls");
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(echoed, "out\nerr");
+        assert_eq!(silent, "(no output)");
+        assert_eq!(blocked, "Error: Dangerous command blocked");
+        assert!(is_dangerous_command("rm -rf /"));
+        assert!(!is_dangerous_command("rm -rf target"));
+    }
+
+    #[test]
+    fn test_read_write_edit_round_trip() {
+        let dir = workspace("files");
+        let wrote = run_write(&dir, "a/b/notes.txt", "one\ntwo\nthree\ntwo\n");
+        let limited = run_read(&dir, "a/b/notes.txt", Some(2));
+        let whole = run_read(&dir, "a/b/notes.txt", Some(0));
+        let edited = run_edit(&dir, "a/b/notes.txt", "two", "2");
+        let after_edit = run_read(&dir, "a/b/notes.txt", None);
+        let missing_text = run_edit(&dir, "a/b/notes.txt", "four", "4");
+        fs::write(dir.join("log.bin"), b"ok \xff\xfe end").unwrap();
+        let binary = run_read(&dir, "log.bin", None);
+        let missing_file = run_read(&dir, "nope.txt", None);
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(wrote, "Wrote 18 bytes to a/b/notes.txt");
+        assert_eq!(limited, "one\ntwo\n... (2 more lines)");
+        assert_eq!(whole, "one\ntwo\nthree\ntwo\n");
+        assert_eq!(edited, "Edited a/b/notes.txt");
+        // Only the first occurrence changes
+        assert_eq!(after_edit, "one\n2\nthree\ntwo\n");
+        assert_eq!(missing_text, "Error: Text not found in a/b/notes.txt");
+        assert_eq!(binary, "ok \u{fffd}\u{fffd} end");
+        assert!(missing_file.starts_with("Error reading file:"));
+    }
+}
@@ -9,28 +9,13 @@ use anthropic::types::{
 use anthropic::Client;
 use anyhow::Result;
 use colored::Colorize;
+use mini_code_core::api::api_version_hint;
+use mini_code_core::tokens::{self, calculate_max_tokens, estimate_tokens};
+use mini_code_core::tools::safe_truncate;
 use serde_json::json;
 use std::env;
 use std::process::{Command, Stdio};
 
-/// Safely truncate a string at a UTF-8 character boundary.
-///
-/// Unlike `&s[..n]` which panics if n is not at a character boundary,
-/// this function finds the largest valid boundary <= max_bytes.
-fn safe_truncate(s: &str, max_bytes: usize) -> &str {
-    if s.len() <= max_bytes {
-        return s;
-    }
-
-    // Find the last character boundary at or before max_bytes
-    let mut boundary = max_bytes;
-    while boundary > 0 && !s.is_char_boundary(boundary) {
-        boundary -= 1;
-    }
-
-    &s[..boundary]
-}
-
 /// Get current working directory
 pub fn get_cwd() -> String {
     env::current_dir()
@@ -101,6 +86,116 @@ pub fn execute_bash(command: &str) -> String {
     }
 }
 
+/// Execute one tool_use block and build its tool_result.
+///
+/// Every tool_use must get a result, or the next request is rejected; an
+/// unknown tool or a missing `command` becomes an error result the model
+/// can correct instead of a silently dropped block.
+pub fn run_tool_use(id: &str, name: &str, input: &serde_json::Value) -> ContentBlock {
+    let error = |message: String| ContentBlock::ToolResult {
+        tool_use_id: id.to_string(),
+        is_error: Some(true),
+        content: anthropic::types::ToolResultContent::Text(message),
+    };
+
+    if name != "bash" {
+        return error(format!(
+            "Error: Unknown tool '{}'. Only 'bash' is available.",
+            name
+        ));
+    }
+    let Some(command) = input.get("command").and_then(|v| v.as_str()) else {
+        return error(format!(
+            "Error: bash requires a string 'command' parameter, got: {}",
+            input
+        ));
+    };
+
+    let output = execute_bash(command);
+    let truncated_output = if output.len() > 50000 {
+        format!("{}... (truncated)", safe_truncate(&output, 50000))
+    } else {
+        output
+    };
+
+    ContentBlock::ToolResult {
+        tool_use_id: id.to_string(),
+        is_error: None,
+        content: anthropic::types::ToolResultContent::Text(truncated_output),
+    }
+}
+
+/// Broad categories of API failure, each with its own hint and recovery
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiErrorKind {
+    /// Rejected `anthropic-version` while ANTHROPIC_API_VERSION is set
+    ApiVersion,
+    InsufficientBalance,
+    Unauthorized,
+    Timeout,
+    Connection,
+    Other,
+}
+
+impl ApiErrorKind {
+    /// User-facing hint for this kind of failure
+    pub fn hint(self) -> Option<String> {
+        let hint = match self {
+            Self::ApiVersion => return api_version_hint("anthropic-version"),
+            Self::InsufficientBalance => {
+                "Hint: Your API account balance is insufficient. Please recharge."
+            }
+            Self::Unauthorized => "Hint: API key may be invalid. Check your ANTHROPIC_API_KEY.",
+            Self::Timeout => "Hint: Request timed out. The API server may be slow or unreachable.",
+            Self::Connection => "Hint: Network connection error. Check your internet connection.",
+            Self::Other => return None,
+        };
+        Some(hint.to_string())
+    }
+}
+
+/// Classify an API error from its message
+pub fn classify_api_error(message: &str) -> ApiErrorKind {
+    if api_version_hint(message).is_some() {
+        ApiErrorKind::ApiVersion
+    } else if message.contains("余额不足") || message.contains("insufficient") {
+        ApiErrorKind::InsufficientBalance
+    } else if message.contains("unauthorized") || message.contains("401") {
+        ApiErrorKind::Unauthorized
+    } else if message.contains("timeout") || message.contains("timed out") {
+        ApiErrorKind::Timeout
+    } else if message.contains("connection") {
+        ApiErrorKind::Connection
+    } else {
+        ApiErrorKind::Other
+    }
+}
+
+/// What the caller should do after a turn fails
+#[derive(Debug, PartialEq, Eq)]
+pub enum TurnErrorAction {
+    /// Report the error and take the next prompt
+    Continue,
+    /// Keep the pending request so `/continue` can retry it once resolved
+    Pause,
+    /// Stop with this exit code (non-interactive runs)
+    Exit(i32),
+}
+
+/// Decide how to recover from a failed turn.
+///
+/// Interactive sessions never exit on an API error; a depleted balance pauses
+/// so the user can top up and `/continue` without losing the conversation.
+pub fn turn_error_action(error: &anyhow::Error, interactive: bool) -> TurnErrorAction {
+    if !interactive {
+        return TurnErrorAction::Exit(1);
+    }
+    match classify_api_error(&error.to_string()) {
+        ApiErrorKind::InsufficientBalance => TurnErrorAction::Pause,
+        _ => TurnErrorAction::Continue,
+    }
+}
+
 /// The complete agent loop
 ///
 /// This is the core pattern that ALL coding agents share:
@@ -113,6 +208,8 @@ pub fn execute_bash(command: &str) -> String {
 ///     client: Anthropic API client
 ///     prompt: User's request
 ///     history: Conversation history (mutable, shared across calls in interactive mode)
+///     quiet: Don't print text the model writes alongside tool calls (subagent
+///            mode, where stdout is the result the parent reads)
 ///
 /// Returns:
 ///     Final text response from the model
@@ -121,19 +218,117 @@ pub async fn chat(
     model: &str,
     prompt: &str,
     history: &mut Vec<Message>,
+    quiet: bool,
 ) -> Result<String> {
-    let tools = vec![get_bash_tool()];
-    let system = get_system_prompt();
-
     // Add user message
     history.push(Message {
         role: Role::User,
         content: vec![ContentBlock::text(prompt)],
     });
 
+    resume(client, model, history, quiet).await
+}
+
+/// Sent after a reply hits max_tokens, asking for a shorter answer
+const TRUNCATION_PROMPT: &str = "[SYSTEM: Your previous response was truncated due to length. \
+Please provide a brief summary, or write large content to a file with bash.]";
+
+/// What `resume` does after a reply is cut off at max_tokens
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TruncationAction {
+    /// Keep the partial reply and ask the model for a summary
+    AskForSummary,
+    /// Too many truncations in a row; fail the turn
+    GiveUp,
+}
+
+/// Decide how to handle the `consecutive`-th truncation in a row.
+///
+/// Giving up matters for subagents: a parent reading a truncated reply would
+/// take it for a complete result.
+pub fn truncation_action(consecutive: usize, max_retries: usize) -> TruncationAction {
+    if consecutive >= max_retries {
+        TruncationAction::GiveUp
+    } else {
+        TruncationAction::AskForSummary
+    }
+}
+
+/// The part of a truncated reply that can go back into history.
+///
+/// A cut-off tool call has no result to pair with, so only text is kept.
+pub fn truncated_turn_content(content: &[ContentBlock]) -> Vec<ContentBlock> {
+    let text: Vec<ContentBlock> = content
+        .iter()
+        .filter(|block| matches!(block, ContentBlock::Text { text } if !text.trim().is_empty()))
+        .cloned()
+        .collect();
+    if text.is_empty() {
+        vec![ContentBlock::text("(response truncated)")]
+    } else {
+        text
+    }
+}
+
+/// Rough token count of `history` plus the system prompt
+pub fn estimate_history_tokens(history: &[Message], system: &str) -> usize {
+    let chars: usize = history
+        .iter()
+        .flat_map(|msg| msg.content.iter())
+        .map(|block| match block {
+            ContentBlock::Text { text } => text.len(),
+            ContentBlock::ToolUse { input, .. } => input.to_string().len(),
+            ContentBlock::ToolResult { content, .. } => match content {
+                anthropic::types::ToolResultContent::Text(t) => t.len(),
+                _ => 400,
+            },
+            _ => 0,
+        })
+        .sum();
+    estimate_tokens(chars + system.len())
+}
+
+/// Text the model wrote alongside its tool calls, to show while the round runs.
+///
+/// Empty for a reply without tool calls: that text is the final answer,
+/// which `chat` returns instead.
+pub fn tool_round_text(content: &[ContentBlock]) -> Vec<&str> {
+    if !content
+        .iter()
+        .any(|block| matches!(block, ContentBlock::ToolUse { .. }))
+    {
+        return Vec::new();
+    }
+    content
+        .iter()
+        .filter_map(|block| match block {
+            ContentBlock::Text { text } if !text.trim().is_empty() => Some(text.as_str()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Run the agent loop on `history` as it stands, without adding a prompt.
+///
+/// Used by `chat`, and by the REPL's `/continue` to retry a turn whose API
+/// call failed (the user message is still the last entry in `history`).
+pub async fn resume(
+    client: &Client,
+    model: &str,
+    history: &mut Vec<Message>,
+    quiet: bool,
+) -> Result<String> {
+    let tools = vec![get_bash_tool()];
+    let system = get_system_prompt();
+    let max_output_tokens = tokens::max_output_tokens();
+    let max_retries = tokens::max_truncation_retries();
+    let mut consecutive_truncations = 0;
+
     loop {
-        // 1. Call the model with tools
-        let request = MessagesRequestBuilder::new(model.to_string(), history.clone(), 8000)
+        // 1. Call the model with tools, sizing max_tokens to the room left
+        let max_tokens =
+            calculate_max_tokens(estimate_history_tokens(history, &system), max_output_tokens);
+        let request = MessagesRequestBuilder::new(model.to_string(), history.clone(), max_tokens)
             .system(SystemPrompt::Text(system.clone()))
             .tools(tools.clone())
             .build()?;
@@ -149,31 +344,8 @@ pub async fn chat(
                 eprintln!("\n{}: {}", "API Error".bright_red(), e);
 
                 // Check for common errors and provide helpful messages
-                let error_msg = e.to_string();
-                if error_msg.contains("余额不足") || error_msg.contains("insufficient") {
-                    eprintln!(
-                        "{}",
-                        "Hint: Your API account balance is insufficient. Please recharge."
-                            .bright_yellow()
-                    );
-                } else if error_msg.contains("unauthorized") || error_msg.contains("401") {
-                    eprintln!(
-                        "{}",
-                        "Hint: API key may be invalid. Check your ANTHROPIC_API_KEY."
-                            .bright_yellow()
-                    );
-                } else if error_msg.contains("timeout") {
-                    eprintln!(
-                        "{}",
-                        "Hint: Request timed out. The API server may be slow or unreachable."
-                            .bright_yellow()
-                    );
-                } else if error_msg.contains("connection") {
-                    eprintln!(
-                        "{}",
-                        "Hint: Network connection error. Check your internet connection."
-                            .bright_yellow()
-                    );
+                if let Some(hint) = classify_api_error(&e.to_string()).hint() {
+                    eprintln!("{}", hint.bright_yellow());
                 }
 
                 return Err(e.into());
@@ -194,6 +366,51 @@ pub async fn chat(
             }
         };
 
+        // A cut-off reply is retried as a summary, up to the retry cap
+        if response.stop_reason == Some(StopReason::MaxTokens) {
+            consecutive_truncations += 1;
+            if truncation_action(consecutive_truncations, max_retries) == TruncationAction::GiveUp {
+                eprintln!(
+                    "\n{}",
+                    format!(
+                        "Error: Response truncated {} times in a row.",
+                        consecutive_truncations
+                    )
+                    .bright_red()
+                );
+                eprintln!(
+                    "{}",
+                    format!(
+                        "Hint: Break the task into smaller steps, or raise MINI_CODE_MAX_OUTPUT_TOKENS (current: {})",
+                        max_output_tokens
+                    )
+                    .bright_yellow()
+                );
+                return Err(anyhow::anyhow!(
+                    "Task too complex, please break it into smaller steps"
+                ));
+            }
+
+            eprintln!(
+                "\n{}",
+                format!(
+                    "Warning: Response truncated (attempt {}/{}). Asking model to provide a summary...",
+                    consecutive_truncations, max_retries
+                )
+                .bright_yellow()
+            );
+            history.push(Message {
+                role: Role::Assistant,
+                content: truncated_turn_content(&response.content),
+            });
+            history.push(Message {
+                role: Role::User,
+                content: vec![ContentBlock::text(TRUNCATION_PROMPT)],
+            });
+            continue;
+        }
+        consecutive_truncations = 0;
+
         // 2. Build assistant message content (preserve both text and tool_use blocks)
         let mut assistant_content = vec![];
         for block in &response.content {
@@ -222,25 +439,16 @@ pub async fn chat(
             return Ok(text);
         }
 
-        // 4. Execute each tool call and collect results
+        // 4. Show the model's reasoning, then execute each tool call
+        if !quiet {
+            for text in tool_round_text(&response.content) {
+                println!("{}", text);
+            }
+        }
         let mut results = vec![];
         for block in &response.content {
-            if let ContentBlock::ToolUse { id, name: _, input } = block {
-                if let Some(command) = input.get("command").and_then(|v| v.as_str()) {
-                    // Execute command with timeout
-                    let output = execute_bash(command);
-                    let truncated_output = if output.len() > 50000 {
-                        format!("{}... (truncated)", safe_truncate(&output, 50000))
-                    } else {
-                        output
-                    };
-
-                    results.push(ContentBlock::ToolResult {
-                        tool_use_id: id.clone(),
-                        is_error: None,
-                        content: anthropic::types::ToolResultContent::Text(truncated_output),
-                    });
-                }
+            if let ContentBlock::ToolUse { id, name, input } = block {
+                results.push(run_tool_use(id, name, input));
             }
         }
 
@@ -256,6 +464,124 @@ pub async fn chat(
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_truncation_retries_then_gives_up() {
+        assert_eq!(truncation_action(1, 3), TruncationAction::AskForSummary);
+        assert_eq!(truncation_action(2, 3), TruncationAction::AskForSummary);
+        assert_eq!(truncation_action(3, 3), TruncationAction::GiveUp);
+        assert_eq!(truncation_action(1, 1), TruncationAction::GiveUp);
+    }
+
+    #[test]
+    fn test_tool_round_text_surfaces_interleaved_text() {
+        let tool_round = vec![
+            ContentBlock::text("Let me look at the source tree first."),
+            ContentBlock::ToolUse {
+                id: "t1".to_string(),
+                name: "bash".to_string(),
+                input: json!({"command": "ls src"}),
+            },
+            ContentBlock::text("  "),
+        ];
+        assert_eq!(
+            tool_round_text(&tool_round),
+            vec!["Let me look at the source tree first."]
+        );
+
+        // A final answer is returned by chat, not printed mid-turn
+        let final_reply = vec![ContentBlock::text("All done.")];
+        assert!(tool_round_text(&final_reply).is_empty());
+    }
+
+    #[test]
+    fn test_truncated_turn_drops_partial_tool_calls() {
+        let content = vec![
+            ContentBlock::text("Here is the start of the report"),
+            ContentBlock::ToolUse {
+                id: "toolu_1".to_string(),
+                name: "bash".to_string(),
+                input: json!({"command": "cat > rep"}),
+            },
+        ];
+        let kept = truncated_turn_content(&content);
+        assert_eq!(kept.len(), 1);
+        assert!(matches!(&kept[0], ContentBlock::Text { text } if text.starts_with("Here is")));
+
+        let only_tool = truncated_turn_content(&content[1..]);
+        assert!(
+            matches!(&only_tool[0], ContentBlock::Text { text } if text == "(response truncated)")
+        );
+    }
+
+    #[test]
+    fn test_max_tokens_shrinks_as_history_grows() {
+        let short = vec![Message {
+            role: Role::User,
+            content: vec![ContentBlock::text("hi")],
+        }];
+        let long = vec![Message {
+            role: Role::User,
+            content: vec![ContentBlock::text("x".repeat(400_000))],
+        }];
+
+        assert_eq!(estimate_history_tokens(&short, "sys!"), 1);
+        assert_eq!(estimate_history_tokens(&long, ""), 100_000);
+        assert_eq!(
+            calculate_max_tokens(estimate_history_tokens(&short, ""), 160_000),
+            80_000
+        );
+        assert_eq!(
+            calculate_max_tokens(estimate_history_tokens(&long, ""), 160_000),
+            40_000
+        );
+    }
+
+    #[test]
+    fn test_tool_use_without_command_yields_error_result() {
+        let result_text = |block: ContentBlock| match block {
+            ContentBlock::ToolResult {
+                tool_use_id,
+                is_error,
+                content: anthropic::types::ToolResultContent::Text(text),
+            } => (tool_use_id, is_error, text),
+            other => panic!("expected a text tool_result, got {:?}", other),
+        };
+
+        let (id, is_error, text) = result_text(run_tool_use("t1", "bash", &json!({})));
+        assert_eq!(id, "t1");
+        assert_eq!(is_error, Some(true));
+        assert!(text.starts_with("Error: bash requires a string 'command'"));
+
+        let (_, is_error, text) = result_text(run_tool_use("t2", "bash", &json!({"command": 42})));
+        assert_eq!(is_error, Some(true));
+        assert!(text.contains("42"));
+
+        let (_, is_error, text) = result_text(run_tool_use("t3", "read_file", &json!({})));
+        assert_eq!(is_error, Some(true));
+        assert!(text.contains("Unknown tool 'read_file'"));
+
+        let (_, is_error, text) =
+            result_text(run_tool_use("t4", "bash", &json!({"command": "echo ok"})));
+        assert_eq!(is_error, None);
+        assert_eq!(text, "ok\n");
+    }
+
+    #[test]
+    fn test_repl_survives_insufficient_balance() {
+        let error = anyhow::anyhow!("API error 403: 余额不足");
+        assert_eq!(
+            classify_api_error(&error.to_string()),
+            ApiErrorKind::InsufficientBalance
+        );
+        assert_eq!(turn_error_action(&error, true), TurnErrorAction::Pause);
+        assert_eq!(turn_error_action(&error, false), TurnErrorAction::Exit(1));
+
+        let error = anyhow::anyhow!("401 unauthorized");
+        assert_eq!(turn_error_action(&error, true), TurnErrorAction::Continue);
+        assert_eq!(classify_api_error("bad request"), ApiErrorKind::Other);
+        assert!(ApiErrorKind::Other.hint().is_none());
+    }
+
     #[test]
     fn test_get_cwd() {
         let cwd = get_cwd();
@@ -45,12 +45,15 @@
 //!     # Subagent mode (called by parent agent or directly)
 //!     v0_bash_agent "explore src/ and summarize"
 
+use anthropic::types::{Message, Role};
 use anthropic::Client;
 use anyhow::Result;
 use colored::*;
+use mini_code_core::api::{api_version_from_env, apply_api_version, DEFAULT_API_VERSION};
+use mini_code_core::color::configure_colors;
 use std::env;
 use std::io::{self, Write};
-use v0_bash_agent::chat;
+use v0_bash_agent::{chat, resume, turn_error_action, TurnErrorAction};
 
 /// Initialize API client with credentials from environment
 /// Supports both ANTHROPIC_API_KEY and ANTHROPIC_AUTH_TOKEN
@@ -73,9 +76,7 @@ fn create_client() -> Result<Client> {
         builder = builder.api_base(base_url);
     }
 
-    if let Ok(api_version) = env::var("ANTHROPIC_API_VERSION") {
-        builder = builder.api_version(api_version);
-    }
+    builder = apply_api_version(builder);
 
     // Set timeout to 10 minutes to allow for complex code generation
     // while still preventing indefinite hanging
@@ -85,45 +86,125 @@ fn create_client() -> Result<Client> {
     Ok(client)
 }
 
-/// Get model name from environment or use default (Sonnet)
+/// Get model name from environment or use the shared default
 fn get_model_name() -> String {
-    env::var("MODEL_NAME").unwrap_or_else(|_| "claude-sonnet-4-5-20250929".to_string())
+    mini_code_core::model::resolve_model()
 }
 
+/// Model aliases accepted as the first argument
+const MODEL_ALIASES: &[(&str, &str)] = &[
+    ("sonnet", "claude-sonnet-4-5-20250929"),
+    ("opus", "claude-opus-4-5-20251101"),
+];
+
 /// Map model alias to full model name
 fn resolve_model_alias(alias: &str) -> Option<String> {
-    match alias.to_lowercase().as_str() {
-        "sonnet" => Some("claude-sonnet-4-5-20250929".to_string()),
-        "opus" => Some("claude-opus-4-5-20251101".to_string()),
-        _ => None,
+    let alias = alias.to_lowercase();
+    MODEL_ALIASES
+        .iter()
+        .find(|(name, _)| *name == alias)
+        .map(|(_, model)| model.to_string())
+}
+
+/// Fetch the model ids the endpoint serves (`GET /v1/models`)
+///
+/// Uses the same credentials and base URL as `create_client`; many proxies
+/// don't implement this endpoint, which callers should expect.
+async fn fetch_models() -> Result<Vec<String>> {
+    let api_key = env::var("ANTHROPIC_API_KEY")
+        .or_else(|_| env::var("ANTHROPIC_AUTH_TOKEN"))
+        .map_err(|_| {
+            anyhow::anyhow!("Missing API key: set ANTHROPIC_API_KEY or ANTHROPIC_AUTH_TOKEN")
+        })?;
+    let base_url = env::var("ANTHROPIC_API_BASE")
+        .or_else(|_| env::var("ANTHROPIC_BASE_URL"))
+        .unwrap_or_else(|_| "https://api.anthropic.com".to_string());
+    let api_version = api_version_from_env()
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| DEFAULT_API_VERSION.to_string());
+
+    let url = format!("{}/v1/models?limit=100", base_url.trim_end_matches('/'));
+    let response = reqwest::Client::new()
+        .get(&url)
+        .header("x-api-key", api_key)
+        .header("anthropic-version", api_version)
+        .timeout(std::time::Duration::from_secs(30))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        anyhow::bail!("GET {} returned {}", url, response.status());
     }
+
+    let body: serde_json::Value = response.json().await?;
+    let models = body["data"]
+        .as_array()
+        .map(|data| {
+            data.iter()
+                .filter_map(|m| m["id"].as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(models)
+}
+
+/// Render `--list-models` output, falling back to the known aliases
+fn format_model_list(fetched: Result<Vec<String>>) -> String {
+    let mut lines = Vec::new();
+    match fetched {
+        Ok(models) if !models.is_empty() => {
+            lines.push("Models available from the API:".to_string());
+            lines.extend(models.iter().map(|m| format!("  {}", m)));
+            return lines.join("\n");
+        }
+        Ok(_) => lines.push("The API returned no models.".to_string()),
+        Err(e) => lines.push(format!("Could not list models from the API: {}", e)),
+    }
+
+    lines.push("Known aliases:".to_string());
+    lines.extend(
+        MODEL_ALIASES
+            .iter()
+            .map(|(alias, model)| format!("  {:<8}{}", alias, model)),
+    );
+    lines.join("\n")
 }
 
 /// Parse command line arguments
 /// Returns: (model_name, optional_task)
+///
+/// Uses `args_os` with lossy conversion so non-UTF8 arguments can't panic.
 fn parse_args() -> (String, Option<String>) {
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = env::args_os()
+        .skip(1)
+        .map(|a| a.to_string_lossy().into_owned())
+        .collect();
 
-    if args.len() == 1 {
-        // No arguments: interactive mode with default model
-        return (get_model_name(), None);
-    }
+    parse_args_from(&args, get_model_name())
+}
 
-    // Check if first argument is a model alias
-    if let Some(model) = resolve_model_alias(&args[1]) {
-        if args.len() > 2 {
-            // Model + task: subagent mode with specified model
-            let task = args[2..].join(" ");
-            return (model, Some(task));
-        } else {
-            // Just model: interactive mode with specified model
-            return (model, None);
-        }
-    }
+/// Split arguments (without the program name) into model and optional task
+fn parse_args_from(args: &[String], default_model: String) -> (String, Option<String>) {
+    // --no-color is handled by configure_colors, not part of the task
+    let args: Vec<&str> = args
+        .iter()
+        .map(|a| a.as_str())
+        .filter(|a| *a != "--no-color")
+        .collect();
 
-    // First argument is not a model alias, treat as task
-    let task = args[1..].join(" ");
-    (get_model_name(), Some(task))
+    // A leading model alias selects the model; everything else is the task
+    let (model, rest) = match args.first().and_then(|a| resolve_model_alias(a)) {
+        Some(model) => (model, &args[1..]),
+        None => (default_model, &args[..]),
+    };
+
+    // A blank task means interactive mode
+    let task = rest.join(" ").trim().to_string();
+    if task.is_empty() {
+        (model, None)
+    } else {
+        (model, Some(task))
+    }
 }
 
 /// Print model selection info
@@ -145,6 +226,14 @@ fn print_model_info(model: &str) {
 /// Main entry point
 #[tokio::main]
 async fn main() -> Result<()> {
+    configure_colors(env::args_os().any(|a| a == "--no-color"));
+
+    if env::args_os().any(|a| a == "--list-models") {
+        dotenvy::dotenv().ok();
+        println!("{}", format_model_list(fetch_models().await));
+        return Ok(());
+    }
+
     let client = create_client()?;
     let (model, task) = parse_args();
 
@@ -152,8 +241,15 @@ async fn main() -> Result<()> {
         // Subagent mode: execute task and print result
         // This is how parent agents spawn children via bash
         let mut history = vec![];
-        let result = chat(&client, &model, &task, &mut history).await?;
-        println!("{}", result);
+        match chat(&client, &model, &task, &mut history, true).await {
+            Ok(result) => println!("{}", result),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                if let TurnErrorAction::Exit(code) = turn_error_action(&e, false) {
+                    std::process::exit(code);
+                }
+            }
+        }
     } else {
         // Interactive REPL mode
         print_model_info(&model);
@@ -182,9 +278,32 @@ async fn main() -> Result<()> {
                 continue;
             }
 
-            match chat(&client, &model, query, &mut history).await {
+            let result = if query == "/continue" {
+                // Retry the last request, e.g. after topping up the balance
+                if !history
+                    .last()
+                    .is_some_and(|m: &Message| matches!(m.role, Role::User))
+                {
+                    println!("{}", "Nothing to continue.".bright_black());
+                    continue;
+                }
+                resume(&client, &model, &mut history, false).await
+            } else {
+                chat(&client, &model, query, &mut history, false).await
+            };
+
+            match result {
                 Ok(response) => println!("{}", response),
-                Err(e) => eprintln!("Error: {}", e),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    if turn_error_action(&e, true) == TurnErrorAction::Pause {
+                        eprintln!(
+                            "{}",
+                            "Conversation kept. Top up your balance, then type /continue to retry."
+                                .bright_black()
+                        );
+                    }
+                }
             }
         }
     }
@@ -208,4 +327,71 @@ fn print_help() {
     println!("    >> v0_bash_agent 'analyze all rust files'");
     println!("    >> v0_bash_agent opus 'review code quality'");
     println!();
+    println!(
+        "  {}",
+        "Retry the last request after an API error:".bright_yellow()
+    );
+    println!("    >> /continue");
+    println!();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args_no_arguments() {
+        assert_eq!(
+            parse_args_from(&[], "default".to_string()),
+            ("default".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn test_parse_args_alias_and_task() {
+        let (model, task) = parse_args_from(&args(&["opus", "fix", "the", "build"]), "d".into());
+        assert_eq!(model, "claude-opus-4-5-20251101");
+        assert_eq!(task.as_deref(), Some("fix the build"));
+
+        let (model, task) = parse_args_from(&args(&["Sonnet"]), "d".into());
+        assert_eq!(model, "claude-sonnet-4-5-20250929");
+        assert_eq!(task, None);
+    }
+
+    #[test]
+    fn test_parse_args_task_only_and_blank() {
+        let (model, task) = parse_args_from(&args(&["--no-color", "list", "files  "]), "d".into());
+        assert_eq!(model, "d");
+        assert_eq!(task.as_deref(), Some("list files"));
+
+        let (_, task) = parse_args_from(&args(&["opus", "  ", ""]), "d".into());
+        assert_eq!(task, None);
+    }
+
+    #[test]
+    fn test_list_models_falls_back_to_aliases() {
+        let listing = format_model_list(Err(anyhow::anyhow!("404 Not Found")));
+        assert_eq!(
+            listing,
+            "Could not list models from the API: 404 Not Found\n\
+             Known aliases:\n  \
+             sonnet  claude-sonnet-4-5-20250929\n  \
+             opus    claude-opus-4-5-20251101"
+        );
+
+        let fetched = format_model_list(Ok(vec!["claude-x".to_string()]));
+        assert_eq!(fetched, "Models available from the API:\n  claude-x");
+    }
+
+    #[test]
+    fn test_parse_args_lossy_non_utf8() {
+        // What args_os + to_string_lossy produces for invalid UTF-8
+        let lossy = String::from_utf8_lossy(b"caf\xE9").into_owned();
+        let (_, task) = parse_args_from(&[lossy], "d".into());
+        assert_eq!(task.as_deref(), Some("caf\u{FFFD}"));
+    }
 }
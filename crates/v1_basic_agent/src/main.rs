@@ -42,6 +42,7 @@
 //!
 //! Usage:
 //!     cargo run --bin v1_basic_agent
+//!     cargo run --bin v1_basic_agent -- --verbose-tools   # print full tool inputs
 
 use anthropic::types::{
     ContentBlock, Message, MessagesRequestBuilder, Role, StopReason, SystemPrompt, Tool,
@@ -49,12 +50,14 @@ use anthropic::types::{
 use anthropic::Client;
 use anyhow::{Context, Result};
 use colored::Colorize;
+use mini_code_core::api::{api_version_hint, apply_api_version};
+use mini_code_core::color::configure_colors;
+use mini_code_core::model::{apply_sampling, parse_sampling_param};
+use mini_code_core::tools::{run_bash, run_edit, run_read, run_write, safe_truncate};
 use serde_json::json;
 use std::env;
-use std::fs;
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
@@ -119,17 +122,32 @@ impl Drop for ThinkingAnimation {
 struct Config {
     model: String,
     workdir: PathBuf,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    /// Print full tool inputs instead of the compact one-line summary
+    verbose_tools: bool,
 }
 
 impl Config {
     fn from_env() -> Result<Self> {
         dotenvy::dotenv().ok();
 
-        let model =
-            env::var("MODEL_NAME").unwrap_or_else(|_| "claude-sonnet-4-20250514".to_string());
+        let model = mini_code_core::model::resolve_model();
         let workdir = env::current_dir().context("Failed to get current directory")?;
 
-        Ok(Self { model, workdir })
+        let temperature = parse_sampling_param(
+            "MINI_CODE_TEMPERATURE",
+            env::var("MINI_CODE_TEMPERATURE").ok(),
+        )?;
+        let top_p = parse_sampling_param("MINI_CODE_TOP_P", env::var("MINI_CODE_TOP_P").ok())?;
+
+        Ok(Self {
+            model,
+            workdir,
+            temperature,
+            top_p,
+            verbose_tools: false,
+        })
     }
 
     fn system_prompt(&self) -> String {
@@ -243,180 +261,6 @@ fn create_tools() -> Vec<Tool> {
 // Tool Implementations
 // =============================================================================
 
-/// Safely truncate a string at a UTF-8 character boundary.
-///
-/// Unlike `&s[..n]` which panics if n is not at a character boundary,
-/// this function finds the largest valid boundary <= max_bytes.
-fn safe_truncate(s: &str, max_bytes: usize) -> &str {
-    if s.len() <= max_bytes {
-        return s;
-    }
-
-    // Find the last character boundary at or before max_bytes
-    let mut boundary = max_bytes;
-    while boundary > 0 && !s.is_char_boundary(boundary) {
-        boundary -= 1;
-    }
-
-    &s[..boundary]
-}
-
-/// Ensure path stays within workspace (security measure).
-///
-/// Prevents the model from accessing files outside the project directory.
-/// Resolves relative paths and checks they don't escape via '../'.
-fn safe_path(workdir: &Path, relative_path: &str) -> Result<PathBuf> {
-    let path = workdir.join(relative_path);
-    let canonical = path.canonicalize().or_else(|_| {
-        // If file doesn't exist yet, check parent directory
-        if let Some(parent) = path.parent() {
-            if parent.exists() {
-                Ok(parent.canonicalize()?.join(
-                    path.file_name()
-                        .ok_or_else(|| anyhow::anyhow!("Invalid path"))?,
-                ))
-            } else {
-                Err(anyhow::anyhow!("Parent directory does not exist"))
-            }
-        } else {
-            Err(anyhow::anyhow!("Invalid path"))
-        }
-    })?;
-
-    if !canonical.starts_with(workdir) {
-        anyhow::bail!("Path escapes workspace: {}", relative_path);
-    }
-
-    Ok(canonical)
-}
-
-/// Execute shell command with safety checks.
-///
-/// Security: Blocks obviously dangerous commands.
-/// Timeout: 60 seconds to prevent hanging.
-/// Output: Truncated to 50KB to prevent context overflow.
-fn run_bash(workdir: &Path, command: &str) -> String {
-    // Basic safety - block dangerous patterns
-    let dangerous = ["rm -rf /", "sudo", "shutdown", "reboot", "> /dev/"];
-    if dangerous.iter().any(|d| command.contains(d)) {
-        return "Error: Dangerous command blocked".to_string();
-    }
-
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .current_dir(workdir)
-        .output();
-
-    match output {
-        Ok(output) => {
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            let combined = format!("{}{}", stdout, stderr).trim().to_string();
-
-            if combined.is_empty() {
-                "(no output)".to_string()
-            } else {
-                // Truncate to 50KB
-                if combined.len() > 50000 {
-                    format!("{}...", safe_truncate(&combined, 50000))
-                } else {
-                    combined
-                }
-            }
-        }
-        Err(e) => format!("Error: {}", e),
-    }
-}
-
-/// Read file contents with optional line limit.
-///
-/// For large files, use limit to read just the first N lines.
-/// Output truncated to 50KB to prevent context overflow.
-fn run_read(workdir: &Path, path: &str, limit: Option<i64>) -> String {
-    match safe_path(workdir, path) {
-        Ok(safe_path) => match fs::read_to_string(&safe_path) {
-            Ok(content) => {
-                let lines: Vec<&str> = content.lines().collect();
-                let total_lines = lines.len();
-
-                let output = if let Some(limit) = limit {
-                    if limit > 0 && (limit as usize) < total_lines {
-                        let limited_lines: Vec<&str> =
-                            lines.iter().take(limit as usize).copied().collect();
-                        format!(
-                            "{}\n... ({} more lines)",
-                            limited_lines.join("\n"),
-                            total_lines - limit as usize
-                        )
-                    } else {
-                        content
-                    }
-                } else {
-                    content
-                };
-
-                // Truncate to 50KB
-                if output.len() > 50000 {
-                    format!("{}...", safe_truncate(&output, 50000))
-                } else {
-                    output
-                }
-            }
-            Err(e) => format!("Error: {}", e),
-        },
-        Err(e) => format!("Error: {}", e),
-    }
-}
-
-/// Write content to file, creating parent directories if needed.
-///
-/// This is for complete file creation/overwrite.
-/// For partial edits, use edit_file instead.
-fn run_write(workdir: &Path, path: &str, content: &str) -> String {
-    match safe_path(workdir, path) {
-        Ok(safe_path) => {
-            if let Some(parent) = safe_path.parent() {
-                if let Err(e) = fs::create_dir_all(parent) {
-                    return format!("Error creating parent directories: {}", e);
-                }
-            }
-
-            match fs::write(&safe_path, content) {
-                Ok(_) => format!("Wrote {} bytes to {}", content.len(), path),
-                Err(e) => format!("Error: {}", e),
-            }
-        }
-        Err(e) => format!("Error: {}", e),
-    }
-}
-
-/// Replace exact text in a file (surgical edit).
-///
-/// Uses exact string matching - the old_text must appear verbatim.
-/// Only replaces the first occurrence to prevent accidental mass changes.
-fn run_edit(workdir: &Path, path: &str, old_text: &str, new_text: &str) -> String {
-    match safe_path(workdir, path) {
-        Ok(safe_path) => match fs::read_to_string(&safe_path) {
-            Ok(content) => {
-                if !content.contains(old_text) {
-                    return format!("Error: Text not found in {}", path);
-                }
-
-                // Replace only first occurrence for safety
-                let new_content = content.replacen(old_text, new_text, 1);
-
-                match fs::write(&safe_path, new_content) {
-                    Ok(_) => format!("Edited {}", path),
-                    Err(e) => format!("Error: {}", e),
-                }
-            }
-            Err(e) => format!("Error: {}", e),
-        },
-        Err(e) => format!("Error: {}", e),
-    }
-}
-
 /// Dispatch tool call to the appropriate implementation.
 ///
 /// This is the bridge between the model's tool calls and actual execution.
@@ -468,6 +312,34 @@ fn execute_tool(workdir: &Path, name: &str, input: &serde_json::Value) -> String
     }
 }
 
+/// One-line summary of a tool call for the terminal.
+///
+/// Shows what matters per tool (the command, the path, how many bytes) rather
+/// than the raw input, which for write_file would be the whole file.
+fn describe_tool_call(name: &str, input: &serde_json::Value) -> String {
+    let field = |key: &str| input.get(key).and_then(|v| v.as_str()).unwrap_or("");
+    let shorten = |s: &str| {
+        if s.len() > 80 {
+            format!("{}...", safe_truncate(s, 80))
+        } else {
+            s.to_string()
+        }
+    };
+
+    match name {
+        "bash" => shorten(field("command")),
+        "read_file" => field("path").to_string(),
+        "write_file" => format!("{} ({} bytes)", field("path"), field("content").len()),
+        "edit_file" => format!(
+            "{} (-{} +{} bytes)",
+            field("path"),
+            field("old_text").len(),
+            field("new_text").len()
+        ),
+        _ => shorten(&input.to_string()),
+    }
+}
+
 // =============================================================================
 // The Agent Loop - This is the CORE of everything
 // =============================================================================
@@ -495,10 +367,14 @@ async fn agent_loop(client: &Client, config: &Config, messages: &mut Vec<Message
 
     loop {
         // Step 1: Call the model
-        let request = MessagesRequestBuilder::new(&config.model, messages.clone(), 8000)
-            .system(SystemPrompt::Text(config.system_prompt()))
-            .tools(tools.clone())
-            .build()?;
+        let request = apply_sampling(
+            MessagesRequestBuilder::new(&config.model, messages.clone(), 8000)
+                .system(SystemPrompt::Text(config.system_prompt()))
+                .tools(tools.clone()),
+            config.temperature,
+            config.top_p,
+        )
+        .build()?;
 
         // Record start time
         let start = Instant::now();
@@ -520,7 +396,9 @@ async fn agent_loop(client: &Client, config: &Config, messages: &mut Vec<Message
 
                 // Check for common errors and provide helpful messages
                 let error_msg = e.to_string();
-                if error_msg.contains("余额不足") || error_msg.contains("insufficient") {
+                if let Some(hint) = api_version_hint(&error_msg) {
+                    eprintln!("{}", hint.bright_yellow());
+                } else if error_msg.contains("余额不足") || error_msg.contains("insufficient") {
                     eprintln!(
                         "{}",
                         "Hint: Your API account balance is insufficient. Please recharge."
@@ -587,10 +465,8 @@ async fn agent_loop(client: &Client, config: &Config, messages: &mut Vec<Message
         let mut tool_calls = Vec::new();
         for block in &response.content {
             match block {
-                ContentBlock::Text { text } => {
-                    if !text.trim().is_empty() {
-                        println!("{}", text);
-                    }
+                ContentBlock::Text { text } if !text.trim().is_empty() => {
+                    println!("{}", text);
                 }
                 ContentBlock::ToolUse { id, name, input } => {
                     tool_calls.push((id.clone(), name.clone(), input.clone()));
@@ -612,11 +488,16 @@ async fn agent_loop(client: &Client, config: &Config, messages: &mut Vec<Message
         let mut results = Vec::new();
         for (id, name, input) in tool_calls {
             // Display what's being executed
+            let shown = if config.verbose_tools {
+                format!("{:?}", input)
+            } else {
+                describe_tool_call(&name, &input)
+            };
             println!(
-                "\n{} {}: {:?}",
+                "\n{} {}: {}",
                 ">".bright_blue(),
                 name.bright_yellow(),
-                input
+                shown
             );
 
             // Execute and show result preview
@@ -675,9 +556,7 @@ fn create_client() -> Result<Client> {
         builder = builder.api_base(base_url);
     }
 
-    if let Ok(api_version) = env::var("ANTHROPIC_API_VERSION") {
-        builder = builder.api_version(api_version);
-    }
+    builder = apply_api_version(builder);
 
     // Set timeout to 10 minutes to allow for complex code generation
     // while still preventing indefinite hanging
@@ -697,7 +576,9 @@ fn create_client() -> Result<Client> {
 /// allowing multi-turn conversations with memory.
 #[tokio::main]
 async fn main() -> Result<()> {
-    let config = Config::from_env()?;
+    configure_colors(env::args().any(|a| a == "--no-color"));
+    let mut config = Config::from_env()?;
+    config.verbose_tools = env::args().any(|a| a == "--verbose-tools");
 
     // Initialize client - from_env() handles both API_KEY and BASE_URL
     let client = create_client()?;
@@ -748,6 +629,7 @@ async fn main() -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use mini_code_core::tools::safe_path;
 
     #[test]
     fn test_safe_truncate_short_string() {
@@ -810,11 +692,47 @@ mod tests {
         assert!(names.contains(&"edit_file"));
     }
 
+    #[test]
+    fn test_describe_tool_call_is_compact() {
+        let content = "x".repeat(5000);
+        assert_eq!(
+            describe_tool_call(
+                "write_file",
+                &json!({"path": "big.txt", "content": content})
+            ),
+            "big.txt (5000 bytes)"
+        );
+        assert_eq!(
+            describe_tool_call(
+                "edit_file",
+                &json!({"path": "a.rs", "old_text": "foo", "new_text": "foobar"})
+            ),
+            "a.rs (-3 +6 bytes)"
+        );
+        assert_eq!(
+            describe_tool_call("read_file", &json!({"path": "src/main.rs", "limit": 10})),
+            "src/main.rs"
+        );
+        assert_eq!(
+            describe_tool_call("bash", &json!({"command": "cargo test"})),
+            "cargo test"
+        );
+        let long = describe_tool_call("bash", &json!({"command": "echo ".repeat(50)}));
+        assert!(long.ends_with("...") && long.len() == 83);
+        assert_eq!(
+            describe_tool_call("web_search", &json!({"query": "rust"})),
+            r#"{"query":"rust"}"#
+        );
+    }
+
     #[test]
     fn test_config_system_prompt() {
         let config = Config {
             model: "test-model".to_string(),
             workdir: PathBuf::from("/test/path"),
+            temperature: None,
+            top_p: None,
+            verbose_tools: false,
         };
         let prompt = config.system_prompt();
         assert!(prompt.contains("/test/path"));
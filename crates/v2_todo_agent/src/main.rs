@@ -47,20 +47,25 @@
 //!     cargo run -p v2_todo_agent
 
 use anthropic::types::{
-    ContentBlock, Message, MessagesRequestBuilder, Role, StopReason, SystemPrompt, Tool,
+    ContentBlock, Message, MessagesRequest, MessagesRequestBuilder, Role, StopReason, SystemPrompt,
+    Tool, ToolChoice,
 };
 use anthropic::Client;
 use anyhow::{Context, Result};
 use colored::Colorize;
-use serde::{Deserialize, Serialize};
+use mini_code_core::api::{api_version_hint, apply_api_version};
+use mini_code_core::color::configure_colors;
+use mini_code_core::model::{apply_sampling, parse_sampling_param};
+use mini_code_core::todo::{TodoItem, TodoManager};
+use mini_code_core::tokens::{self, calculate_max_tokens};
+use mini_code_core::tools::{run_bash, run_edit, run_read, run_write, safe_truncate};
 use serde_json::json;
 use std::env;
 use std::fs;
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
 
@@ -135,38 +140,156 @@ struct Config {
     workdir: PathBuf,
     max_output_tokens: u32,
     max_truncation_retries: usize,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    todo_nag_rounds: usize,
+    todo_nag_message: Option<String>,
+    force_plan: bool,
+}
+
+/// Requests longer than this many words count as multi-step
+const MULTI_STEP_WORDS: usize = 30;
+
+/// Words that usually join the steps of a task ("add a flag, then test it")
+const STEP_WORDS: &[&str] = &[
+    "then",
+    "after",
+    "afterwards",
+    "next",
+    "finally",
+    "also",
+    "steps",
+];
+
+/// Whether a request looks like more than one step: several lines or
+/// sentences, a step word, or more than `MULTI_STEP_WORDS` words.
+/// "What is 2+2?" is one step; "add a flag, then test it" is not.
+fn is_multi_step(request: &str) -> bool {
+    let request = request.trim();
+    let lines = request.lines().filter(|l| !l.trim().is_empty()).count();
+    // A sentence end followed by more text; "main.rs" doesn't count
+    let sentences = request
+        .split(['.', '?', '!'])
+        .skip(1)
+        .any(|rest| rest.starts_with(char::is_whitespace) && !rest.trim().is_empty());
+    let words: Vec<String> = request
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect();
+
+    lines > 1
+        || sentences
+        || words.len() > MULTI_STEP_WORDS
+        || words.iter().any(|w| STEP_WORDS.contains(&w.as_str()))
+}
+
+/// Nag once the model has gone more than `nag_rounds` rounds without a
+/// TodoWrite. A threshold of 0 disables the reminder.
+fn should_nag(rounds_without_todo: usize, nag_rounds: usize) -> bool {
+    nag_rounds > 0 && rounds_without_todo > nag_rounds
 }
 
 impl Config {
     fn from_env() -> Result<Self> {
         dotenvy::dotenv().ok();
 
-        let model =
-            env::var("MODEL_NAME").unwrap_or_else(|_| "claude-sonnet-4-20250514".to_string());
+        let model = mini_code_core::model::resolve_model();
         let workdir = env::current_dir().context("Failed to get current directory")?;
 
-        // Read MINI_CODE_MAX_OUTPUT_TOKENS from environment, default to 160000
-        let max_output_tokens = env::var("MINI_CODE_MAX_OUTPUT_TOKENS")
-            .ok()
-            .and_then(|s| s.parse::<u32>().ok())
-            .unwrap_or(160000)
-            .clamp(1000, 100_000_000); // Clamp between 1000 and 100M tokens
+        let max_output_tokens = tokens::max_output_tokens();
+        let max_truncation_retries = tokens::max_truncation_retries();
+
+        let temperature = parse_sampling_param(
+            "MINI_CODE_TEMPERATURE",
+            env::var("MINI_CODE_TEMPERATURE").ok(),
+        )?;
+        let top_p = parse_sampling_param("MINI_CODE_TOP_P", env::var("MINI_CODE_TOP_P").ok())?;
 
-        // Read max truncation retries, default to 3
-        let max_truncation_retries = env::var("MINI_CODE_MAX_TRUNCATION_RETRIES")
+        // Rounds without a TodoWrite before nagging, default 10 (0 disables)
+        let todo_nag_rounds = env::var("MINI_CODE_TODO_NAG_ROUNDS")
             .ok()
             .and_then(|s| s.parse::<usize>().ok())
-            .unwrap_or(3)
-            .clamp(1, 10); // Clamp between 1 and 10 retries
+            .unwrap_or(10);
+
+        // Optional file overriding the nag reminder text
+        let todo_nag_message = match env::var("MINI_CODE_TODO_NAG_FILE") {
+            Ok(path) if !path.trim().is_empty() => Some(
+                fs::read_to_string(&path)
+                    .with_context(|| format!("Failed to read MINI_CODE_TODO_NAG_FILE {}", path))?,
+            ),
+            _ => None,
+        };
+
+        // Require a TodoWrite plan as the first step of multi-step requests
+        let force_plan = env::var("MINI_CODE_FORCE_PLAN")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
 
         Ok(Self {
             model,
             workdir,
             max_output_tokens,
             max_truncation_retries,
+            temperature,
+            top_p,
+            todo_nag_rounds,
+            todo_nag_message,
+            force_plan,
         })
     }
 
+    /// Reminder appended when the model hasn't touched the todo list in a while.
+    fn nag_reminder(&self) -> String {
+        match &self.todo_nag_message {
+            Some(message) => message.clone(),
+            None => format!(
+                r#"<system-reminder>
+It's been {}+ tool calls without updating the todo list.
+
+Please update the TodoWrite to:
+1. Mark completed tasks as "completed"
+2. Update current task to "in_progress" with activeForm
+3. Add any new tasks discovered during work
+
+This helps maintain visibility and focus.
+</system-reminder>"#,
+                self.todo_nag_rounds
+            ),
+        }
+    }
+
+    /// Build one turn's request. With MINI_CODE_FORCE_PLAN, the first turn of
+    /// a multi-step request forces a TodoWrite call; single-step requests and
+    /// later turns leave the choice to the model.
+    fn build_request(
+        &self,
+        messages: &[Message],
+        max_output: u32,
+        tools: &[Tool],
+        first_turn: bool,
+    ) -> Result<MessagesRequest> {
+        let mut builder = apply_sampling(
+            MessagesRequestBuilder::new(&self.model, messages.to_vec(), max_output)
+                .system(SystemPrompt::Text(self.system_prompt()))
+                .tools(tools.to_vec()),
+            self.temperature,
+            self.top_p,
+        );
+        let multi_step = messages.last().is_some_and(|m| {
+            m.content.iter().any(|block| match block {
+                ContentBlock::Text { text } => is_multi_step(text),
+                _ => false,
+            })
+        });
+        if self.force_plan && first_turn && multi_step {
+            builder = builder.tool_choice(ToolChoice::Tool {
+                name: "TodoWrite".to_string(),
+            });
+        }
+        Ok(builder.build()?)
+    }
+
     fn system_prompt(&self) -> String {
         format!(
             r#"You are a coding agent at {}.
@@ -209,126 +332,6 @@ OUTPUT:
     }
 }
 
-// =============================================================================
-// TodoManager - The core addition in v2
-// =============================================================================
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct TodoItem {
-    content: String,
-    status: TodoStatus,
-    #[serde(rename = "activeForm")]
-    active_form: String,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "snake_case")]
-enum TodoStatus {
-    Pending,
-    InProgress,
-    Completed,
-}
-
-/// Manages a structured task list with enforced constraints.
-///
-/// Key Design Decisions:
-/// --------------------
-/// 1. Max 20 items: Prevents the model from creating endless lists
-/// 2. One in_progress: Forces focus - can only work on ONE thing at a time
-/// 3. Required fields: Each item needs content, status, and activeForm
-///
-/// The activeForm field deserves explanation:
-/// - It's the PRESENT TENSE form of what's happening
-/// - Shown when status is "in_progress"
-/// - Example: content="Add tests", activeForm="Adding unit tests..."
-///
-/// This gives real-time visibility into what the agent is doing.
-struct TodoManager {
-    items: Arc<Mutex<Vec<TodoItem>>>,
-}
-
-impl TodoManager {
-    fn new() -> Self {
-        Self {
-            items: Arc::new(Mutex::new(Vec::new())),
-        }
-    }
-
-    /// Validate and update the todo list.
-    ///
-    /// The model sends a complete new list each time. We validate it,
-    /// store it, and return a rendered view that the model will see.
-    ///
-    /// Validation Rules:
-    /// - Each item must have: content, status, activeForm
-    /// - Status must be: pending | in_progress | completed
-    /// - Only ONE item can be in_progress at a time
-    /// - Maximum 20 items allowed
-    fn update(&self, new_items: Vec<TodoItem>) -> Result<String> {
-        let mut in_progress_count = 0;
-
-        // Validate items
-        for (i, item) in new_items.iter().enumerate() {
-            if item.content.trim().is_empty() {
-                anyhow::bail!("Item {}: content required", i);
-            }
-            if item.active_form.trim().is_empty() {
-                anyhow::bail!("Item {}: activeForm required", i);
-            }
-            if item.status == TodoStatus::InProgress {
-                in_progress_count += 1;
-            }
-        }
-
-        // Enforce constraints
-        if new_items.len() > 20 {
-            anyhow::bail!("Max 20 todos allowed");
-        }
-        if in_progress_count > 1 {
-            anyhow::bail!("Only one task can be in_progress at a time");
-        }
-
-        // Update stored items
-        *self.items.lock().unwrap() = new_items;
-
-        Ok(self.render())
-    }
-
-    /// Render the todo list as human-readable text.
-    ///
-    /// Format:
-    ///     [x] Completed task
-    ///     [>] In progress task <- Doing something...
-    ///     [ ] Pending task
-    ///
-    ///     (2/3 completed)
-    fn render(&self) -> String {
-        let items = self.items.lock().unwrap();
-
-        if items.is_empty() {
-            return "No todos.".to_string();
-        }
-
-        let mut lines = Vec::new();
-        for item in items.iter() {
-            let line = match item.status {
-                TodoStatus::Completed => format!("[x] {}", item.content),
-                TodoStatus::InProgress => format!("[>] {} <- {}", item.content, item.active_form),
-                TodoStatus::Pending => format!("[ ] {}", item.content),
-            };
-            lines.push(line);
-        }
-
-        let completed = items
-            .iter()
-            .filter(|t| t.status == TodoStatus::Completed)
-            .count();
-        lines.push(format!("\n({}/{} completed)", completed, items.len()));
-
-        lines.join("\n")
-    }
-}
-
 // =============================================================================
 // Tool Definitions (v1 tools + TodoWrite)
 // =============================================================================
@@ -449,152 +452,6 @@ fn create_tools() -> Vec<Tool> {
 // Tool Implementations
 // =============================================================================
 
-/// Safely truncate a string at a UTF-8 character boundary.
-///
-/// Unlike `&s[..n]` which panics if n is not at a character boundary,
-/// this function finds the largest valid boundary <= max_bytes.
-fn safe_truncate(s: &str, max_bytes: usize) -> &str {
-    if s.len() <= max_bytes {
-        return s;
-    }
-
-    // Find the last character boundary at or before max_bytes
-    let mut boundary = max_bytes;
-    while boundary > 0 && !s.is_char_boundary(boundary) {
-        boundary -= 1;
-    }
-
-    &s[..boundary]
-}
-
-fn safe_path(workdir: &Path, relative_path: &str) -> Result<PathBuf> {
-    let path = workdir.join(relative_path);
-    let canonical = path.canonicalize().or_else(|_| {
-        if let Some(parent) = path.parent() {
-            if parent.exists() {
-                Ok(parent.canonicalize()?.join(
-                    path.file_name()
-                        .ok_or_else(|| anyhow::anyhow!("Invalid path"))?,
-                ))
-            } else {
-                Err(anyhow::anyhow!("Parent directory does not exist"))
-            }
-        } else {
-            Err(anyhow::anyhow!("Invalid path"))
-        }
-    })?;
-
-    if !canonical.starts_with(workdir) {
-        anyhow::bail!("Path escapes workspace: {}", relative_path);
-    }
-
-    Ok(canonical)
-}
-
-fn run_bash(workdir: &Path, command: &str) -> String {
-    let dangerous = ["rm -rf /", "sudo", "shutdown", "reboot", "> /dev/"];
-    if dangerous.iter().any(|d| command.contains(d)) {
-        return "Error: Dangerous command blocked".to_string();
-    }
-
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .current_dir(workdir)
-        .output();
-
-    match output {
-        Ok(output) => {
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            let combined = format!("{}{}", stdout, stderr).trim().to_string();
-
-            if combined.is_empty() {
-                "(no output)".to_string()
-            } else if combined.len() > 50000 {
-                format!("{}...", safe_truncate(&combined, 50000))
-            } else {
-                combined
-            }
-        }
-        Err(e) => format!("Error: {}", e),
-    }
-}
-
-fn run_read(workdir: &Path, path: &str, limit: Option<i64>) -> String {
-    match safe_path(workdir, path) {
-        Ok(safe_path) => match fs::read_to_string(&safe_path) {
-            Ok(content) => {
-                let lines: Vec<&str> = content.lines().collect();
-                let total_lines = lines.len();
-
-                let output = if let Some(limit) = limit {
-                    if limit > 0 && (limit as usize) < total_lines {
-                        let limited_lines: Vec<&str> =
-                            lines.iter().take(limit as usize).copied().collect();
-                        format!(
-                            "{}\n... ({} more lines)",
-                            limited_lines.join("\n"),
-                            total_lines - limit as usize
-                        )
-                    } else {
-                        content
-                    }
-                } else {
-                    content
-                };
-
-                if output.len() > 50000 {
-                    format!("{}...", safe_truncate(&output, 50000))
-                } else {
-                    output
-                }
-            }
-            Err(e) => format!("Error: {}", e),
-        },
-        Err(e) => format!("Error: {}", e),
-    }
-}
-
-fn run_write(workdir: &Path, path: &str, content: &str) -> String {
-    match safe_path(workdir, path) {
-        Ok(safe_path) => {
-            if let Some(parent) = safe_path.parent() {
-                if let Err(e) = fs::create_dir_all(parent) {
-                    return format!("Error creating parent directories: {}", e);
-                }
-            }
-
-            match fs::write(&safe_path, content) {
-                Ok(_) => format!("Wrote {} bytes to {}", content.len(), path),
-                Err(e) => format!("Error: {}", e),
-            }
-        }
-        Err(e) => format!("Error: {}", e),
-    }
-}
-
-fn run_edit(workdir: &Path, path: &str, old_text: &str, new_text: &str) -> String {
-    match safe_path(workdir, path) {
-        Ok(safe_path) => match fs::read_to_string(&safe_path) {
-            Ok(content) => {
-                if !content.contains(old_text) {
-                    return format!("Error: Text not found in {}", path);
-                }
-
-                let new_content = content.replacen(old_text, new_text, 1);
-
-                match fs::write(&safe_path, new_content) {
-                    Ok(_) => format!("Edited {}", path),
-                    Err(e) => format!("Error: {}", e),
-                }
-            }
-            Err(e) => format!("Error: {}", e),
-        },
-        Err(e) => format!("Error: {}", e),
-    }
-}
-
 fn run_todo(todo_manager: &TodoManager, items: Vec<TodoItem>) -> String {
     match todo_manager.update(items) {
         Ok(rendered) => rendered,
@@ -691,18 +548,6 @@ fn estimate_context_tokens(messages: &[Message]) -> usize {
         .sum()
 }
 
-/// Calculate appropriate max_tokens based on context length and config
-fn calculate_max_tokens(context_tokens: usize, max_configured: u32) -> u32 {
-    const MAX_CONTEXT: usize = 200000; // Claude's context window
-    const OUTPUT_RATIO: f64 = 0.4; // Use 40% of remaining space for output
-
-    let available = MAX_CONTEXT.saturating_sub(context_tokens);
-    let max_output = (available as f64 * OUTPUT_RATIO) as u32;
-
-    // Keep within reasonable bounds and configured maximum
-    max_output.max(4000).min(max_configured)
-}
-
 // =============================================================================
 // Agent Loop (with todo tracking)
 // =============================================================================
@@ -716,16 +561,15 @@ async fn agent_loop(
 ) -> Result<()> {
     let tools = create_tools();
     let mut consecutive_truncations = 0;
+    let mut first_turn = true;
 
     loop {
         // Calculate dynamic max_tokens based on context and config
         let context_tokens = estimate_context_tokens(messages);
         let max_output = calculate_max_tokens(context_tokens, config.max_output_tokens);
 
-        let request = MessagesRequestBuilder::new(&config.model, messages.clone(), max_output)
-            .system(SystemPrompt::Text(config.system_prompt()))
-            .tools(tools.clone())
-            .build()?;
+        let request = config.build_request(messages, max_output, &tools, first_turn)?;
+        first_turn = false;
 
         // Record start time
         let start = Instant::now();
@@ -747,7 +591,9 @@ async fn agent_loop(
 
                 // Check for common errors and provide helpful messages
                 let error_msg = e.to_string();
-                if error_msg.contains("余额不足") || error_msg.contains("insufficient") {
+                if let Some(hint) = api_version_hint(&error_msg) {
+                    eprintln!("{}", hint.bright_yellow());
+                } else if error_msg.contains("余额不足") || error_msg.contains("insufficient") {
                     eprintln!(
                         "{}",
                         "Hint: Your API account balance is insufficient. Please recharge."
@@ -904,10 +750,8 @@ async fn agent_loop(
         let mut tool_calls = Vec::new();
         for block in &response.content {
             match block {
-                ContentBlock::Text { text } => {
-                    if !text.trim().is_empty() {
-                        println!("{}", text);
-                    }
+                ContentBlock::Text { text } if !text.trim().is_empty() => {
+                    println!("{}", text);
                 }
                 ContentBlock::ToolUse { id, name, input } => {
                     tool_calls.push((id.clone(), name.clone(), input.clone()));
@@ -1002,9 +846,7 @@ fn create_client() -> Result<Client> {
         builder = builder.api_base(base_url);
     }
 
-    if let Ok(api_version) = env::var("ANTHROPIC_API_VERSION") {
-        builder = builder.api_version(api_version);
-    }
+    builder = apply_api_version(builder);
 
     // Set timeout to 10 minutes to allow for complex code generation
     // while still preventing indefinite hanging
@@ -1053,6 +895,7 @@ fn prompt_user_with_rl(editor: &mut Editor<(), DefaultHistory>) -> Result<String
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    configure_colors(env::args().any(|a| a == "--no-color"));
     let config = Config::from_env()?;
     let client = create_client()?;
     let todo_manager = TodoManager::new();
@@ -1106,16 +949,7 @@ Benefits:
 - Maximum 20 tasks to keep plans manageable
 </system-reminder>"#;
 
-    let nag_reminder = r#"<system-reminder>
-It's been 10+ tool calls without updating the todo list.
-
-Please update the TodoWrite to:
-1. Mark completed tasks as "completed"
-2. Update current task to "in_progress" with activeForm
-3. Add any new tasks discovered during work
-
-This helps maintain visibility and focus.
-</system-reminder>"#;
+    let nag_reminder = config.nag_reminder();
 
     loop {
         let user_input = {
@@ -1141,8 +975,8 @@ This helps maintain visibility and focus.
         if first_message {
             content.push(ContentBlock::text(initial_reminder));
             first_message = false;
-        } else if rounds_without_todo > 10 {
-            content.push(ContentBlock::text(nag_reminder));
+        } else if should_nag(rounds_without_todo, config.todo_nag_rounds) {
+            content.push(ContentBlock::text(nag_reminder.clone()));
         }
 
         content.push(ContentBlock::text(user_input));
@@ -1177,8 +1011,88 @@ This helps maintain visibility and focus.
 #[cfg(test)]
 mod tests {
     use super::*;
+    use mini_code_core::todo::TodoStatus;
+    use mini_code_core::tools::safe_path;
     use serial_test::serial;
 
+    #[test]
+    fn test_force_plan_sets_tool_choice_on_first_turn_only() {
+        let mut config = Config {
+            model: "test-model".to_string(),
+            workdir: PathBuf::from("/test/path"),
+            max_output_tokens: 160000,
+            max_truncation_retries: 3,
+            temperature: None,
+            top_p: None,
+            todo_nag_rounds: 10,
+            todo_nag_message: None,
+            force_plan: true,
+        };
+        let messages = vec![Message {
+            role: Role::User,
+            content: vec![ContentBlock::text(
+                "Refactor the parser, then add tests for it",
+            )],
+        }];
+        let tools = create_tools();
+        let tool_choice = |config: &Config, messages: &[Message], first_turn: bool| {
+            let request = config
+                .build_request(messages, 1000, &tools, first_turn)
+                .unwrap();
+            serde_json::to_value(request).unwrap()["tool_choice"].clone()
+        };
+
+        assert_eq!(tool_choice(&config, &messages, true)["name"], "TodoWrite");
+        assert!(tool_choice(&config, &messages, false).is_null());
+
+        // A one-step question is answered directly
+        let question = vec![Message {
+            role: Role::User,
+            content: vec![ContentBlock::text("What is 2+2?")],
+        }];
+        assert!(tool_choice(&config, &question, true).is_null());
+
+        config.force_plan = false;
+        assert!(tool_choice(&config, &messages, true).is_null());
+    }
+
+    #[test]
+    fn test_is_multi_step() {
+        assert!(!is_multi_step("What is 2+2?"));
+        assert!(!is_multi_step("Explain what src/main.rs does"));
+        assert!(is_multi_step(
+            "Add a --verbose flag, then update the README"
+        ));
+        assert!(is_multi_step("Fix the build. Run the tests."));
+        assert!(is_multi_step("- rename foo\n- update callers"));
+        assert!(is_multi_step(&"word ".repeat(MULTI_STEP_WORDS + 1)));
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_from_env_sampling() {
+        std::env::remove_var("MINI_CODE_TEMPERATURE");
+        std::env::remove_var("MINI_CODE_TOP_P");
+
+        let config = Config::from_env().unwrap();
+        assert_eq!(config.temperature, None);
+        assert_eq!(config.top_p, None);
+
+        std::env::set_var("MINI_CODE_TEMPERATURE", "0.2");
+        std::env::set_var("MINI_CODE_TOP_P", "0.9");
+        let config = Config::from_env().unwrap();
+        assert_eq!(config.temperature, Some(0.2));
+        assert_eq!(config.top_p, Some(0.9));
+
+        // Out of range is an error, not a silent clamp
+        std::env::set_var("MINI_CODE_TEMPERATURE", "2.0");
+        assert!(Config::from_env().is_err());
+
+        // Clean up
+        std::env::remove_var("MINI_CODE_TEMPERATURE");
+        std::env::remove_var("MINI_CODE_TOP_P");
+    }
+
     #[test]
     fn test_safe_truncate_short_string() {
         let s = "Hello, World!";
@@ -1348,6 +1262,11 @@ mod tests {
             workdir: PathBuf::from("/test/path"),
             max_output_tokens: 160000,
             max_truncation_retries: 3,
+            temperature: None,
+            top_p: None,
+            todo_nag_rounds: 10,
+            todo_nag_message: None,
+            force_plan: false,
         };
         let prompt = config.system_prompt();
         assert!(prompt.contains("/test/path"));
@@ -1579,4 +1498,37 @@ mod tests {
         std::env::remove_var("MINI_CODE_MAX_OUTPUT_TOKENS");
         std::env::remove_var("MINI_CODE_MAX_TRUNCATION_RETRIES");
     }
+
+    #[test]
+    fn test_should_nag_boundaries() {
+        assert!(!should_nag(9, 10)); // just below
+        assert!(!should_nag(10, 10)); // exactly at
+        assert!(should_nag(11, 10)); // just above
+        assert!(!should_nag(100, 0)); // disabled
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_todo_nag_from_env() {
+        std::env::remove_var("MINI_CODE_TODO_NAG_ROUNDS");
+        std::env::remove_var("MINI_CODE_TODO_NAG_FILE");
+
+        let config = Config::from_env().unwrap();
+        assert_eq!(config.todo_nag_rounds, 10);
+        assert!(config.nag_reminder().contains("10+ tool calls"));
+
+        let path = std::env::temp_dir().join(format!("v2_nag_{}.txt", std::process::id()));
+        std::fs::write(&path, "Update your todos!").unwrap();
+        std::env::set_var("MINI_CODE_TODO_NAG_ROUNDS", "0");
+        std::env::set_var("MINI_CODE_TODO_NAG_FILE", &path);
+
+        let config = Config::from_env().unwrap();
+        assert_eq!(config.todo_nag_rounds, 0);
+        assert_eq!(config.nag_reminder(), "Update your todos!");
+
+        // Clean up
+        std::fs::remove_file(&path).ok();
+        std::env::remove_var("MINI_CODE_TODO_NAG_ROUNDS");
+        std::env::remove_var("MINI_CODE_TODO_NAG_FILE");
+    }
 }
@@ -57,14 +57,17 @@ use anthropic::types::{
 use anthropic::Client;
 use anyhow::{Context, Result};
 use colored::Colorize;
-use serde::{Deserialize, Serialize};
+use mini_code_core::api::{api_version_hint, apply_api_version};
+use mini_code_core::color::configure_colors;
+use mini_code_core::model::{apply_sampling, parse_sampling_param};
+use mini_code_core::todo::{TodoItem, TodoManager};
+use mini_code_core::tokens::{self, calculate_max_tokens};
+use mini_code_core::tools::{run_bash, run_edit, run_read, run_write, safe_truncate};
 use serde_json::json;
 use std::collections::HashMap;
 use std::env;
-use std::fs;
 use std::io::{self, Write};
-use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
@@ -132,6 +135,19 @@ impl Drop for ThinkingAnimation {
     }
 }
 
+// =============================================================================
+// Startup Banner
+// =============================================================================
+
+/// Decorative startup output is skipped for `--no-banner` or MINI_CODE_NO_BANNER
+fn should_print_banner(no_banner_flag: bool, no_banner_env: bool) -> bool {
+    !(no_banner_flag || no_banner_env)
+}
+
+fn no_banner_env() -> bool {
+    env::var("MINI_CODE_NO_BANNER").is_ok_and(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+}
+
 // =============================================================================
 // Configuration
 // =============================================================================
@@ -141,35 +157,33 @@ struct Config {
     workdir: PathBuf,
     max_output_tokens: u32,
     max_truncation_retries: usize,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
 }
 
 impl Config {
     fn from_env() -> Result<Self> {
         dotenvy::dotenv().ok();
 
-        let model =
-            env::var("MODEL_NAME").unwrap_or_else(|_| "claude-sonnet-4-20250514".to_string());
+        let model = mini_code_core::model::resolve_model();
         let workdir = env::current_dir().context("Failed to get current directory")?;
 
-        // Read MINI_CODE_MAX_OUTPUT_TOKENS from environment, default to 160000
-        let max_output_tokens = env::var("MINI_CODE_MAX_OUTPUT_TOKENS")
-            .ok()
-            .and_then(|s| s.parse::<u32>().ok())
-            .unwrap_or(160000)
-            .clamp(1000, 100_000_000); // Clamp between 1000 and 100M tokens
+        let max_output_tokens = tokens::max_output_tokens();
+        let max_truncation_retries = tokens::max_truncation_retries();
 
-        // Read max truncation retries, default to 3
-        let max_truncation_retries = env::var("MINI_CODE_MAX_TRUNCATION_RETRIES")
-            .ok()
-            .and_then(|s| s.parse::<usize>().ok())
-            .unwrap_or(3)
-            .clamp(1, 10); // Clamp between 1 and 10 retries
+        let temperature = parse_sampling_param(
+            "MINI_CODE_TEMPERATURE",
+            env::var("MINI_CODE_TEMPERATURE").ok(),
+        )?;
+        let top_p = parse_sampling_param("MINI_CODE_TOP_P", env::var("MINI_CODE_TOP_P").ok())?;
 
         Ok(Self {
             model,
             workdir,
             max_output_tokens,
             max_truncation_retries,
+            temperature,
+            top_p,
         })
     }
 
@@ -250,97 +264,6 @@ fn get_agent_descriptions() -> String {
         .join("\n")
 }
 
-// =============================================================================
-// TodoManager (from v2, unchanged)
-// =============================================================================
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct TodoItem {
-    content: String,
-    status: TodoStatus,
-    #[serde(rename = "activeForm")]
-    active_form: String,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "snake_case")]
-enum TodoStatus {
-    Pending,
-    InProgress,
-    Completed,
-}
-
-/// Manages a structured task list with enforced constraints.
-struct TodoManager {
-    items: Arc<Mutex<Vec<TodoItem>>>,
-}
-
-impl TodoManager {
-    fn new() -> Self {
-        Self {
-            items: Arc::new(Mutex::new(Vec::new())),
-        }
-    }
-
-    /// Validate and update the todo list.
-    fn update(&self, new_items: Vec<TodoItem>) -> Result<String> {
-        let mut in_progress_count = 0;
-
-        // Validate items
-        for (i, item) in new_items.iter().enumerate() {
-            if item.content.trim().is_empty() {
-                anyhow::bail!("Item {}: content required", i);
-            }
-            if item.active_form.trim().is_empty() {
-                anyhow::bail!("Item {}: activeForm required", i);
-            }
-            if item.status == TodoStatus::InProgress {
-                in_progress_count += 1;
-            }
-        }
-
-        // Enforce constraints
-        if new_items.len() > 20 {
-            anyhow::bail!("Max 20 todos allowed");
-        }
-        if in_progress_count > 1 {
-            anyhow::bail!("Only one task can be in_progress at a time");
-        }
-
-        // Update stored items
-        *self.items.lock().unwrap() = new_items;
-
-        Ok(self.render())
-    }
-
-    /// Render the todo list as human-readable text.
-    fn render(&self) -> String {
-        let items = self.items.lock().unwrap();
-
-        if items.is_empty() {
-            return "No todos.".to_string();
-        }
-
-        let mut lines = Vec::new();
-        for item in items.iter() {
-            let line = match item.status {
-                TodoStatus::Completed => format!("[x] {}", item.content),
-                TodoStatus::InProgress => format!("[>] {} <- {}", item.content, item.active_form),
-                TodoStatus::Pending => format!("[ ] {}", item.content),
-            };
-            lines.push(line);
-        }
-
-        let completed = items
-            .iter()
-            .filter(|t| t.status == TodoStatus::Completed)
-            .count();
-        lines.push(format!("\n({}/{} completed)", completed, items.len()));
-
-        lines.join("\n")
-    }
-}
-
 // =============================================================================
 // Tool Definitions (v2 tools + Task)
 // =============================================================================
@@ -533,168 +456,6 @@ fn get_tools_for_agent(agent_type: &str) -> Vec<Tool> {
 // Tool Implementations (from v2, unchanged)
 // =============================================================================
 
-/// Safely truncate a string at a UTF-8 character boundary.
-fn safe_truncate(s: &str, max_bytes: usize) -> &str {
-    if s.len() <= max_bytes {
-        return s;
-    }
-
-    let mut boundary = max_bytes;
-    while boundary > 0 && !s.is_char_boundary(boundary) {
-        boundary -= 1;
-    }
-
-    &s[..boundary]
-}
-
-fn safe_path(workdir: &Path, relative_path: &str) -> Result<PathBuf> {
-    let path = workdir.join(relative_path);
-    let canonical = path.canonicalize().or_else(|_| {
-        if let Some(parent) = path.parent() {
-            if parent.exists() {
-                Ok(parent.canonicalize()?.join(
-                    path.file_name()
-                        .ok_or_else(|| anyhow::anyhow!("Invalid path"))?,
-                ))
-            } else {
-                Err(anyhow::anyhow!("Parent directory does not exist"))
-            }
-        } else {
-            Err(anyhow::anyhow!("Invalid path"))
-        }
-    })?;
-
-    if !canonical.starts_with(workdir) {
-        anyhow::bail!("Path escapes workspace: {}", relative_path);
-    }
-
-    Ok(canonical)
-}
-
-fn run_bash(workdir: &Path, command: &str) -> String {
-    let dangerous = ["rm -rf /", "sudo", "shutdown", "reboot", "> /dev/"];
-    if dangerous.iter().any(|d| command.contains(d)) {
-        return "Error: Dangerous command blocked".to_string();
-    }
-
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .current_dir(workdir)
-        .output();
-
-    match output {
-        Ok(output) => {
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            let combined = format!("{}{}", stdout, stderr).trim().to_string();
-
-            if combined.is_empty() {
-                "(no output)".to_string()
-            } else if combined.len() > 50000 {
-                format!("{}...", safe_truncate(&combined, 50000))
-            } else {
-                combined
-            }
-        }
-        Err(e) => format!("Error: {}", e),
-    }
-}
-
-fn run_read(workdir: &Path, path: &str, limit: Option<i64>) -> String {
-    match safe_path(workdir, path) {
-        Ok(safe_path) => {
-            // Read file as raw bytes first to handle non-UTF8 content gracefully
-            match fs::read(&safe_path) {
-                Ok(bytes) => {
-                    // Check for non-UTF8 bytes
-                    let has_invalid_utf8 = bytes.iter().any(|&b| b > 0x7F && !b.is_ascii());
-
-                    let content = if has_invalid_utf8 {
-                        // Use lossy conversion to handle binary data in log files
-                        String::from_utf8_lossy(&bytes).to_string()
-                    } else {
-                        // Safe to use strict UTF-8 for pure ASCII files
-                        match String::from_utf8(bytes) {
-                            Ok(s) => s,
-                            Err(e) => {
-                                // Fallback to lossy conversion
-                                String::from_utf8_lossy(e.as_bytes()).to_string()
-                            }
-                        }
-                    };
-
-                    let lines: Vec<&str> = content.lines().collect();
-                    let total_lines = lines.len();
-
-                    let output = if let Some(limit) = limit {
-                        if limit > 0 && (limit as usize) < total_lines {
-                            let limited_lines: Vec<&str> =
-                                lines.iter().take(limit as usize).copied().collect();
-                            format!(
-                                "{}\n... ({} more lines)",
-                                limited_lines.join("\n"),
-                                total_lines - limit as usize
-                            )
-                        } else {
-                            content
-                        }
-                    } else {
-                        content
-                    };
-
-                    if output.len() > 50000 {
-                        format!("{}...", safe_truncate(&output, 50000))
-                    } else {
-                        output
-                    }
-                }
-                Err(e) => format!("Error reading file: {}", e),
-            }
-        }
-        Err(e) => format!("Error: {}", e),
-    }
-}
-
-fn run_write(workdir: &Path, path: &str, content: &str) -> String {
-    match safe_path(workdir, path) {
-        Ok(safe_path) => {
-            if let Some(parent) = safe_path.parent() {
-                if let Err(e) = fs::create_dir_all(parent) {
-                    return format!("Error creating parent directories: {}", e);
-                }
-            }
-
-            match fs::write(&safe_path, content) {
-                Ok(_) => format!("Wrote {} bytes to {}", content.len(), path),
-                Err(e) => format!("Error: {}", e),
-            }
-        }
-        Err(e) => format!("Error: {}", e),
-    }
-}
-
-fn run_edit(workdir: &Path, path: &str, old_text: &str, new_text: &str) -> String {
-    match safe_path(workdir, path) {
-        Ok(safe_path) => match fs::read_to_string(&safe_path) {
-            Ok(content) => {
-                if !content.contains(old_text) {
-                    return format!("Error: Text not found in {}", path);
-                }
-
-                let new_content = content.replacen(old_text, new_text, 1);
-
-                match fs::write(&safe_path, new_content) {
-                    Ok(_) => format!("Edited {}", path),
-                    Err(e) => format!("Error: {}", e),
-                }
-            }
-            Err(e) => format!("Error: {}", e),
-        },
-        Err(e) => format!("Error: {}", e),
-    }
-}
-
 fn run_todo(todo_manager: &TodoManager, items: Vec<TodoItem>) -> String {
     match todo_manager.update(items) {
         Ok(rendered) => rendered,
@@ -848,10 +609,14 @@ Complete the task and return a clear, concise summary."#,
 
     // Run the same agent loop (with real-time progress display)
     let result = loop {
-        let request = MessagesRequestBuilder::new(&config.model, sub_messages.clone(), 8000)
-            .system(SystemPrompt::Text(sub_system.clone()))
-            .tools(sub_tools.clone())
-            .build();
+        let request = apply_sampling(
+            MessagesRequestBuilder::new(&config.model, sub_messages.clone(), 8000)
+                .system(SystemPrompt::Text(sub_system.clone()))
+                .tools(sub_tools.clone()),
+            config.temperature,
+            config.top_p,
+        )
+        .build();
 
         let request = match request {
             Ok(r) => r,
@@ -980,14 +745,12 @@ Complete the task and return a clear, concise summary."#,
 
             Some(StopReason::EndTurn) | Some(StopReason::StopSequence) | None => {
                 // Extract final text and return
-                let mut text_result = None;
-                for block in &response.content {
-                    if let ContentBlock::Text { text } = block {
-                        text_result = Some(text.clone());
-                        break;
-                    }
-                }
-                break text_result.unwrap_or_else(|| "(subagent returned no text)".to_string());
+                let text_result = response.content.iter().find_map(|block| match block {
+                    ContentBlock::Text { text } if !text.trim().is_empty() => Some(text.clone()),
+                    _ => None,
+                });
+                break text_result
+                    .unwrap_or_else(|| empty_subagent_result(progress.lock().unwrap().tool_count));
             }
         }
     };
@@ -1150,16 +913,26 @@ fn estimate_context_tokens(messages: &[Message]) -> usize {
         .sum()
 }
 
-/// Calculate appropriate max_tokens based on context length and config
-fn calculate_max_tokens(context_tokens: usize, max_configured: u32) -> u32 {
-    const MAX_CONTEXT: usize = 200000; // Claude's context window
-    const OUTPUT_RATIO: f64 = 0.4; // Use 40% of remaining space for output
+/// Note shown (and kept in history) when the model ends a turn with no text.
+const NO_RESPONSE_TEXT: &str = "(no response text)";
+
+/// One-time nudge sent after an empty final response.
+const EMPTY_RESPONSE_NUDGE: &str =
+    "Your last reply had no text. Please provide a final summary of what you did.";
 
-    let available = MAX_CONTEXT.saturating_sub(context_tokens);
-    let max_output = (available as f64 * OUTPUT_RATIO) as u32;
+/// True if any text block has non-whitespace content
+fn has_response_text(content: &[ContentBlock]) -> bool {
+    content
+        .iter()
+        .any(|block| matches!(block, ContentBlock::Text { text } if !text.trim().is_empty()))
+}
 
-    // Keep within reasonable bounds and configured maximum
-    max_output.max(4000).min(max_configured)
+/// Result returned by a subagent that ended without any text
+fn empty_subagent_result(tool_count: usize) -> String {
+    format!(
+        "(subagent finished after {} tool calls without a text summary; inspect its changes directly)",
+        tool_count
+    )
 }
 
 // =============================================================================
@@ -1174,16 +947,21 @@ async fn agent_loop(
 ) -> Result<()> {
     let tools = create_all_tools();
     let mut consecutive_truncations = 0;
+    let mut nudged_empty = false;
 
     loop {
         // Calculate dynamic max_tokens based on context and config
         let context_tokens = estimate_context_tokens(messages);
         let max_output = calculate_max_tokens(context_tokens, config.max_output_tokens);
 
-        let request = MessagesRequestBuilder::new(&config.model, messages.clone(), max_output)
-            .system(SystemPrompt::Text(config.system_prompt()))
-            .tools(tools.clone())
-            .build()?;
+        let request = apply_sampling(
+            MessagesRequestBuilder::new(&config.model, messages.clone(), max_output)
+                .system(SystemPrompt::Text(config.system_prompt()))
+                .tools(tools.clone()),
+            config.temperature,
+            config.top_p,
+        )
+        .build()?;
 
         let start = Instant::now();
         let _animation = spawn_thinking_animation();
@@ -1196,6 +974,9 @@ async fn agent_loop(
             Ok(Err(e)) => {
                 drop(_animation);
                 eprintln!("\n{}: {}", "API Error".bright_red(), e);
+                if let Some(hint) = api_version_hint(&e.to_string()) {
+                    eprintln!("{}", hint.bright_yellow());
+                }
                 return Err(e.into());
             }
             Err(_) => {
@@ -1297,10 +1078,8 @@ async fn agent_loop(
                 let mut tool_calls = Vec::new();
                 for block in &response.content {
                     match block {
-                        ContentBlock::Text { text } => {
-                            if !text.trim().is_empty() {
-                                println!("{}", text);
-                            }
+                        ContentBlock::Text { text } if !text.trim().is_empty() => {
+                            println!("{}", text);
                         }
                         ContentBlock::ToolUse { id, name, input } => {
                             tool_calls.push((id.clone(), name.clone(), input.clone()));
@@ -1363,6 +1142,7 @@ async fn agent_loop(
 
             Some(StopReason::EndTurn) | Some(StopReason::StopSequence) | None => {
                 // Normal end - display text and return
+                let has_text = has_response_text(&response.content);
                 for block in &response.content {
                     if let ContentBlock::Text { text } = block {
                         if !text.trim().is_empty() {
@@ -1371,11 +1151,27 @@ async fn agent_loop(
                     }
                 }
 
+                // An empty assistant turn can't be sent back to the API
+                let content = if has_text {
+                    response.content
+                } else {
+                    println!("{}", NO_RESPONSE_TEXT.bright_black());
+                    vec![ContentBlock::text(NO_RESPONSE_TEXT)]
+                };
                 messages.push(Message {
                     role: Role::Assistant,
-                    content: response.content,
+                    content,
                 });
 
+                if !has_text && !nudged_empty {
+                    nudged_empty = true;
+                    messages.push(Message {
+                        role: Role::User,
+                        content: vec![ContentBlock::text(EMPTY_RESPONSE_NUDGE)],
+                    });
+                    continue;
+                }
+
                 return Ok(());
             }
         }
@@ -1402,9 +1198,7 @@ fn create_client() -> Result<Client> {
         builder = builder.api_base(base_url);
     }
 
-    if let Ok(api_version) = env::var("ANTHROPIC_API_VERSION") {
-        builder = builder.api_version(api_version);
-    }
+    builder = apply_api_version(builder);
 
     builder = builder.timeout(Duration::from_secs(600));
 
@@ -1451,31 +1245,34 @@ fn prompt_user_with_rl(editor: &mut Editor<(), DefaultHistory>) -> Result<String
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    configure_colors(env::args().any(|a| a == "--no-color"));
     let config = Config::from_env()?;
     let client = create_client()?;
     let todo_manager = TodoManager::new();
 
-    println!(
-        "{}",
-        format!(
-            "Mini Claude Code v3 (with Subagents) - {}",
-            config.workdir.display()
-        )
-        .bright_green()
-    );
-    println!(
-        "{}",
-        format!(
-            "Agent types: {}",
-            get_agent_types()
-                .keys()
-                .map(|s| s.as_str())
-                .collect::<Vec<_>>()
-                .join(", ")
-        )
-        .bright_black()
-    );
-    println!("{}\n", "Type 'exit' to quit.".bright_black());
+    if should_print_banner(env::args().any(|a| a == "--no-banner"), no_banner_env()) {
+        println!(
+            "{}",
+            format!(
+                "Mini Claude Code v3 (with Subagents) - {}",
+                config.workdir.display()
+            )
+            .bright_green()
+        );
+        println!(
+            "{}",
+            format!(
+                "Agent types: {}",
+                get_agent_types()
+                    .keys()
+                    .map(|s| s.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+            .bright_black()
+        );
+        println!("{}\n", "Type 'exit' to quit.".bright_black());
+    }
 
     let mut history: Vec<Message> = Vec::new();
 
@@ -1522,8 +1319,43 @@ async fn main() -> Result<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use mini_code_core::todo::TodoStatus;
+    use mini_code_core::tools::safe_path;
     use serial_test::serial;
 
+    #[test]
+    fn test_has_response_text() {
+        assert!(!has_response_text(&[]));
+        assert!(!has_response_text(&[ContentBlock::text("   ")]));
+        assert!(has_response_text(&[ContentBlock::text("Done.")]));
+        assert!(empty_subagent_result(2).contains("after 2 tool calls"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_config_from_env_sampling() {
+        std::env::remove_var("MINI_CODE_TEMPERATURE");
+        std::env::remove_var("MINI_CODE_TOP_P");
+
+        let config = Config::from_env().unwrap();
+        assert_eq!(config.temperature, None);
+        assert_eq!(config.top_p, None);
+
+        std::env::set_var("MINI_CODE_TEMPERATURE", "0.2");
+        std::env::set_var("MINI_CODE_TOP_P", "0.9");
+        let config = Config::from_env().unwrap();
+        assert_eq!(config.temperature, Some(0.2));
+        assert_eq!(config.top_p, Some(0.9));
+
+        // Out of range is an error, not a silent clamp
+        std::env::set_var("MINI_CODE_TEMPERATURE", "2.0");
+        assert!(Config::from_env().is_err());
+
+        // Clean up
+        std::env::remove_var("MINI_CODE_TEMPERATURE");
+        std::env::remove_var("MINI_CODE_TOP_P");
+    }
+
     #[test]
     fn test_safe_truncate_short_string() {
         let s = "Hello, World!";
@@ -0,0 +1,252 @@
+//! Approval Policy Module
+//!
+//! Decides whether a mutating tool call (shell commands, file writes, process
+//! control) may run. One policy is active per session, chosen with
+//! `MINI_CODE_APPROVAL`; read-only tools never consult it.
+
+use anyhow::Result;
+use serde_json::Value;
+use std::collections::BTreeSet;
+
+/// Tools that change the workspace or the processes running in it
+const MUTATING_TOOLS: &[&str] = &[
+    "bash",
+    "write_file",
+    "edit_file",
+    "bash_background",
+    "bash_kill",
+    "run_project_task",
+    "rust_rename",
+];
+
+/// Whether a call can change anything; `diff` only writes with `save_as`
+/// and `replace_in_files` not at all with `dry_run`
+pub fn is_mutating(tool: &str, input: &Value) -> bool {
+    match tool {
+        "diff" => input.get("save_as").is_some(),
+        "replace_in_files" => input.get("dry_run") != Some(&Value::Bool(true)),
+        _ => MUTATING_TOOLS.contains(&tool),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Approval {
+    Allow,
+    Deny { reason: String },
+    AskUser,
+}
+
+pub trait ApprovalPolicy: Send + Sync {
+    fn approve(&self, tool: &str, input: &Value) -> Approval;
+
+    /// Short label for `/config`
+    fn describe(&self) -> String;
+}
+
+/// Run everything (the default)
+pub struct AllowAll;
+
+impl ApprovalPolicy for AllowAll {
+    fn approve(&self, _tool: &str, _input: &Value) -> Approval {
+        Approval::Allow
+    }
+
+    fn describe(&self) -> String {
+        "allow".to_string()
+    }
+}
+
+/// Refuse every mutating call
+pub struct ReadOnly;
+
+impl ApprovalPolicy for ReadOnly {
+    fn approve(&self, tool: &str, input: &Value) -> Approval {
+        if is_mutating(tool, input) {
+            Approval::Deny {
+                reason: "the session is read-only".to_string(),
+            }
+        } else {
+            Approval::Allow
+        }
+    }
+
+    fn describe(&self) -> String {
+        "read-only".to_string()
+    }
+}
+
+/// Ask the user before mutating calls; `tools` narrows which ones
+pub struct Interactive {
+    tools: Option<BTreeSet<String>>,
+}
+
+impl Interactive {
+    /// Ask before every mutating call
+    pub fn all() -> Self {
+        Self { tools: None }
+    }
+
+    /// Ask only before the named tools (e.g. `edit_file` for confirm-edits)
+    pub fn only(tools: &[&str]) -> Self {
+        Self {
+            tools: Some(tools.iter().map(|t| t.to_string()).collect()),
+        }
+    }
+}
+
+impl ApprovalPolicy for Interactive {
+    fn approve(&self, tool: &str, input: &Value) -> Approval {
+        let asks = match &self.tools {
+            Some(tools) => tools.contains(tool),
+            None => is_mutating(tool, input),
+        };
+        if asks {
+            Approval::AskUser
+        } else {
+            Approval::Allow
+        }
+    }
+
+    fn describe(&self) -> String {
+        match &self.tools {
+            Some(tools) => format!("interactive ({})", join(tools)),
+            None => "interactive".to_string(),
+        }
+    }
+}
+
+/// Allow only the listed mutating tools
+pub struct Allowlist {
+    tools: BTreeSet<String>,
+}
+
+impl Allowlist {
+    pub fn new<I: IntoIterator<Item = String>>(tools: I) -> Self {
+        Self {
+            tools: tools.into_iter().collect(),
+        }
+    }
+}
+
+impl ApprovalPolicy for Allowlist {
+    fn approve(&self, tool: &str, input: &Value) -> Approval {
+        if !is_mutating(tool, input) || self.tools.contains(tool) {
+            Approval::Allow
+        } else {
+            Approval::Deny {
+                reason: format!("{} is not in the allowlist", tool),
+            }
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("allowlist ({})", join(&self.tools))
+    }
+}
+
+fn join(tools: &BTreeSet<String>) -> String {
+    tools.iter().cloned().collect::<Vec<_>>().join(", ")
+}
+
+/// Parse a MINI_CODE_APPROVAL value:
+/// `allow`, `read-only`, `interactive` or `allowlist:tool1,tool2`
+pub fn parse_policy(value: &str) -> Result<Box<dyn ApprovalPolicy>> {
+    let value = value.trim();
+    let policy: Box<dyn ApprovalPolicy> = match value.to_lowercase().as_str() {
+        "" | "allow" | "allow-all" => Box::new(AllowAll),
+        "read-only" | "readonly" => Box::new(ReadOnly),
+        "interactive" | "ask" => Box::new(Interactive::all()),
+        _ => match value.split_once(':') {
+            Some((kind, tools)) if kind.eq_ignore_ascii_case("allowlist") => {
+                Box::new(Allowlist::new(
+                    tools
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|t| !t.is_empty())
+                        .map(str::to_string),
+                ))
+            }
+            _ => anyhow::bail!(
+                "Invalid MINI_CODE_APPROVAL '{}': expected allow, read-only, interactive or allowlist:<tools>",
+                value
+            ),
+        },
+    };
+    Ok(policy)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn deny(reason: &str) -> Approval {
+        Approval::Deny {
+            reason: reason.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_builtin_policy_decisions() {
+        let bash = json!({"command": "rm -rf target"});
+        let read = json!({"path": "src/main.rs"});
+        let diff_only = json!({"path": "a.rs", "content": "x"});
+        let diff_save = json!({"path": "a.rs", "content": "x", "save_as": "a.patch"});
+
+        assert_eq!(AllowAll.approve("bash", &bash), Approval::Allow);
+
+        let replace = json!({"pattern": "a", "replacement": "b"});
+        let replace_dry = json!({"pattern": "a", "replacement": "b", "dry_run": true});
+        assert_eq!(
+            ReadOnly.approve("replace_in_files", &replace),
+            deny("the session is read-only")
+        );
+        assert_eq!(
+            ReadOnly.approve("replace_in_files", &replace_dry),
+            Approval::Allow
+        );
+
+        assert_eq!(
+            ReadOnly.approve("bash", &bash),
+            deny("the session is read-only")
+        );
+        assert_eq!(ReadOnly.approve("read_file", &read), Approval::Allow);
+        assert_eq!(ReadOnly.approve("diff", &diff_only), Approval::Allow);
+        assert_eq!(
+            ReadOnly.approve("diff", &diff_save),
+            deny("the session is read-only")
+        );
+
+        let interactive = Interactive::all();
+        assert_eq!(interactive.approve("write_file", &read), Approval::AskUser);
+        assert_eq!(interactive.approve("read_file", &read), Approval::Allow);
+        let edits_only = Interactive::only(&["edit_file"]);
+        assert_eq!(edits_only.approve("edit_file", &read), Approval::AskUser);
+        assert_eq!(edits_only.approve("bash", &bash), Approval::Allow);
+
+        let allowlist = Allowlist::new(["bash".to_string()]);
+        assert_eq!(allowlist.approve("bash", &bash), Approval::Allow);
+        assert_eq!(allowlist.approve("read_file", &read), Approval::Allow);
+        assert_eq!(
+            allowlist.approve("write_file", &read),
+            deny("write_file is not in the allowlist")
+        );
+    }
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!(parse_policy("").unwrap().describe(), "allow");
+        assert_eq!(parse_policy("Read-Only").unwrap().describe(), "read-only");
+        assert_eq!(
+            parse_policy("interactive").unwrap().describe(),
+            "interactive"
+        );
+        assert_eq!(
+            parse_policy("allowlist: edit_file, bash")
+                .unwrap()
+                .describe(),
+            "allowlist (bash, edit_file)"
+        );
+        assert!(parse_policy("yolo").is_err());
+    }
+}
@@ -0,0 +1,265 @@
+//! Background Process Module
+//!
+//! Long-running commands (dev servers, watchers) started with the
+//! `bash_background` tool. Each process gets a numeric id; its combined
+//! stdout/stderr is captured into a bounded buffer that `bash_logs` reads.
+//! Everything still running is killed when the registry is dropped.
+//!
+//! Each command runs in its own process group and is killed as a group, so
+//! a dev server started through `npm run dev` dies with its shell instead
+//! of being orphaned and holding on to its port.
+
+use anyhow::{Context, Result};
+use mini_code_core::cancel::kill_process_group;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Captured output kept per process; older bytes are dropped first
+const MAX_LOG_BYTES: usize = 100_000;
+
+struct BackgroundProcess {
+    command: String,
+    child: Child,
+    output: Arc<Mutex<String>>,
+}
+
+/// Shared table of background processes, keyed by handle id
+#[derive(Default)]
+pub struct ProcessRegistry {
+    processes: Mutex<BTreeMap<usize, BackgroundProcess>>,
+    next_id: Mutex<usize>,
+}
+
+impl ProcessRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start `command` under `sh -c` in `workdir` and return its handle id
+    pub fn spawn(&self, workdir: &Path, command: &str) -> Result<usize> {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c")
+            .arg(command)
+            .current_dir(workdir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        #[cfg(unix)]
+        std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+        let mut child = cmd
+            .spawn()
+            .with_context(|| format!("Failed to start '{}'", command))?;
+
+        let output = Arc::new(Mutex::new(String::new()));
+        if let Some(stdout) = child.stdout.take() {
+            capture(stdout, Arc::clone(&output));
+        }
+        if let Some(stderr) = child.stderr.take() {
+            capture(stderr, Arc::clone(&output));
+        }
+
+        let mut next_id = self.next_id.lock().unwrap();
+        *next_id += 1;
+        let id = *next_id;
+
+        self.processes.lock().unwrap().insert(
+            id,
+            BackgroundProcess {
+                command: command.to_string(),
+                child,
+                output,
+            },
+        );
+        Ok(id)
+    }
+
+    /// Status line plus the last `tail_bytes` of captured output
+    pub fn logs(&self, id: usize, tail_bytes: usize) -> Result<String> {
+        let mut processes = self.processes.lock().unwrap();
+        let process = processes
+            .get_mut(&id)
+            .ok_or_else(|| anyhow::anyhow!("No background process with id {}", id))?;
+
+        let status = match process.child.try_wait()? {
+            Some(status) => match status.code() {
+                Some(code) => format!("exited with code {}", code),
+                None => "terminated by signal".to_string(),
+            },
+            None => "running".to_string(),
+        };
+
+        let output = process.output.lock().unwrap();
+        let mut start = output.len().saturating_sub(tail_bytes);
+        while !output.is_char_boundary(start) {
+            start += 1;
+        }
+        let tail = &output[start..];
+
+        Ok(format!(
+            "[{}] {} ({})\n{}",
+            id,
+            process.command,
+            status,
+            if tail.is_empty() {
+                "(no output yet)"
+            } else {
+                tail
+            }
+        ))
+    }
+
+    /// Kill a process and drop it from the registry
+    pub fn kill(&self, id: usize) -> Result<String> {
+        let mut process = self
+            .processes
+            .lock()
+            .unwrap()
+            .remove(&id)
+            .ok_or_else(|| anyhow::anyhow!("No background process with id {}", id))?;
+
+        stop(&mut process.child);
+        Ok(format!("Killed [{}] {}", id, process.command))
+    }
+
+    /// Kill everything still registered; returns how many were stopped
+    pub fn kill_all(&self) -> usize {
+        let mut processes = self.processes.lock().unwrap();
+        let count = processes.len();
+        for (_, mut process) in std::mem::take(&mut *processes) {
+            stop(&mut process.child);
+        }
+        count
+    }
+}
+
+impl Drop for ProcessRegistry {
+    fn drop(&mut self) {
+        self.kill_all();
+    }
+}
+
+/// Kill a background process along with everything it started, then reap it
+fn stop(child: &mut Child) {
+    kill_process_group(child.id());
+    child.kill().ok();
+    child.wait().ok();
+}
+
+/// Append lines from a pipe to the shared buffer until it closes
+fn capture(pipe: impl Read + Send + 'static, output: Arc<Mutex<String>>) {
+    thread::spawn(move || {
+        for line in BufReader::new(pipe).lines() {
+            let Ok(line) = line else { break };
+            let mut output = output.lock().unwrap();
+            output.push_str(&line);
+            output.push('\n');
+            if output.len() > MAX_LOG_BYTES {
+                let mut cut = output.len() - MAX_LOG_BYTES;
+                while !output.is_char_boundary(cut) {
+                    cut += 1;
+                }
+                output.drain(..cut);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_spawn_logs_kill_lifecycle() {
+        let registry = ProcessRegistry::new();
+        let id = registry
+            .spawn(
+                Path::new("."),
+                "i=0; while true; do i=$((i+1)); echo tick $i; sleep 0.05; done",
+            )
+            .unwrap();
+
+        // Wait for a few ticks to be captured
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut logs = String::new();
+        while Instant::now() < deadline {
+            logs = registry.logs(id, 1000).unwrap();
+            if logs.contains("tick 3") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(logs.contains("(running)"), "{}", logs);
+        assert!(logs.contains("tick 3"), "{}", logs);
+
+        assert!(registry.kill(id).unwrap().starts_with("Killed [1]"));
+        assert!(registry.logs(id, 1000).is_err());
+        assert!(registry.kill(id).is_err());
+    }
+
+    #[test]
+    fn test_logs_report_exit_and_kill_all() {
+        let registry = ProcessRegistry::new();
+        let done = registry.spawn(Path::new("."), "echo finished").unwrap();
+        registry.spawn(Path::new("."), "sleep 30").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut logs = String::new();
+        while Instant::now() < deadline {
+            logs = registry.logs(done, 1000).unwrap();
+            if logs.contains("exited with code 0") && logs.ends_with("finished\n") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(logs.contains("exited with code 0"), "{}", logs);
+        assert!(logs.ends_with("finished\n"), "{}", logs);
+
+        assert_eq!(registry.kill_all(), 2);
+    }
+
+    /// Whether `pid` is still running (zombies waiting to be reaped count as gone)
+    #[cfg(target_os = "linux")]
+    fn is_running(pid: &str) -> bool {
+        std::fs::read_to_string(format!("/proc/{}/stat", pid))
+            .map(|stat| {
+                let state = stat.rsplit(')').next().unwrap_or("").trim_start();
+                !state.starts_with('Z')
+            })
+            .unwrap_or(false)
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_kill_reaches_processes_the_shell_started() {
+        let registry = ProcessRegistry::new();
+        let id = registry
+            .spawn(Path::new("."), "sleep 30 & echo child $!; wait")
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut logs = String::new();
+        let mut pid = None;
+        while Instant::now() < deadline && pid.is_none() {
+            logs = registry.logs(id, 1000).unwrap();
+            pid = logs
+                .lines()
+                .find_map(|line| line.strip_prefix("child "))
+                .map(str::to_string);
+            thread::sleep(Duration::from_millis(20));
+        }
+        let pid = pid.unwrap_or_else(|| panic!("no child pid in {}", logs));
+        assert!(is_running(&pid));
+
+        registry.kill(id).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline && is_running(&pid) {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(!is_running(&pid), "sleep {} outlived its shell", pid);
+    }
+}
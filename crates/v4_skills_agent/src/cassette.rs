@@ -0,0 +1,187 @@
+//! API Cassette Module
+//!
+//! Records API responses keyed by a hash of the request, then replays them on
+//! later runs without touching the network. Enabled with `MINI_CODE_CASSETTE`.
+//!
+//! One cassette is shared by the whole process; every recording rewrites the
+//! file from the shared map through a temp file and a rename, so a crash or a
+//! concurrent reader never sees half a file.
+
+use anthropic::types::{MessagesRequest, MessagesResponse};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A JSON file of `request hash -> response` pairs
+pub struct Cassette {
+    path: PathBuf,
+    entries: Mutex<HashMap<String, MessagesResponse>>,
+}
+
+impl Cassette {
+    /// Load a cassette, starting empty if the file doesn't exist yet
+    pub fn load(path: &Path) -> Result<Self> {
+        let entries = if path.exists() {
+            let data = fs::read_to_string(path)
+                .with_context(|| format!("Failed to read cassette {}", path.display()))?;
+            serde_json::from_str(&data)
+                .with_context(|| format!("Invalid cassette file {}", path.display()))?
+        } else {
+            HashMap::new()
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// Stable key for a request (FNV-1a over its JSON form)
+    ///
+    /// `DefaultHasher` isn't guaranteed stable across Rust releases, and the
+    /// cassette file has to outlive the binary that wrote it.
+    pub fn key(request: &MessagesRequest) -> Result<String> {
+        let json = serde_json::to_string(request)?;
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in json.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        Ok(format!("{:016x}", hash))
+    }
+
+    /// Replay the recorded response for `request`, or call `send` and record it
+    pub async fn replay_or_record<F, Fut>(
+        &self,
+        request: MessagesRequest,
+        send: F,
+    ) -> Result<MessagesResponse>
+    where
+        F: FnOnce(MessagesRequest) -> Fut,
+        Fut: Future<Output = Result<MessagesResponse>>,
+    {
+        let key = Self::key(&request)?;
+
+        if let Some(response) = self.entries.lock().unwrap().get(&key) {
+            return Ok(response.clone());
+        }
+
+        let response = send(request).await?;
+        self.record(key, response.clone())?;
+        Ok(response)
+    }
+
+    fn record(&self, key: String, response: MessagesResponse) -> Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(key, response);
+
+        let data = serde_json::to_string_pretty(&*entries)?;
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+        fs::write(&tmp, data)
+            .and_then(|_| fs::rename(&tmp, &self.path))
+            .with_context(|| format!("Failed to write cassette {}", self.path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anthropic::types::{ContentBlock, Message, MessagesRequestBuilder, Role};
+
+    fn sample_request(prompt: &str) -> MessagesRequest {
+        MessagesRequestBuilder::new(
+            "test-model",
+            vec![Message {
+                role: Role::User,
+                content: vec![ContentBlock::text(prompt)],
+            }],
+            1000,
+        )
+        .build()
+        .unwrap()
+    }
+
+    fn sample_response() -> MessagesResponse {
+        serde_json::from_value(serde_json::json!({
+            "id": "msg_test",
+            "type": "message",
+            "role": "assistant",
+            "model": "test-model",
+            "content": [{"type": "text", "text": "recorded answer"}],
+            "stop_reason": "end_turn",
+            "stop_sequence": null,
+            "usage": {"input_tokens": 10, "output_tokens": 5}
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_replay_skips_network() {
+        let path = std::env::temp_dir().join(format!("v4_cassette_{}.json", std::process::id()));
+        fs::remove_file(&path).ok();
+
+        // First run records
+        let cassette = Cassette::load(&path).unwrap();
+        cassette
+            .replay_or_record(sample_request("hello"), |_| async { Ok(sample_response()) })
+            .await
+            .unwrap();
+
+        // Second run replays from disk; the sender must never be called
+        let cassette = Cassette::load(&path).unwrap();
+        let replayed = cassette
+            .replay_or_record(sample_request("hello"), |_| async {
+                panic!("network should not be used on replay")
+            })
+            .await
+            .unwrap();
+        fs::remove_file(&path).ok();
+
+        assert_eq!(
+            replayed.content,
+            vec![ContentBlock::text("recorded answer")]
+        );
+    }
+
+    #[test]
+    fn test_shared_cassette_keeps_concurrent_recordings() {
+        let path =
+            std::env::temp_dir().join(format!("v4_cassette_shared_{}.json", std::process::id()));
+        fs::remove_file(&path).ok();
+
+        let cassette = std::sync::Arc::new(Cassette::load(&path).unwrap());
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let cassette = cassette.clone();
+                std::thread::spawn(move || {
+                    let key = Cassette::key(&sample_request(&format!("call {}", i))).unwrap();
+                    cassette.record(key, sample_response()).unwrap();
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let reloaded = Cassette::load(&path).unwrap();
+        let count = reloaded.entries.lock().unwrap().len();
+        let tmp_left = path.with_extension("json.tmp").exists();
+        fs::remove_file(&path).ok();
+
+        assert_eq!(count, 8);
+        assert!(!tmp_left);
+    }
+
+    #[test]
+    fn test_key_depends_on_request() {
+        let a = Cassette::key(&sample_request("a")).unwrap();
+        let b = Cassette::key(&sample_request("b")).unwrap();
+        assert_eq!(a, Cassette::key(&sample_request("a")).unwrap());
+        assert_ne!(a, b);
+    }
+}
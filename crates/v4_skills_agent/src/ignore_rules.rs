@@ -0,0 +1,110 @@
+//! Ignore Rules Module
+//!
+//! Which paths the directory-walking tools skip. Build output, dependency
+//! trees and VCS metadata are ignored by default because they flood the
+//! context and make walks slow; the workspace `.gitignore` and
+//! `MINI_CODE_IGNORE` add to that. Rules use gitignore syntax and later
+//! rules win, so `MINI_CODE_IGNORE=!target/` brings `target/` back.
+
+use anyhow::{Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::path::Path;
+
+/// Skipped unless a later rule re-includes them
+pub const DEFAULT_IGNORES: &[&str] = &["target/", "node_modules/", ".git/"];
+
+pub struct IgnoreMatcher {
+    rules: Gitignore,
+    /// `MINI_CODE_IGNORE` patterns, for `/config`
+    extra: Vec<String>,
+}
+
+impl IgnoreMatcher {
+    /// Defaults, then `root/.gitignore`, then `extra`
+    pub fn new(root: &Path, extra: &[String]) -> Result<Self> {
+        let mut builder = GitignoreBuilder::new(root);
+        for pattern in DEFAULT_IGNORES {
+            builder.add_line(None, pattern)?;
+        }
+        let gitignore = root.join(".gitignore");
+        if gitignore.is_file() {
+            if let Some(e) = builder.add(&gitignore) {
+                return Err(e).with_context(|| format!("Invalid {}", gitignore.display()));
+            }
+        }
+        for pattern in extra {
+            builder
+                .add_line(None, pattern)
+                .with_context(|| format!("Invalid MINI_CODE_IGNORE pattern '{}'", pattern))?;
+        }
+
+        Ok(Self {
+            rules: builder.build()?,
+            extra: extra.to_vec(),
+        })
+    }
+
+    /// Whether `path` (absolute, or relative to the root) or a directory
+    /// containing it is ignored. Paths outside the root never are.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        let relative = if path.is_absolute() {
+            match path.strip_prefix(self.rules.path()) {
+                Ok(relative) => relative,
+                Err(_) => return false,
+            }
+        } else {
+            path
+        };
+        !relative.as_os_str().is_empty()
+            && self
+                .rules
+                .matched_path_or_any_parents(relative, is_dir)
+                .is_ignore()
+    }
+
+    pub fn describe(&self) -> String {
+        let mut rules: Vec<String> = DEFAULT_IGNORES.iter().map(|p| p.to_string()).collect();
+        if self.rules.path().join(".gitignore").is_file() {
+            rules.push(".gitignore".to_string());
+        }
+        rules.extend(self.extra.iter().cloned());
+        rules.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_defaults_gitignore_and_force_include() {
+        let root = std::env::temp_dir().join(format!("v4_ignore_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+
+        let defaults = IgnoreMatcher::new(&root, &[]).unwrap();
+        let forced = IgnoreMatcher::new(&root, &["!target/".to_string()]).unwrap();
+        let extended = IgnoreMatcher::new(&root, &["fixtures/".to_string()]).unwrap();
+        let described = extended.describe();
+        fs::remove_dir_all(&root).ok();
+
+        assert!(defaults.is_ignored(Path::new("target"), true));
+        assert!(defaults.is_ignored(&root.join("target/debug/app"), false));
+        assert!(defaults.is_ignored(Path::new("web/node_modules/x/index.js"), false));
+        assert!(defaults.is_ignored(Path::new(".git/HEAD"), false));
+        assert!(defaults.is_ignored(Path::new("logs/app.log"), false));
+        assert!(!defaults.is_ignored(Path::new("src/main.rs"), false));
+        // A file named like a default directory is not a directory
+        assert!(!defaults.is_ignored(Path::new("docs/target"), false));
+        assert!(!defaults.is_ignored(Path::new("/elsewhere/target/x"), false));
+
+        assert!(!forced.is_ignored(Path::new("target/debug/app"), false));
+        assert!(forced.is_ignored(Path::new("node_modules"), true));
+        assert!(extended.is_ignored(Path::new("fixtures/big.json"), false));
+        assert_eq!(
+            described,
+            "target/, node_modules/, .git/, .gitignore, fixtures/"
+        );
+    }
+}
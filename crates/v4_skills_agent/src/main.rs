@@ -75,24 +75,56 @@
 //!     cargo run -p v4_skills_agent
 
 use anthropic::types::{
-    ContentBlock, Message, MessagesRequestBuilder, Role, StopReason, SystemPrompt, Tool,
+    ContentBlock, Message, MessagesRequest, MessagesRequestBuilder, MessagesResponse, Role,
+    StopReason, SystemPrompt, Tool,
 };
 use anthropic::Client;
 use anyhow::{Context, Result};
 use colored::Colorize;
+use mini_code_core::api::{api_version_hint, apply_api_version};
+use mini_code_core::archive::run_read_with;
+use mini_code_core::cancel::{run_bash_cancellable, CANCELLED_TOOL_CALL, CANCEL_POLL_INTERVAL};
+use mini_code_core::color::configure_colors;
+use mini_code_core::locking::{
+    diff_stat, run_edit_locked, run_write_locked, write_if_unchanged, write_lock, ReadSnapshot,
+};
+use mini_code_core::model::{apply_sampling, parse_sampling_param};
+use mini_code_core::todo::{TodoItem, TodoManager, TodoRenderStyle};
+use mini_code_core::tokens;
+use mini_code_core::tools::{decode_lossy, safe_path, safe_truncate, MAX_TOOL_OUTPUT};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::env;
 use std::fs;
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use tracing::Instrument;
+
+mod approval;
+mod background;
+mod cassette;
+mod ignore_rules;
+mod injection;
+mod project_tasks;
+mod redact;
+mod rust_rename;
+mod scratch;
+mod trace;
+use approval::{AllowAll, Approval, ApprovalPolicy, Interactive};
+use background::ProcessRegistry;
+use cassette::Cassette;
+use ignore_rules::IgnoreMatcher;
+use injection::InjectionGuard;
+use redact::Redactor;
+use scratch::ScratchDir;
 
 #[cfg(not(feature = "readline"))]
 use std::io::BufRead;
@@ -105,13 +137,43 @@ use rustyline::history::DefaultHistory;
 use rustyline::Editor;
 
 // =============================================================================
-// Thinking Animation (from v2/v3)
+// Thinking Animation and Heartbeat
 // =============================================================================
 
-fn spawn_thinking_animation() -> ThinkingAnimation {
+/// `MINI_CODE_HEARTBEAT_SECS` when unset
+const DEFAULT_HEARTBEAT_SECS: u64 = 30;
+
+/// How many heartbeats are due after `elapsed` (0 interval = never)
+fn heartbeats_due(elapsed: Duration, interval_secs: u64) -> u64 {
+    elapsed.as_secs().checked_div(interval_secs).unwrap_or(0)
+}
+
+/// Spinner on a terminal. Without one (CI logs, pipes) the spinner would
+/// be invisible, so a `still thinking...` line goes to stderr every
+/// `heartbeat_secs` instead.
+fn spawn_thinking_animation(heartbeat_secs: u64) -> ThinkingAnimation {
     let running = Arc::new(AtomicBool::new(true));
     let running_clone = running.clone();
 
+    if !io::stdout().is_terminal() {
+        let handle = thread::spawn(move || {
+            let started = Instant::now();
+            let mut beats = 0;
+            while running_clone.load(Ordering::Relaxed) {
+                let due = heartbeats_due(started.elapsed(), heartbeat_secs);
+                if due > beats {
+                    beats = due;
+                    eprintln!("still thinking... {}s elapsed", beats * heartbeat_secs);
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+        return ThinkingAnimation {
+            running,
+            handle: Some(handle),
+        };
+    }
+
     let handle = thread::spawn(move || {
         let frames = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
         let mut idx = 0;
@@ -152,6 +214,20 @@ impl Drop for ThinkingAnimation {
     }
 }
 
+// =============================================================================
+// Startup Banner
+// =============================================================================
+
+/// Decorative startup output is skipped for `--no-banner`, MINI_CODE_NO_BANNER
+/// or `--json`; warnings are printed either way.
+fn should_print_banner(no_banner_flag: bool, no_banner_env: bool, json: bool) -> bool {
+    !(no_banner_flag || no_banner_env || json)
+}
+
+fn no_banner_env() -> bool {
+    env::var("MINI_CODE_NO_BANNER").is_ok_and(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+}
+
 // =============================================================================
 // Configuration
 // =============================================================================
@@ -160,18 +236,113 @@ struct Config {
     model: String,
     workdir: PathBuf,
     skills_dir: PathBuf,
+    skills_path: Vec<PathBuf>,
     max_output_tokens: u32,
     max_truncation_retries: usize,
+    max_repeated_tool_calls: usize,
+    cassette_path: Option<PathBuf>,
+    /// Loaded once in `main` and shared by the agent loop and every subagent,
+    /// so concurrent recordings land in one map instead of overwriting each other
+    cassette: Option<Arc<Cassette>>,
+    auto_continue: usize,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    interactive_skills: bool,
+    tee_path: Option<PathBuf>,
+    subagent_timeout_secs: u64,
+    project_context: Option<String>,
+    manifest_path: Option<PathBuf>,
+    confirm_edits: bool,
+    idle_timeout_secs: u64,
+    persona_name: Option<String>,
+    prompt_footer: Option<String>,
+    debug_requests: bool,
+    redactor: Arc<Redactor>,
+    max_history_messages: usize,
+    approval: Box<dyn ApprovalPolicy>,
+    reflect: bool,
+    todo_style: TodoRenderStyle,
+    enabled_tools: Option<Vec<String>>,
+    disabled_tools: Vec<String>,
+    max_tools_per_turn: usize,
+    trace_path: Option<PathBuf>,
+    /// Summarize messages cut by the history window instead of dropping them
+    compact_history: bool,
+    /// Answer `ask_user` gets when nobody is at the terminal
+    ask_user_default: Option<String>,
+    /// Whether a user can answer prompts (stdin is a terminal, not `--json`)
+    interactive: bool,
+    /// Rotate the MINI_CODE_TEE log past this size; 0 means never
+    log_max_bytes: u64,
+    /// Rotated logs to keep (`.1` is the newest)
+    log_keep: usize,
+    /// Session temp dir for `scratch_write`/`scratch_read`, removed on drop
+    scratch: ScratchDir,
+    /// Files `read_files` reads at once
+    read_concurrency: usize,
+    /// Append each subagent's tool timeline to the result it returns
+    subagent_timeline: bool,
+    /// Extensions write_file/edit_file may touch (lowercase, no dot); empty = any
+    editable_extensions: Vec<String>,
+    /// Remind the model once about unfinished todos before ending a turn
+    enforce_todos: bool,
+    /// Rewrite the conversation here after every REPL turn, for `--resume`
+    autosave_path: Option<PathBuf>,
+    /// Run after a batch of tool calls that wrote or edited files (opt-in)
+    format_command: Option<String>,
+    /// Ask for a one-sentence rationale before each tool call and show it
+    explain: bool,
+    /// Drop network tools and refuse them if called anyway
+    offline: bool,
+    /// `/run` prompt templates from the `[macros]` table of the TOML config
+    macros: BTreeMap<String, String>,
+    /// Paths hash_file and `--context` globs skip (defaults + .gitignore + MINI_CODE_IGNORE)
+    ignore: IgnoreMatcher,
+    /// Byte budget for all of one turn's tool results together (0 = no limit)
+    max_turn_results: usize,
+    /// Tags tee log lines, trace spans and `--json` output (MINI_CODE_SESSION_ID or a UUID)
+    session_id: String,
+    /// Seconds between `still thinking...` lines when stdout is not a TTY (0 = off)
+    heartbeat_secs: u64,
+    /// read_file `limit` when the model gives none (None = read everything)
+    default_read_lines: Option<usize>,
+    /// Wraps tool output that looks like prompt injection (None = off)
+    injection_guard: Option<InjectionGuard>,
+}
+
+/// Load `.env` files without overriding variables that are already set.
+///
+/// Precedence: process env > `--env-file` > `<workdir>/.env` > `./.env`.
+/// dotenvy never overwrites, so loading in that order gives the first one
+/// to define a variable the win.
+fn load_env_files(workdir: &Path, env_file: Option<&Path>) -> Result<()> {
+    if let Some(path) = env_file {
+        dotenvy::from_path(path)
+            .with_context(|| format!("Failed to load --env-file {}", path.display()))?;
+    }
+
+    let workdir_env = workdir.join(".env");
+    if workdir_env.is_file() {
+        dotenvy::from_path(&workdir_env)
+            .with_context(|| format!("Failed to load {}", workdir_env.display()))?;
+    }
+
+    dotenvy::dotenv().ok();
+    Ok(())
 }
 
 impl Config {
-    fn from_env() -> Result<Self> {
-        dotenvy::dotenv().ok();
+    fn from_env(workdir: Option<&Path>, env_file: Option<&Path>) -> Result<Self> {
+        let workdir = resolve_workdir(workdir)?;
+        load_env_files(&workdir, env_file)?;
 
-        let model =
-            env::var("MODEL_NAME").unwrap_or_else(|_| "claude-sonnet-4-20250514".to_string());
-        let workdir = env::current_dir().context("Failed to get current directory")?;
+        let model = mini_code_core::model::resolve_model();
         let skills_dir = workdir.join("skills");
+        let skills_path = parse_skills_path(
+            &workdir,
+            env::var("MINI_CODE_SKILLS_PATH").ok().as_deref(),
+            env::var("HOME").ok().as_deref(),
+        );
 
         let max_output_tokens = env::var("MINI_CODE_MAX_OUTPUT_TOKENS")
             .ok()
@@ -185,18 +356,421 @@ impl Config {
             .unwrap_or(3)
             .clamp(1, 10);
 
+        let max_repeated_tool_calls = env::var("MINI_CODE_MAX_REPEATED_TOOL_CALLS")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(3)
+            .clamp(2, 20);
+
+        let cassette_path = env::var("MINI_CODE_CASSETTE")
+            .ok()
+            .filter(|s| !s.trim().is_empty())
+            .map(PathBuf::from);
+
+        let auto_continue = env::var("MINI_CODE_AUTO_CONTINUE")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(0)
+            .min(10);
+
+        let temperature = parse_sampling_param(
+            "MINI_CODE_TEMPERATURE",
+            env::var("MINI_CODE_TEMPERATURE").ok(),
+        )?;
+        let top_p = parse_sampling_param("MINI_CODE_TOP_P", env::var("MINI_CODE_TOP_P").ok())?;
+
+        let interactive_skills = env::var("MINI_CODE_INTERACTIVE_SKILLS")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+
+        let tee_path = env::var("MINI_CODE_TEE")
+            .ok()
+            .filter(|s| !s.trim().is_empty())
+            .map(PathBuf::from);
+
+        let subagent_timeout_secs = env::var("MINI_CODE_SUBAGENT_TIMEOUT_SECS")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(300)
+            .clamp(10, 86_400);
+
+        let project_context = load_project_context(
+            &workdir,
+            &env::var("MINI_CODE_PROJECT_CONTEXT").unwrap_or_else(|_| "AGENTS.md".to_string()),
+        );
+
+        let manifest_path = env::var("MINI_CODE_MANIFEST")
+            .ok()
+            .filter(|s| !s.trim().is_empty())
+            .map(PathBuf::from);
+
+        let confirm_edits = env::var("MINI_CODE_CONFIRM_EDITS")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+
+        let idle_timeout_secs = env::var("MINI_CODE_IDLE_TIMEOUT_SECS")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        let persona_name = env::var("MINI_CODE_PERSONA_NAME")
+            .ok()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty());
+        let prompt_footer = env::var("MINI_CODE_PROMPT_FOOTER")
+            .ok()
+            .map(|s| s.trim().replace("\\n", "\n"))
+            .filter(|s| !s.is_empty());
+
+        let debug_requests = env::var("MINI_CODE_DEBUG_REQUESTS")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+
+        let redactor = Arc::new(Redactor::from_env()?);
+
+        let max_history_messages = env::var("MINI_CODE_MAX_HISTORY_MESSAGES")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        // MINI_CODE_CONFIRM_EDITS is shorthand for asking before edit_file only
+        let approval: Box<dyn ApprovalPolicy> = match env::var("MINI_CODE_APPROVAL") {
+            Ok(value) => approval::parse_policy(&value)?,
+            Err(_) if confirm_edits => Box::new(Interactive::only(&["edit_file"])),
+            Err(_) => Box::new(AllowAll),
+        };
+
+        let reflect = env::var("MINI_CODE_REFLECT")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+
+        let tool_list = |name: &str| -> Option<Vec<String>> {
+            env::var(name).ok().map(|v| {
+                v.split(',')
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+        };
+        let enabled_tools = tool_list("MINI_CODE_ENABLED_TOOLS").filter(|t| !t.is_empty());
+        let disabled_tools = tool_list("MINI_CODE_DISABLED_TOOLS").unwrap_or_default();
+
+        let max_tools_per_turn = env::var("MINI_CODE_MAX_TOOLS_PER_TURN")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_MAX_TOOLS_PER_TURN);
+
+        let trace_path = env::var("MINI_CODE_TRACE")
+            .ok()
+            .filter(|s| !s.trim().is_empty())
+            .map(PathBuf::from);
+
+        let todo_style =
+            TodoRenderStyle::parse(&env::var("MINI_CODE_TODO_STYLE").unwrap_or_default())?;
+
+        let compact_history = env::var("MINI_CODE_COMPACT_HISTORY")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+
+        let ask_user_default = env::var("MINI_CODE_ASK_USER_DEFAULT")
+            .ok()
+            .filter(|s| !s.trim().is_empty());
+
+        let log_max_bytes = env::var("MINI_CODE_LOG_MAX_BYTES")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(0);
+
+        let log_keep = env::var("MINI_CODE_LOG_KEEP")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_LOG_KEEP);
+
+        let read_concurrency = env::var("MINI_CODE_READ_CONCURRENCY")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_READ_CONCURRENCY)
+            .clamp(1, 64);
+
+        let subagent_timeline = env::var("MINI_CODE_SUBAGENT_TIMELINE")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+
+        let editable_extensions =
+            parse_extension_list(&env::var("MINI_CODE_EDITABLE_EXTENSIONS").unwrap_or_default());
+
+        let enforce_todos = env::var("MINI_CODE_ENFORCE_TODOS")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+
+        let autosave_path = env::var("MINI_CODE_AUTOSAVE")
+            .ok()
+            .filter(|s| !s.trim().is_empty())
+            .map(PathBuf::from);
+
+        let format_command = env::var("MINI_CODE_FORMAT_COMMAND")
+            .ok()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty());
+
+        let explain = env::var("MINI_CODE_EXPLAIN")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+
+        let offline = env::var("MINI_CODE_OFFLINE")
+            .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+            .unwrap_or(false);
+
+        let ignore =
+            IgnoreMatcher::new(&workdir, &tool_list("MINI_CODE_IGNORE").unwrap_or_default())?;
+
+        let max_turn_results = env::var("MINI_CODE_MAX_TURN_RESULTS_BYTES")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .unwrap_or(DEFAULT_MAX_TURN_RESULTS);
+
+        let session_id = env::var("MINI_CODE_SESSION_ID")
+            .ok()
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+        let heartbeat_secs = env::var("MINI_CODE_HEARTBEAT_SECS")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_HEARTBEAT_SECS);
+
+        let default_read_lines = env::var("MINI_CODE_DEFAULT_READ_LINES")
+            .ok()
+            .and_then(|s| s.parse::<usize>().ok())
+            .filter(|&n| n > 0);
+
+        let injection_guard = InjectionGuard::from_env();
+
+        let macros = load_macros(&workdir.join(
+            env::var("MINI_CODE_TOML_CONFIG").unwrap_or_else(|_| ".mini-code.toml".to_string()),
+        ))?;
+
         Ok(Self {
             model,
             workdir,
             skills_dir,
+            skills_path,
             max_output_tokens,
             max_truncation_retries,
+            max_repeated_tool_calls,
+            cassette_path,
+            cassette: None,
+            auto_continue,
+            temperature,
+            top_p,
+            interactive_skills,
+            tee_path,
+            subagent_timeout_secs,
+            project_context,
+            manifest_path,
+            confirm_edits,
+            idle_timeout_secs,
+            persona_name,
+            prompt_footer,
+            debug_requests,
+            redactor,
+            max_history_messages,
+            approval,
+            reflect,
+            todo_style,
+            enabled_tools,
+            disabled_tools,
+            max_tools_per_turn,
+            trace_path,
+            compact_history,
+            ask_user_default,
+            interactive: io::stdin().is_terminal(),
+            log_max_bytes,
+            log_keep,
+            scratch: ScratchDir::for_session(),
+            read_concurrency,
+            subagent_timeline,
+            editable_extensions,
+            enforce_todos,
+            autosave_path,
+            format_command,
+            explain,
+            offline,
+            macros,
+            ignore,
+            max_turn_results,
+            session_id,
+            heartbeat_secs,
+            default_read_lines,
+            injection_guard,
         })
     }
 
+    /// `tools` as this session may use them: the enabled/disabled lists
+    /// applied, and without network tools when offline
+    fn tool_set(&self, tools: Vec<Tool>) -> Vec<Tool> {
+        filter_tools(tools, self.enabled_tools.as_deref(), &self.disabled_tools)
+            .into_iter()
+            .filter(|t| !(self.offline && NETWORK_TOOLS.contains(&t.name.as_str())))
+            .collect()
+    }
+
+    fn load_cassette(&self) -> Result<Option<Cassette>> {
+        self.cassette_path
+            .as_deref()
+            .map(Cassette::load)
+            .transpose()
+    }
+
+    /// Human-readable summary for `/config`. Deliberately excludes secrets.
+    fn describe(&self) -> String {
+        let optional = |v: Option<f32>| v.map_or("API default".to_string(), |v| v.to_string());
+        [
+            format!(
+                "model:                   {} ({})",
+                self.model,
+                mini_code_core::model::model_source()
+            ),
+            format!("workdir:                 {}", self.workdir.display()),
+            format!("skills_dir:              {}", self.skills_dir.display()),
+            format!(
+                "skills_path:             {}",
+                self.skills_path
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .collect::<Vec<_>>()
+                    .join(":")
+            ),
+            format!("max_output_tokens:       {}", self.max_output_tokens),
+            format!("max_truncation_retries:  {}", self.max_truncation_retries),
+            format!("max_repeated_tool_calls: {}", self.max_repeated_tool_calls),
+            format!("auto_continue:           {}", self.auto_continue),
+            format!("temperature:             {}", optional(self.temperature)),
+            format!("top_p:                   {}", optional(self.top_p)),
+            format!("interactive_skills:      {}", self.interactive_skills),
+            format!("confirm_edits:           {}", self.confirm_edits),
+            format!("debug_requests:          {}", self.debug_requests),
+            format!("redact_patterns:         {}", self.redactor.pattern_count()),
+            format!("max_history_messages:    {}", self.max_history_messages),
+            format!("compact_history:         {}", self.compact_history),
+            format!(
+                "ask_user_default:        {}",
+                self.ask_user_default.as_deref().unwrap_or("none")
+            ),
+            format!("interactive:             {}", self.interactive),
+            format!("log_max_bytes:           {}", self.log_max_bytes),
+            format!("log_keep:                {}", self.log_keep),
+            format!("scratch_dir:             {}", self.scratch.root().display()),
+            format!("read_concurrency:        {}", self.read_concurrency),
+            format!("subagent_timeline:       {}", self.subagent_timeline),
+            format!(
+                "editable_extensions:     {}",
+                if self.editable_extensions.is_empty() {
+                    "any".to_string()
+                } else {
+                    self.editable_extensions.join(",")
+                }
+            ),
+            format!("enforce_todos:           {}", self.enforce_todos),
+            format!(
+                "autosave:                {}",
+                self.autosave_path
+                    .as_ref()
+                    .map_or("off".to_string(), |p| p.display().to_string())
+            ),
+            format!(
+                "format_command:          {}",
+                self.format_command.as_deref().unwrap_or("off")
+            ),
+            format!("explain:                 {}", self.explain),
+            format!("offline:                 {}", self.offline),
+            format!(
+                "macros:                  {}",
+                if self.macros.is_empty() {
+                    "none".to_string()
+                } else {
+                    self.macros.keys().cloned().collect::<Vec<_>>().join(", ")
+                }
+            ),
+            format!("ignore:                  {}", self.ignore.describe()),
+            format!("max_turn_results:        {} bytes", self.max_turn_results),
+            format!("session_id:              {}", self.session_id),
+            format!("heartbeat_secs:          {}", self.heartbeat_secs),
+            format!(
+                "default_read_lines:      {}",
+                self.default_read_lines
+                    .map_or("all".to_string(), |n| n.to_string())
+            ),
+            format!(
+                "injection_guard:         {}",
+                self.injection_guard
+                    .as_ref()
+                    .map_or("off".to_string(), |g| {
+                        format!("on ({} phrases)", g.phrase_count())
+                    })
+            ),
+            format!("approval:                {}", self.approval.describe()),
+            format!("reflect:                 {}", self.reflect),
+            format!("todo_style:              {:?}", self.todo_style),
+            format!(
+                "enabled_tools:           {}",
+                self.enabled_tools
+                    .as_ref()
+                    .map_or("all".to_string(), |t| t.join(","))
+            ),
+            format!("disabled_tools:          {}", self.disabled_tools.join(",")),
+            format!("max_tools_per_turn:      {}", self.max_tools_per_turn),
+            format!(
+                "trace_path:              {}",
+                self.trace_path
+                    .as_ref()
+                    .map_or("off".to_string(), |p| p.display().to_string())
+            ),
+            format!(
+                "persona_name:            {}",
+                self.persona_name.as_deref().unwrap_or("(default)")
+            ),
+            format!(
+                "prompt_footer:           {}",
+                self.prompt_footer
+                    .as_ref()
+                    .map_or("none".to_string(), |f| format!("{} bytes", f.len()))
+            ),
+            format!("subagent_timeout_secs:   {}", self.subagent_timeout_secs),
+            format!("idle_timeout_secs:       {}", self.idle_timeout_secs),
+            format!(
+                "project_context:         {}",
+                self.project_context
+                    .as_ref()
+                    .map_or("none".to_string(), |c| format!("{} bytes", c.len()))
+            ),
+            format!(
+                "tee:                     {}",
+                self.tee_path
+                    .as_ref()
+                    .map_or("off".to_string(), |p| p.display().to_string())
+            ),
+            format!(
+                "manifest:                {}",
+                self.manifest_path
+                    .as_ref()
+                    .map_or("off".to_string(), |p| p.display().to_string())
+            ),
+            format!(
+                "cassette:                {}",
+                self.cassette_path
+                    .as_ref()
+                    .map_or("off".to_string(), |p| p.display().to_string())
+            ),
+        ]
+        .join("\n")
+    }
+
     fn system_prompt(&self, skill_descriptions: &str, agent_descriptions: &str) -> String {
         format!(
-            r#"You are a coding agent at {}.
+            r#"You are {} at {}.
 
 Loop: plan -> act with tools -> report.
 
@@ -208,17 +782,164 @@ Loop: plan -> act with tools -> report.
 
 Rules:
 - Use Skill tool IMMEDIATELY when a task matches a skill description
+- If several skills might fit, call suggest_skill first instead of guessing
 - Use Task tool for subtasks needing focused exploration or implementation
 - Use TodoWrite to track multi-step work
 - Prefer tools over prose. Act, don't just explain.
-- After finishing, summarize what changed."#,
+- After finishing, summarize what changed.{}{}{}"#,
+            self.persona_name
+                .as_ref()
+                .map_or("a coding agent".to_string(), |name| format!(
+                    "{}, a coding agent,",
+                    name
+                )),
             self.workdir.display(),
             skill_descriptions,
-            agent_descriptions
+            agent_descriptions,
+            if self.explain { EXPLAIN_RULE } else { "" },
+            self.project_context
+                .as_ref()
+                .map_or(String::new(), |c| format!(
+                    "\n\n**Project context**:\n{}",
+                    c
+                )),
+            self.prompt_footer
+                .as_ref()
+                .map_or(String::new(), |f| format!("\n\n{}", f))
         )
     }
 }
 
+/// Extra system-prompt rule for MINI_CODE_EXPLAIN
+const EXPLAIN_RULE: &str =
+    "\n- Before each tool call, write one sentence explaining why you are making it";
+
+/// The text the model wrote just before each tool call, keyed by tool_use id
+fn tool_rationales(content: &[ContentBlock]) -> HashMap<String, String> {
+    let mut rationales = HashMap::new();
+    let mut pending: Option<&str> = None;
+    for block in content {
+        match block {
+            ContentBlock::Text { text } if !text.trim().is_empty() => pending = Some(text.trim()),
+            ContentBlock::ToolUse { id, .. } => {
+                if let Some(text) = pending.take() {
+                    rationales.insert(id.clone(), text.to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+    rationales
+}
+
+/// Skill directories from MINI_CODE_SKILLS_PATH (colon-separated), lowest
+/// precedence first. Defaults to `~/.mini-code/skills` then `<workdir>/skills`.
+///
+/// A leading `~/` expands to `home`; relative entries resolve against the workdir.
+fn parse_skills_path(workdir: &Path, value: Option<&str>, home: Option<&str>) -> Vec<PathBuf> {
+    let resolve = |entry: &str| -> Option<PathBuf> {
+        match entry.strip_prefix("~/") {
+            Some(rest) => home.map(|h| Path::new(h).join(rest)),
+            None => Some(workdir.join(entry)),
+        }
+    };
+
+    match value.filter(|v| !v.trim().is_empty()) {
+        Some(value) => value
+            .split(':')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+            .filter_map(resolve)
+            .collect(),
+        None => ["~/.mini-code/skills", "skills"]
+            .into_iter()
+            .filter_map(resolve)
+            .collect(),
+    }
+}
+
+/// Cap on project context copied into every system prompt
+const PROJECT_CONTEXT_MAX_BYTES: usize = 8000;
+
+/// Read project conventions (AGENTS.md by default) from the workdir.
+///
+/// An empty `file` disables it; a missing file is simply no context.
+fn load_project_context(workdir: &Path, file: &str) -> Option<String> {
+    if file.trim().is_empty() {
+        return None;
+    }
+    let content = fs::read_to_string(workdir.join(file.trim())).ok()?;
+    let content = content.trim();
+    if content.is_empty() {
+        return None;
+    }
+    Some(safe_truncate(content, PROJECT_CONTEXT_MAX_BYTES).to_string())
+}
+
+/// The `[macros]` table of the TOML config: macro name -> prompt template.
+///
+/// A missing file means no macros; a malformed one fails startup.
+fn load_macros(path: &Path) -> Result<BTreeMap<String, String>> {
+    #[derive(Deserialize)]
+    struct TomlConfig {
+        #[serde(default)]
+        macros: BTreeMap<String, String>,
+    }
+
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let config: TomlConfig =
+        toml::from_str(&text).with_context(|| format!("Invalid TOML config {}", path.display()))?;
+    Ok(config.macros)
+}
+
+/// Fill a macro template: `{args}` becomes `args`. Arguments given to a
+/// template without the placeholder are appended rather than dropped.
+fn expand_macro(template: &str, args: &str) -> String {
+    let args = args.trim();
+    if template.contains("{args}") {
+        template.replace("{args}", args).trim().to_string()
+    } else if args.is_empty() {
+        template.trim().to_string()
+    } else {
+        format!("{}\n\n{}", template.trim(), args)
+    }
+}
+
+/// Expand `/run <macro> [args]` into the prompt to submit.
+/// None if `input` is not a `/run` command.
+fn run_macro_command(input: &str, macros: &BTreeMap<String, String>) -> Option<Result<String>> {
+    let rest = input.strip_prefix("/run")?;
+    if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+    let rest = rest.trim_start();
+    let (name, args) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+    let available = || {
+        if macros.is_empty() {
+            "none; add a [macros] table to .mini-code.toml".to_string()
+        } else {
+            macros.keys().cloned().collect::<Vec<_>>().join(", ")
+        }
+    };
+
+    Some(match macros.get(name) {
+        Some(template) => Ok(expand_macro(template, args)),
+        None if name.is_empty() => Err(anyhow::anyhow!(
+            "Usage: /run <macro> [args] (macros: {})",
+            available()
+        )),
+        None => Err(anyhow::anyhow!(
+            "Unknown macro '{}' (macros: {})",
+            name,
+            available()
+        )),
+    })
+}
+
 // =============================================================================
 // SkillLoader - The core addition in v4
 // =============================================================================
@@ -263,11 +984,15 @@ struct SkillLoader {
 }
 
 impl SkillLoader {
-    fn new(skills_dir: &Path) -> Self {
+    /// Load skills from each directory in turn; on a name conflict the later
+    /// directory wins, so project skills shadow global ones.
+    fn new(skills_dirs: &[PathBuf]) -> Self {
         let mut loader = Self {
             skills: HashMap::new(),
         };
-        loader.load_skills(skills_dir);
+        for dir in skills_dirs {
+            loader.load_skills(dir);
+        }
         loader
     }
 
@@ -362,6 +1087,25 @@ impl SkillLoader {
         let mut content = format!("# Skill: {}\n\n{}", skill.name, skill.body);
 
         // List available resources (Layer 3 hints)
+        let resources = self.resource_manifest(name)?;
+
+        if !resources.is_empty() {
+            content.push_str(&format!(
+                "\n\n**Available resources in {}:**\n",
+                skill.dir.display()
+            ));
+            for r in resources {
+                content.push_str(&format!("- {}\n", r));
+            }
+        }
+
+        Some(content)
+    }
+
+    /// Bundled resources (Layer 3 hints), one `Label: file, file` line per folder
+    fn resource_manifest(&self, name: &str) -> Option<Vec<String>> {
+        let skill = self.skills.get(name)?;
+
         let mut resources = Vec::new();
         for (folder, label) in [
             ("scripts", "Scripts"),
@@ -371,10 +1115,11 @@ impl SkillLoader {
             let folder_path = skill.dir.join(folder);
             if folder_path.exists() {
                 if let Ok(entries) = fs::read_dir(&folder_path) {
-                    let files: Vec<_> = entries
+                    let mut files: Vec<_> = entries
                         .flatten()
                         .map(|e| e.file_name().to_string_lossy().to_string())
                         .collect();
+                    files.sort();
                     if !files.is_empty() {
                         resources.push(format!("{}: {}", label, files.join(", ")));
                     }
@@ -382,22 +1127,185 @@ impl SkillLoader {
             }
         }
 
-        if !resources.is_empty() {
-            content.push_str(&format!(
-                "\n\n**Available resources in {}:**\n",
-                skill.dir.display()
-            ));
-            for r in resources {
-                content.push_str(&format!("- {}\n", r));
+        Some(resources)
+    }
+
+    /// Lines in the skill's `references/` docs containing every query word.
+    ///
+    /// Returns (path relative to the skill dir, 1-based line, excerpt) with a
+    /// line of context on each side, at most `limit` hits.
+    fn search_references(
+        &self,
+        name: &str,
+        query: &str,
+        limit: usize,
+    ) -> Option<Vec<(String, usize, String)>> {
+        fn collect(dir: &Path, files: &mut Vec<PathBuf>) {
+            let Ok(entries) = fs::read_dir(dir) else {
+                return;
+            };
+            let mut paths: Vec<PathBuf> = entries.flatten().map(|e| e.path()).collect();
+            paths.sort();
+            for path in paths {
+                if path.is_dir() {
+                    collect(&path, files);
+                } else {
+                    files.push(path);
+                }
             }
         }
 
-        Some(content)
+        let skill = self.skills.get(name)?;
+        let words: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+
+        let mut files = Vec::new();
+        collect(&skill.dir.join("references"), &mut files);
+
+        let mut hits = Vec::new();
+        for file in files {
+            // Binary files fail the UTF-8 read and are skipped
+            let Ok(content) = fs::read_to_string(&file) else {
+                continue;
+            };
+            let lines: Vec<&str> = content.lines().collect();
+            let label = file
+                .strip_prefix(&skill.dir)
+                .unwrap_or(&file)
+                .display()
+                .to_string();
+
+            for (i, line) in lines.iter().enumerate() {
+                let lower = line.to_lowercase();
+                if words.is_empty() || !words.iter().all(|w| lower.contains(w.as_str())) {
+                    continue;
+                }
+                let excerpt = lines[i.saturating_sub(1)..(i + 2).min(lines.len())].join("\n");
+                hits.push((label.clone(), i + 1, excerpt));
+                if hits.len() >= limit {
+                    return Some(hits);
+                }
+            }
+        }
+        Some(hits)
     }
 
     fn list_skills(&self) -> Vec<String> {
         self.skills.keys().cloned().collect()
     }
+
+    /// Rank skills against a free-text task description.
+    ///
+    /// Scores each query word found in the skill name (x3) or description
+    /// (x1); returns up to `limit` matches, best first.
+    fn search(&self, query: &str, limit: usize) -> Vec<&Skill> {
+        let words: Vec<String> = query
+            .to_lowercase()
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|w| w.len() >= 2)
+            .map(|w| w.to_string())
+            .collect();
+
+        let mut scored: Vec<(usize, &Skill)> = self
+            .skills
+            .values()
+            .map(|skill| {
+                let name = skill.name.to_lowercase();
+                let description = skill.description.to_lowercase();
+                let score = words
+                    .iter()
+                    .map(|w| {
+                        let mut s = 0;
+                        if name.contains(w.as_str()) {
+                            s += 3;
+                        }
+                        if description.contains(w.as_str()) {
+                            s += 1;
+                        }
+                        s
+                    })
+                    .sum();
+                (score, skill)
+            })
+            .filter(|(score, _)| *score > 0)
+            .collect();
+
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.name.cmp(&b.1.name)));
+        scored
+            .into_iter()
+            .take(limit)
+            .map(|(_, skill)| skill)
+            .collect()
+    }
+
+    /// Re-scan the skills directories (picks up newly created skills).
+    fn reload(&mut self, skills_dirs: &[PathBuf]) {
+        *self = Self::new(skills_dirs);
+    }
+
+    /// `name  (source dir)` lines for `/skills list`, sorted by name
+    fn list_with_sources(&self) -> Vec<String> {
+        let mut skills: Vec<&Skill> = self.skills.values().collect();
+        skills.sort_by(|a, b| a.name.cmp(&b.name));
+        skills
+            .iter()
+            .map(|skill| {
+                let source = skill.dir.parent().unwrap_or(&skill.dir);
+                format!("{}  ({})", skill.name, source.display())
+            })
+            .collect()
+    }
+}
+
+/// Scaffold a new skill folder with a SKILL.md template.
+///
+/// Creates `<skills_dir>/<name>/SKILL.md` plus empty scripts/, references/
+/// and assets/ folders. Refuses to touch an existing skill.
+fn create_skill_scaffold(skills_dir: &Path, name: &str) -> Result<PathBuf> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    {
+        anyhow::bail!(
+            "Invalid skill name '{}': use lowercase letters, digits and '-'",
+            name
+        );
+    }
+
+    let skill_dir = skills_dir.join(name);
+    if skill_dir.exists() {
+        anyhow::bail!("Skill '{}' already exists at {}", name, skill_dir.display());
+    }
+
+    for folder in ["scripts", "references", "assets"] {
+        fs::create_dir_all(skill_dir.join(folder))
+            .with_context(|| format!("Failed to create {}", skill_dir.join(folder).display()))?;
+    }
+
+    let skill_md = skill_dir.join("SKILL.md");
+    fs::write(
+        &skill_md,
+        format!(
+            r#"---
+name: {name}
+description: TODO - describe what this skill does and when to use it
+---
+
+# {name}
+
+## When to Use
+
+TODO - the tasks this skill is for.
+
+## Instructions
+
+TODO - step-by-step guidance for the model.
+"#
+        ),
+    )
+    .with_context(|| format!("Failed to write {}", skill_md.display()))?;
+
+    Ok(skill_md)
 }
 
 // =============================================================================
@@ -419,7 +1327,19 @@ fn get_agent_types() -> HashMap<String, AgentConfig> {
         AgentConfig {
             description: "Read-only agent for exploring code, finding files, searching"
                 .to_string(),
-            tools: vec!["bash".to_string(), "read_file".to_string()],
+            tools: vec![
+                "bash".to_string(),
+                "read_file".to_string(),
+                "diff".to_string(),
+                "count_tokens".to_string(),
+                "git_log".to_string(),
+                "git_blame".to_string(),
+                "validate_file".to_string(),
+                "read_files".to_string(),
+                "hash_file".to_string(),
+                "tail_file".to_string(),
+                "list_tasks".to_string(),
+            ],
             prompt: "You are an exploration agent. Search and analyze, but never modify files. Return a concise summary.".to_string(),
         },
     );
@@ -438,11 +1358,43 @@ fn get_agent_types() -> HashMap<String, AgentConfig> {
         "plan".to_string(),
         AgentConfig {
             description: "Planning agent for designing implementation strategies".to_string(),
-            tools: vec!["bash".to_string(), "read_file".to_string()],
+            tools: vec![
+                "bash".to_string(),
+                "read_file".to_string(),
+                "diff".to_string(),
+                "count_tokens".to_string(),
+                "git_log".to_string(),
+                "git_blame".to_string(),
+                "validate_file".to_string(),
+                "read_files".to_string(),
+                "hash_file".to_string(),
+                "tail_file".to_string(),
+                "list_tasks".to_string(),
+            ],
             prompt: "You are a planning agent. Analyze the codebase and output a numbered implementation plan. Do NOT make changes.".to_string(),
         },
     );
 
+    types.insert(
+        "reviewer".to_string(),
+        AgentConfig {
+            description: "Review agent that proposes changes as unified diffs without applying them"
+                .to_string(),
+            tools: vec![
+                "read_file".to_string(),
+                "count_tokens".to_string(),
+                "git_log".to_string(),
+                "git_blame".to_string(),
+                "validate_file".to_string(),
+                "read_files".to_string(),
+                "hash_file".to_string(),
+                "tail_file".to_string(),
+                "list_tasks".to_string(),
+            ],
+            prompt: "You are a review agent. You cannot modify files: express every change you recommend with propose_patch, then summarize what the patches do and why.".to_string(),
+        },
+    );
+
     types
 }
 
@@ -456,88 +1408,315 @@ fn get_agent_descriptions() -> String {
 }
 
 // =============================================================================
-// TodoManager (from v2)
+// Output Sink
 // =============================================================================
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct TodoItem {
-    content: String,
-    status: TodoStatus,
-    #[serde(rename = "activeForm")]
-    active_form: String,
+/// Destination for the REPL's tool-call and result lines.
+///
+/// agent_loop prints through this instead of `println!`, so output can be
+/// tee'd to a file (MINI_CODE_TEE) or captured in tests.
+trait Writer: Send + Sync {
+    fn line(&self, text: &str);
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "snake_case")]
-enum TodoStatus {
-    Pending,
-    InProgress,
-    Completed,
+/// Default sink: plain stdout.
+struct StdoutWriter;
+
+impl Writer for StdoutWriter {
+    fn line(&self, text: &str) {
+        println!("{}", text);
+    }
 }
 
-struct TodoManager {
-    items: Arc<Mutex<Vec<TodoItem>>>,
+/// Rotated MINI_CODE_TEE logs kept by default
+const DEFAULT_LOG_KEEP: usize = 3;
+
+/// Open log file and how much it holds, guarded together so rotation is atomic
+struct LogFile {
+    file: fs::File,
+    size: u64,
 }
 
-impl TodoManager {
-    fn new() -> Self {
-        Self {
-            items: Arc::new(Mutex::new(Vec::new())),
-        }
+/// Stdout plus an append-only log file with ANSI colors stripped.
+///
+/// With `max_bytes` set, a line that would push the file past it first
+/// rotates `path` to `path.1` (shifting older copies up to `path.<keep>`)
+/// and starts a fresh file.
+struct TeeWriter {
+    path: PathBuf,
+    log: Mutex<LogFile>,
+    max_bytes: u64,
+    keep: usize,
+    ansi: Regex,
+    /// `[session_id] ` prefix for each logged line, empty if untagged
+    tag: String,
+}
+
+impl TeeWriter {
+    fn open(path: &Path, max_bytes: u64, keep: usize) -> Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            log: Mutex::new(Self::open_log(path)?),
+            max_bytes,
+            keep,
+            ansi: Regex::new(r"\x1b\[[0-9;]*[A-Za-z]").unwrap(),
+            tag: String::new(),
+        })
     }
 
-    fn update(&self, new_items: Vec<TodoItem>) -> Result<String> {
-        let mut in_progress_count = 0;
+    /// Prefix every logged line with `[session_id]`, so logs from
+    /// concurrent sessions can be told apart (stdout stays as is)
+    fn tagged(mut self, session_id: &str) -> Self {
+        self.tag = format!("[{}] ", session_id);
+        self
+    }
 
-        for (i, item) in new_items.iter().enumerate() {
-            if item.content.trim().is_empty() {
-                anyhow::bail!("Item {}: content required", i);
-            }
-            if item.active_form.trim().is_empty() {
-                anyhow::bail!("Item {}: activeForm required", i);
-            }
-            if item.status == TodoStatus::InProgress {
-                in_progress_count += 1;
+    fn open_log(path: &Path) -> Result<LogFile> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open MINI_CODE_TEE file {}", path.display()))?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Ok(LogFile { file, size })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    /// Shift `path.N` to `path.N+1` (dropping the oldest) and `path` to `path.1`
+    fn rotate(&self) -> Result<LogFile> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path).ok();
+        } else {
+            fs::remove_file(self.rotated(self.keep)).ok();
+            for n in (1..self.keep).rev() {
+                fs::rename(self.rotated(n), self.rotated(n + 1)).ok();
             }
+            fs::rename(&self.path, self.rotated(1))?;
         }
+        Self::open_log(&self.path)
+    }
+}
+
+impl Writer for TeeWriter {
+    fn line(&self, text: &str) {
+        println!("{}", text);
+        let plain = format!("{}{}\n", self.tag, self.ansi.replace_all(text, ""));
+        let mut log = self.log.lock().unwrap();
 
-        if new_items.len() > 20 {
-            anyhow::bail!("Max 20 todos allowed");
+        let incoming = plain.len() as u64;
+        if self.max_bytes > 0 && log.size > 0 && log.size + incoming > self.max_bytes {
+            if let Ok(fresh) = self.rotate() {
+                *log = fresh;
+            }
         }
-        if in_progress_count > 1 {
-            anyhow::bail!("Only one task can be in_progress at a time");
+        if log.file.write_all(plain.as_bytes()).is_ok() {
+            log.size += incoming;
         }
+    }
+}
+
+/// Choose the sink from config: tee to a file if MINI_CODE_TEE is set.
+fn create_writer(config: &Config) -> Result<Box<dyn Writer>> {
+    match &config.tee_path {
+        Some(path) => Ok(Box::new(
+            TeeWriter::open(path, config.log_max_bytes, config.log_keep)?
+                .tagged(&config.session_id),
+        )),
+        None => Ok(Box::new(StdoutWriter)),
+    }
+}
 
-        *self.items.lock().unwrap() = new_items;
+// =============================================================================
+// Session Statistics
+// =============================================================================
+
+#[derive(Debug, Default, Clone, Serialize)]
+struct StatsCounters {
+    turns: usize,
+    tool_calls: BTreeMap<String, usize>,
+    input_tokens: u64,
+    output_tokens: u64,
+    subagents: usize,
+    skill_tokens: BTreeMap<String, usize>,
+}
+
+/// How a tool touched a file, for the session manifest
+#[derive(Debug, Clone, Copy)]
+enum FileAccess {
+    Read,
+    Write,
+    Edit,
+}
+
+/// Workdir-relative paths the agent read, wrote or edited this session
+#[derive(Debug, Default, Clone)]
+struct TouchedFiles {
+    read: HashSet<PathBuf>,
+    written: HashSet<PathBuf>,
+    edited: HashSet<PathBuf>,
+}
 
-        Ok(self.render())
+impl TouchedFiles {
+    fn sorted(set: &HashSet<PathBuf>) -> Vec<String> {
+        let mut paths: Vec<String> = set.iter().map(|p| p.display().to_string()).collect();
+        paths.sort();
+        paths
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "read": Self::sorted(&self.read),
+            "written": Self::sorted(&self.written),
+            "edited": Self::sorted(&self.edited),
+        })
     }
 
     fn render(&self) -> String {
-        let items = self.items.lock().unwrap();
+        let mut lines = Vec::new();
+        for (label, set) in [
+            ("Read", &self.read),
+            ("Written", &self.written),
+            ("Edited", &self.edited),
+        ] {
+            if !set.is_empty() {
+                lines.push(format!("{}:", label));
+                lines.extend(Self::sorted(set).into_iter().map(|p| format!("  {}", p)));
+            }
+        }
+        if lines.is_empty() {
+            "No files touched yet.".to_string()
+        } else {
+            lines.join("\n")
+        }
+    }
+}
+
+/// Per-session counters, printed as a recap when the REPL exits.
+///
+/// Shared the same way as TodoManager: `&self` methods over a Mutex, so
+/// agent_loop and run_task can both record into it.
+struct SessionStats {
+    counters: Mutex<StatsCounters>,
+    files: Mutex<TouchedFiles>,
+    started: Instant,
+}
 
-        if items.is_empty() {
-            return "No todos.".to_string();
+impl SessionStats {
+    fn new() -> Self {
+        Self {
+            counters: Mutex::new(StatsCounters::default()),
+            files: Mutex::new(TouchedFiles::default()),
+            started: Instant::now(),
         }
+    }
 
-        let mut lines = Vec::new();
-        for item in items.iter() {
-            let line = match item.status {
-                TodoStatus::Completed => format!("[x] {}", item.content),
-                TodoStatus::InProgress => format!("[>] {} <- {}", item.content, item.active_form),
-                TodoStatus::Pending => format!("[ ] {}", item.content),
-            };
-            lines.push(line);
+    /// Record a file tool's target, unless the tool reported an error.
+    fn record_file(&self, access: FileAccess, workdir: &Path, path: &str, output: &str) {
+        if output.starts_with("Error") {
+            return;
         }
+        let Ok(full) = safe_path(workdir, path) else {
+            return;
+        };
+        let relative = full.strip_prefix(workdir).unwrap_or(&full).to_path_buf();
 
-        let completed = items
-            .iter()
-            .filter(|t| t.status == TodoStatus::Completed)
-            .count();
-        lines.push(format!("\n({}/{} completed)", completed, items.len()));
+        let mut files = self.files.lock().unwrap();
+        match access {
+            FileAccess::Read => files.read.insert(relative),
+            FileAccess::Write => files.written.insert(relative),
+            FileAccess::Edit => files.edited.insert(relative),
+        };
+    }
+
+    fn touched_files(&self) -> TouchedFiles {
+        self.files.lock().unwrap().clone()
+    }
+
+    fn record_turn(&self) {
+        self.counters.lock().unwrap().turns += 1;
+    }
+
+    fn record_tool_call(&self, name: &str) {
+        *self
+            .counters
+            .lock()
+            .unwrap()
+            .tool_calls
+            .entry(name.to_string())
+            .or_insert(0) += 1;
+    }
+
+    fn record_usage(&self, input_tokens: u64, output_tokens: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.input_tokens += input_tokens;
+        counters.output_tokens += output_tokens;
+    }
+
+    fn record_subagent(&self) {
+        self.counters.lock().unwrap().subagents += 1;
+    }
+
+    /// Estimated tokens injected by each Skill load (cumulative per skill).
+    fn record_skill_tokens(&self, skill: &str, tokens: usize) {
+        *self
+            .counters
+            .lock()
+            .unwrap()
+            .skill_tokens
+            .entry(skill.to_string())
+            .or_insert(0) += tokens;
+    }
+
+    fn snapshot(&self) -> StatsCounters {
+        self.counters.lock().unwrap().clone()
+    }
 
+    fn render(&self) -> String {
+        let counters = self.snapshot();
+        let total_tools: usize = counters.tool_calls.values().sum();
+        let breakdown = counters
+            .tool_calls
+            .iter()
+            .map(|(name, count)| format!("{} {}", name, count))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let elapsed = self.started.elapsed().as_secs();
+
+        let mut lines = vec![
+            format!("Turns:      {}", counters.turns),
+            format!("Tool calls: {}", total_tools),
+        ];
+        if !breakdown.is_empty() {
+            lines.push(format!("            ({})", breakdown));
+        }
+        lines.push(format!("Subagents:  {}", counters.subagents));
+        if !counters.skill_tokens.is_empty() {
+            let skills = counters
+                .skill_tokens
+                .iter()
+                .map(|(name, tokens)| format!("{} ~{}", name, tokens))
+                .collect::<Vec<_>>()
+                .join(", ");
+            lines.push(format!("Skills:     {} (est. tokens)", skills));
+        }
+        lines.push(format!(
+            "Tokens:     {} in / {} out",
+            counters.input_tokens, counters.output_tokens
+        ));
+        lines.push(format!("Wall time:  {}m {}s", elapsed / 60, elapsed % 60));
         lines.join("\n")
     }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self.snapshot()).unwrap_or_else(|_| json!({}));
+        value["wall_time_secs"] = json!(self.started.elapsed().as_secs_f64());
+        value
+    }
 }
 
 // =============================================================================
@@ -681,6 +1860,110 @@ fn parse_search_html(html: &str, max_results: usize) -> Vec<SearchResult> {
     results.into_iter().take(max_results).collect()
 }
 
+/// Byte budget for the formatted web_search tool result
+const WEB_SEARCH_MAX_BYTES: usize = 6000;
+
+/// Snippet length used once the full results exceed the budget
+const WEB_SEARCH_SHORT_SNIPPET: usize = 160;
+
+/// Comparable form of a URL: no fragment, no `utm_*` params, no trailing slash.
+fn normalize_url(url: &str) -> String {
+    let url = url.split('#').next().unwrap_or(url);
+    let (base, query) = match url.split_once('?') {
+        Some((base, query)) => (base, Some(query)),
+        None => (url, None),
+    };
+
+    let params: Vec<&str> = query
+        .into_iter()
+        .flat_map(|q| q.split('&'))
+        .filter(|p| !p.is_empty() && !p.starts_with("utm_"))
+        .collect();
+
+    let base = base.trim_end_matches('/');
+    if params.is_empty() {
+        base.to_string()
+    } else {
+        format!("{}?{}", base, params.join("&"))
+    }
+}
+
+/// Drop results whose normalized URL was already seen, keeping the first.
+fn dedupe_search_results(results: Vec<SearchResult>) -> Vec<SearchResult> {
+    let mut seen = HashSet::new();
+    results
+        .into_iter()
+        .filter(|r| seen.insert(normalize_url(&r.url)))
+        .collect()
+}
+
+/// Format results for the model, shortening snippets if over `max_bytes`.
+///
+/// Titles and URLs are kept; snippets are cut shorter and shorter, then
+/// dropped, with a note of how many were shortened. Only if the bare titles
+/// and URLs still don't fit is the text itself cut.
+fn format_search_results(query: &str, results: &[SearchResult], max_bytes: usize) -> String {
+    let render = |snippet_limit: Option<usize>| -> (String, usize) {
+        let mut shortened = 0;
+        let formatted = results
+            .iter()
+            .enumerate()
+            .map(|(i, r)| {
+                let snippet = match snippet_limit {
+                    Some(0) => {
+                        shortened += 1;
+                        return format!("{}. **{}**\n   URL: {}\n", i + 1, r.title, r.url);
+                    }
+                    Some(limit) if r.snippet.len() > limit => {
+                        shortened += 1;
+                        format!("{}...", safe_truncate(&r.snippet, limit))
+                    }
+                    _ => r.snippet.clone(),
+                };
+                format!(
+                    "{}. **{}**\n   URL: {}\n   {}\n",
+                    i + 1,
+                    r.title,
+                    r.url,
+                    snippet
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        (
+            format!("## Search Results for: {}\n\n{}", query, formatted),
+            shortened,
+        )
+    };
+
+    let (full, _) = render(None);
+    if full.len() <= max_bytes {
+        return full;
+    }
+
+    let limits = [
+        WEB_SEARCH_SHORT_SNIPPET,
+        WEB_SEARCH_SHORT_SNIPPET / 2,
+        WEB_SEARCH_SHORT_SNIPPET / 4,
+    ];
+    for limit in limits {
+        let (short, shortened) = render(Some(limit));
+        if short.len() <= max_bytes {
+            return format!(
+                "{}\n({} snippets shortened to fit the {}-byte limit)",
+                short, shortened, max_bytes
+            );
+        }
+    }
+
+    let (bare, _) = render(Some(0));
+    format!(
+        "{}\n(snippets dropped to fit the {}-byte limit)",
+        safe_truncate(&bare, max_bytes),
+        max_bytes
+    )
+}
+
 /// Extract the domain name from a URL.
 fn extract_domain(url: &str) -> Option<String> {
     url.split("//")
@@ -705,6 +1988,10 @@ fn create_base_tools() -> Vec<Tool> {
                     "command": {
                         "type": "string",
                         "description": "The shell command to execute"
+                    },
+                    "retry": {
+                        "type": "integer",
+                        "description": "Times to re-run on nonzero exit, for transient failures like network fetches (default 0, max 5)"
                     }
                 },
                 "required": ["command"]
@@ -722,12 +2009,39 @@ fn create_base_tools() -> Vec<Tool> {
                     },
                     "limit": {
                         "type": "integer",
-                        "description": "Max lines to read (default: all)"
+                        "description": "Max lines to read (default: all, unless the session sets a default)"
+                    },
+                    "line_numbers": {
+                        "type": "boolean",
+                        "description": "Prefix each line with its line number (default: false)"
+                    },
+                    "member": {
+                        "type": "string",
+                        "description": "Entry to read from a .zip with several entries (.gz and single-entry .zip files are decompressed automatically)"
                     }
                 },
                 "required": ["path"]
             }),
         },
+        Tool {
+            name: "read_files".to_string(),
+            description: "Read several files at once. Each file is reported under its own header, in the order given; a missing file doesn't stop the others.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "paths": {
+                        "type": "array",
+                        "items": {"type": "string"},
+                        "description": "Relative paths of the files"
+                    },
+                    "limit": {
+                        "type": "integer",
+                        "description": "Max lines to read per file (default: all)"
+                    }
+                },
+                "required": ["paths"]
+            }),
+        },
         Tool {
             name: "write_file".to_string(),
             description: "Write content to file.".to_string(),
@@ -741,6 +2055,15 @@ fn create_base_tools() -> Vec<Tool> {
                     "content": {
                         "type": "string",
                         "description": "Content to write"
+                    },
+                    "line_ending": {
+                        "type": "string",
+                        "enum": ["lf", "crlf"],
+                        "description": "Convert all line endings (default: keep content as given)"
+                    },
+                    "ensure_trailing_newline": {
+                        "type": "boolean",
+                        "description": "Append a newline if the content doesn't end with one (default false)"
                     }
                 },
                 "required": ["path", "content"]
@@ -758,7 +2081,7 @@ fn create_base_tools() -> Vec<Tool> {
                     },
                     "old_text": {
                         "type": "string",
-                        "description": "Exact text to find (must match precisely)"
+                        "description": "Exact text to find (must match precisely). Empty creates a missing or empty file with new_text"
                     },
                     "new_text": {
                         "type": "string",
@@ -769,102 +2092,416 @@ fn create_base_tools() -> Vec<Tool> {
             }),
         },
         Tool {
-            name: "web_search".to_string(),
-            description: "Search the web using DuckDuckGo. Use this to find current information about any topic.".to_string(),
+            name: "diff".to_string(),
+            description: "Show a unified diff between a file and another file or proposed content. Read-only unless save_as is given.".to_string(),
             input_schema: json!({
                 "type": "object",
                 "properties": {
-                    "query": {
+                    "path": {
                         "type": "string",
-                        "description": "The search query to find information about"
+                        "description": "Relative path to the original file"
                     },
-                    "max_results": {
+                    "other_path": {
+                        "type": "string",
+                        "description": "Relative path to compare against"
+                    },
+                    "content": {
+                        "type": "string",
+                        "description": "Proposed new content to compare against (instead of other_path)"
+                    },
+                    "save_as": {
+                        "type": "string",
+                        "description": "Optional relative path of a .diff file to write the diff to"
+                    }
+                },
+                "required": ["path"]
+            }),
+        },
+        Tool {
+            name: "count_tokens".to_string(),
+            description: "Estimate the token count of text or a file (same estimate used for context accounting). Use it to check whether output fits before writing it.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "text": {
+                        "type": "string",
+                        "description": "Text to count"
+                    },
+                    "path": {
+                        "type": "string",
+                        "description": "Relative path of a file to count instead of text"
+                    }
+                }
+            }),
+        },
+        Tool {
+            name: "bash_background".to_string(),
+            description: "Start a long-running shell command (dev server, watcher) without waiting. Returns a process id for bash_logs and bash_kill.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "command": {
+                        "type": "string",
+                        "description": "The shell command to start"
+                    }
+                },
+                "required": ["command"]
+            }),
+        },
+        Tool {
+            name: "bash_logs".to_string(),
+            description: "Show the status and latest output of a background process.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "id": {
                         "type": "integer",
-                        "description": "Maximum number of results to return (default: 5)",
-                        "minimum": 1,
-                        "maximum": 10
+                        "description": "Process id returned by bash_background"
+                    },
+                    "tail_bytes": {
+                        "type": "integer",
+                        "description": "How much recent output to return (default 4000)"
                     }
                 },
-                "required": ["query"]
+                "required": ["id"]
             }),
         },
         Tool {
-            name: "TodoWrite".to_string(),
-            description: "Update the task list. Use to plan and track progress.".to_string(),
+            name: "bash_kill".to_string(),
+            description: "Stop a background process.".to_string(),
             input_schema: json!({
                 "type": "object",
                 "properties": {
-                    "items": {
-                        "type": "array",
-                        "description": "Complete list of tasks (replaces existing)",
-                        "items": {
-                            "type": "object",
-                            "properties": {
-                                "content": {
-                                    "type": "string",
-                                    "description": "Task description"
-                                },
-                                "status": {
-                                    "type": "string",
-                                    "enum": ["pending", "in_progress", "completed"],
-                                    "description": "Task status"
-                                },
-                                "activeForm": {
-                                    "type": "string",
-                                    "description": "Present tense action, e.g. 'Reading files'"
-                                }
-                            },
-                            "required": ["content", "status", "activeForm"]
-                        }
+                    "id": {
+                        "type": "integer",
+                        "description": "Process id returned by bash_background"
                     }
                 },
-                "required": ["items"]
+                "required": ["id"]
             }),
         },
-    ]
-}
-
-/// Create the Task tool (from v3)
-fn create_task_tool() -> Tool {
-    let agent_types = get_agent_types();
-    let type_names: Vec<String> = agent_types.keys().cloned().collect();
-
-    Tool {
-        name: "Task".to_string(),
-        description: format!(
-            r#"Spawn a subagent for a focused subtask.
-
-Agent types:
-{}
-
-Example uses:
-- Task(explore): "Find all files using the auth module"
-- Task(plan): "Design a migration strategy for the database"
-- Task(code): "Implement the user registration form"
-"#,
-            get_agent_descriptions()
-        ),
-        input_schema: json!({
-            "type": "object",
-            "properties": {
-                "description": {
-                    "type": "string",
-                    "description": "Short task name (3-5 words) for progress display"
+        Tool {
+            name: "rust_check".to_string(),
+            description: "Run `cargo check` on a Rust snippet in a scratch project and return the compiler diagnostics. Code without `fn main` is wrapped in one. Dependencies beyond std are not available.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "code": {
+                        "type": "string",
+                        "description": "A complete main.rs, or statements to place inside fn main"
+                    }
                 },
-                "prompt": {
-                    "type": "string",
-                    "description": "Detailed instructions for the subagent"
+                "required": ["code"]
+            }),
+        },
+        Tool {
+            name: "git_log".to_string(),
+            description: "List recent commits touching a file (hash, date, subject), newest first.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "Relative path of the file"
+                    },
+                    "max": {
+                        "type": "integer",
+                        "description": "Max commits to return (default 10)"
+                    }
                 },
-                "agent_type": {
-                    "type": "string",
-                    "enum": type_names,
-                    "description": "Type of agent to spawn"
-                }
-            },
-            "required": ["description", "prompt", "agent_type"]
-        }),
-    }
-}
+                "required": ["path"]
+            }),
+        },
+        Tool {
+            name: "git_blame".to_string(),
+            description: "Show who last changed each line of a file and in which commit.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "Relative path of the file"
+                    },
+                    "start_line": {
+                        "type": "integer",
+                        "description": "First line to blame (1-based, default 1)"
+                    },
+                    "end_line": {
+                        "type": "integer",
+                        "description": "Last line to blame (default: end of file)"
+                    }
+                },
+                "required": ["path"]
+            }),
+        },
+        Tool {
+            name: "validate_file".to_string(),
+            description: "Parse a .json, .yaml/.yml or .toml file and report whether it is valid, with the line and column of any syntax error. Use it after writing config files instead of shelling out.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "Relative path of the file"
+                    },
+                    "canonical": {
+                        "type": "boolean",
+                        "description": "Also return the pretty-printed canonical form (default false)"
+                    }
+                },
+                "required": ["path"]
+            }),
+        },
+        Tool {
+            name: "scratch_write".to_string(),
+            description: "Write a temporary file to the session scratch directory (outside the workspace, deleted when the session ends). Use it for notes and intermediate output that shouldn't clutter the project.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "Relative path inside the scratch directory"
+                    },
+                    "content": {
+                        "type": "string",
+                        "description": "Content to write"
+                    }
+                },
+                "required": ["path", "content"]
+            }),
+        },
+        Tool {
+            name: "scratch_read".to_string(),
+            description: "Read a file previously written with scratch_write.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "Relative path inside the scratch directory"
+                    }
+                },
+                "required": ["path"]
+            }),
+        },
+        Tool {
+            name: "hash_file".to_string(),
+            description: "Compute the hex digest of a file, or for a directory a sorted manifest of every file's digest plus one digest of the whole manifest. Use it to verify generated artifacts.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "Relative path of a file or directory"
+                    },
+                    "algo": {
+                        "type": "string",
+                        "enum": ["sha256", "md5"],
+                        "description": "Hash algorithm (default sha256)"
+                    }
+                },
+                "required": ["path"]
+            }),
+        },
+        Tool {
+            name: "tail_file".to_string(),
+            description: "Show the last lines of a file. With follow_secs, keep watching it and also return whatever is appended in that time (max 60s). Use it for growing logs.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "Relative path to the file"
+                    },
+                    "lines": {
+                        "type": "integer",
+                        "description": "Number of trailing lines to show (default 20)"
+                    },
+                    "follow_secs": {
+                        "type": "integer",
+                        "description": "Seconds to keep collecting appended lines (default 0)"
+                    }
+                },
+                "required": ["path"]
+            }),
+        },
+        Tool {
+            name: "list_tasks".to_string(),
+            description: "List the commands this project defines: package.json scripts, Makefile targets and justfile recipes at the workspace root. Check it before guessing how to build or test.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {}
+            }),
+        },
+        Tool {
+            name: "run_project_task".to_string(),
+            description: "Run a task found by list_tasks (e.g. \"test\" runs `npm run test`, `make test` or `just test`) and return its output.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "name": {
+                        "type": "string",
+                        "description": "Task name as shown by list_tasks"
+                    },
+                    "source": {
+                        "type": "string",
+                        "enum": ["npm", "make", "just"],
+                        "description": "Which file's task to run, when several define the name"
+                    }
+                },
+                "required": ["name"]
+            }),
+        },
+        Tool {
+            name: "rust_rename".to_string(),
+            description: "Rename a Rust identifier within one .rs file. Only identifier tokens spelled exactly `from` change; comments, strings and names that merely contain `from` are left alone. Prefer it over edit_file for renames.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "path": {
+                        "type": "string",
+                        "description": "Relative path of the .rs file"
+                    },
+                    "from": {
+                        "type": "string",
+                        "description": "Identifier to rename"
+                    },
+                    "to": {
+                        "type": "string",
+                        "description": "New identifier"
+                    }
+                },
+                "required": ["path", "from", "to"]
+            }),
+        },
+        Tool {
+            name: "replace_in_files".to_string(),
+            description: "Regex find-and-replace across the workspace (target/, node_modules/, .git/ and ignored paths are skipped). Reports how many replacements each file got. Use dry_run first to check what would change.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "pattern": {
+                        "type": "string",
+                        "description": "Regular expression to find (Rust regex syntax)"
+                    },
+                    "replacement": {
+                        "type": "string",
+                        "description": "Replacement text; $1 or ${name} insert capture groups"
+                    },
+                    "glob": {
+                        "type": "string",
+                        "description": "Only files whose relative path matches, e.g. src/**/*.rs (default: all files)"
+                    },
+                    "dry_run": {
+                        "type": "boolean",
+                        "description": "Count matches without writing (default: false)"
+                    }
+                },
+                "required": ["pattern", "replacement"]
+            }),
+        },
+        Tool {
+            name: "web_search".to_string(),
+            description: "Search the web using DuckDuckGo. Use this to find current information about any topic.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "query": {
+                        "type": "string",
+                        "description": "The search query to find information about"
+                    },
+                    "max_results": {
+                        "type": "integer",
+                        "description": "Maximum number of results to return (default: 5)",
+                        "minimum": 1,
+                        "maximum": 10
+                    }
+                },
+                "required": ["query"]
+            }),
+        },
+        Tool {
+            name: "TodoWrite".to_string(),
+            description: "Update the task list. Use to plan and track progress.".to_string(),
+            input_schema: json!({
+                "type": "object",
+                "properties": {
+                    "items": {
+                        "type": "array",
+                        "description": "Complete list of tasks (replaces existing)",
+                        "items": {
+                            "type": "object",
+                            "properties": {
+                                "content": {
+                                    "type": "string",
+                                    "description": "Task description"
+                                },
+                                "status": {
+                                    "type": "string",
+                                    "enum": ["pending", "in_progress", "completed"],
+                                    "description": "Task status"
+                                },
+                                "activeForm": {
+                                    "type": "string",
+                                    "description": "Present tense action, e.g. 'Reading files'"
+                                }
+                            },
+                            "required": ["content", "status", "activeForm"]
+                        }
+                    }
+                },
+                "required": ["items"]
+            }),
+        },
+    ]
+}
+
+/// Create the Task tool (from v3)
+fn create_task_tool() -> Tool {
+    let agent_types = get_agent_types();
+    let type_names: Vec<String> = agent_types.keys().cloned().collect();
+
+    Tool {
+        name: "Task".to_string(),
+        description: format!(
+            r#"Spawn a subagent for a focused subtask.
+
+Agent types:
+{}
+
+Example uses:
+- Task(explore): "Find all files using the auth module"
+- Task(plan): "Design a migration strategy for the database"
+- Task(code): "Implement the user registration form"
+- Task(reviewer): "Propose a fix for the failing parser test as a patch"
+"#,
+            get_agent_descriptions()
+        ),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "description": {
+                    "type": "string",
+                    "description": "Short task name (3-5 words) for progress display"
+                },
+                "prompt": {
+                    "type": "string",
+                    "description": "Detailed instructions for the subagent"
+                },
+                "agent_type": {
+                    "type": "string",
+                    "enum": type_names,
+                    "description": "Type of agent to spawn"
+                },
+                "session_id": {
+                    "type": "string",
+                    "description": "Resume an earlier subagent (from its result) to ask a follow-up with its context intact"
+                }
+            },
+            "required": ["description", "prompt", "agent_type"]
+        }),
+    }
+}
 
 /// Create the Skill tool - NEW in v4
 fn create_skill_tool(skill_loader: &SkillLoader) -> Tool {
@@ -897,47 +2534,227 @@ detailed instructions and access to resources."#,
     }
 }
 
-/// Get all tools for main agent (includes Task and Skill)
-fn create_all_tools(skill_loader: &SkillLoader) -> Vec<Tool> {
-    let mut tools = create_base_tools();
-    tools.push(create_task_tool());
-    tools.push(create_skill_tool(skill_loader));
-    tools
+fn create_suggest_skill_tool() -> Tool {
+    Tool {
+        name: "suggest_skill".to_string(),
+        description: "Find the skills that best match a task when unsure which one applies. Returns up to 3 candidates; then load one with the Skill tool.".to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "task": {
+                    "type": "string",
+                    "description": "Short description of the task"
+                }
+            },
+            "required": ["task"]
+        }),
+    }
 }
 
-/// Filter tools based on agent type
-/// Note: This does NOT include the Skill tool - that must be added separately
-/// by calling with skill_loader if needed
-fn get_tools_for_agent(agent_type: &str) -> Vec<Tool> {
-    let agent_types = get_agent_types();
-    let config = match agent_types.get(agent_type) {
-        Some(cfg) => cfg,
-        None => return Vec::new(),
-    };
-
-    let base_tools = create_base_tools();
-
-    if config.tools.contains(&"*".to_string()) {
+fn create_skill_info_tool() -> Tool {
+    Tool {
+        name: "skill_info".to_string(),
+        description: "List the scripts, references and assets a skill ships, without loading its instructions.".to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "skill": {
+                    "type": "string",
+                    "description": "Name of the skill to inspect"
+                }
+            },
+            "required": ["skill"]
+        }),
+    }
+}
+
+fn create_skill_reference_tool() -> Tool {
+    Tool {
+        name: "skill_reference".to_string(),
+        description: "Search a skill's references/ documents and return matching excerpts with file names and line numbers. Use it to pull only the reference material relevant to the task.".to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "skill": {
+                    "type": "string",
+                    "description": "Name of the skill whose references to search"
+                },
+                "query": {
+                    "type": "string",
+                    "description": "Words that must all appear on a matching line (case-insensitive)"
+                }
+            },
+            "required": ["skill", "query"]
+        }),
+    }
+}
+
+/// Tools that reach the network, removed by MINI_CODE_OFFLINE
+const NETWORK_TOOLS: &[&str] = &["web_search"];
+
+/// What a network tool returns if it is called in offline mode anyway
+const OFFLINE_TOOL_CALL: &str = "Error: offline mode";
+
+/// Apply MINI_CODE_ENABLED_TOOLS / MINI_CODE_DISABLED_TOOLS to a tool set.
+///
+/// With an enabled list only those tools remain; disabled tools are then
+/// removed from whatever is left.
+fn filter_tools(tools: Vec<Tool>, enabled: Option<&[String]>, disabled: &[String]) -> Vec<Tool> {
+    tools
+        .into_iter()
+        .filter(|t| enabled.is_none_or(|enabled| enabled.contains(&t.name)))
+        .filter(|t| !disabled.contains(&t.name))
+        .collect()
+}
+
+/// Names in the enabled/disabled lists that match no known tool
+fn unknown_tool_names(
+    tools: &[Tool],
+    enabled: Option<&[String]>,
+    disabled: &[String],
+) -> Vec<String> {
+    enabled
+        .unwrap_or_default()
+        .iter()
+        .chain(disabled)
+        .filter(|name| !tools.iter().any(|t| &t.name == *name))
+        .cloned()
+        .collect()
+}
+
+/// Get all tools for main agent (includes Task and Skill)
+fn create_all_tools(skill_loader: &SkillLoader) -> Vec<Tool> {
+    let mut tools = create_base_tools();
+    tools.push(create_task_tool());
+    tools.push(create_skill_tool(skill_loader));
+    tools.push(create_suggest_skill_tool());
+    tools.push(create_skill_info_tool());
+    tools.push(create_skill_reference_tool());
+    tools.push(create_ask_user_tool());
+    tools
+}
+
+/// Filter tools based on agent type
+/// Note: This does NOT include the Skill tool - that must be added separately
+/// by calling with skill_loader if needed
+fn get_tools_for_agent(agent_type: &str) -> Vec<Tool> {
+    let agent_types = get_agent_types();
+    let config = match agent_types.get(agent_type) {
+        Some(cfg) => cfg,
+        None => return Vec::new(),
+    };
+
+    let base_tools = create_base_tools();
+
+    if config.tools.contains(&"*".to_string()) {
         return base_tools;
     }
 
+    // Agents without write_file get diff without save_as
+    let read_only = !config.tools.contains(&"write_file".to_string());
     base_tools
         .into_iter()
         .filter(|t| config.tools.contains(&t.name))
+        .map(|mut t| {
+            if read_only && t.name == "diff" {
+                if let Some(properties) = t.input_schema["properties"].as_object_mut() {
+                    properties.remove("save_as");
+                }
+            }
+            t
+        })
         .collect()
 }
 
+/// Refuse `diff` with `save_as` from an agent that can't write files.
+/// Its schema leaves the field out, but the model can still send it.
+fn check_diff_save(sub_tools: &[Tool], name: &str, input: &serde_json::Value) -> Option<String> {
+    let can_write = sub_tools.iter().any(|t| t.name == "write_file");
+    (name == "diff" && input.get("save_as").is_some() && !can_write).then(|| {
+        "Error: save_as is not available to read-only agents; return the diff instead".to_string()
+    })
+}
+
+fn create_report_progress_tool() -> Tool {
+    Tool {
+        name: "report_progress".to_string(),
+        description: "Tell the user how a long task is going (e.g. \"found 12 matching files so far\"). Shown on the parent's progress line; call it occasionally, not after every step.".to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "message": {
+                    "type": "string",
+                    "description": "One short line describing progress so far"
+                }
+            },
+            "required": ["message"]
+        }),
+    }
+}
+
+/// Reviewer subagents only: changes are collected as a patch, never written
+fn create_propose_patch_tool() -> Tool {
+    Tool {
+        name: "propose_patch".to_string(),
+        description: "Propose replacing exact text in a file, like edit_file, but without touching the filesystem. Proposals to the same file build on each other; the combined unified diff is returned to whoever started you, who decides whether to apply it. Empty old_text proposes a new file.".to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "path": {
+                    "type": "string",
+                    "description": "Relative path to the file"
+                },
+                "old_text": {
+                    "type": "string",
+                    "description": "Exact text to replace (must match, including earlier proposals)"
+                },
+                "new_text": {
+                    "type": "string",
+                    "description": "Replacement text"
+                }
+            },
+            "required": ["path", "old_text", "new_text"]
+        }),
+    }
+}
+
+/// Main agent only: subagents report back instead of asking
+fn create_ask_user_tool() -> Tool {
+    Tool {
+        name: "ask_user".to_string(),
+        description: "Ask the user a clarifying question and wait for the answer. Use it only when you cannot proceed without their input; the answer comes back as the tool result.".to_string(),
+        input_schema: json!({
+            "type": "object",
+            "properties": {
+                "question": {
+                    "type": "string",
+                    "description": "The question to ask, self-contained and specific"
+                }
+            },
+            "required": ["question"]
+        }),
+    }
+}
+
 /// Get tools for subagent, including Skill tool if agent type supports it
 fn get_tools_for_subagent(agent_type: &str, skill_loader: &SkillLoader) -> Vec<Tool> {
     let mut tools = get_tools_for_agent(agent_type);
+    tools.push(create_report_progress_tool());
+    if agent_type == "reviewer" {
+        tools.push(create_propose_patch_tool());
+    }
 
     // Add Skill tool for agent types that can benefit from domain knowledge
     // explore: read-only, can use skills for analysis patterns
     // code: full access, can use skills for implementation guidance
     // plan: read-only, can use skills for design patterns
+    // reviewer: read-only, can use skills for review checklists
     match agent_type {
-        "explore" | "code" | "plan" => {
+        "explore" | "code" | "plan" | "reviewer" => {
             tools.push(create_skill_tool(skill_loader));
+            tools.push(create_suggest_skill_tool());
+            tools.push(create_skill_info_tool());
+            tools.push(create_skill_reference_tool());
         }
         _ => {
             // Other agent types don't get Skill tool
@@ -951,1014 +2768,7038 @@ fn get_tools_for_subagent(agent_type: &str, skill_loader: &SkillLoader) -> Vec<T
 // Tool Implementations
 // =============================================================================
 
-fn safe_truncate(s: &str, max_bytes: usize) -> &str {
-    if s.len() <= max_bytes {
-        return s;
-    }
+/// Pick the workspace (`--workdir` or the current directory) and make sure
+/// it is an existing directory, so a bad path fails at startup with a clear
+/// message rather than deep inside a tool.
+fn resolve_workdir(workdir: Option<&Path>) -> Result<PathBuf> {
+    let dir = match workdir {
+        Some(dir) => dir.to_path_buf(),
+        None => env::current_dir().map_err(|e| {
+            anyhow::anyhow!(
+                "The current directory is unavailable (was it deleted?): {}",
+                e
+            )
+        })?,
+    };
 
-    let mut boundary = max_bytes;
-    while boundary > 0 && !s.is_char_boundary(boundary) {
-        boundary -= 1;
+    if !dir.exists() {
+        anyhow::bail!("Workspace {} does not exist", dir.display());
     }
-
-    &s[..boundary]
+    if !dir.is_dir() {
+        anyhow::bail!(
+            "Workspace {} is not a directory; pass a directory with --workdir",
+            dir.display()
+        );
+    }
+    dir.canonicalize()
+        .with_context(|| format!("Invalid workspace {}", dir.display()))
 }
 
-fn safe_path(workdir: &Path, relative_path: &str) -> Result<PathBuf> {
-    let path = workdir.join(relative_path);
-    let canonical = path.canonicalize().or_else(|_| {
-        if let Some(parent) = path.parent() {
-            if parent.exists() {
-                Ok(parent.canonicalize()?.join(
-                    path.file_name()
-                        .ok_or_else(|| anyhow::anyhow!("Invalid path"))?,
-                ))
-            } else {
-                Err(anyhow::anyhow!("Parent directory does not exist"))
-            }
-        } else {
-            Err(anyhow::anyhow!("Invalid path"))
-        }
-    })?;
+/// Result given to a tool_use that somehow ended up without one
+const MISSING_TOOL_RESULT: &str = "Error: no result was recorded for this tool call";
 
-    if !canonical.starts_with(workdir) {
-        anyhow::bail!("Path escapes workspace: {}", relative_path);
+/// Backfill an error result for every tool_use in `assistant_content` that
+/// has no matching tool_result, so the next request stays valid.
+fn reconcile_tool_results(assistant_content: &[ContentBlock], results: &mut Vec<ContentBlock>) {
+    for block in assistant_content {
+        let ContentBlock::ToolUse { id, .. } = block else {
+            continue;
+        };
+        let answered = results.iter().any(
+            |r| matches!(r, ContentBlock::ToolResult { tool_use_id, .. } if tool_use_id == id),
+        );
+        if !answered {
+            results.push(ContentBlock::ToolResult {
+                tool_use_id: id.clone(),
+                is_error: Some(true),
+                content: anthropic::types::ToolResultContent::Text(MISSING_TOOL_RESULT.to_string()),
+            });
+        }
     }
-
-    Ok(canonical)
 }
 
-fn run_bash(workdir: &Path, command: &str) -> String {
-    let dangerous = ["rm -rf /", "sudo", "shutdown", "reboot", "> /dev/"];
-    if dangerous.iter().any(|d| command.contains(d)) {
-        return "Error: Dangerous command blocked".to_string();
+/// Show a tool's result to the user: errors in red, long output cut to a
+/// preview, and Task output skipped (run_task already printed it).
+fn display_tool_output(out: &dyn Writer, name: &str, output: &str) {
+    let preview = if matches!(name, "TodoWrite" | "Task" | "Skill" | "web_search") {
+        output.to_string()
+    } else if output.len() > 300 {
+        format!("{}...", safe_truncate(output, 300))
+    } else {
+        output.to_string()
+    };
+
+    if output.starts_with("Error:") {
+        out.line(&preview.bright_red().to_string());
+    } else if name == "TodoWrite" {
+        out.line(&preview.bright_green().to_string());
+    } else if name == "Task" {
+        // Task output already printed by run_task
+    } else if name == "Skill" {
+        out.line(&format!(
+            "{} {}",
+            "Skill loaded:".bright_green(),
+            preview.lines().next().unwrap_or("").bright_black()
+        ));
+    } else if name == "web_search" {
+        out.line(&preview.bright_black().to_string());
+    } else {
+        out.line(&format!("  {}", preview.bright_black()));
     }
+}
 
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .current_dir(workdir)
-        .output();
+/// Wrap `output` in `<untrusted-content>` if the injection guard flags it.
+/// The warning, if any, is for the user; the caller decides where it goes.
+fn guard_tool_output(config: &Config, name: &str, output: String) -> (String, Option<String>) {
+    let Some(guard) = &config.injection_guard else {
+        return (output, None);
+    };
+    let (output, matched) = guard.guard(name, output);
+    let warning = (!matched.is_empty()).then(|| {
+        format!(
+            "{} output looks like prompt injection (\"{}\"); passed on as untrusted content",
+            name,
+            matched.join("\", \"")
+        )
+    });
+    (output, warning)
+}
 
-    match output {
-        Ok(output) => {
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            let combined = format!("{}{}", stdout, stderr).trim().to_string();
+/// What a tool call produced: its text, and whether it failed in a way the
+/// model should see as `is_error` (a `bash` command that didn't succeed)
+struct ToolOutput {
+    text: String,
+    failed: bool,
+}
 
-            if combined.is_empty() {
-                "(no output)".to_string()
-            } else if combined.len() > 50000 {
-                format!("{}...", safe_truncate(&combined, 50000))
-            } else {
-                combined
-            }
+impl From<String> for ToolOutput {
+    fn from(text: String) -> Self {
+        Self {
+            text,
+            failed: false,
         }
-        Err(e) => format!("Error: {}", e),
     }
 }
 
-fn run_read(workdir: &Path, path: &str, limit: Option<i64>) -> String {
-    match safe_path(workdir, path) {
-        Ok(safe_path) => {
-            // Read file as raw bytes first to handle non-UTF8 content gracefully
-            match fs::read(&safe_path) {
-                Ok(bytes) => {
-                    // Check for non-UTF8 bytes
-                    let has_invalid_utf8 = bytes.iter().any(|&b| b > 0x7F && !b.is_ascii());
+/// Build the `tool_result` block for a tool's output.
+///
+/// `failed` sets `is_error`, so the model can tell a failed command from one
+/// that merely printed to stderr.
+fn tool_result(tool_use_id: String, output: String, failed: bool) -> ContentBlock {
+    ContentBlock::ToolResult {
+        tool_use_id,
+        is_error: failed.then_some(true),
+        content: anthropic::types::ToolResultContent::Text(output),
+    }
+}
 
-                    let content = if has_invalid_utf8 {
-                        // Use lossy conversion to handle binary data in log files
-                        String::from_utf8_lossy(&bytes).to_string()
-                    } else {
-                        // Safe to use strict UTF-8 for pure ASCII files
-                        match String::from_utf8(bytes) {
-                            Ok(s) => s,
-                            Err(e) => {
-                                // Fallback to lossy conversion
-                                String::from_utf8_lossy(e.as_bytes()).to_string()
-                            }
-                        }
-                    };
+/// Default for MINI_CODE_READ_CONCURRENCY
+const DEFAULT_READ_CONCURRENCY: usize = 8;
 
-                    let lines: Vec<&str> = content.lines().collect();
-                    let total_lines = lines.len();
-
-                    let output = if let Some(limit) = limit {
-                        if limit > 0 && (limit as usize) < total_lines {
-                            let limited_lines: Vec<&str> =
-                                lines.iter().take(limit as usize).copied().collect();
-                            format!(
-                                "{}\n... ({} more lines)",
-                                limited_lines.join("\n"),
-                                total_lines - limit as usize
-                            )
-                        } else {
-                            content
-                        }
-                    } else {
-                        content
-                    };
+/// Read `paths` with at most `concurrency` reads in flight.
+///
+/// Results come back in input order, one `run_read_with` output per file, so a
+/// missing file shows up as that file's error instead of failing the batch.
+async fn run_read_files(
+    workdir: &Path,
+    paths: &[String],
+    limit: Option<i64>,
+    concurrency: usize,
+    redactor: &Arc<Redactor>,
+) -> Vec<String> {
+    let mut outputs = vec!["Error: read did not complete".to_string(); paths.len()];
+    let mut pending = paths.iter().cloned().enumerate();
+    let mut reads = tokio::task::JoinSet::new();
 
-                    if output.len() > 50000 {
-                        format!("{}...", safe_truncate(&output, 50000))
-                    } else {
-                        output
-                    }
-                }
-                Err(e) => format!("Error reading file: {}", e),
-            }
+    loop {
+        while reads.len() < concurrency.max(1) {
+            let Some((i, path)) = pending.next() else {
+                break;
+            };
+            let workdir = workdir.to_path_buf();
+            let redactor = Arc::clone(redactor);
+            reads.spawn_blocking(move || {
+                let redact = |text: &str| redactor.redact(text);
+                (
+                    i,
+                    run_read_with(&workdir, &path, limit, false, None, &redact),
+                )
+            });
+        }
+        match reads.join_next().await {
+            Some(Ok((i, output))) => outputs[i] = output,
+            // A panicked read keeps its placeholder error
+            Some(Err(_)) => {}
+            None => break,
         }
-        Err(e) => format!("Error: {}", e),
     }
+    outputs
 }
 
-fn run_write(workdir: &Path, path: &str, content: &str) -> String {
-    match safe_path(workdir, path) {
-        Ok(safe_path) => {
-            if let Some(parent) = safe_path.parent() {
-                if let Err(e) = fs::create_dir_all(parent) {
-                    return format!("Error creating parent directories: {}", e);
-                }
-            }
+/// `paths` and `limit` from a read_files call; `None` without any paths
+fn read_files_input(input: &serde_json::Value) -> Option<(Vec<String>, Option<i64>)> {
+    let paths: Vec<String> = input
+        .get("paths")
+        .and_then(|v| v.as_array())?
+        .iter()
+        .filter_map(|p| p.as_str().map(str::to_string))
+        .collect();
+    let limit = input.get("limit").and_then(|v| v.as_i64());
+    (!paths.is_empty()).then_some((paths, limit))
+}
 
-            match fs::write(&safe_path, content) {
-                Ok(_) => format!("Wrote {} bytes to {}", content.len(), path),
-                Err(e) => format!("Error: {}", e),
-            }
-        }
-        Err(e) => format!("Error: {}", e),
-    }
+fn format_read_files(paths: &[String], outputs: &[String]) -> String {
+    paths
+        .iter()
+        .zip(outputs)
+        .map(|(path, output)| format!("=== {} ===\n{}", path, output))
+        .collect::<Vec<_>>()
+        .join("\n\n")
 }
 
-fn run_edit(workdir: &Path, path: &str, old_text: &str, new_text: &str) -> String {
-    match safe_path(workdir, path) {
-        Ok(safe_path) => match fs::read_to_string(&safe_path) {
-            Ok(content) => {
-                if !content.contains(old_text) {
-                    return format!("Error: Text not found in {}", path);
-                }
+/// Parse "md, .TXT" into ["md", "txt"]
+fn parse_extension_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+        .filter(|e| !e.is_empty())
+        .collect()
+}
 
-                let new_content = content.replacen(old_text, new_text, 1);
+/// Refuse writes to files whose extension isn't in `allowed` (empty = any)
+fn check_editable(path: &str, allowed: &[String]) -> std::result::Result<(), String> {
+    if allowed.is_empty() {
+        return Ok(());
+    }
+    let ext = Path::new(path)
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase);
+    match ext {
+        Some(ext) if allowed.contains(&ext) => Ok(()),
+        Some(ext) => Err(format!("Error: editing .{} files is not permitted", ext)),
+        None => Err("Error: editing files without an extension is not permitted".to_string()),
+    }
+}
 
-                match fs::write(&safe_path, new_content) {
-                    Ok(_) => format!("Edited {}", path),
-                    Err(e) => format!("Error: {}", e),
-                }
-            }
-            Err(e) => format!("Error: {}", e),
+/// Rename identifier tokens in a Rust file (see the rust_rename module)
+fn run_rust_rename(workdir: &Path, path: &str, from: &str, to: &str) -> String {
+    if Path::new(path).extension().and_then(|e| e.to_str()) != Some("rs") {
+        return format!("Error: rust_rename only handles .rs files, not {}", path);
+    }
+    let full = match safe_path(workdir, path) {
+        Ok(p) => p,
+        Err(e) => return format!("Error: {}", e),
+    };
+    let lock = write_lock(&full);
+    let _guard = lock.lock().unwrap();
+
+    match ReadSnapshot::take(&full) {
+        Ok(read) if !read.exists => format!("Error: {} does not exist", path),
+        Ok(read) => match rust_rename::rename_identifiers(&read.content, from, to) {
+            Ok((_, 0)) => format!("Error: No identifier `{}` in {}", from, path),
+            Ok((renamed, count)) => match write_if_unchanged(&full, &read, &renamed) {
+                Ok(_) => format!(
+                    "Edited {}: renamed {} occurrence(s) of `{}` to `{}`",
+                    path, count, from, to
+                ),
+                Err(e) => e,
+            },
+            Err(e) => format!("Error: {} {:#}", path, e),
         },
         Err(e) => format!("Error: {}", e),
     }
 }
 
-fn run_todo(todo_manager: &TodoManager, items: Vec<TodoItem>) -> String {
-    match todo_manager.update(items) {
-        Ok(rendered) => rendered,
-        Err(e) => format!("Error: {}", e),
+/// Paths a successful edit touched: the `path` of `write_file`/`edit_file`/
+/// `rust_rename`, or each file replace_in_files reports as edited
+fn edited_paths(name: &str, input: &serde_json::Value, output: &str) -> Vec<String> {
+    if name == "replace_in_files" {
+        return output
+            .lines()
+            .filter_map(|line| line.strip_prefix("Edited "))
+            .filter_map(|rest| rest.rsplit_once(" (").map(|(path, _)| path.to_string()))
+            .collect();
+    }
+    let succeeded = ["Wrote ", "Edited ", "Created "]
+        .iter()
+        .any(|prefix| output.starts_with(prefix));
+    if matches!(name, "write_file" | "edit_file" | "rust_rename") && succeeded {
+        input
+            .get("path")
+            .and_then(|v| v.as_str())
+            .map(str::to_string)
+            .into_iter()
+            .collect()
+    } else {
+        Vec::new()
     }
 }
 
-/// Load a skill and inject it into the conversation - NEW in v4
-///
-/// This is the key mechanism:
-/// 1. Get skill content (SKILL.md body + resource hints)
-/// 2. Return it wrapped in <skill-loaded> tags
-/// 3. Model receives this as tool_result (user message)
-/// 4. Model now "knows" how to do the task
-///
-/// Why tool_result instead of system prompt?
-/// - System prompt changes invalidate cache (20-50x cost increase)
-/// - Tool results append to end (prefix unchanged, cache hit)
-///
-/// This is how production systems stay cost-efficient.
-fn run_skill(skill_loader: &SkillLoader, skill_name: &str) -> String {
-    match skill_loader.get_skill_content(skill_name) {
-        Some(content) => {
-            format!(
-                r#"<skill-loaded name="{}">
-{}
-</skill-loaded>
+/// Run MINI_CODE_FORMAT_COMMAND once after a batch of edits and report
+/// which of the `edited` files it changed.
+fn run_format_command(
+    workdir: &Path,
+    command: &str,
+    edited: &[String],
+    cancel: &CancellationToken,
+    redactor: &Redactor,
+) -> String {
+    let mut paths = edited.to_vec();
+    paths.sort();
+    paths.dedup();
+    let snapshot = |path: &String| safe_path(workdir, path).ok().and_then(|p| fs::read(p).ok());
+    let before: Vec<Option<Vec<u8>>> = paths.iter().map(snapshot).collect();
+
+    let (success, output) =
+        run_bash_cancellable(workdir, command, 0, cancel, &|text| redactor.redact(text));
+    if !success {
+        return format!("[format] `{}` failed:\n{}", command, output);
+    }
 
-Follow the instructions in the skill above to complete the user's task."#,
-                skill_name, content
-            )
-        }
-        None => {
-            let available = skill_loader.list_skills().join(", ");
-            let available = if available.is_empty() {
-                "none".to_string()
-            } else {
-                available
-            };
-            format!(
-                "Error: Unknown skill '{}'. Available: {}",
-                skill_name, available
-            )
-        }
+    let changed: Vec<&str> = paths
+        .iter()
+        .zip(before)
+        .filter(|(path, before)| snapshot(path) != *before)
+        .map(|(path, _)| path.as_str())
+        .collect();
+    if changed.is_empty() {
+        format!("[format] `{}` made no changes", command)
+    } else {
+        format!(
+            "[format] `{}` reformatted {} - re-read before editing again",
+            command,
+            changed.join(", ")
+        )
     }
 }
 
-// =============================================================================
-// Subagent Progress Tracking (from v3)
-// =============================================================================
-
-struct SubagentProgress {
-    tool_count: usize,
-    current_tool: Option<String>,
-    start_time: Instant,
+/// Unified diff with 3 lines of context; empty when the inputs match.
+fn unified_diff(old: &str, new: &str, old_label: &str, new_label: &str) -> String {
+    similar::TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(old_label, new_label)
+        .to_string()
 }
 
-impl SubagentProgress {
-    fn new() -> Self {
-        Self {
-            tool_count: 0,
-            current_tool: None,
-            start_time: Instant::now(),
+/// Diff an `edit_file` call would produce, or None if the edit can't apply
+/// (run_edit_locked then reports the error itself).
+fn edit_preview(workdir: &Path, path: &str, old_text: &str, new_text: &str) -> Option<String> {
+    let full = safe_path(workdir, path).ok()?;
+    let current = fs::read_to_string(&full).unwrap_or_default();
+
+    let updated = if old_text.is_empty() {
+        if !current.is_empty() {
+            return None;
         }
+        new_text.to_string()
+    } else if current.contains(old_text) {
+        current.replacen(old_text, new_text, 1)
+    } else {
+        return None;
+    };
+
+    Some(unified_diff(
+        &current,
+        &updated,
+        path,
+        &format!("{} (edited)", path),
+    ))
+}
+
+/// Show an edit's diff and ask y/N on `input`; anything but y/yes denies.
+fn confirm_edit(diff: &str, input: &mut dyn io::BufRead) -> bool {
+    for line in diff.lines() {
+        let colored = if line.starts_with('+') && !line.starts_with("+++") {
+            line.bright_green()
+        } else if line.starts_with('-') && !line.starts_with("---") {
+            line.bright_red()
+        } else {
+            line.bright_black()
+        };
+        println!("  {}", colored);
+    }
+    print!("{} ", "Apply this edit? [y/N]".bright_yellow());
+    io::stdout().flush().ok();
+
+    let mut answer = String::new();
+    if input.read_line(&mut answer).is_err() {
+        return false;
     }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
 }
 
-fn spawn_subagent_progress_updater(
-    agent_type: String,
-    description: String,
-    progress: Arc<Mutex<SubagentProgress>>,
-    stop_signal: Arc<AtomicBool>,
-) -> thread::JoinHandle<()> {
-    thread::spawn(move || {
-        println!();
-        io::stdout().flush().ok();
+/// Held while a question is on screen. Parallel subagents can each reach a
+/// prompt; one at a time keeps their questions and answers from interleaving.
+static PROMPT_LOCK: Mutex<()> = Mutex::new(());
 
-        while !stop_signal.load(Ordering::Relaxed) {
-            thread::sleep(Duration::from_millis(1000));
+/// Ask y/N before a tool call the approval policy flagged.
+///
+/// Edits show their diff; an edit that can't apply isn't asked about, so
+/// run_edit_locked reports the real error. Other tools show their input.
+fn ask_approval(
+    workdir: &Path,
+    name: &str,
+    input: &serde_json::Value,
+    reader: &mut dyn io::BufRead,
+) -> bool {
+    let _prompt = PROMPT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    if name == "edit_file" {
+        let field = |key: &str| input.get(key).and_then(|v| v.as_str()).unwrap_or("");
+        return match edit_preview(workdir, field("path"), field("old_text"), field("new_text")) {
+            Some(diff) => confirm_edit(&diff, reader),
+            None => true,
+        };
+    }
 
-            if stop_signal.load(Ordering::Relaxed) {
-                break;
+    let summary = input.to_string();
+    println!(
+        "  {} {}",
+        name.bright_yellow(),
+        safe_truncate(&summary, 500).bright_black()
+    );
+    print!("{} ", "Run this tool call? [y/N]".bright_yellow());
+    io::stdout().flush().ok();
+
+    let mut answer = String::new();
+    if reader.read_line(&mut answer).is_err() {
+        return false;
+    }
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// Put a question to the user and return their answer as the tool result.
+///
+/// Without an interactive user this returns `default`, or an error telling
+/// the model to decide on its own.
+fn run_ask_user(
+    question: &str,
+    interactive: bool,
+    default: Option<&str>,
+    reader: &mut dyn io::BufRead,
+) -> String {
+    if !interactive {
+        return match default {
+            Some(answer) => answer.to_string(),
+            None => {
+                "Error: no interactive user; make a reasonable assumption and state it".to_string()
             }
+        };
+    }
 
-            let progress_guard = progress.lock().unwrap();
-            let elapsed = progress_guard.start_time.elapsed().as_secs_f64();
-            let tool_count = progress_guard.tool_count;
-            let current_tool = progress_guard.current_tool.clone();
-            drop(progress_guard);
+    let _prompt = PROMPT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    println!("\n{} {}", "?".bright_cyan(), question.bright_white());
+    print!("{} ", ">".bright_cyan());
+    io::stdout().flush().ok();
 
-            println!(
-                "\x1B[1A\x1B[K  {} {} ... {} tools, {:.1}s",
-                format!("[{}]", agent_type).bright_magenta(),
-                description,
-                tool_count,
-                elapsed
-            );
+    let mut answer = String::new();
+    match reader.read_line(&mut answer) {
+        Ok(n) if n > 0 && !answer.trim().is_empty() => answer.trim().to_string(),
+        _ => "(the user gave no answer)".to_string(),
+    }
+}
 
-            if let Some(tool_info) = current_tool {
-                println!(
-                    "\x1B[K    {} {}",
-                    "→".bright_blue(),
-                    tool_info.bright_black()
-                );
+/// Refuse a call to a tool the session doesn't offer.
+///
+/// MINI_CODE_ENABLED_TOOLS/MINI_CODE_DISABLED_TOOLS, offline mode and agent
+/// types only shape the advertised schema; the model can still name any
+/// tool, so dispatch checks the same list.
+fn check_tool_available(tools: &[Tool], name: &str) -> Option<String> {
+    (!tools.iter().any(|t| t.name == name))
+        .then(|| format!("Error: tool {} is not available", name))
+}
+
+/// Check a call's input against the tool's declared `input_schema`.
+/// Returns the error reported back to the model, listing every bad field.
+///
+/// Tools not in `tools` are refused by `check_tool_available`; ones with a
+/// schema that doesn't compile are left to the tool's own parameter checks.
+fn validate_tool_input(tools: &[Tool], name: &str, input: &serde_json::Value) -> Option<String> {
+    let tool = tools.iter().find(|t| t.name == name)?;
+    let validator = jsonschema::validator_for(&tool.input_schema).ok()?;
+    let problems: Vec<String> = validator
+        .iter_errors(input)
+        .map(|e| {
+            let field = e.instance_path.to_string();
+            if field.is_empty() {
+                format!("- {}", e)
             } else {
-                println!("\x1B[K");
+                format!("- {}: {}", field, e)
             }
+        })
+        .collect();
+    if problems.is_empty() {
+        None
+    } else {
+        Some(format!(
+            "Error: Invalid input for {}:\n{}",
+            name,
+            problems.join("\n")
+        ))
+    }
+}
 
-            print!("\x1B[1A");
-            io::stdout().flush().ok();
+/// Consult the approval policy before a mutating tool runs.
+/// Returns the message reported back to the model when the call is refused.
+fn check_approval(config: &Config, name: &str, input: &serde_json::Value) -> Option<String> {
+    if !approval::is_mutating(name, input) {
+        return None;
+    }
+    match config.approval.approve(name, input) {
+        Approval::Allow => None,
+        Approval::Deny { reason } => Some(format!("Error: {} is not allowed: {}", name, reason)),
+        // Reading stdin here would take the next scripted line as the answer
+        Approval::AskUser if !config.interactive && name == "edit_file" => Some(
+            "Error: edit confirmation required but stdin is not interactive; the edit was not made"
+                .to_string(),
+        ),
+        Approval::AskUser if !config.interactive => Some(format!(
+            "Error: approval required but stdin is not interactive; {} was not run",
+            name
+        )),
+        Approval::AskUser => {
+            if ask_approval(&config.workdir, name, input, &mut io::stdin().lock()) {
+                None
+            } else if name == "edit_file" {
+                Some("User rejected the edit".to_string())
+            } else {
+                Some(format!("User rejected the {} call", name))
+            }
         }
-    })
+    }
 }
 
-// =============================================================================
-// Subagent Execution (from v3, adapted for v4)
-// =============================================================================
+/// Edits a reviewer subagent proposed with `propose_patch`, kept in memory.
+#[derive(Default)]
+struct PatchSet {
+    /// path -> (content on disk, content with every proposal applied)
+    files: BTreeMap<String, (String, String)>,
+}
 
-async fn run_task(
-    client: &Client,
-    config: &Config,
-    todo_manager: &TodoManager,
-    skill_loader: &SkillLoader,
-    description: &str,
-    prompt: &str,
-    agent_type: &str,
+impl PatchSet {
+    /// Apply one proposal to the in-memory copy of `path`
+    fn propose(&mut self, workdir: &Path, path: &str, old_text: &str, new_text: &str) -> String {
+        if !self.files.contains_key(path) {
+            let full = match safe_path(workdir, path) {
+                Ok(p) => p,
+                Err(e) => return format!("Error: {}", e),
+            };
+            let original = if full.exists() {
+                match fs::read_to_string(&full) {
+                    Ok(content) => content,
+                    Err(e) => return format!("Error: {}", e),
+                }
+            } else {
+                String::new()
+            };
+            self.files
+                .insert(path.to_string(), (original.clone(), original));
+        }
+        let (original, proposed) = self.files.get_mut(path).unwrap();
+
+        if old_text.is_empty() {
+            if !proposed.is_empty() {
+                return format!("Error: old_text is empty but {} already has content", path);
+            }
+            *proposed = new_text.to_string();
+        } else if proposed.contains(old_text) {
+            *proposed = proposed.replacen(old_text, new_text, 1);
+        } else {
+            return format!("Error: Text not found in {}", path);
+        }
+
+        let (added, removed) = diff_stat(original, proposed);
+        format!(
+            "Proposed change to {} (+{}/-{} lines so far); nothing was written",
+            path, added, removed
+        )
+    }
+
+    /// Combined unified diff of every proposal, `git apply`-able from the workdir
+    fn render(&self) -> String {
+        self.files
+            .iter()
+            .map(|(path, (original, proposed))| {
+                unified_diff(
+                    original,
+                    proposed,
+                    &format!("a/{}", path),
+                    &format!("b/{}", path),
+                )
+            })
+            .filter(|diff| !diff.is_empty())
+            .collect::<Vec<_>>()
+            .join("")
+    }
+
+    /// `result` followed by the patch, when anything was proposed
+    fn append_to(&self, result: String, redactor: &Redactor) -> String {
+        let patch = self.render();
+        if patch.is_empty() {
+            return result;
+        }
+        redactor.cap(format!(
+            "{}\n\nProposed patch (not applied):\n```diff\n{}```",
+            result, patch
+        ))
+    }
+}
+
+/// Unified diff of `path` against `other_path` or proposed `content`.
+fn run_diff(
+    workdir: &Path,
+    path: &str,
+    other_path: Option<&str>,
+    content: Option<&str>,
+    save_as: Option<&str>,
+    redactor: &Redactor,
 ) -> String {
-    let agent_types = get_agent_types();
-    let agent_config = match agent_types.get(agent_type) {
-        Some(cfg) => cfg,
-        None => return format!("Error: Unknown agent type '{}'", agent_type),
+    let read = |p: &str| -> Result<String> {
+        let full = safe_path(workdir, p)?;
+        fs::read_to_string(&full).with_context(|| format!("Failed to read {}", p))
     };
 
-    let sub_system = format!(
-        r#"You are a {} subagent at {}.
+    let old = match read(path) {
+        Ok(s) => s,
+        Err(e) => return format!("Error: {}", e),
+    };
 
-{}
+    let (new, new_label) = match (other_path, content) {
+        (Some(other), None) => match read(other) {
+            Ok(s) => (s, other.to_string()),
+            Err(e) => return format!("Error: {}", e),
+        },
+        (None, Some(content)) => (content.to_string(), format!("{} (proposed)", path)),
+        _ => return "Error: Provide exactly one of 'other_path' or 'content'".to_string(),
+    };
 
-Complete the task and return a clear, concise summary."#,
-        agent_type,
-        config.workdir.display(),
-        agent_config.prompt
-    );
+    let diff = unified_diff(&old, &new, path, &new_label);
 
-    // Get tools including Skill tool for subagent
-    let sub_tools = get_tools_for_subagent(agent_type, skill_loader);
+    if diff.is_empty() {
+        return "(no differences)".to_string();
+    }
 
-    let mut sub_messages = vec![Message {
-        role: Role::User,
-        content: vec![ContentBlock::text(prompt)],
-    }];
+    if let Some(save_as) = save_as {
+        if !save_as.ends_with(".diff") {
+            return "Error: save_as must end with .diff".to_string();
+        }
+        let target = match safe_path(workdir, save_as) {
+            Ok(p) => p,
+            Err(e) => return format!("Error: {}", e),
+        };
+        let lock = write_lock(&target);
+        let _guard = lock.lock().unwrap();
+        if let Some(parent) = target.parent() {
+            if let Err(e) = fs::create_dir_all(parent) {
+                return format!("Error creating parent directories: {}", e);
+            }
+        }
+        if let Err(e) = fs::write(&target, &diff) {
+            return format!("Error: {}", e);
+        }
+    }
 
-    let progress = Arc::new(Mutex::new(SubagentProgress::new()));
-    let progress_clone = progress.clone();
-    let stop_signal = Arc::new(AtomicBool::new(false));
-    let stop_signal_clone = stop_signal.clone();
+    redactor.cap(diff)
+}
 
-    println!(
-        "  {} {}",
-        format!("[{}]", agent_type).bright_magenta(),
-        description
-    );
+fn run_count_tokens(workdir: &Path, text: Option<&str>, path: Option<&str>) -> String {
+    let (label, content) = match (text, path) {
+        (Some(text), None) => ("text".to_string(), text.to_string()),
+        (None, Some(path)) => {
+            let content = match safe_path(workdir, path).and_then(|p| Ok(fs::read(p)?)) {
+                Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+                Err(e) => return format!("Error: {}", e),
+            };
+            (path.to_string(), content)
+        }
+        _ => return "Error: Provide exactly one of 'text' or 'path'".to_string(),
+    };
 
-    let updater = spawn_subagent_progress_updater(
-        agent_type.to_string(),
-        description.to_string(),
-        progress_clone,
-        stop_signal_clone,
-    );
+    format!(
+        "{}: ~{} tokens ({} bytes, {} lines)",
+        label,
+        estimate_tokens(&content),
+        content.len(),
+        content.lines().count()
+    )
+}
 
-    let mut consecutive_truncations = 0;
+/// 1-based line and column of a byte offset, for parsers that only report spans
+fn line_col(text: &str, offset: usize) -> (usize, usize) {
+    let before = &text[..offset.min(text.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+    (line, column)
+}
 
-    let result = loop {
-        let request = MessagesRequestBuilder::new(&config.model, sub_messages.clone(), 8000)
-            .system(SystemPrompt::Text(sub_system.clone()))
-            .tools(sub_tools.clone())
-            .build();
+/// Parse a config file by extension and pretty-print it.
+///
+/// Returns the canonical form, or the parse error with its 1-based line and
+/// column when the parser reports one.
+fn parse_config_file(ext: &str, text: &str) -> Result<std::result::Result<String, String>> {
+    let parsed = match ext {
+        "json" => serde_json::from_str::<serde_json::Value>(text)
+            .map(|v| serde_json::to_string_pretty(&v).unwrap_or_default())
+            .map_err(|e| format!("line {}, column {}: {}", e.line(), e.column(), e)),
+        "yaml" | "yml" => serde_yaml::from_str::<serde_yaml::Value>(text)
+            .map(|v| serde_yaml::to_string(&v).unwrap_or_default())
+            .map_err(|e| match e.location() {
+                Some(loc) => format!("line {}, column {}: {}", loc.line(), loc.column(), e),
+                None => e.to_string(),
+            }),
+        "toml" => toml::from_str::<toml::Table>(text)
+            .map(|v| toml::to_string_pretty(&v).unwrap_or_default())
+            .map_err(|e| match e.span() {
+                Some(span) => {
+                    let (line, column) = line_col(text, span.start);
+                    format!("line {}, column {}: {}", line, column, e.message())
+                }
+                None => e.message().to_string(),
+            }),
+        _ => anyhow::bail!(
+            "Unsupported file type '.{}': expected .json, .yaml, .yml or .toml",
+            ext
+        ),
+    };
+    Ok(parsed)
+}
 
-        let request = match request {
-            Ok(r) => r,
-            Err(e) => break format!("Error building request: {}", e),
-        };
+fn run_validate_file(workdir: &Path, path: &str, canonical: bool, redactor: &Redactor) -> String {
+    let result = safe_path(workdir, path).and_then(|full| {
+        let ext = full
+            .extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+        let text = fs::read_to_string(&full)?;
+        parse_config_file(&ext, &text)
+    });
 
-        let response = match client.messages(request).await {
-            Ok(r) => r,
-            Err(e) => break format!("Error calling API: {}", e),
-        };
+    match result {
+        Ok(Ok(pretty)) if canonical => {
+            let pretty = redactor.cap(pretty);
+            format!("valid\n\n{}", pretty.trim_end())
+        }
+        Ok(Ok(_)) => "valid".to_string(),
+        Ok(Err(e)) => format!("invalid: {}", e),
+        Err(e) => format!("Error: {}", e),
+    }
+}
 
-        match response.stop_reason {
-            Some(StopReason::MaxTokens) => {
-                consecutive_truncations += 1;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HashAlgo {
+    Sha256,
+    Md5,
+}
 
-                if consecutive_truncations >= 2 {
-                    let progress_guard = progress.lock().unwrap();
-                    let elapsed = progress_guard.start_time.elapsed();
-                    let tool_count = progress_guard.tool_count;
-                    drop(progress_guard);
+impl HashAlgo {
+    fn parse(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "sha256" => Ok(Self::Sha256),
+            "md5" => Ok(Self::Md5),
+            other => anyhow::bail!("Unsupported algo '{}': expected sha256 or md5", other),
+        }
+    }
 
-                    break format!(
-                        "[ERROR] Subagent output truncated {} times ({} tools, {:.1}s). Task too complex.",
-                        consecutive_truncations,
-                        tool_count,
-                        elapsed.as_secs_f64()
-                    );
+    fn hex_digest(self, bytes: &[u8]) -> String {
+        use sha2::Digest;
+        match self {
+            Self::Sha256 => format!("{:x}", sha2::Sha256::digest(bytes)),
+            Self::Md5 => format!("{:x}", md5::Md5::digest(bytes)),
+        }
+    }
+}
+
+/// Directories with more files than this are refused by hash_file
+const MAX_HASH_MANIFEST_FILES: usize = 10000;
+
+/// Every regular file under `dir`, skipping symlinks (so a walk never
+/// leaves the workspace) and anything `ignore` matches. Fails with
+/// `advice` once more than `max` files turn up.
+fn walk_files(
+    dir: &Path,
+    ignore: &IgnoreMatcher,
+    max: usize,
+    advice: &str,
+) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in fs::read_dir(&current)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if ignore.is_ignored(&entry.path(), file_type.is_dir()) {
+                continue;
+            }
+            if file_type.is_dir() {
+                pending.push(entry.path());
+            } else if file_type.is_file() {
+                files.push(entry.path());
+                if files.len() > max {
+                    anyhow::bail!("{} has more than {} files; {}", dir.display(), max, advice);
                 }
+            }
+        }
+    }
+    Ok(files)
+}
 
-                sub_messages.push(Message {
-                    role: Role::Assistant,
-                    content: response.content,
-                });
+/// `digest  relative/path` for every regular file under `dir`, sorted by path.
+/// Symlinks are skipped so the manifest never reaches outside the workspace,
+/// and so is anything `ignore` matches.
+fn hash_manifest(
+    dir: &Path,
+    algo: HashAlgo,
+    ignore: &IgnoreMatcher,
+) -> Result<Vec<(String, String)>> {
+    let files = walk_files(
+        dir,
+        ignore,
+        MAX_HASH_MANIFEST_FILES,
+        "hash a subdirectory instead",
+    )?;
+
+    let mut manifest = files
+        .iter()
+        .map(|file| {
+            let relative = file
+                .strip_prefix(dir)
+                .unwrap_or(file)
+                .to_string_lossy()
+                .replace('\\', "/");
+            Ok((relative, algo.hex_digest(&fs::read(file)?)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    manifest.sort();
+    Ok(manifest)
+}
 
-                sub_messages.push(Message {
-                    role: Role::User,
-                    content: vec![ContentBlock::text(
-                        "[SYSTEM: Response truncated. Provide a brief summary only (max 200 words).]"
-                    )],
-                });
+fn run_hash_file(
+    workdir: &Path,
+    path: &str,
+    algo: Option<&str>,
+    ignore: &IgnoreMatcher,
+    redactor: &Redactor,
+) -> String {
+    let result = HashAlgo::parse(algo.unwrap_or("sha256")).and_then(|algo| {
+        let full = safe_path(workdir, path)?;
+        if !full.is_dir() {
+            return Ok(format!("{}  {}", algo.hex_digest(&fs::read(&full)?), path));
+        }
+
+        let lines: Vec<String> = hash_manifest(&full, algo, ignore)?
+            .into_iter()
+            .map(|(file, digest)| format!("{}  {}", digest, file))
+            .collect();
+        let manifest = lines.join("\n");
+        Ok(format!(
+            "{}\n\n{} files, manifest {}",
+            manifest,
+            lines.len(),
+            algo.hex_digest(manifest.as_bytes())
+        ))
+    });
+
+    match result {
+        Ok(output) => redactor.cap(output),
+        Err(e) => format!("Error: {}", e),
+    }
+}
+
+/// Files replace_in_files will scan in one call
+const MAX_REPLACE_FILES: usize = 10000;
+
+/// Regex find-and-replace across the workspace, one line per changed file.
+///
+/// Files are filtered by `glob` (relative to the workdir; `*` stays within
+/// one directory, `**` crosses them) and the ignore rules. Each file is
+/// rewritten under its write lock with the same changed-on-disk check as
+/// edit_file. `dry_run` only counts.
+fn run_replace_in_files(
+    workdir: &Path,
+    pattern: &str,
+    replacement: &str,
+    glob: Option<&str>,
+    dry_run: bool,
+    ignore: &IgnoreMatcher,
+    editable_extensions: &[String],
+) -> String {
+    let regex = match Regex::new(pattern) {
+        Ok(regex) => regex,
+        Err(e) => return format!("Error: Invalid pattern: {}", e),
+    };
+    let filter = match glob.map(glob::Pattern::new).transpose() {
+        Ok(filter) => filter,
+        Err(e) => return format!("Error: Invalid glob: {}", e),
+    };
+    let options = glob::MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+    let mut files = match walk_files(
+        workdir,
+        ignore,
+        MAX_REPLACE_FILES,
+        "add paths to MINI_CODE_IGNORE",
+    ) {
+        Ok(files) => files,
+        Err(e) => return format!("Error: {}", e),
+    };
+    files.sort();
+
+    let mut lines = Vec::new();
+    let (mut changed, mut total) = (0, 0);
+    for file in files {
+        let relative = file
+            .strip_prefix(workdir)
+            .unwrap_or(&file)
+            .to_string_lossy()
+            .replace('\\', "/");
+        let wanted = filter
+            .as_ref()
+            .is_none_or(|f| f.matches_with(&relative, options));
+        if !wanted || check_editable(&relative, editable_extensions).is_err() {
+            continue;
+        }
+        let Ok(full) = safe_path(workdir, &relative) else {
+            continue;
+        };
+
+        let lock = write_lock(&full);
+        let _guard = lock.lock().unwrap();
+        // Unreadable or non-UTF-8 files are not text we can edit
+        let Ok(read) = ReadSnapshot::take(&full) else {
+            continue;
+        };
+        let count = regex.find_iter(&read.content).count();
+        if count == 0 {
+            continue;
+        }
+        let noun = if count == 1 {
+            "replacement"
+        } else {
+            "replacements"
+        };
 
+        if dry_run {
+            lines.push(format!("Would edit {} ({} {})", relative, count, noun));
+        } else {
+            let new = regex.replace_all(&read.content, replacement);
+            if let Err(e) = write_if_unchanged(&full, &read, &new) {
+                lines.push(format!("{}: {}", relative, e));
                 continue;
             }
+            lines.push(format!("Edited {} ({} {})", relative, count, noun));
+        }
+        changed += 1;
+        total += count;
+    }
 
-            Some(StopReason::ToolUse) => {
-                consecutive_truncations = 0;
+    if changed == 0 {
+        return format!("No matches for `{}`", pattern);
+    }
+    lines.push(if dry_run {
+        format!(
+            "\nDry run: {} file(s) would change, {} replacement(s); nothing written",
+            changed, total
+        )
+    } else {
+        format!("\n{} file(s) changed, {} replacement(s)", changed, total)
+    });
+    lines.join("\n")
+}
 
-                let mut results = Vec::new();
-                for block in &response.content {
-                    if let ContentBlock::ToolUse { id, name, input } = block {
-                        {
-                            let mut progress_guard = progress.lock().unwrap();
-                            progress_guard.tool_count += 1;
+/// Run a task `list_tasks` found, by name, through `run_bash_cancellable`
+fn run_project_task(
+    workdir: &Path,
+    name: &str,
+    source: Option<&str>,
+    cancel: &CancellationToken,
+    redactor: &Redactor,
+) -> String {
+    let command = source
+        .map(project_tasks::TaskSource::parse)
+        .transpose()
+        .and_then(|source| {
+            let tasks = project_tasks::discover(workdir)?;
+            Ok(project_tasks::find(&tasks, name, source)?.command())
+        });
+    match command {
+        Ok(command) => format!(
+            "$ {}\n{}",
+            command,
+            run_bash_cancellable(workdir, &command, 0, cancel, &|text| redactor.redact(text)).1
+        ),
+        Err(e) => format!("Error: {}", e),
+    }
+}
 
-                            let tool_display = match name.as_str() {
-                                "bash" => {
-                                    if let Some(cmd) = input.get("command").and_then(|v| v.as_str())
-                                    {
-                                        let short_cmd = if cmd.len() > 60 {
-                                            format!("{}...", &cmd[..60])
-                                        } else {
-                                            cmd.to_string()
-                                        };
-                                        format!("bash: {}", short_cmd)
-                                    } else {
-                                        "bash".to_string()
-                                    }
-                                }
-                                "read_file" => {
-                                    if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
-                                        format!("read: {}", path)
-                                    } else {
-                                        "read_file".to_string()
-                                    }
-                                }
-                                "write_file" => {
-                                    if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
-                                        format!("write: {}", path)
-                                    } else {
-                                        "write_file".to_string()
-                                    }
-                                }
-                                "edit_file" => {
-                                    if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
-                                        format!("edit: {}", path)
-                                    } else {
-                                        "edit_file".to_string()
-                                    }
-                                }
-                                "web_search" => {
-                                    if let Some(query) = input.get("query").and_then(|v| v.as_str())
-                                    {
-                                        let short_query = if query.len() > 40 {
-                                            format!("{}...", &query[..40])
-                                        } else {
-                                            query.to_string()
-                                        };
-                                        format!("search: {}", short_query)
-                                    } else {
-                                        "web_search".to_string()
-                                    }
-                                }
-                                "Skill" => {
-                                    if let Some(skill) = input.get("skill").and_then(|v| v.as_str())
-                                    {
-                                        format!("skill: {}", skill)
-                                    } else {
-                                        "Skill".to_string()
-                                    }
-                                }
-                                other => other.to_string(),
-                            };
-
-                            progress_guard.current_tool = Some(tool_display);
-                        }
-
-                        let output = execute_tool(config, todo_manager, skill_loader, name, input);
+/// Lines `tail_file` returns when the model doesn't say
+const DEFAULT_TAIL_LINES: usize = 20;
 
-                        results.push(ContentBlock::ToolResult {
-                            tool_use_id: id.clone(),
-                            is_error: None,
-                            content: anthropic::types::ToolResultContent::Text(output),
-                        });
+/// Longest `tail_file` follows a file before returning
+const MAX_TAIL_FOLLOW: Duration = Duration::from_secs(60);
 
-                        {
-                            let mut progress_guard = progress.lock().unwrap();
-                            progress_guard.current_tool = None;
-                        }
-                    }
-                }
+/// Block size `tail_file` reads backwards from the end of a file
+const TAIL_CHUNK: u64 = 8 * 1024;
 
-                sub_messages.push(Message {
-                    role: Role::Assistant,
-                    content: response.content,
-                });
-                sub_messages.push(Message {
-                    role: Role::User,
-                    content: results,
-                });
-            }
+/// The last `count` lines of the first `len` bytes of `file`, read backwards
+/// in chunks so a large log isn't loaded whole. Reading stops once more than
+/// MAX_TOOL_OUTPUT bytes are in hand, since the result is capped to that.
+fn read_last_lines(file: &mut fs::File, len: u64, count: usize) -> io::Result<String> {
+    use std::io::{Read as _, Seek as _, SeekFrom};
 
-            Some(StopReason::EndTurn) | Some(StopReason::StopSequence) | None => {
-                // Normal end - extract text and return
-                let mut text_result = None;
-                for block in &response.content {
-                    if let ContentBlock::Text { text } = block {
-                        text_result = Some(text.clone());
-                        break;
-                    }
-                }
-                break text_result.unwrap_or_else(|| "(subagent returned no text)".to_string());
-            }
+    let mut pos = len;
+    let mut tail: Vec<u8> = Vec::new();
+    loop {
+        // A newline ending the file doesn't start another line
+        let newlines =
+            tail.iter().filter(|&&b| b == b'\n').count() - usize::from(tail.ends_with(b"\n"));
+        if pos == 0 || newlines >= count || tail.len() > MAX_TOOL_OUTPUT {
+            break;
         }
-    };
+        let start = pos.saturating_sub(TAIL_CHUNK);
+        let mut chunk = vec![0; (pos - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+        pos = start;
+    }
 
-    stop_signal.store(true, Ordering::Relaxed);
-    updater.join().ok();
+    // Unless the read reached the start, the first line is only partly read
+    if pos > 0 {
+        if let Some(newline) = tail.iter().position(|&b| b == b'\n') {
+            tail.drain(..=newline);
+        }
+    }
+    let text = decode_lossy(tail);
+    let lines: Vec<&str> = text.lines().collect();
+    Ok(lines[lines.len().saturating_sub(count)..].join("\n"))
+}
 
-    let progress_guard = progress.lock().unwrap();
-    let elapsed = progress_guard.start_time.elapsed();
-    let tool_count = progress_guard.tool_count;
-    drop(progress_guard);
+/// Last `lines` lines of a file, then, for `follow_secs`, whatever is appended.
+///
+/// Following polls like a running `bash` command: it stops early on Ctrl-C
+/// or once the output reaches the tool byte cap. A file that shrinks (log
+/// rotation) is read again from the start.
+fn run_tail_file(
+    workdir: &Path,
+    path: &str,
+    lines: Option<u64>,
+    follow_secs: Option<u64>,
+    cancel: &CancellationToken,
+    redactor: &Redactor,
+) -> String {
+    use std::io::{Read as _, Seek as _, SeekFrom};
 
-    print!("\x1B[1A\x1B[K\x1B[1A\x1B[K");
+    let full = match safe_path(workdir, path) {
+        Ok(p) => p,
+        Err(e) => return format!("Error: {}", e),
+    };
+    let count = lines.map_or(DEFAULT_TAIL_LINES, |n| n as usize);
+    let tail = fs::File::open(&full).and_then(|mut file| {
+        let len = file.metadata()?.len();
+        Ok((read_last_lines(&mut file, len, count)?, len))
+    });
+    let (mut output, mut offset) = match tail {
+        Ok(tail) => tail,
+        Err(e) => return format!("Error reading file: {}", e),
+    };
 
-    if result.starts_with("[ERROR]") {
-        println!(
-            "  {} {} - {} ({} tools, {:.1}s)",
-            format!("[{}]", agent_type).bright_red(),
-            description,
-            "ERROR".bright_red(),
-            tool_count,
-            elapsed.as_secs_f64()
-        );
-    } else {
-        println!(
-            "  {} {} - {} ({} tools, {:.1}s)",
-            format!("[{}]", agent_type).bright_magenta(),
-            description,
-            "done".bright_green(),
-            tool_count,
-            elapsed.as_secs_f64()
-        );
+    let follow = Duration::from_secs(follow_secs.unwrap_or(0)).min(MAX_TAIL_FOLLOW);
+    if follow.is_zero() {
+        return redactor.cap(output);
     }
 
-    result
-}
-
-fn execute_tool(
-    config: &Config,
-    todo_manager: &TodoManager,
-    skill_loader: &SkillLoader,
-    name: &str,
-    input: &serde_json::Value,
-) -> String {
-    match name {
-        "bash" => {
-            if let Some(command) = input.get("command").and_then(|v| v.as_str()) {
-                run_bash(&config.workdir, command)
-            } else {
-                "Error: Missing 'command' parameter".to_string()
-            }
-        }
-        "read_file" => {
-            if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
-                let limit = input.get("limit").and_then(|v| v.as_i64());
-                run_read(&config.workdir, path, limit)
-            } else {
-                "Error: Missing 'path' parameter".to_string()
-            }
+    let deadline = Instant::now() + follow;
+    let mut appended = Vec::new();
+    while !cancel.is_cancelled() && output.len() + appended.len() < MAX_TOOL_OUTPUT {
+        let now = Instant::now();
+        if now >= deadline {
+            break;
         }
-        "write_file" => {
-            if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
-                if let Some(content) = input.get("content").and_then(|v| v.as_str()) {
-                    run_write(&config.workdir, path, content)
-                } else {
-                    "Error: Missing 'content' parameter".to_string()
-                }
-            } else {
-                "Error: Missing 'path' parameter".to_string()
-            }
+        thread::sleep(CANCEL_POLL_INTERVAL.min(deadline - now));
+
+        let Ok(mut file) = fs::File::open(&full) else {
+            continue;
+        };
+        let len = file.metadata().map_or(offset, |m| m.len());
+        if len < offset {
+            offset = 0;
         }
-        "edit_file" => {
-            if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
-                if let Some(old_text) = input.get("old_text").and_then(|v| v.as_str()) {
-                    if let Some(new_text) = input.get("new_text").and_then(|v| v.as_str()) {
-                        run_edit(&config.workdir, path, old_text, new_text)
-                    } else {
-                        "Error: Missing 'new_text' parameter".to_string()
-                    }
-                } else {
-                    "Error: Missing 'old_text' parameter".to_string()
-                }
-            } else {
-                "Error: Missing 'path' parameter".to_string()
+        if file.seek(SeekFrom::Start(offset)).is_ok() {
+            if let Ok(read) = file.read_to_end(&mut appended) {
+                offset += read as u64;
             }
         }
-        "web_search" => {
-            // Note: web_search is async, so we return a placeholder
-            // It will be handled in execute_tool_async
-            "Error: web_search must be called via execute_tool_async".to_string()
-        }
-        "TodoWrite" => {
-            if let Some(items_value) = input.get("items") {
-                match serde_json::from_value::<Vec<TodoItem>>(items_value.clone()) {
-                    Ok(items) => run_todo(todo_manager, items),
-                    Err(e) => format!("Error parsing todo items: {}", e),
-                }
+    }
+
+    let appended = decode_lossy(appended);
+    if appended.is_empty() {
+        output.push_str(&format!("\n(no new lines in {}s)", follow.as_secs()));
+    } else {
+        output.push_str(&format!(
+            "\n--- appended during {}s ---\n{}",
+            follow.as_secs(),
+            appended.trim_end()
+        ));
+    }
+    redactor.cap(output)
+}
+
+/// Run `git` with `args` in `workdir` for the read-only git tools.
+///
+/// Outside a repository this returns an explanatory error instead of git's
+/// own message, so the model stops retrying.
+fn run_git(workdir: &Path, path: &str, args: &[String], redactor: &Redactor) -> String {
+    if let Err(e) = safe_path(workdir, path) {
+        return format!("Error: {}", e);
+    }
+
+    let inside = Command::new("git")
+        .args(["rev-parse", "--is-inside-work-tree"])
+        .current_dir(workdir)
+        .output();
+    if !matches!(inside, Ok(ref o) if o.status.success()) {
+        return format!(
+            "Error: {} is not a git repository (or git is not installed)",
+            workdir.display()
+        );
+    }
+
+    match Command::new("git").args(args).current_dir(workdir).output() {
+        Ok(output) if output.status.success() => {
+            let text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+            if text.is_empty() {
+                format!("No git history for {}", path)
             } else {
-                "Error: Missing 'items' parameter".to_string()
+                redactor.cap(text)
             }
         }
-        "Skill" => {
-            let skill_name = input.get("skill").and_then(|v| v.as_str()).unwrap_or("");
-            run_skill(skill_loader, skill_name)
-        }
-        _ => format!("Unknown tool: {}", name),
+        Ok(output) => format!("Error: {}", String::from_utf8_lossy(&output.stderr).trim()),
+        Err(e) => format!("Error: {}", e),
     }
 }
 
-async fn execute_tool_async(
-    client: &Client,
-    config: &Config,
-    todo_manager: &TodoManager,
-    skill_loader: &SkillLoader,
-    name: &str,
-    input: &serde_json::Value,
+fn run_git_log(workdir: &Path, path: &str, max: usize, redactor: &Redactor) -> String {
+    run_git(
+        workdir,
+        path,
+        &[
+            "log".to_string(),
+            format!("--max-count={}", max.max(1)),
+            "--date=short".to_string(),
+            "--format=%h %ad %an: %s".to_string(),
+            "--".to_string(),
+            path.to_string(),
+        ],
+        redactor,
+    )
+}
+
+fn run_git_blame(
+    workdir: &Path,
+    path: &str,
+    start: Option<u64>,
+    end: Option<u64>,
+    redactor: &Redactor,
 ) -> String {
-    if name == "Task" {
-        let description = input
-            .get("description")
-            .and_then(|v| v.as_str())
-            .unwrap_or("subtask");
-        let prompt = input.get("prompt").and_then(|v| v.as_str()).unwrap_or("");
-        let agent_type = input
-            .get("agent_type")
-            .and_then(|v| v.as_str())
-            .unwrap_or("explore");
+    let mut args = vec!["blame".to_string(), "--date=short".to_string()];
+    match (start, end) {
+        (None, None) => {}
+        (start, end) => args.push(format!(
+            "-L{},{}",
+            start.unwrap_or(1).max(1),
+            end.map(|e| e.to_string()).unwrap_or_default()
+        )),
+    }
+    args.push("--".to_string());
+    args.push(path.to_string());
+    run_git(workdir, path, &args, redactor)
+}
 
-        run_task(
-            client,
-            config,
-            todo_manager,
-            skill_loader,
-            description,
-            prompt,
-            agent_type,
-        )
-        .await
-    } else if name == "web_search" {
-        let query = input.get("query").and_then(|v| v.as_str()).unwrap_or("");
-        let max_results = input
-            .get("max_results")
-            .and_then(|v| v.as_i64())
-            .unwrap_or(5) as usize;
+/// Upper bound for one `rust_check` run (cold `cargo check` included)
+const RUST_CHECK_TIMEOUT: Duration = Duration::from_secs(120);
 
-        match web_search(query, max_results).await {
-            Ok(results) => {
-                if results.is_empty() {
-                    format!("No search results found for: {}", query)
-                } else {
-                    let formatted: String = results
-                        .iter()
-                        .enumerate()
-                        .map(|(i, r)| {
-                            format!(
-                                "{}. **{}**\n   URL: {}\n   {}\n",
-                                i + 1,
-                                r.title,
-                                r.url,
-                                r.snippet
-                            )
-                        })
-                        .collect::<Vec<_>>()
-                        .join("\n");
+/// Compile-check a Rust snippet in a throwaway cargo project.
+///
+/// A snippet without `fn main` is wrapped in one, so bare statements and
+/// expressions work too. The project lives under the system temp dir and is
+/// removed afterwards, whatever the outcome.
+fn run_rust_check(code: &str, timeout: Duration) -> String {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let dir = env::temp_dir().join(format!(
+        "mini_code_rust_check_{}_{}",
+        std::process::id(),
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let result = rust_check_in(&dir, code, timeout);
+    fs::remove_dir_all(&dir).ok();
+    result.unwrap_or_else(|e| format!("Error: {}", e))
+}
 
-                    format!("## Search Results for: {}\n\n{}", query, formatted)
-                }
-            }
-            Err(e) => format!("Error performing web search: {}", e),
-        }
+fn rust_check_in(dir: &Path, code: &str, timeout: Duration) -> Result<String> {
+    let main_rs = if code.contains("fn main") {
+        code.to_string()
     } else {
-        execute_tool(config, todo_manager, skill_loader, name, input)
-    }
+        format!("fn main() {{\n{}\n}}\n", code)
+    };
+
+    fs::create_dir_all(dir.join("src"))?;
+    fs::write(
+        dir.join("Cargo.toml"),
+        "[package]\nname = \"snippet\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[workspace]\n",
+    )?;
+    fs::write(dir.join("src/main.rs"), main_rs)?;
+
+    // Diagnostics go to a file so a chatty build can't fill a pipe and stall
+    let log_path = dir.join("check.log");
+    let log = fs::File::create(&log_path)?;
+    let mut child = Command::new("cargo")
+        .args(["check", "--quiet", "--message-format=short"])
+        .current_dir(dir)
+        .env("CARGO_TARGET_DIR", dir.join("target"))
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .spawn()
+        .context("Failed to run cargo")?;
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            child.kill().ok();
+            child.wait().ok();
+            anyhow::bail!("cargo check timed out after {}s", timeout.as_secs());
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let diagnostics = fs::read_to_string(&log_path)?;
+    let diagnostics = diagnostics.trim();
+    Ok(match (status.success(), diagnostics.is_empty()) {
+        (true, true) => "Compiles cleanly".to_string(),
+        (true, false) => format!("Compiles with warnings:\n{}", diagnostics),
+        (false, _) => format!("Does not compile:\n{}", diagnostics),
+    })
 }
 
-// =============================================================================
-// Token Management (from v3)
-// =============================================================================
+fn run_todo(todo_manager: &TodoManager, items: Vec<TodoItem>) -> String {
+    match todo_manager.update(items) {
+        Ok(rendered) => rendered,
+        Err(e) => format!("Error: {}", e),
+    }
+}
 
-fn estimate_context_tokens(messages: &[Message], system: &str) -> usize {
-    let messages_tokens: usize = messages
-        .iter()
-        .map(|msg| {
-            msg.content
+/// Load a skill and inject it into the conversation - NEW in v4
+///
+/// This is the key mechanism:
+/// 1. Get skill content (SKILL.md body + resource hints)
+/// 2. Return it wrapped in <skill-loaded> tags
+/// 3. Model receives this as tool_result (user message)
+/// 4. Model now "knows" how to do the task
+///
+/// Why tool_result instead of system prompt?
+/// - System prompt changes invalidate cache (20-50x cost increase)
+/// - Tool results append to end (prefix unchanged, cache hit)
+///
+/// This is how production systems stay cost-efficient.
+fn run_skill(skill_loader: &SkillLoader, stats: &SessionStats, skill_name: &str) -> String {
+    match skill_loader.get_skill_content(skill_name) {
+        Some(content) => {
+            let output = format!(
+                r#"<skill-loaded name="{}">
+{}
+</skill-loaded>
+
+Follow the instructions in the skill above to complete the user's task."#,
+                skill_name, content
+            );
+            stats.record_skill_tokens(skill_name, estimate_tokens(&output));
+            output
+        }
+        None => {
+            let available = skill_loader.list_skills().join(", ");
+            let available = if available.is_empty() {
+                "none".to_string()
+            } else {
+                available
+            };
+            format!(
+                "Error: Unknown skill '{}'. Available: {}",
+                skill_name, available
+            )
+        }
+    }
+}
+
+fn run_skill_info(skill_loader: &SkillLoader, skill_name: &str) -> String {
+    let (Some(skill), Some(resources)) = (
+        skill_loader.skills.get(skill_name),
+        skill_loader.resource_manifest(skill_name),
+    ) else {
+        return format!("Error: Unknown skill '{}'", skill_name);
+    };
+
+    let mut output = format!("Skill: {}\n{}\n", skill.name, skill.description);
+    if resources.is_empty() {
+        output.push_str("\nNo bundled resources.");
+    } else {
+        output.push_str(&format!("\nResources in {}:", skill.dir.display()));
+        for r in resources {
+            output.push_str(&format!("\n- {}", r));
+        }
+    }
+    output
+}
+
+/// Most excerpts one skill_reference call returns
+const MAX_REFERENCE_MATCHES: usize = 20;
+
+fn run_skill_reference(skill_loader: &SkillLoader, skill_name: &str, query: &str) -> String {
+    if query.trim().is_empty() {
+        return "Error: Missing 'query' parameter".to_string();
+    }
+    let Some(hits) = skill_loader.search_references(skill_name, query, MAX_REFERENCE_MATCHES)
+    else {
+        return format!("Error: Unknown skill '{}'", skill_name);
+    };
+    if hits.is_empty() {
+        return format!(
+            "No matches for '{}' in {}'s references/. Use skill_info to list its files.",
+            query, skill_name
+        );
+    }
+
+    let mut output = format!(
+        "{} match(es) for '{}' in {}:",
+        hits.len(),
+        query,
+        skill_name
+    );
+    for (path, line, excerpt) in &hits {
+        output.push_str(&format!("\n\n{}:{}\n{}", path, line, excerpt));
+    }
+    if hits.len() >= MAX_REFERENCE_MATCHES {
+        output.push_str("\n\n[more matches omitted; refine the query]");
+    }
+    output
+}
+
+fn run_suggest_skill(skill_loader: &SkillLoader, task: &str) -> String {
+    let matches = skill_loader.search(task, 3);
+    if matches.is_empty() {
+        return format!(
+            "No skills match '{}'. Proceed without a skill, or check the Skill tool's list.",
+            task
+        );
+    }
+
+    let list = matches
+        .iter()
+        .enumerate()
+        .map(|(i, skill)| format!("{}. {}: {}", i + 1, skill.name, skill.description))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    format!(
+        "Skills matching '{}':\n{}\n\nPick the best fit and load it with the Skill tool.",
+        task, list
+    )
+}
+
+/// Let the user pick among suggested skills; loads the choice directly.
+///
+/// Returns None (model decides) if nothing matched or the user skipped.
+fn prompt_skill_choice(
+    skill_loader: &SkillLoader,
+    stats: &SessionStats,
+    task: &str,
+) -> Option<String> {
+    let matches = skill_loader.search(task, 3);
+    if matches.is_empty() {
+        return None;
+    }
+
+    println!("{}", "Which skill fits this task?".bright_green());
+    for (i, skill) in matches.iter().enumerate() {
+        println!(
+            "  {} {} {}",
+            format!("{}.", i + 1).bright_black(),
+            skill.name.bright_green(),
+            skill.description.bright_black()
+        );
+    }
+    print!(
+        "Choose 1-{} (Enter to let the model decide): ",
+        matches.len()
+    );
+    io::stdout().flush().ok()?;
+
+    let mut line = String::new();
+    io::stdin().read_line(&mut line).ok()?;
+    let choice = line.trim().parse::<usize>().ok()?;
+    let skill = matches.get(choice.checked_sub(1)?)?;
+
+    Some(run_skill(skill_loader, stats, &skill.name))
+}
+
+// =============================================================================
+// Subagent Progress Tracking (from v3)
+// =============================================================================
+
+struct SubagentProgress {
+    tool_count: usize,
+    current_tool: Option<String>,
+    start_time: Instant,
+    /// Latest message from the subagent's `report_progress` tool
+    last_report: Option<String>,
+    /// Every tool call so far, for MINI_CODE_SUBAGENT_TIMELINE
+    timeline: Vec<TimelineEntry>,
+    /// Shown after the summary line so they don't break the progress display
+    warnings: Vec<String>,
+}
+
+/// One subagent tool call: name, shortened input and how long it took
+struct TimelineEntry {
+    tool: String,
+    input: String,
+    duration: Duration,
+}
+
+/// Longest `report_progress` message shown on the progress line
+const MAX_PROGRESS_REPORT_CHARS: usize = 120;
+
+/// Longest tool input shown in a timeline entry
+const MAX_TIMELINE_INPUT_BYTES: usize = 80;
+
+impl SubagentProgress {
+    fn new() -> Self {
+        Self {
+            tool_count: 0,
+            current_tool: None,
+            start_time: Instant::now(),
+            last_report: None,
+            timeline: Vec::new(),
+            warnings: Vec::new(),
+        }
+    }
+
+    fn record_call(&mut self, tool: &str, input: &serde_json::Value, duration: Duration) {
+        let input = input.to_string();
+        let input = if input.len() > MAX_TIMELINE_INPUT_BYTES {
+            format!("{}...", safe_truncate(&input, MAX_TIMELINE_INPUT_BYTES))
+        } else {
+            input
+        };
+        self.timeline.push(TimelineEntry {
+            tool: tool.to_string(),
+            input,
+            duration,
+        });
+    }
+
+    /// Append the tool timeline below `result` when `verbose` is set
+    fn with_timeline(&self, result: String, verbose: bool) -> String {
+        if !verbose || self.timeline.is_empty() {
+            return result;
+        }
+        let lines: Vec<String> = self
+            .timeline
+            .iter()
+            .enumerate()
+            .map(|(i, e)| {
+                format!(
+                    "  {}. {} {} ({:.1}s)",
+                    i + 1,
+                    e.tool,
+                    e.input,
+                    e.duration.as_secs_f64()
+                )
+            })
+            .collect();
+        format!("{}\n\nTool timeline:\n{}", result, lines.join("\n"))
+    }
+
+    /// Record a `report_progress` message (first line, shortened for display)
+    fn report(&mut self, message: &str) {
+        let line = message.lines().next().unwrap_or("").trim();
+        self.last_report = (!line.is_empty()).then(|| {
+            if line.chars().count() > MAX_PROGRESS_REPORT_CHARS {
+                let short: String = line.chars().take(MAX_PROGRESS_REPORT_CHARS).collect();
+                format!("{}...", short)
+            } else {
+                line.to_string()
+            }
+        });
+    }
+
+    /// "N tools, T s" plus the latest report, for the parent's progress line
+    fn status(&self) -> String {
+        let mut status = format!(
+            "{} tools, {:.1}s",
+            self.tool_count,
+            self.start_time.elapsed().as_secs_f64()
+        );
+        if let Some(report) = &self.last_report {
+            status.push_str(&format!(" | {}", report));
+        }
+        status
+    }
+
+    /// Time left before the subagent's wall-clock budget runs out.
+    ///
+    /// Returns the `[ERROR]` result to report once the budget is spent.
+    fn remaining(&self, budget: Duration) -> std::result::Result<Duration, String> {
+        let elapsed = self.start_time.elapsed();
+        budget
+            .checked_sub(elapsed)
+            .filter(|d| !d.is_zero())
+            .ok_or_else(|| {
+                format!(
+                    "[ERROR] Subagent exceeded time budget ({}s, {} tools)",
+                    budget.as_secs(),
+                    self.tool_count
+                )
+            })
+    }
+}
+
+fn run_report_progress(progress: &Mutex<SubagentProgress>, input: &serde_json::Value) -> String {
+    match input.get("message").and_then(|v| v.as_str()) {
+        Some(message) if !message.trim().is_empty() => {
+            progress.lock().unwrap().report(message);
+            "Progress reported.".to_string()
+        }
+        _ => "Error: Missing 'message' parameter".to_string(),
+    }
+}
+
+fn spawn_subagent_progress_updater(
+    agent_type: String,
+    description: String,
+    progress: Arc<Mutex<SubagentProgress>>,
+    stop_signal: Arc<AtomicBool>,
+) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        println!();
+        io::stdout().flush().ok();
+
+        while !stop_signal.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(1000));
+
+            if stop_signal.load(Ordering::Relaxed) {
+                break;
+            }
+
+            let progress_guard = progress.lock().unwrap();
+            let status = progress_guard.status();
+            let current_tool = progress_guard.current_tool.clone();
+            drop(progress_guard);
+
+            println!(
+                "\x1B[1A\x1B[K  {} {} ... {}",
+                format!("[{}]", agent_type).bright_magenta(),
+                description,
+                status
+            );
+
+            if let Some(tool_info) = current_tool {
+                println!(
+                    "\x1B[K    {} {}",
+                    "→".bright_blue(),
+                    tool_info.bright_black()
+                );
+            } else {
+                println!("\x1B[K");
+            }
+
+            print!("\x1B[1A");
+            io::stdout().flush().ok();
+        }
+    })
+}
+
+// =============================================================================
+// Subagent Result Contract
+// =============================================================================
+
+/// Normalized subagent output. Subagents are asked to end with a JSON block
+/// of this shape; plain-text replies become a summary with no artifacts.
+#[derive(Debug, Default, PartialEq, Deserialize)]
+struct SubagentResult {
+    #[serde(default)]
+    summary: String,
+    #[serde(default)]
+    files: Vec<String>,
+    #[serde(default)]
+    findings: Vec<String>,
+}
+
+impl SubagentResult {
+    fn parse(text: &str) -> Self {
+        let fenced = Regex::new(r"(?s)```json\s*(\{.*?\})\s*```").unwrap();
+
+        let (json_str, prose) = match fenced.captures(text) {
+            Some(caps) => {
+                let whole = caps.get(0).unwrap();
+                let prose = format!("{}{}", &text[..whole.start()], &text[whole.end()..]);
+                (caps[1].to_string(), prose)
+            }
+            None => (text.trim().to_string(), String::new()),
+        };
+
+        match serde_json::from_str::<SubagentResult>(&json_str) {
+            Ok(mut result) => {
+                if result.summary.trim().is_empty() {
+                    result.summary = prose.trim().to_string();
+                }
+                result
+            }
+            Err(_) => SubagentResult {
+                summary: text.trim().to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut output = self.summary.clone();
+
+        if !self.files.is_empty() {
+            output.push_str("\n\nFiles:");
+            for file in &self.files {
+                output.push_str(&format!("\n- {}", file));
+            }
+        }
+
+        if !self.findings.is_empty() {
+            output.push_str("\n\nFindings:");
+            for finding in &self.findings {
+                output.push_str(&format!("\n- {}", finding));
+            }
+        }
+
+        output
+    }
+}
+
+// =============================================================================
+// Subagent Execution (from v3, adapted for v4)
+// =============================================================================
+
+/// System prompt for a subagent, prefixed with the parent's project context
+fn subagent_system_prompt(
+    workdir: &Path,
+    project_context: Option<&str>,
+    agent_type: &str,
+    agent_prompt: &str,
+) -> String {
+    let prefix = project_context.map_or(String::new(), |context| {
+        format!(
+            "You were spawned by a parent agent. Follow the project's conventions:\n{}\n\n",
+            context
+        )
+    });
+
+    format!(
+        r#"{}You are a {} subagent at {}.
+
+{}
+
+Complete the task and return a clear, concise summary.
+
+End your final message with a JSON block so the parent agent can use your results:
+```json
+{{"summary": "what you did or found", "files": ["paths created or changed"], "findings": ["key facts"]}}
+```"#,
+        prefix,
+        agent_type,
+        workdir.display(),
+        agent_prompt
+    )
+}
+
+// =============================================================================
+// Subagent Sessions
+// =============================================================================
+
+/// Most finished subagent conversations kept for follow-ups; oldest go first
+const MAX_SUBAGENT_SESSIONS: usize = 8;
+
+/// A subagent conversation that can be resumed with the Task tool's `session_id`
+struct SubagentSession {
+    id: String,
+    agent_type: String,
+    messages: Vec<Message>,
+}
+
+/// Finished subagent conversations, most recent last
+#[derive(Default)]
+struct SubagentSessions {
+    sessions: Mutex<VecDeque<SubagentSession>>,
+    next_id: AtomicUsize,
+}
+
+impl SubagentSessions {
+    /// Resume `session_id` with a follow-up prompt, or start a new session.
+    ///
+    /// A resumed session keeps its original agent type and message history.
+    fn begin(
+        &self,
+        session_id: Option<&str>,
+        agent_type: &str,
+        prompt: &str,
+    ) -> std::result::Result<SubagentSession, String> {
+        let follow_up = Message {
+            role: Role::User,
+            content: vec![ContentBlock::text(prompt)],
+        };
+
+        match session_id {
+            Some(id) => {
+                let mut sessions = self.sessions.lock().unwrap();
+                let index = sessions.iter().position(|s| s.id == id).ok_or_else(|| {
+                    format!("Error: Unknown or expired subagent session '{}'", id)
+                })?;
+                let mut session = sessions.remove(index).unwrap();
+                session.messages.push(follow_up);
+                Ok(session)
+            }
+            None => Ok(SubagentSession {
+                id: format!("sub-{}", self.next_id.fetch_add(1, Ordering::Relaxed) + 1),
+                agent_type: agent_type.to_string(),
+                messages: vec![follow_up],
+            }),
+        }
+    }
+
+    /// Keep a finished session, evicting the oldest beyond the cap
+    fn store(&self, session: SubagentSession) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.push_back(session);
+        while sessions.len() > MAX_SUBAGENT_SESSIONS {
+            sessions.pop_front();
+        }
+    }
+}
+
+/// Subagent conversations for the whole process, shared by every Task call
+static SUBAGENT_SESSIONS: std::sync::LazyLock<SubagentSessions> =
+    std::sync::LazyLock::new(SubagentSessions::default);
+
+/// Log id for a subagent: the parent's session id plus its own `sub-N`
+fn subagent_log_id(parent: &str, subagent: &str) -> String {
+    format!("{}/{}", parent, subagent)
+}
+
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(
+    name = "run_task",
+    skip_all,
+    fields(
+        agent_type = %agent_type,
+        description = %description,
+        session_id = tracing::field::Empty
+    )
+)]
+async fn run_task(
+    client: &Client,
+    config: &Config,
+    todo_manager: &TodoManager,
+    skill_loader: &SkillLoader,
+    stats: &SessionStats,
+    processes: &ProcessRegistry,
+    cancel: &CancellationToken,
+    description: &str,
+    prompt: &str,
+    agent_type: &str,
+    session_id: Option<&str>,
+) -> String {
+    let SubagentSession {
+        id: session_id,
+        agent_type,
+        messages: mut sub_messages,
+    } = match SUBAGENT_SESSIONS.begin(session_id, agent_type, prompt) {
+        Ok(session) => session,
+        Err(e) => return e,
+    };
+    let agent_type = agent_type.as_str();
+    tracing::Span::current().record(
+        "session_id",
+        subagent_log_id(&config.session_id, &session_id).as_str(),
+    );
+
+    let agent_types = get_agent_types();
+    let agent_config = match agent_types.get(agent_type) {
+        Some(cfg) => cfg,
+        None => return format!("Error: Unknown agent type '{}'", agent_type),
+    };
+
+    stats.record_subagent();
+
+    let sub_system = subagent_system_prompt(
+        &config.workdir,
+        config.project_context.as_deref(),
+        agent_type,
+        &agent_config.prompt,
+    );
+
+    // Get tools including Skill tool for subagent
+    let sub_tools = config.tool_set(get_tools_for_subagent(agent_type, skill_loader));
+
+    let progress = Arc::new(Mutex::new(SubagentProgress::new()));
+    let progress_clone = progress.clone();
+    let stop_signal = Arc::new(AtomicBool::new(false));
+    let stop_signal_clone = stop_signal.clone();
+
+    println!(
+        "  {} {}",
+        format!("[{}]", agent_type).bright_magenta(),
+        description
+    );
+
+    let updater = spawn_subagent_progress_updater(
+        agent_type.to_string(),
+        description.to_string(),
+        progress_clone,
+        stop_signal_clone,
+    );
+
+    let mut consecutive_truncations = 0;
+    let mut patches = PatchSet::default();
+
+    let budget = Duration::from_secs(config.subagent_timeout_secs);
+
+    let result = loop {
+        if cancel.is_cancelled() {
+            break CANCELLED_TOOL_CALL.to_string();
+        }
+        let remaining = match progress.lock().unwrap().remaining(budget) {
+            Ok(remaining) => remaining,
+            Err(timeout) => break timeout,
+        };
+
+        let request = apply_sampling(
+            MessagesRequestBuilder::new(&config.model, sub_messages.clone(), 8000)
+                .system(SystemPrompt::Text(sub_system.clone()))
+                .tools(sub_tools.clone()),
+            config.temperature,
+            config.top_p,
+        )
+        .build();
+
+        let request = match request {
+            Ok(r) => r,
+            Err(e) => break format!("Error building request: {}", e),
+        };
+
+        let response =
+            match tokio::time::timeout(remaining, send_request(client, config, request)).await {
+                Ok(Ok(r)) => r,
+                Ok(Err(e)) => break format!("Error calling API: {}", e),
+                Err(_) => {
+                    let progress_guard = progress.lock().unwrap();
+                    break format!(
+                        "[ERROR] Subagent exceeded time budget ({}s, {} tools)",
+                        budget.as_secs(),
+                        progress_guard.tool_count
+                    );
+                }
+            };
+        stats.record_usage(
+            u64::from(response.usage.input_tokens),
+            u64::from(response.usage.output_tokens),
+        );
+
+        match response.stop_reason {
+            Some(StopReason::MaxTokens) => {
+                consecutive_truncations += 1;
+
+                if consecutive_truncations >= 2 {
+                    let progress_guard = progress.lock().unwrap();
+                    let elapsed = progress_guard.start_time.elapsed();
+                    let tool_count = progress_guard.tool_count;
+                    drop(progress_guard);
+
+                    break format!(
+                        "[ERROR] Subagent output truncated {} times ({} tools, {:.1}s). Task too complex.",
+                        consecutive_truncations,
+                        tool_count,
+                        elapsed.as_secs_f64()
+                    );
+                }
+
+                sub_messages.push(Message {
+                    role: Role::Assistant,
+                    content: response.content,
+                });
+
+                sub_messages.push(Message {
+                    role: Role::User,
+                    content: vec![ContentBlock::text(
+                        "[SYSTEM: Response truncated. Provide a brief summary only (max 200 words).]"
+                    )],
+                });
+
+                continue;
+            }
+
+            Some(StopReason::ToolUse) => {
+                consecutive_truncations = 0;
+
+                let mut results = Vec::new();
+                let mut edited = Vec::new();
+                for block in &response.content {
+                    if let ContentBlock::ToolUse { id, name, input } = block {
+                        // Progress reports update the parent's status line only
+                        if name == "report_progress" {
+                            let output = run_report_progress(&progress, input);
+                            results.push(tool_result(id.clone(), output, false));
+                            continue;
+                        }
+                        // Proposals stay in memory until the subagent finishes
+                        if name == "propose_patch" && agent_type == "reviewer" {
+                            let arg = |key: &str| input.get(key).and_then(|v| v.as_str());
+                            let output = match (arg("path"), arg("old_text"), arg("new_text")) {
+                                (Some(path), Some(old_text), Some(new_text)) => {
+                                    patches.propose(&config.workdir, path, old_text, new_text)
+                                }
+                                _ => "Error: Missing 'path', 'old_text' or 'new_text' parameter"
+                                    .to_string(),
+                            };
+                            results.push(tool_result(id.clone(), output, false));
+                            continue;
+                        }
+
+                        {
+                            let mut progress_guard = progress.lock().unwrap();
+                            progress_guard.tool_count += 1;
+
+                            let tool_display = match name.as_str() {
+                                "bash" => {
+                                    if let Some(cmd) = input.get("command").and_then(|v| v.as_str())
+                                    {
+                                        let short_cmd = if cmd.len() > 60 {
+                                            format!("{}...", &cmd[..60])
+                                        } else {
+                                            cmd.to_string()
+                                        };
+                                        format!("bash: {}", short_cmd)
+                                    } else {
+                                        "bash".to_string()
+                                    }
+                                }
+                                "read_file" => {
+                                    if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
+                                        format!("read: {}", path)
+                                    } else {
+                                        "read_file".to_string()
+                                    }
+                                }
+                                "write_file" => {
+                                    if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
+                                        format!("write: {}", path)
+                                    } else {
+                                        "write_file".to_string()
+                                    }
+                                }
+                                "edit_file" => {
+                                    if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
+                                        format!("edit: {}", path)
+                                    } else {
+                                        "edit_file".to_string()
+                                    }
+                                }
+                                "web_search" => {
+                                    if let Some(query) = input.get("query").and_then(|v| v.as_str())
+                                    {
+                                        let short_query = if query.len() > 40 {
+                                            format!("{}...", &query[..40])
+                                        } else {
+                                            query.to_string()
+                                        };
+                                        format!("search: {}", short_query)
+                                    } else {
+                                        "web_search".to_string()
+                                    }
+                                }
+                                "Skill" => {
+                                    if let Some(skill) = input.get("skill").and_then(|v| v.as_str())
+                                    {
+                                        format!("skill: {}", skill)
+                                    } else {
+                                        "Skill".to_string()
+                                    }
+                                }
+                                other => other.to_string(),
+                            };
+
+                            progress_guard.current_tool = Some(tool_display);
+                        }
+
+                        stats.record_tool_call(name);
+                        let started = Instant::now();
+                        let ToolOutput {
+                            text: output,
+                            failed,
+                        } = dispatch_subagent_tool(
+                            config,
+                            &sub_tools,
+                            todo_manager,
+                            skill_loader,
+                            stats,
+                            processes,
+                            cancel,
+                            name,
+                            input,
+                        );
+                        let output = config.redactor.redact(&output);
+
+                        edited.extend(edited_paths(name, input, &output));
+                        let (output, warning) = guard_tool_output(config, name, output);
+                        results.push(tool_result(id.clone(), output, failed));
+
+                        {
+                            let mut progress_guard = progress.lock().unwrap();
+                            progress_guard.warnings.extend(warning);
+                            progress_guard.current_tool = None;
+                            progress_guard.record_call(name, input, started.elapsed());
+                        }
+                    }
+                }
+
+                if let Some(command) = &config.format_command {
+                    if !edited.is_empty() && !cancel.is_cancelled() {
+                        let report = run_format_command(
+                            &config.workdir,
+                            command,
+                            &edited,
+                            cancel,
+                            &config.redactor,
+                        );
+                        results.push(ContentBlock::text(report));
+                    }
+                }
+
+                enforce_results_budget(&mut results, config.max_turn_results);
+                reconcile_tool_results(&response.content, &mut results);
+                sub_messages.push(Message {
+                    role: Role::Assistant,
+                    content: response.content,
+                });
+                sub_messages.push(Message {
+                    role: Role::User,
+                    content: results,
+                });
+            }
+
+            Some(StopReason::EndTurn) | Some(StopReason::StopSequence) | None => {
+                // Normal end - extract text and return
+                let text_result = response.content.iter().find_map(|block| match block {
+                    ContentBlock::Text { text } if !text.trim().is_empty() => Some(text.clone()),
+                    _ => None,
+                });
+                // Keep the final reply so a follow-up sees the whole exchange
+                sub_messages.push(Message {
+                    role: Role::Assistant,
+                    content: vec![ContentBlock::text(
+                        text_result.as_deref().unwrap_or(NO_RESPONSE_TEXT),
+                    )],
+                });
+                break match text_result {
+                    Some(text) => SubagentResult::parse(&text).render(),
+                    None => empty_subagent_result(progress.lock().unwrap().tool_count),
+                };
+            }
+        }
+    };
+
+    stop_signal.store(true, Ordering::Relaxed);
+    updater.join().ok();
+
+    let mut progress_guard = progress.lock().unwrap();
+    let elapsed = progress_guard.start_time.elapsed();
+    let tool_count = progress_guard.tool_count;
+    let result = progress_guard.with_timeline(
+        patches.append_to(result, &config.redactor),
+        config.subagent_timeline,
+    );
+    let warnings = std::mem::take(&mut progress_guard.warnings);
+    drop(progress_guard);
+
+    print!("\x1B[1A\x1B[K\x1B[1A\x1B[K");
+
+    if result.starts_with("[ERROR]") {
+        println!(
+            "  {} {} - {} ({} tools, {:.1}s)",
+            format!("[{}]", agent_type).bright_red(),
+            description,
+            "ERROR".bright_red(),
+            tool_count,
+            elapsed.as_secs_f64()
+        );
+    } else {
+        println!(
+            "  {} {} - {} ({} tools, {:.1}s)",
+            format!("[{}]", agent_type).bright_magenta(),
+            description,
+            "done".bright_green(),
+            tool_count,
+            elapsed.as_secs_f64()
+        );
+    }
+    for warning in warnings {
+        println!("    {} {}", "Warning:".bright_yellow(), warning);
+    }
+
+    // Only a conversation that ended on the subagent's reply can be resumed
+    if sub_messages
+        .last()
+        .is_some_and(|m| m.role == Role::Assistant)
+    {
+        SUBAGENT_SESSIONS.store(SubagentSession {
+            id: session_id.clone(),
+            agent_type: agent_type.to_string(),
+            messages: sub_messages,
+        });
+        return format!(
+            "{}\n\n(session_id: {} - pass it to Task for a follow-up)",
+            result, session_id
+        );
+    }
+
+    result
+}
+
+/// Run one subagent tool call, unless it names a tool outside `sub_tools`,
+/// fails its schema, saves a diff from a read-only agent or is refused by
+/// the approval policy
+#[allow(clippy::too_many_arguments)]
+fn dispatch_subagent_tool(
+    config: &Config,
+    sub_tools: &[Tool],
+    todo_manager: &TodoManager,
+    skill_loader: &SkillLoader,
+    stats: &SessionStats,
+    processes: &ProcessRegistry,
+    cancel: &CancellationToken,
+    name: &str,
+    input: &serde_json::Value,
+) -> ToolOutput {
+    let refusal = check_tool_available(sub_tools, name)
+        .or_else(|| validate_tool_input(sub_tools, name, input))
+        .or_else(|| check_diff_save(sub_tools, name, input))
+        .or_else(|| check_approval(config, name, input));
+    match refusal {
+        Some(refusal) => refusal.into(),
+        None => tracing::info_span!("tool", name = %name).in_scope(|| {
+            execute_tool(
+                config,
+                todo_manager,
+                skill_loader,
+                stats,
+                processes,
+                cancel,
+                name,
+                input,
+            )
+        }),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn execute_tool(
+    config: &Config,
+    todo_manager: &TodoManager,
+    skill_loader: &SkillLoader,
+    stats: &SessionStats,
+    processes: &ProcessRegistry,
+    cancel: &CancellationToken,
+    name: &str,
+    input: &serde_json::Value,
+) -> ToolOutput {
+    let text = match name {
+        "bash" => {
+            if let Some(command) = input.get("command").and_then(|v| v.as_str()) {
+                let retry = input.get("retry").and_then(|v| v.as_u64()).unwrap_or(0);
+                let (success, text) =
+                    run_bash_cancellable(&config.workdir, command, retry, cancel, &|text| {
+                        config.redactor.redact(text)
+                    });
+                return ToolOutput {
+                    text,
+                    failed: !success,
+                };
+            } else {
+                "Error: Missing 'command' parameter".to_string()
+            }
+        }
+        "read_file" => {
+            if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
+                let limit = input.get("limit").and_then(|v| v.as_i64());
+                let line_numbers = input
+                    .get("line_numbers")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false);
+                let member = input.get("member").and_then(|v| v.as_str());
+                let default = config.default_read_lines.filter(|_| limit.is_none());
+                let limit = limit.or(default.map(|n| n as i64));
+                let mut output = run_read_with(
+                    &config.workdir,
+                    path,
+                    limit,
+                    line_numbers,
+                    member,
+                    &|text| config.redactor.redact(text),
+                );
+                if let Some(lines) = default {
+                    if output.ends_with(" more lines)") {
+                        output.push_str(&format!(
+                            "\n(stopped at the default {} lines; pass a larger limit to read more)",
+                            lines
+                        ));
+                    }
+                }
+                stats.record_file(FileAccess::Read, &config.workdir, path, &output);
+                output
+            } else {
+                "Error: Missing 'path' parameter".to_string()
+            }
+        }
+        "write_file" => {
+            if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
+                if let Err(refusal) = check_editable(path, &config.editable_extensions) {
+                    return refusal.into();
+                }
+                if let Some(content) = input.get("content").and_then(|v| v.as_str()) {
+                    let line_ending = input.get("line_ending").and_then(|v| v.as_str());
+                    let ensure_trailing_newline = input
+                        .get("ensure_trailing_newline")
+                        .and_then(|v| v.as_bool())
+                        .unwrap_or(false);
+                    let output = run_write_locked(
+                        &config.workdir,
+                        path,
+                        content,
+                        line_ending,
+                        ensure_trailing_newline,
+                    );
+                    stats.record_file(FileAccess::Write, &config.workdir, path, &output);
+                    output
+                } else {
+                    "Error: Missing 'content' parameter".to_string()
+                }
+            } else {
+                "Error: Missing 'path' parameter".to_string()
+            }
+        }
+        "edit_file" => {
+            if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
+                if let Err(refusal) = check_editable(path, &config.editable_extensions) {
+                    return refusal.into();
+                }
+                if let Some(old_text) = input.get("old_text").and_then(|v| v.as_str()) {
+                    if let Some(new_text) = input.get("new_text").and_then(|v| v.as_str()) {
+                        let output = run_edit_locked(&config.workdir, path, old_text, new_text);
+                        stats.record_file(FileAccess::Edit, &config.workdir, path, &output);
+                        output
+                    } else {
+                        "Error: Missing 'new_text' parameter".to_string()
+                    }
+                } else {
+                    "Error: Missing 'old_text' parameter".to_string()
+                }
+            } else {
+                "Error: Missing 'path' parameter".to_string()
+            }
+        }
+        "diff" => {
+            if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
+                let other_path = input.get("other_path").and_then(|v| v.as_str());
+                let content = input.get("content").and_then(|v| v.as_str());
+                let save_as = input.get("save_as").and_then(|v| v.as_str());
+                if let Some(save_as) = save_as {
+                    if let Err(refusal) = check_editable(save_as, &config.editable_extensions) {
+                        return refusal.into();
+                    }
+                }
+                run_diff(
+                    &config.workdir,
+                    path,
+                    other_path,
+                    content,
+                    save_as,
+                    &config.redactor,
+                )
+            } else {
+                "Error: Missing 'path' parameter".to_string()
+            }
+        }
+        "count_tokens" => {
+            let text = input.get("text").and_then(|v| v.as_str());
+            let path = input.get("path").and_then(|v| v.as_str());
+            run_count_tokens(&config.workdir, text, path)
+        }
+        "bash_background" => {
+            if let Some(command) = input.get("command").and_then(|v| v.as_str()) {
+                match processes.spawn(&config.workdir, command) {
+                    Ok(id) => format!(
+                        "Started background process {} ({}). Use bash_logs to check output.",
+                        id, command
+                    ),
+                    Err(e) => format!("Error: {}", e),
+                }
+            } else {
+                "Error: Missing 'command' parameter".to_string()
+            }
+        }
+        "bash_logs" | "bash_kill" => {
+            let Some(id) = input.get("id").and_then(|v| v.as_u64()) else {
+                return "Error: Missing 'id' parameter".to_string().into();
+            };
+            let result = if name == "bash_logs" {
+                let tail_bytes = input
+                    .get("tail_bytes")
+                    .and_then(|v| v.as_u64())
+                    .unwrap_or(4000)
+                    .min(50000);
+                processes.logs(id as usize, tail_bytes as usize)
+            } else {
+                processes.kill(id as usize)
+            };
+            result.unwrap_or_else(|e| format!("Error: {}", e))
+        }
+        "rust_check" => {
+            if let Some(code) = input.get("code").and_then(|v| v.as_str()) {
+                run_rust_check(code, RUST_CHECK_TIMEOUT)
+            } else {
+                "Error: Missing 'code' parameter".to_string()
+            }
+        }
+        "validate_file" => {
+            if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
+                let canonical = input
+                    .get("canonical")
+                    .and_then(|v| v.as_bool())
+                    .unwrap_or(false);
+                run_validate_file(&config.workdir, path, canonical, &config.redactor)
+            } else {
+                "Error: Missing 'path' parameter".to_string()
+            }
+        }
+        "scratch_write" => {
+            let path = input.get("path").and_then(|v| v.as_str());
+            let content = input.get("content").and_then(|v| v.as_str());
+            match (path, content) {
+                (Some(path), Some(content)) => config
+                    .scratch
+                    .write(path, content)
+                    .unwrap_or_else(|e| format!("Error: {}", e)),
+                _ => "Error: Missing 'path' or 'content' parameter".to_string(),
+            }
+        }
+        "scratch_read" => {
+            if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
+                config
+                    .scratch
+                    .read(path)
+                    .unwrap_or_else(|e| format!("Error: {}", e))
+            } else {
+                "Error: Missing 'path' parameter".to_string()
+            }
+        }
+        "hash_file" => {
+            if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
+                let algo = input.get("algo").and_then(|v| v.as_str());
+                run_hash_file(
+                    &config.workdir,
+                    path,
+                    algo,
+                    &config.ignore,
+                    &config.redactor,
+                )
+            } else {
+                "Error: Missing 'path' parameter".to_string()
+            }
+        }
+        "rust_rename" => {
+            let field = |key: &str| input.get(key).and_then(|v| v.as_str());
+            match (field("path"), field("from"), field("to")) {
+                (Some(path), Some(from), Some(to)) => {
+                    if let Err(refusal) = check_editable(path, &config.editable_extensions) {
+                        return refusal.into();
+                    }
+                    let output = run_rust_rename(&config.workdir, path, from, to);
+                    stats.record_file(FileAccess::Edit, &config.workdir, path, &output);
+                    output
+                }
+                _ => "Error: rust_rename needs 'path', 'from' and 'to'".to_string(),
+            }
+        }
+        "replace_in_files" => {
+            let field = |key: &str| input.get(key).and_then(|v| v.as_str());
+            match (field("pattern"), field("replacement")) {
+                (Some(pattern), Some(replacement)) => {
+                    let dry_run = input
+                        .get("dry_run")
+                        .and_then(|v| v.as_bool())
+                        .unwrap_or(false);
+                    let output = run_replace_in_files(
+                        &config.workdir,
+                        pattern,
+                        replacement,
+                        field("glob"),
+                        dry_run,
+                        &config.ignore,
+                        &config.editable_extensions,
+                    );
+                    for path in edited_paths(name, input, &output) {
+                        stats.record_file(FileAccess::Edit, &config.workdir, &path, &output);
+                    }
+                    output
+                }
+                _ => "Error: replace_in_files needs 'pattern' and 'replacement'".to_string(),
+            }
+        }
+        "list_tasks" => match project_tasks::discover(&config.workdir) {
+            Ok(tasks) => project_tasks::render(&tasks),
+            Err(e) => format!("Error: {}", e),
+        },
+        "run_project_task" => {
+            if let Some(name) = input.get("name").and_then(|v| v.as_str()) {
+                let source = input.get("source").and_then(|v| v.as_str());
+                run_project_task(&config.workdir, name, source, cancel, &config.redactor)
+            } else {
+                "Error: Missing 'name' parameter".to_string()
+            }
+        }
+        "tail_file" => {
+            if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
+                let lines = input.get("lines").and_then(|v| v.as_u64());
+                let follow_secs = input.get("follow_secs").and_then(|v| v.as_u64());
+                run_tail_file(
+                    &config.workdir,
+                    path,
+                    lines,
+                    follow_secs,
+                    cancel,
+                    &config.redactor,
+                )
+            } else {
+                "Error: Missing 'path' parameter".to_string()
+            }
+        }
+        "git_log" => {
+            if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
+                let max = input.get("max").and_then(|v| v.as_u64()).unwrap_or(10);
+                run_git_log(&config.workdir, path, max as usize, &config.redactor)
+            } else {
+                "Error: Missing 'path' parameter".to_string()
+            }
+        }
+        "git_blame" => {
+            if let Some(path) = input.get("path").and_then(|v| v.as_str()) {
+                let start = input.get("start_line").and_then(|v| v.as_u64());
+                let end = input.get("end_line").and_then(|v| v.as_u64());
+                run_git_blame(&config.workdir, path, start, end, &config.redactor)
+            } else {
+                "Error: Missing 'path' parameter".to_string()
+            }
+        }
+        "read_files" => {
+            // Subagents run tools synchronously, so files are read one by one
+            let Some((paths, limit)) = read_files_input(input) else {
+                return "Error: Missing 'paths' parameter".to_string().into();
+            };
+            let outputs: Vec<String> = paths
+                .iter()
+                .map(|path| {
+                    run_read_with(&config.workdir, path, limit, false, None, &|text| {
+                        config.redactor.redact(text)
+                    })
+                })
+                .collect();
+            for (path, output) in paths.iter().zip(&outputs) {
+                stats.record_file(FileAccess::Read, &config.workdir, path, output);
+            }
+            format_read_files(&paths, &outputs)
+        }
+        "web_search" if config.offline => OFFLINE_TOOL_CALL.to_string(),
+        "web_search" => {
+            // Note: web_search is async, so we return a placeholder
+            // It will be handled in execute_tool_async
+            "Error: web_search must be called via execute_tool_async".to_string()
+        }
+        "TodoWrite" => {
+            if let Some(items_value) = input.get("items") {
+                match serde_json::from_value::<Vec<TodoItem>>(items_value.clone()) {
+                    Ok(items) => run_todo(todo_manager, items),
+                    Err(e) => format!("Error parsing todo items: {}", e),
+                }
+            } else {
+                "Error: Missing 'items' parameter".to_string()
+            }
+        }
+        "Skill" => {
+            let skill_name = input.get("skill").and_then(|v| v.as_str()).unwrap_or("");
+            run_skill(skill_loader, stats, skill_name)
+        }
+        "suggest_skill" => {
+            if let Some(task) = input.get("task").and_then(|v| v.as_str()) {
+                run_suggest_skill(skill_loader, task)
+            } else {
+                "Error: Missing 'task' parameter".to_string()
+            }
+        }
+        "skill_reference" => {
+            let query = input.get("query").and_then(|v| v.as_str()).unwrap_or("");
+            if let Some(skill) = input.get("skill").and_then(|v| v.as_str()) {
+                run_skill_reference(skill_loader, skill, query)
+            } else {
+                "Error: Missing 'skill' parameter".to_string()
+            }
+        }
+        "skill_info" => {
+            if let Some(skill) = input.get("skill").and_then(|v| v.as_str()) {
+                run_skill_info(skill_loader, skill)
+            } else {
+                "Error: Missing 'skill' parameter".to_string()
+            }
+        }
+        _ => format!("Unknown tool: {}", name),
+    };
+    text.into()
+}
+
+/// Cancel `token` on Ctrl-C until the returned guard is dropped
+fn cancel_on_ctrl_c(token: CancellationToken) -> DropGuard {
+    let listener = token.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => listener.cancel(),
+            _ = listener.cancelled() => {}
+        }
+    });
+    token.drop_guard()
+}
+
+/// Assistant note that closes a cancelled turn, so roles keep alternating
+const CANCELLED_TURN: &str = "[Turn cancelled by the user]";
+
+fn end_cancelled_turn(out: &dyn Writer, messages: &mut Vec<Message>) {
+    out.line(&format!(
+        "{} {}",
+        "Cancelled:".bright_yellow(),
+        "turn stopped by Ctrl-C".bright_black()
+    ));
+    messages.push(Message {
+        role: Role::Assistant,
+        content: vec![ContentBlock::text(CANCELLED_TURN)],
+    });
+}
+
+/// Assistant note that closes a turn the context window can't hold
+const CONTEXT_TOO_LARGE_TURN: &str =
+    "[Turn stopped: the conversation no longer fits in the context window]";
+
+fn end_oversized_turn(out: &dyn Writer, messages: &mut Vec<Message>, estimated: usize) {
+    out.line(&format!(
+        "{} Context too large: ~{} tokens against a {} token window. \
+         Run /clear to start over, or set MINI_CODE_MAX_HISTORY_MESSAGES \
+         (with MINI_CODE_COMPACT_HISTORY=true to keep a summary).",
+        "Error:".bright_red(),
+        estimated,
+        tokens::CONTEXT_WINDOW
+    ));
+    messages.push(Message {
+        role: Role::Assistant,
+        content: vec![ContentBlock::text(CONTEXT_TOO_LARGE_TURN)],
+    });
+}
+
+/// Assistant note that closes a turn the loop watchdog stopped
+const LOOP_ABORTED_TURN: &str = "[Turn stopped: the same tool call kept repeating]";
+
+/// Result for the calls of the message that tripped the watchdog
+const LOOP_ABORTED_TOOL_CALL: &str =
+    "Not run: the turn was stopped after repeated identical tool calls";
+
+/// Close a turn the watchdog aborted. `assistant_content` is the message that
+/// tripped it; its tool calls get error results so none is left unanswered.
+fn end_looping_turn(
+    out: &dyn Writer,
+    messages: &mut Vec<Message>,
+    assistant_content: Vec<ContentBlock>,
+    name: &str,
+    count: usize,
+    limit: usize,
+) {
+    out.line(&format!(
+        "{} '{}' was called {} times in a row with identical input after a warning.          Aborting this turn. You can raise MINI_CODE_MAX_REPEATED_TOOL_CALLS (current: {})",
+        "Error:".bright_red(),
+        name,
+        count,
+        limit
+    ));
+    let results = assistant_content
+        .iter()
+        .filter_map(|block| match block {
+            ContentBlock::ToolUse { id, .. } => Some(tool_result(
+                id.clone(),
+                LOOP_ABORTED_TOOL_CALL.to_string(),
+                true,
+            )),
+            _ => None,
+        })
+        .collect();
+    messages.push(Message {
+        role: Role::Assistant,
+        content: assistant_content,
+    });
+    messages.push(Message {
+        role: Role::User,
+        content: results,
+    });
+    messages.push(Message {
+        role: Role::Assistant,
+        content: vec![ContentBlock::text(LOOP_ABORTED_TURN)],
+    });
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn execute_tool_async(
+    client: &Client,
+    config: &Config,
+    todo_manager: &TodoManager,
+    skill_loader: &SkillLoader,
+    stats: &SessionStats,
+    processes: &ProcessRegistry,
+    cancel: &CancellationToken,
+    name: &str,
+    input: &serde_json::Value,
+) -> ToolOutput {
+    if let Some(refusal) = check_approval(config, name, input) {
+        return refusal.into();
+    }
+    // Offline sessions never offer these, but never build a web client either
+    if config.offline && NETWORK_TOOLS.contains(&name) {
+        return OFFLINE_TOOL_CALL.to_string().into();
+    }
+
+    let text = if name == "Task" {
+        let description = input
+            .get("description")
+            .and_then(|v| v.as_str())
+            .unwrap_or("subtask");
+        let prompt = input.get("prompt").and_then(|v| v.as_str()).unwrap_or("");
+        let agent_type = input
+            .get("agent_type")
+            .and_then(|v| v.as_str())
+            .unwrap_or("explore");
+        let session_id = input.get("session_id").and_then(|v| v.as_str());
+
+        run_task(
+            client,
+            config,
+            todo_manager,
+            skill_loader,
+            stats,
+            processes,
+            cancel,
+            description,
+            prompt,
+            agent_type,
+            session_id,
+        )
+        .await
+    } else if name == "suggest_skill" && config.interactive_skills && io::stdin().is_terminal() {
+        let task = input.get("task").and_then(|v| v.as_str()).unwrap_or("");
+        match prompt_skill_choice(skill_loader, stats, task) {
+            Some(choice) => choice,
+            None => {
+                return execute_tool(
+                    config,
+                    todo_manager,
+                    skill_loader,
+                    stats,
+                    processes,
+                    cancel,
+                    name,
+                    input,
+                )
+            }
+        }
+    } else if name == "read_files" {
+        let Some((paths, limit)) = read_files_input(input) else {
+            return "Error: Missing 'paths' parameter".to_string().into();
+        };
+        let outputs = run_read_files(
+            &config.workdir,
+            &paths,
+            limit,
+            config.read_concurrency,
+            &config.redactor,
+        )
+        .await;
+        for (path, output) in paths.iter().zip(&outputs) {
+            stats.record_file(FileAccess::Read, &config.workdir, path, output);
+        }
+        format_read_files(&paths, &outputs)
+    } else if name == "ask_user" {
+        match input.get("question").and_then(|v| v.as_str()) {
+            Some(question) if !question.trim().is_empty() => run_ask_user(
+                question,
+                config.interactive,
+                config.ask_user_default.as_deref(),
+                &mut io::stdin().lock(),
+            ),
+            _ => "Error: Missing 'question' parameter".to_string(),
+        }
+    } else if name == "web_search" {
+        let query = input.get("query").and_then(|v| v.as_str()).unwrap_or("");
+        let max_results = input
+            .get("max_results")
+            .and_then(|v| v.as_i64())
+            .unwrap_or(5) as usize;
+
+        match web_search(query, max_results).await {
+            Ok(results) => {
+                let results = dedupe_search_results(results);
+                if results.is_empty() {
+                    format!("No search results found for: {}", query)
+                } else {
+                    format_search_results(query, &results, WEB_SEARCH_MAX_BYTES)
+                }
+            }
+            Err(e) => format!("Error performing web search: {}", e),
+        }
+    } else {
+        return execute_tool(
+            config,
+            todo_manager,
+            skill_loader,
+            stats,
+            processes,
+            cancel,
+            name,
+            input,
+        );
+    };
+    text.into()
+}
+
+// =============================================================================
+// Token Management (from v3)
+// =============================================================================
+
+/// Rough token estimate: 4 chars ≈ 1 token.
+fn estimate_tokens(text: &str) -> usize {
+    text.len() / 4
+}
+
+fn estimate_context_tokens(messages: &[Message], system: &str) -> usize {
+    let messages_tokens: usize = messages
+        .iter()
+        .map(|msg| {
+            msg.content
                 .iter()
                 .map(|block| match block {
-                    ContentBlock::Text { text } => text.len() / 4, // Rough: 4 chars ≈ 1 token
+                    ContentBlock::Text { text } => estimate_tokens(text),
                     ContentBlock::ToolUse { input, .. } => {
-                        serde_json::to_string(input).unwrap_or_default().len() / 4
+                        estimate_tokens(&serde_json::to_string(input).unwrap_or_default())
+                    }
+                    ContentBlock::ToolResult { content, .. } => match content {
+                        anthropic::types::ToolResultContent::Text(t) => estimate_tokens(t),
+                        _ => 100, // Default estimate
+                    },
+                    _ => 0,
+                })
+                .sum::<usize>()
+        })
+        .sum();
+
+    let system_tokens = estimate_tokens(system);
+    messages_tokens + system_tokens
+}
+
+/// Drop the oldest exchanges so at most `max` messages remain.
+///
+/// History is only cut right before a plain user message (one carrying no
+/// tool_result), so the kept slice still starts with a user turn and every
+/// tool_use keeps its result. If no such cut fits within `max`, the latest
+/// one is used. Returns how many messages were dropped.
+fn trim_history(messages: &mut Vec<Message>, max: usize) -> usize {
+    match history_cut(messages, max) {
+        Some(cut) => {
+            messages.drain(..cut);
+            cut
+        }
+        None => 0,
+    }
+}
+
+/// Index of the first message `trim_history` would keep, if any are cut
+fn history_cut(messages: &[Message], max: usize) -> Option<usize> {
+    if max == 0 || messages.len() <= max {
+        return None;
+    }
+
+    let is_turn_start = |message: &Message| {
+        matches!(message.role, Role::User)
+            && !message
+                .content
+                .iter()
+                .any(|block| matches!(block, ContentBlock::ToolResult { .. }))
+    };
+
+    let cuts: Vec<usize> = (1..messages.len())
+        .filter(|&i| is_turn_start(&messages[i]))
+        .collect();
+    cuts.iter()
+        .copied()
+        .find(|&i| messages.len() - i <= max)
+        .or_else(|| cuts.last().copied())
+}
+
+/// Heading of the section a compaction summary must carry over verbatim
+const FACTS_HEADER: &str = "## Facts";
+
+const COMPACTION_PROMPT: &str = "[SYSTEM: The conversation above is about to be removed from \
+your context. Summarize it for yourself. Start with a section headed exactly \"## Facts\" \
+listing, as bullets: file paths created, read or changed; decisions made and why; open \
+questions and unfinished work. Then add a short \"## Summary\" of what happened. Be \
+specific; anything not written down here is lost.]";
+
+/// Build the note that replaces compacted messages.
+///
+/// The facts section is lifted to the top verbatim so it survives even if
+/// the rest of the summary is vague; a summary without one gets an empty
+/// facts section rather than none.
+fn compaction_note(summary: &str, compacted: usize) -> String {
+    let summary = summary.trim();
+    let (facts, rest) = match summary.find(FACTS_HEADER) {
+        Some(start) => {
+            let body_start = start + FACTS_HEADER.len();
+            let end = summary[body_start..]
+                .find("\n## ")
+                .map_or(summary.len(), |i| body_start + i);
+            let rest = format!("{}{}", &summary[..start], &summary[end..]);
+            (summary[start..end].trim().to_string(), rest)
+        }
+        None => (
+            format!("{}\n- (none recorded)", FACTS_HEADER),
+            summary.to_string(),
+        ),
+    };
+
+    format!(
+        "[SYSTEM: {} earlier messages were compacted into this summary.]\n\n{}\n\n{}",
+        compacted,
+        facts,
+        rest.trim()
+    )
+    .trim_end()
+    .to_string()
+}
+
+/// Like `trim_history`, but first asks the model (via `summarize`) to
+/// summarize the messages being cut and prepends the result to the first
+/// kept message, so file paths and decisions outlive the window.
+async fn compact_history<F, Fut>(
+    messages: &mut Vec<Message>,
+    max: usize,
+    summarize: F,
+) -> Result<usize>
+where
+    F: FnOnce(Vec<Message>) -> Fut,
+    Fut: std::future::Future<Output = Result<MessagesResponse>>,
+{
+    let Some(cut) = history_cut(messages, max) else {
+        return Ok(0);
+    };
+
+    let mut request = messages[..cut].to_vec();
+    request.push(Message {
+        role: Role::User,
+        content: vec![ContentBlock::text(COMPACTION_PROMPT)],
+    });
+    let response = summarize(request).await?;
+    let summary = response
+        .content
+        .iter()
+        .filter_map(|block| match block {
+            ContentBlock::Text { text } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    messages.drain(..cut);
+    // The first kept message is a plain user message, so roles still alternate
+    messages[0]
+        .content
+        .insert(0, ContentBlock::text(compaction_note(&summary, cut)));
+    Ok(cut)
+}
+
+/// Tokens kept free for the reply when checking a request against the window
+const CONTEXT_SAFETY_MARGIN: usize = 8000;
+
+/// Estimated input tokens, if sending `messages` would overflow the window.
+///
+/// `tokens::calculate_max_tokens` never goes below its output floor, so
+/// without this check an oversized history only fails once the API rejects it.
+fn context_overflow(messages: &[Message], system: &str) -> Option<usize> {
+    let estimated = estimate_context_tokens(messages, system);
+    (estimated > tokens::CONTEXT_WINDOW - CONTEXT_SAFETY_MARGIN).then_some(estimated)
+}
+
+// =============================================================================
+// API Calls
+// =============================================================================
+
+/// Strings longer than this are cut in MINI_CODE_DEBUG_REQUESTS output
+const DEBUG_MAX_STRING_BYTES: usize = 2000;
+
+/// Pretty JSON of a request for MINI_CODE_DEBUG_REQUESTS.
+///
+/// Long strings (file contents, tool output) are shortened so the dump stays
+/// readable; the result is still valid JSON. The API key lives in the client,
+/// never in the request, so nothing secret is printed.
+fn format_debug_request(request: &MessagesRequest) -> Result<String> {
+    fn shorten(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::String(s) if s.len() > DEBUG_MAX_STRING_BYTES => {
+                let omitted = s.len() - safe_truncate(s, DEBUG_MAX_STRING_BYTES).len();
+                *s = format!(
+                    "{}... [{} bytes truncated]",
+                    safe_truncate(s, DEBUG_MAX_STRING_BYTES),
+                    omitted
+                );
+            }
+            serde_json::Value::Array(items) => items.iter_mut().for_each(shorten),
+            serde_json::Value::Object(map) => map.values_mut().for_each(shorten),
+            _ => {}
+        }
+    }
+
+    let mut value = serde_json::to_value(request)?;
+    shorten(&mut value);
+    Ok(serde_json::to_string_pretty(&value)?)
+}
+
+/// Send a request, replaying from the cassette when MINI_CODE_CASSETTE is set.
+#[tracing::instrument(
+    name = "api_call",
+    skip_all,
+    fields(
+        model = %config.model,
+        input_tokens = tracing::field::Empty,
+        output_tokens = tracing::field::Empty
+    )
+)]
+async fn send_request(
+    client: &Client,
+    config: &Config,
+    request: MessagesRequest,
+) -> Result<MessagesResponse> {
+    if config.debug_requests {
+        eprintln!(
+            "{}\n{}",
+            "--- request ---".bright_black(),
+            format_debug_request(&request)?
+        );
+    }
+
+    let response = match &config.cassette {
+        Some(cassette) => {
+            cassette
+                .replay_or_record(
+                    request,
+                    |req| async move { Ok(client.messages(req).await?) },
+                )
+                .await?
+        }
+        None => client.messages(request).await?,
+    };
+
+    let span = tracing::Span::current();
+    span.record("input_tokens", response.usage.input_tokens);
+    span.record("output_tokens", response.usage.output_tokens);
+    Ok(response)
+}
+
+// =============================================================================
+// Loop Watchdog
+// =============================================================================
+
+/// What the agent loop should do after a tool call has been observed.
+#[derive(Debug, PartialEq)]
+enum WatchdogVerdict {
+    Continue,
+    Warn(usize),
+    Abort(usize),
+}
+
+/// Detects a model stuck calling the same tool with the same input.
+///
+/// Only consecutive identical calls count: any different call is progress
+/// and resets the window. Reaching the threshold yields a warning; one more
+/// identical call after that aborts the turn.
+struct LoopWatchdog {
+    threshold: usize,
+    last_call: Option<(String, u64)>,
+    repeats: usize,
+}
+
+impl LoopWatchdog {
+    fn new(threshold: usize) -> Self {
+        Self {
+            threshold,
+            last_call: None,
+            repeats: 0,
+        }
+    }
+
+    fn observe(&mut self, name: &str, input: &serde_json::Value) -> WatchdogVerdict {
+        use std::hash::{Hash, Hasher};
+
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        serde_json::to_string(input)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+        let call = (name.to_string(), hasher.finish());
+
+        if self.last_call.as_ref() == Some(&call) {
+            self.repeats += 1;
+        } else {
+            self.last_call = Some(call);
+            self.repeats = 1;
+        }
+
+        if self.repeats > self.threshold {
+            WatchdogVerdict::Abort(self.repeats)
+        } else if self.repeats == self.threshold {
+            WatchdogVerdict::Warn(self.repeats)
+        } else {
+            WatchdogVerdict::Continue
+        }
+    }
+}
+
+// =============================================================================
+// Continuation
+// =============================================================================
+
+/// Pick the longest partial text once truncation retries run out.
+///
+/// Only errors when no attempt produced any usable text.
+fn salvage_truncated_text(
+    partial_texts: &[String],
+    attempts: usize,
+    max_output_tokens: u32,
+) -> Result<String> {
+    match partial_texts.iter().max_by_key(|t| t.len()) {
+        Some(best) => Ok(best.clone()),
+        None => anyhow::bail!(
+            "Error: Response truncated {} times in a row with no usable text. Task may be too complex.\n\n\
+             Hint: Break the task into smaller steps, or write large outputs\n\
+             to files using write_file.\n\n\
+             You can also increase MINI_CODE_MAX_OUTPUT_TOKENS (current: {})",
+            attempts,
+            max_output_tokens
+        ),
+    }
+}
+
+/// Note shown (and kept in history) when the model ends a turn with no text.
+const NO_RESPONSE_TEXT: &str = "(no response text)";
+
+/// One-time nudge sent after an empty final response.
+const EMPTY_RESPONSE_NUDGE: &str =
+    "Your last reply had no text. Please provide a final summary of what you did.";
+
+/// True if any text block has non-whitespace content
+fn has_response_text(content: &[ContentBlock]) -> bool {
+    content
+        .iter()
+        .any(|block| matches!(block, ContentBlock::Text { text } if !text.trim().is_empty()))
+}
+
+/// Result returned by a subagent that ended without any text
+fn empty_subagent_result(tool_count: usize) -> String {
+    format!(
+        "(subagent finished after {} tool calls without a text summary; inspect its changes directly)",
+        tool_count
+    )
+}
+
+/// Tool calls run from one assistant message unless overridden
+const DEFAULT_MAX_TOOLS_PER_TURN: usize = 10;
+
+/// Result for tool calls beyond MINI_CODE_MAX_TOOLS_PER_TURN
+const SKIPPED_TOOL_CALL: &str = "Skipped: too many tool calls in one turn; will run next round";
+
+/// Split one message's tool calls into those to run now and those to skip.
+/// `max` of 0 means no limit.
+fn cap_tool_calls<T>(mut calls: Vec<T>, max: usize) -> (Vec<T>, Vec<T>) {
+    if max == 0 || calls.len() <= max {
+        return (calls, Vec::new());
+    }
+    let skipped = calls.split_off(max);
+    (calls, skipped)
+}
+
+/// Combined tool-result bytes per turn unless overridden (three full outputs)
+const DEFAULT_MAX_TURN_RESULTS: usize = 3 * MAX_TOOL_OUTPUT;
+
+/// Appended to a result cut to fit the turn budget
+const TURN_BUDGET_MARKER: &str =
+    "... [cut: this turn's tool results were over budget; re-run with a narrower request for the rest]";
+
+fn result_text(block: &mut ContentBlock) -> Option<&mut String> {
+    match block {
+        ContentBlock::ToolResult {
+            content: anthropic::types::ToolResultContent::Text(text),
+            ..
+        } => Some(text),
+        _ => None,
+    }
+}
+
+fn results_size(results: &[ContentBlock]) -> usize {
+    results
+        .iter()
+        .map(|block| match block {
+            ContentBlock::Text { text } => text.len(),
+            ContentBlock::ToolResult {
+                content: anthropic::types::ToolResultContent::Text(text),
+                ..
+            } => text.len(),
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Keep one turn's results within `budget` bytes (0 = no limit).
+///
+/// The largest tool results are cut first: every result is capped at the
+/// same length, chosen as high as the budget allows, so small outputs stay
+/// whole. Returns the note appended when anything was cut.
+fn enforce_results_budget(results: &mut Vec<ContentBlock>, budget: usize) -> Option<String> {
+    let total = results_size(results);
+    if budget == 0 || total <= budget {
+        return None;
+    }
+    let note = format!(
+        "[Tool results this turn came to {} bytes, over the {}-byte budget \
+         (MINI_CODE_MAX_TURN_RESULTS_BYTES); the largest were cut.]",
+        total, budget
+    );
+
+    let sizes: Vec<usize> = results
+        .iter_mut()
+        .filter_map(|block| result_text(block).map(|t| t.len()))
+        .collect();
+    let fixed = total - sizes.iter().sum::<usize>() + note.len();
+    let marker = TURN_BUDGET_MARKER.len();
+    // Size of the results if each is capped at `cap` bytes plus the marker
+    let capped_size = |cap: usize| -> usize {
+        sizes
+            .iter()
+            .map(|&size| size.min(cap + marker))
+            .sum::<usize>()
+    };
+
+    // Largest cap that fits; capped_size grows with cap
+    let available = budget.saturating_sub(fixed);
+    let (mut low, mut high) = (0, sizes.iter().copied().max().unwrap_or(0));
+    while low < high {
+        let mid = (low + high).div_ceil(2);
+        if capped_size(mid) <= available {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+
+    for block in results.iter_mut() {
+        if let Some(text) = result_text(block) {
+            if text.len() > low + marker {
+                let kept = safe_truncate(text, low).len();
+                text.truncate(kept);
+                text.push_str(TURN_BUDGET_MARKER);
+            }
+        }
+    }
+    results.push(ContentBlock::text(note.clone()));
+    Some(note)
+}
+
+/// Synthetic user message used by `/continue` and auto-continue.
+const CONTINUE_PROMPT: &str = "Continue where you left off.";
+
+/// The model ended its turn with a todo still in progress: nudge it onward,
+/// at most `max_continues` times per user turn.
+fn should_auto_continue(
+    todo_manager: &TodoManager,
+    continues_so_far: usize,
+    max_continues: usize,
+) -> bool {
+    continues_so_far < max_continues && todo_manager.has_in_progress()
+}
+
+/// MINI_CODE_ENFORCE_TODOS: the model tried to finish with unfinished todos.
+/// Returns the one-time reminder to send, or None when the turn may end.
+fn todo_reminder(todo_manager: &TodoManager, enforce: bool, reminded: bool) -> Option<String> {
+    if !enforce || reminded {
+        return None;
+    }
+    let incomplete = todo_manager.incomplete();
+    if incomplete.is_empty() {
+        return None;
+    }
+    let list: Vec<String> = incomplete.iter().map(|t| format!("- {}", t)).collect();
+    Some(format!(
+        "[SYSTEM: You are ending your turn, but these todos are not completed:\n{}\n\
+         Finish them, or update the todo list if they are done or no longer needed. \
+         If you have a reason to stop anyway, say so and end your turn.]",
+        list.join("\n")
+    ))
+}
+
+/// Reply that keeps the original answer in a MINI_CODE_REFLECT review
+const REFLECT_APPROVED: &str = "LGTM";
+
+/// Self-review request appended after the final answer (MINI_CODE_REFLECT).
+fn reflect_prompt(todos: &str) -> String {
+    format!(
+        "[SYSTEM: Review your final answer above against the user's original request \
+         and the todo list below. Check that it is correct, complete and answers what \
+         was asked. If it needs no changes, reply with exactly {}. Otherwise reply with \
+         the full revised answer only, with no commentary about the review.]\n\nTodos:\n{}",
+        REFLECT_APPROVED, todos
+    )
+}
+
+/// One extra model call that critiques the final answer.
+///
+/// `send` gets the conversation plus the review prompt. Returns the revised
+/// answer, or None when reflection is off or the model stands by its answer.
+/// The answer is already on screen, so a failed review is only logged.
+async fn reflect_on_answer<F, Fut>(
+    enabled: bool,
+    messages: &[Message],
+    todos: &str,
+    out: &dyn Writer,
+    send: F,
+) -> Option<String>
+where
+    F: FnOnce(Vec<Message>) -> Fut,
+    Fut: std::future::Future<Output = Result<MessagesResponse>>,
+{
+    if !enabled {
+        return None;
+    }
+
+    let mut review = messages.to_vec();
+    review.push(Message {
+        role: Role::User,
+        content: vec![ContentBlock::text(reflect_prompt(todos))],
+    });
+    let response = match send(review).await {
+        Ok(response) => response,
+        Err(e) => {
+            out.line(&format!(
+                "{} {}",
+                "Warning:".bright_yellow(),
+                format!("Review failed, keeping the answer: {}", e).bright_black()
+            ));
+            return None;
+        }
+    };
+
+    let text = response
+        .content
+        .iter()
+        .filter_map(|block| match block {
+            ContentBlock::Text { text } => Some(text.as_str()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let text = text.trim();
+
+    if text.is_empty() || text == REFLECT_APPROVED {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+// =============================================================================
+// Main Agent Loop (adapted for v4 with Skills + Task + Todo)
+// =============================================================================
+
+#[tracing::instrument(name = "agent_loop", skip_all, fields(session_id = %config.session_id))]
+async fn agent_loop(
+    client: &Client,
+    config: &Config,
+    skill_loader: &SkillLoader,
+    stats: &SessionStats,
+    processes: &ProcessRegistry,
+    out: &dyn Writer,
+    messages: &mut Vec<Message>,
+) -> Result<()> {
+    let todo_manager = TodoManager::with_style(config.todo_style);
+    stats.record_turn();
+
+    let skill_descriptions = skill_loader.get_descriptions();
+    let agent_descriptions = get_agent_descriptions();
+    let system = config.system_prompt(&skill_descriptions, &agent_descriptions);
+
+    let tools = config.tool_set(create_all_tools(skill_loader));
+
+    let mut consecutive_truncations = 0;
+    let mut partial_texts: Vec<String> = Vec::new();
+    let mut watchdog = LoopWatchdog::new(config.max_repeated_tool_calls);
+    let mut auto_continues = 0;
+    let mut nudged_empty = false;
+    let mut reminded_todos = false;
+
+    // Ctrl-C cancels this turn: running tools are killed and the loop stops
+    let cancel = CancellationToken::new();
+    let _stop_listening = cancel_on_ctrl_c(cancel.clone());
+
+    loop {
+        if config.compact_history {
+            // Over the window, compact half the history even below the cap
+            let max = if context_overflow(messages, &system).is_some() {
+                (messages.len() / 2).max(1)
+            } else {
+                config.max_history_messages
+            };
+            let compacted = compact_history(messages, max, |request| async {
+                let request = MessagesRequestBuilder::new(&config.model, request, 4000)
+                    .system(SystemPrompt::Text(system.clone()))
+                    .build()?;
+                send_request(client, config, request).await
+            })
+            .await;
+            match compacted {
+                Ok(0) => {}
+                Ok(compacted) => out.line(&format!(
+                    "{}",
+                    format!("[history: compacted {} oldest messages]", compacted).bright_black()
+                )),
+                Err(e) => out.line(&format!(
+                    "{} {}",
+                    "Warning:".bright_yellow(),
+                    format!("Compaction failed, dropping old messages instead: {}", e)
+                        .bright_black()
+                )),
+            }
+        }
+
+        let dropped = trim_history(messages, config.max_history_messages);
+        if dropped > 0 {
+            out.line(&format!(
+                "{}",
+                format!("[history: dropped {} oldest messages]", dropped).bright_black()
+            ));
+        }
+
+        // Can fire mid-turn, after a tool_result was pushed; erroring out would
+        // let main pop that result and strand its tool_use
+        if let Some(estimated) = context_overflow(messages, &system) {
+            end_oversized_turn(out, messages, estimated);
+            return Ok(());
+        }
+
+        let max_tokens = tokens::calculate_max_tokens(
+            estimate_context_tokens(messages, &system),
+            config.max_output_tokens,
+        );
+
+        let request = apply_sampling(
+            MessagesRequestBuilder::new(&config.model, messages.clone(), max_tokens)
+                .system(SystemPrompt::Text(system.clone()))
+                .tools(tools.clone()),
+            config.temperature,
+            config.top_p,
+        )
+        .build()?;
+
+        let animation = spawn_thinking_animation(config.heartbeat_secs);
+        let response = tokio::select! {
+            response = send_request(client, config, request) => response?,
+            _ = cancel.cancelled() => {
+                drop(animation);
+                end_cancelled_turn(out, messages);
+                return Ok(());
+            }
+        };
+        drop(animation);
+        stats.record_usage(
+            u64::from(response.usage.input_tokens),
+            u64::from(response.usage.output_tokens),
+        );
+
+        match response.stop_reason {
+            Some(StopReason::MaxTokens) => {
+                consecutive_truncations += 1;
+
+                out.line(&format!(
+                    "{} {}",
+                    "Warning:".bright_yellow(),
+                    format!(
+                        "Response truncated (attempt {}/{})",
+                        consecutive_truncations, config.max_truncation_retries
+                    )
+                    .bright_black()
+                ));
+
+                let text = response
+                    .content
+                    .iter()
+                    .filter_map(|block| match block {
+                        ContentBlock::Text { text } => Some(text.as_str()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                if !text.trim().is_empty() {
+                    partial_texts.push(text);
+                }
+
+                if consecutive_truncations >= config.max_truncation_retries {
+                    // Keep the conversation intact with the best partial answer
+                    // so the user can say "continue" instead of losing the turn
+                    let best = salvage_truncated_text(
+                        &partial_texts,
+                        consecutive_truncations,
+                        config.max_output_tokens,
+                    )?;
+
+                    out.line(&format!(
+                        "{} {}",
+                        "Warning:".bright_yellow(),
+                        "Showing partial response. Say \"continue\" (or /continue) to resume."
+                            .bright_black()
+                    ));
+                    out.line(&best);
+
+                    messages.push(Message {
+                        role: Role::Assistant,
+                        content: vec![ContentBlock::text(best)],
+                    });
+
+                    return Ok(());
+                }
+
+                messages.push(Message {
+                    role: Role::Assistant,
+                    content: response.content,
+                });
+
+                messages.push(Message {
+                    role: Role::User,
+                    content: vec![ContentBlock::text(
+                        "[SYSTEM: Your response was truncated due to length. \
+                         Please provide a shorter summary of the key points \
+                         (max 3-4 sentences), or write detailed content to a file instead.]",
+                    )],
+                });
+
+                continue;
+            }
+
+            Some(StopReason::ToolUse) => {
+                consecutive_truncations = 0;
+                partial_texts.clear();
+
+                let mut tool_calls = Vec::new();
+                for block in &response.content {
+                    if let ContentBlock::ToolUse { id, name, input } = block {
+                        tool_calls.push((id.clone(), name.clone(), input.clone()));
                     }
-                    ContentBlock::ToolResult { content, .. } => match content {
-                        anthropic::types::ToolResultContent::Text(t) => t.len() / 4,
-                        _ => 100, // Default estimate
+                }
+
+                let (tool_calls, skipped_calls) =
+                    cap_tool_calls(tool_calls, config.max_tools_per_turn);
+                if !skipped_calls.is_empty() {
+                    out.line(&format!(
+                        "{} {}",
+                        "Warning:".bright_yellow(),
+                        format!(
+                            "{} tool calls in one message; running the first {}, skipping {}",
+                            tool_calls.len() + skipped_calls.len(),
+                            tool_calls.len(),
+                            skipped_calls.len()
+                        )
+                        .bright_black()
+                    ));
+                }
+
+                // Check for a repetition loop before running anything
+                let mut repetition_warning = None;
+                let mut aborted = None;
+                for (_, name, input) in &tool_calls {
+                    match watchdog.observe(name, input) {
+                        WatchdogVerdict::Continue => {}
+                        WatchdogVerdict::Warn(count) => {
+                            out.line(&format!(
+                                "{} {}",
+                                "Warning:".bright_yellow(),
+                                format!("'{}' called {} times with identical input", name, count)
+                                    .bright_black()
+                            ));
+                            repetition_warning = Some(format!(
+                                "[SYSTEM: You have called '{}' {} times in a row with identical \
+                                 input and gotten the same result. Repeating it will not help. \
+                                 Step back and try a different approach.]",
+                                name, count
+                            ));
+                        }
+                        WatchdogVerdict::Abort(count) => {
+                            aborted = Some((name.clone(), count));
+                            break;
+                        }
+                    }
+                }
+                // Erroring out would let main pop the last tool_result and
+                // strand its tool_use
+                if let Some((name, count)) = aborted {
+                    end_looping_turn(
+                        out,
+                        messages,
+                        response.content,
+                        &name,
+                        count,
+                        config.max_repeated_tool_calls,
+                    );
+                    return Ok(());
+                }
+
+                let rationales = if config.explain {
+                    tool_rationales(&response.content)
+                } else {
+                    HashMap::new()
+                };
+                let mut results = Vec::new();
+                let mut edited = Vec::new();
+                for (id, name, input) in tool_calls {
+                    if let Some(rationale) = rationales.get(&id) {
+                        out.line(&format!(
+                            "\n{} {}",
+                            "Why:".bright_blue().italic(),
+                            rationale.italic()
+                        ));
+                    }
+
+                    // Display tool call
+                    let tool_display = match name.as_str() {
+                        "Task" => format!("{} {}", ">".bright_blue(), name.bright_magenta()),
+                        "Skill" => format!("{} {}", ">".bright_blue(), name.bright_green()),
+                        "TodoWrite" => format!("{} {}", ">".bright_blue(), name.bright_magenta()),
+                        "bash" => format!("{} {}", ">".bright_blue(), name.bright_yellow()),
+                        "web_search" => format!("{} {}", ">".bright_blue(), name.bright_cyan()),
+                        _ => format!("{} {}", ">".bright_blue(), name.bright_cyan()),
+                    };
+                    out.line(&format!("\n{}", tool_display));
+
+                    if cancel.is_cancelled() {
+                        results.push(tool_result(id, CANCELLED_TOOL_CALL.to_string(), false));
+                        continue;
+                    }
+
+                    stats.record_tool_call(&name);
+                    let refusal = check_tool_available(&tools, &name)
+                        .or_else(|| validate_tool_input(&tools, &name, &input));
+                    let ToolOutput {
+                        text: output,
+                        failed,
+                    } = match refusal {
+                        Some(refusal) => refusal.into(),
+                        None => {
+                            execute_tool_async(
+                                client,
+                                config,
+                                &todo_manager,
+                                skill_loader,
+                                stats,
+                                processes,
+                                &cancel,
+                                &name,
+                                &input,
+                            )
+                            .instrument(tracing::info_span!("tool", name = %name))
+                            .await
+                        }
+                    };
+                    // Scrub secrets before they reach the screen, logs or history
+                    let output = config.redactor.redact(&output);
+
+                    display_tool_output(out, &name, &output);
+
+                    edited.extend(edited_paths(&name, &input, &output));
+                    let (output, warning) = guard_tool_output(config, &name, output);
+                    if let Some(warning) = warning {
+                        out.line(&format!("{} {}", "Warning:".bright_yellow(), warning));
+                    }
+                    results.push(tool_result(id, output, failed));
+                }
+
+                // Format once per batch, after every edit in it has landed
+                if let Some(command) = &config.format_command {
+                    if !edited.is_empty() && !cancel.is_cancelled() {
+                        let report = run_format_command(
+                            &config.workdir,
+                            command,
+                            &edited,
+                            &cancel,
+                            &config.redactor,
+                        );
+                        out.line(&report.bright_black().to_string());
+                        results.push(ContentBlock::text(report));
+                    }
+                }
+
+                // Every tool_use needs a result, skipped ones included
+                for (id, _, _) in skipped_calls {
+                    results.push(tool_result(id, SKIPPED_TOOL_CALL.to_string(), false));
+                }
+
+                if let Some(warning) = repetition_warning {
+                    results.push(ContentBlock::text(warning));
+                }
+
+                if let Some(note) = enforce_results_budget(&mut results, config.max_turn_results) {
+                    out.line(&note.bright_yellow().to_string());
+                }
+
+                reconcile_tool_results(&response.content, &mut results);
+                messages.push(Message {
+                    role: Role::Assistant,
+                    content: response.content,
+                });
+                messages.push(Message {
+                    role: Role::User,
+                    content: results,
+                });
+
+                if cancel.is_cancelled() {
+                    end_cancelled_turn(out, messages);
+                    return Ok(());
+                }
+            }
+
+            Some(StopReason::EndTurn) | Some(StopReason::StopSequence) | None => {
+                // Normal end - display text and return
+                let has_text = has_response_text(&response.content);
+                for block in &response.content {
+                    if let ContentBlock::Text { text } = block {
+                        if !text.trim().is_empty() {
+                            out.line(text);
+                        }
+                    }
+                }
+
+                // An empty assistant turn can't be sent back to the API
+                let content = if has_text {
+                    response.content
+                } else {
+                    out.line(&NO_RESPONSE_TEXT.bright_black().to_string());
+                    vec![ContentBlock::text(NO_RESPONSE_TEXT)]
+                };
+                messages.push(Message {
+                    role: Role::Assistant,
+                    content,
+                });
+
+                if !has_text && !nudged_empty {
+                    nudged_empty = true;
+                    messages.push(Message {
+                        role: Role::User,
+                        content: vec![ContentBlock::text(EMPTY_RESPONSE_NUDGE)],
+                    });
+                    continue;
+                }
+
+                if should_auto_continue(&todo_manager, auto_continues, config.auto_continue) {
+                    auto_continues += 1;
+                    out.line(&format!(
+                        "{} {}",
+                        "Auto-continue:".bright_yellow(),
+                        format!(
+                            "todo still in progress ({}/{})",
+                            auto_continues, config.auto_continue
+                        )
+                        .bright_black()
+                    ));
+                    messages.push(Message {
+                        role: Role::User,
+                        content: vec![ContentBlock::text(CONTINUE_PROMPT)],
+                    });
+                    continue;
+                }
+
+                if let Some(reminder) =
+                    todo_reminder(&todo_manager, config.enforce_todos, reminded_todos)
+                {
+                    reminded_todos = true;
+                    out.line(&format!(
+                        "{} {}",
+                        "Todos:".bright_yellow(),
+                        "unfinished items, reminding the model".bright_black()
+                    ));
+                    messages.push(Message {
+                        role: Role::User,
+                        content: vec![ContentBlock::text(reminder)],
+                    });
+                    continue;
+                }
+
+                let revised = reflect_on_answer(
+                    config.reflect && has_text,
+                    messages,
+                    &todo_manager.render(),
+                    out,
+                    // No tools: the review must answer in text
+                    |review| async {
+                        let request = apply_sampling(
+                            MessagesRequestBuilder::new(&config.model, review, max_tokens)
+                                .system(SystemPrompt::Text(system.clone())),
+                            config.temperature,
+                            config.top_p,
+                        )
+                        .build()?;
+                        let animation = spawn_thinking_animation(config.heartbeat_secs);
+                        let response = send_request(client, config, request).await?;
+                        drop(animation);
+                        stats.record_usage(
+                            u64::from(response.usage.input_tokens),
+                            u64::from(response.usage.output_tokens),
+                        );
+                        Ok(response)
                     },
-                    _ => 0,
+                )
+                .await;
+
+                // The revision replaces the answer; the review exchange isn't kept
+                if let Some(revised) = revised {
+                    out.line(&"Revised after review:".bright_yellow().to_string());
+                    out.line(&revised);
+                    if let Some(last) = messages.last_mut() {
+                        last.content = vec![ContentBlock::text(revised)];
+                    }
+                }
+
+                return Ok(());
+            }
+        }
+    }
+}
+
+// =============================================================================
+// Client Initialization (from v3)
+// =============================================================================
+
+fn create_client() -> Result<Client> {
+    dotenvy::dotenv().ok();
+    build_client(&api_key_from_env()?, api_base_from_env().as_deref())
+}
+
+fn api_key_from_env() -> Result<String> {
+    env::var("ANTHROPIC_API_KEY")
+        .or_else(|_| env::var("ANTHROPIC_AUTH_TOKEN"))
+        .map_err(|_| {
+            anyhow::anyhow!("Missing API key: set ANTHROPIC_API_KEY or ANTHROPIC_AUTH_TOKEN")
+        })
+}
+
+fn api_base_from_env() -> Option<String> {
+    env::var("ANTHROPIC_API_BASE")
+        .or_else(|_| env::var("ANTHROPIC_BASE_URL"))
+        .ok()
+}
+
+/// Client for `base_url` (the library default when None)
+fn build_client(api_key: &str, base_url: Option<&str>) -> Result<Client> {
+    let mut builder = anthropic::client::ClientBuilder::new().api_key(api_key);
+
+    if let Some(base_url) = base_url {
+        builder = builder.api_base(base_url);
+    }
+
+    builder = apply_api_version(builder);
+
+    builder = builder.timeout(Duration::from_secs(600));
+
+    let client = builder.build()?;
+    Ok(client)
+}
+
+/// Build a client for a new endpoint and keep it only if `check` succeeds.
+///
+/// `check` gets the candidate client and hands it back once a request has
+/// gone through; on any error the caller keeps its current client.
+async fn connect_endpoint<F, Fut>(base_url: &str, api_key: &str, check: F) -> Result<Client>
+where
+    F: FnOnce(Client) -> Fut,
+    Fut: std::future::Future<Output = Result<Client>>,
+{
+    if !base_url.starts_with("http://") && !base_url.starts_with("https://") {
+        anyhow::bail!(
+            "Endpoint must be an http:// or https:// URL, got '{}'",
+            base_url
+        );
+    }
+    if api_key.trim().is_empty() {
+        anyhow::bail!("API key for {} is empty", base_url);
+    }
+    let client = build_client(api_key, Some(base_url))?;
+    check(client)
+        .await
+        .with_context(|| format!("Could not reach {}", base_url))
+}
+
+/// The cheapest real request: one output token
+async fn ping_endpoint(client: Client, model: &str) -> Result<Client> {
+    let request = MessagesRequestBuilder::new(
+        model,
+        vec![Message {
+            role: Role::User,
+            content: vec![ContentBlock::text("ping")],
+        }],
+        1,
+    )
+    .build()?;
+    client.messages(request).await?;
+    Ok(client)
+}
+
+/// Parse `/endpoint <url> [--key]`; the flag asks for a new API key.
+/// None if `input` is not an `/endpoint` command.
+fn parse_endpoint_command(input: &str) -> Option<Result<(String, bool)>> {
+    let mut parts = input.split_whitespace();
+    if parts.next() != Some("/endpoint") {
+        return None;
+    }
+    let args: Vec<&str> = parts.collect();
+    Some(match args.as_slice() {
+        [url] => Ok((url.trim_end_matches('/').to_string(), false)),
+        [url, "--key"] | ["--key", url] => Ok((url.trim_end_matches('/').to_string(), true)),
+        _ => Err(anyhow::anyhow!("Usage: /endpoint <url> [--key]")),
+    })
+}
+
+/// Read a new API key from stdin without echoing it (blank keeps `current`)
+fn prompt_api_key(base_url: &str, current: &str) -> Result<String> {
+    print!(
+        "{} ",
+        format!("API key for {} (blank keeps the current key):", base_url).bright_yellow()
+    );
+    io::stdout().flush()?;
+
+    // Keep the key off the screen while it is typed
+    let terminal_mode = save_terminal_mode();
+    if terminal_mode.is_some() {
+        set_terminal_mode("-echo");
+    }
+    let key = read_api_key(&mut io::stdin().lock(), current);
+    if let Some(mode) = &terminal_mode {
+        set_terminal_mode(mode);
+        // The Enter that ended the key wasn't echoed either
+        println!();
+    }
+    key
+}
+
+/// One line from `reader` as the new API key; blank keeps `current`
+fn read_api_key(reader: &mut dyn io::BufRead, current: &str) -> Result<String> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let key = line.trim();
+    Ok(if key.is_empty() { current } else { key }.to_string())
+}
+
+// =============================================================================
+// Input Handling (from v3)
+// =============================================================================
+
+/// Read one line of input. Returns `Ok(None)` when the user wants to exit.
+#[cfg(feature = "readline")]
+fn prompt_user() -> Result<Option<String>> {
+    let mut rl = Editor::<(), DefaultHistory>::new()?;
+
+    match rl.readline("You: ") {
+        Ok(line) => {
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                anyhow::bail!("Empty input")
+            }
+            Ok(Some(trimmed.to_string()))
+        }
+        Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
+            println!("\nExiting...");
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Read one line of input. Returns `Ok(None)` when the user wants to exit.
+#[cfg(not(feature = "readline"))]
+fn prompt_user() -> Result<Option<String>> {
+    print!("You: ");
+    io::stdout().flush()?;
+
+    let stdin = io::stdin();
+    let mut line = String::new();
+    if stdin.lock().read_line(&mut line)? == 0 {
+        println!("\nExiting...");
+        return Ok(None);
+    }
+
+    let trimmed = line.trim();
+    if trimmed.is_empty() {
+        anyhow::bail!("Empty input")
+    }
+
+    Ok(Some(trimmed.to_string()))
+}
+
+/// Run a blocking read on its own thread, giving up after `timeout`.
+///
+/// On timeout the reader thread stays blocked (e.g. on stdin); callers are
+/// expected to exit soon after, which takes it down with the process. A
+/// stranded rustyline read leaves the terminal in raw mode, so interactive
+/// callers save it with `save_terminal_mode` first and restore it on timeout.
+fn read_with_timeout<T, F>(timeout: Duration, read: F) -> Option<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    let (tx, rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        tx.send(read()).ok();
+    });
+    rx.recv_timeout(timeout).ok()
+}
+
+/// Current terminal settings (`stty -g`), or `None` when stdin isn't a TTY
+fn save_terminal_mode() -> Option<String> {
+    if !cfg!(unix) || !io::stdin().is_terminal() {
+        return None;
+    }
+    let output = Command::new("stty")
+        .arg("-g")
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let mode = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !mode.is_empty()).then_some(mode)
+}
+
+/// Apply `stty` settings, e.g. ones `save_terminal_mode` returned
+fn set_terminal_mode(mode: &str) {
+    Command::new("stty")
+        .arg(mode)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .status()
+        .ok();
+}
+
+// =============================================================================
+// Context Preload
+// =============================================================================
+
+/// Files passed with `--context`, rendered as one user message
+struct ContextPreload {
+    text: String,
+    files: usize,
+    bytes: usize,
+}
+
+/// Expand `--context` globs relative to the workdir.
+///
+/// Every match goes through `safe_path`, so a pattern like `../*` is rejected
+/// rather than silently reading outside the workspace. Matches under ignored
+/// directories (`target/`, `node_modules/`, ...) are dropped.
+fn expand_context_globs(
+    workdir: &Path,
+    patterns: &[String],
+    ignore: &IgnoreMatcher,
+) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+
+    for pattern in patterns {
+        let full = workdir.join(pattern);
+        let entries = glob::glob(&full.to_string_lossy())
+            .with_context(|| format!("Invalid --context pattern: {}", pattern))?;
+
+        let mut matched = false;
+        for entry in entries {
+            let path = entry?;
+            if !path.is_file() {
+                continue;
+            }
+            let path = safe_path(workdir, &path.to_string_lossy())?;
+            if ignore.is_ignored(&path, false) {
+                continue;
+            }
+            matched = true;
+            if !files.contains(&path) {
+                files.push(path);
+            }
+        }
+
+        if !matched {
+            anyhow::bail!("--context pattern matched no files: {}", pattern);
+        }
+    }
+
+    Ok(files)
+}
+
+/// Read the `--context` files into a single message with `=== path ===` separators
+fn load_context_files(
+    workdir: &Path,
+    patterns: &[String],
+    ignore: &IgnoreMatcher,
+    redactor: &Redactor,
+) -> Result<ContextPreload> {
+    let files = expand_context_globs(workdir, patterns, ignore)?;
+    let mut sections = Vec::new();
+    let mut bytes = 0;
+
+    for path in &files {
+        let relative = path.strip_prefix(workdir).unwrap_or(path);
+        // run_read_with applies the same byte cap as the read_file tool
+        let content = run_read_with(
+            workdir,
+            &relative.to_string_lossy(),
+            None,
+            false,
+            None,
+            &|text| redactor.redact(text),
+        );
+        bytes += content.len();
+        sections.push(format!("=== {} ===\n{}", relative.display(), content));
+    }
+
+    Ok(ContextPreload {
+        text: format!(
+            "<context>\nFiles preloaded for this session:\n\n{}\n</context>",
+            sections.join("\n\n")
+        ),
+        files: files.len(),
+        bytes,
+    })
+}
+
+// =============================================================================
+// History Autosave
+// =============================================================================
+
+/// Write the conversation to `path` atomically: a crash mid-write leaves the
+/// previous save intact instead of a truncated file.
+fn save_history(path: &Path, messages: &[Message]) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    fs::write(&tmp, serde_json::to_string(messages)?)
+        .with_context(|| format!("Failed to write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("Failed to replace {}", path.display()))
+}
+
+/// Read back a conversation written by `save_history`
+fn load_history(path: &Path) -> Result<Vec<Message>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read autosave {}", path.display()))?;
+    serde_json::from_str(&text)
+        .with_context(|| format!("Autosave {} is not a saved conversation", path.display()))
+}
+
+// =============================================================================
+// Command Line Arguments
+// =============================================================================
+
+#[derive(Debug, Default)]
+struct CliArgs {
+    new_skill: Option<String>,
+    json: bool,
+    no_color: bool,
+    no_banner: bool,
+    resume: bool,
+    workdir: Option<PathBuf>,
+    env_file: Option<PathBuf>,
+    context: Vec<String>,
+    trace: Option<PathBuf>,
+}
+
+fn parse_args() -> Result<CliArgs> {
+    let mut cli = CliArgs::default();
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--new-skill" => {
+                let name = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--new-skill requires a skill name"))?;
+                cli.new_skill = Some(name);
+            }
+            "--json" => cli.json = true,
+            "--no-color" => cli.no_color = true,
+            "--no-banner" => cli.no_banner = true,
+            "--resume" => cli.resume = true,
+            "--workdir" => {
+                let dir = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--workdir requires a directory"))?;
+                cli.workdir = Some(PathBuf::from(dir));
+            }
+            "--env-file" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--env-file requires a path"))?;
+                cli.env_file = Some(PathBuf::from(path));
+            }
+            "--context" => {
+                let pattern = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--context requires a glob pattern"))?;
+                cli.context.push(pattern);
+            }
+            "--trace" => {
+                let path = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("--trace requires a file path"))?;
+                cli.trace = Some(PathBuf::from(path));
+            }
+            other => anyhow::bail!("Unknown argument: {}", other),
+        }
+    }
+
+    Ok(cli)
+}
+
+// =============================================================================
+// REPL Commands
+// =============================================================================
+
+/// Handle a `/command` typed at the prompt. Returns false if `input` isn't one.
+fn handle_repl_command(
+    input: &str,
+    config: &Config,
+    skill_loader: &mut SkillLoader,
+    stats: &SessionStats,
+) -> bool {
+    let parts: Vec<&str> = input.split_whitespace().collect();
+
+    match parts.as_slice() {
+        ["/skill", "new", name] => {
+            match create_skill_scaffold(&config.skills_dir, name) {
+                Ok(path) => {
+                    skill_loader.reload(&config.skills_path);
+                    println!("{} {}", "Created skill:".bright_green(), path.display());
+                }
+                Err(e) => println!("{} {}", "Error:".bright_red(), e),
+            }
+            true
+        }
+        ["/skills", "list"] => {
+            let skills = skill_loader.list_with_sources();
+            if skills.is_empty() {
+                println!("{}", "No skills loaded".bright_yellow());
+            }
+            for line in skills {
+                println!("  {} {}", "-".bright_black(), line);
+            }
+            true
+        }
+        ["/skill", ..] | ["/skills", ..] => {
+            println!(
+                "{} /skill new <name> | /skills list",
+                "Usage:".bright_yellow()
+            );
+            true
+        }
+        ["/config"] => {
+            // Effective settings only; API keys and other .env secrets are never shown
+            println!("{}", config.describe());
+            true
+        }
+        ["/files"] => {
+            println!("{}", stats.touched_files().render());
+            true
+        }
+        _ => false,
+    }
+}
+
+// =============================================================================
+// Main Entry Point
+// =============================================================================
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = parse_args()?;
+    configure_colors(cli.no_color);
+    let mut config = Config::from_env(cli.workdir.as_deref(), cli.env_file.as_deref())?;
+    config.cassette = config.load_cassette()?.map(Arc::new);
+    // Scripted --json runs never stop to ask the user anything
+    config.interactive &= !cli.json;
+    if let Some(path) = cli.trace.as_ref().or(config.trace_path.as_ref()) {
+        trace::init(path)?;
+    }
+
+    if let Some(name) = &cli.new_skill {
+        let path = create_skill_scaffold(&config.skills_dir, name)?;
+        println!("{} {}", "Created skill:".bright_green(), path.display());
+        return Ok(());
+    }
+
+    let mut client = create_client()?;
+    let mut api_key = api_key_from_env()?;
+    let mut skill_loader = SkillLoader::new(&config.skills_path);
+    let mut preload = if cli.context.is_empty() {
+        None
+    } else {
+        Some(load_context_files(
+            &config.workdir,
+            &cli.context,
+            &config.ignore,
+            &config.redactor,
+        )?)
+    };
+
+    // Display startup info
+    let banner = should_print_banner(cli.no_banner, no_banner_env(), cli.json);
+    if banner {
+        println!("{}", "=".repeat(60).bright_black());
+        println!(
+            "{} {} {}",
+            "Mini Claude Code".bright_cyan().bold(),
+            "v4".bright_magenta(),
+            "(Skills + Subagents + Todo)".bright_black()
+        );
+        println!("{}", "=".repeat(60).bright_black());
+        println!("{} {}", "Model:".bright_black(), config.model);
+        println!("{} {}", "Workdir:".bright_black(), config.workdir.display());
+        println!("{} {}", "Session:".bright_black(), config.session_id);
+    }
+
+    let mut known_tools = create_all_tools(&skill_loader);
+    known_tools.push(create_report_progress_tool());
+    known_tools.push(create_propose_patch_tool());
+    for name in unknown_tool_names(
+        &known_tools,
+        config.enabled_tools.as_deref(),
+        &config.disabled_tools,
+    ) {
+        println!(
+            "{} {}",
+            "Warning:".bright_yellow(),
+            format!(
+                "unknown tool '{}' in MINI_CODE_ENABLED_TOOLS/MINI_CODE_DISABLED_TOOLS",
+                name
+            )
+            .bright_black()
+        );
+    }
+
+    if banner {
+        let skill_count = skill_loader.list_skills().len();
+        if skill_count > 0 {
+            println!("{} {} skills loaded", "Skills:".bright_green(), skill_count);
+            for skill_name in skill_loader.list_skills() {
+                println!("  {} {}", "-".bright_black(), skill_name.bright_green());
+            }
+        } else {
+            println!(
+                "{} {}",
+                "Skills:".bright_black(),
+                "none (create skills/ folder with SKILL.md files)".bright_yellow()
+            );
+        }
+
+        if let Some(preload) = &preload {
+            println!(
+                "{} {} files ({} bytes) preloaded",
+                "Context:".bright_green(),
+                preload.files,
+                preload.bytes
+            );
+        }
+
+        println!("{}", "=".repeat(60).bright_black());
+        println!();
+    }
+
+    let stats = SessionStats::new();
+    let processes = ProcessRegistry::new();
+    let out = create_writer(&config)?;
+    let mut messages = Vec::new();
+    if cli.resume {
+        let path = config
+            .autosave_path
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("--resume needs MINI_CODE_AUTOSAVE to be set"))?;
+        messages = load_history(path)?;
+        println!(
+            "{}",
+            format!(
+                "Resumed {} messages from {}",
+                messages.len(),
+                path.display()
+            )
+            .bright_black()
+        );
+    }
+
+    // Taken before any read, while the terminal is still in cooked mode
+    let terminal_mode = (config.idle_timeout_secs > 0)
+        .then(save_terminal_mode)
+        .flatten();
+    loop {
+        let read = if config.idle_timeout_secs > 0 {
+            read_with_timeout(Duration::from_secs(config.idle_timeout_secs), prompt_user)
+        } else {
+            Some(prompt_user())
+        };
+        let Some(read) = read else {
+            // The abandoned readline still holds the terminal in raw mode
+            if let Some(mode) = &terminal_mode {
+                set_terminal_mode(mode);
+            }
+            println!(
+                "\n{}",
+                format!(
+                    "No input for {}s (MINI_CODE_IDLE_TIMEOUT_SECS), exiting.",
+                    config.idle_timeout_secs
+                )
+                .bright_yellow()
+            );
+            break;
+        };
+
+        let input = match read {
+            Ok(Some(input)) => input,
+            Ok(None) => break,
+            Err(_) => continue,
+        };
+
+        if input == "exit" || input == "quit" {
+            break;
+        }
+
+        // Let the model pick up an unfinished task without retyping context
+        let input = if input == "/continue" {
+            CONTINUE_PROMPT.to_string()
+        } else {
+            input
+        };
+
+        let input = match run_macro_command(&input, &config.macros) {
+            Some(Ok(prompt)) => {
+                println!("{}", prompt.bright_black());
+                prompt
+            }
+            Some(Err(e)) => {
+                println!("{} {}", "Error:".bright_red(), e);
+                continue;
+            }
+            None => input,
+        };
+
+        if handle_repl_command(&input, &config, &mut skill_loader, &stats) {
+            continue;
+        }
+
+        if let Some(parsed) = parse_endpoint_command(&input) {
+            let switch = async {
+                let (url, new_key) = parsed?;
+                let key = if new_key {
+                    prompt_api_key(&url, &api_key)?
+                } else {
+                    api_key.clone()
+                };
+                let connected = connect_endpoint(&url, &key, |candidate| {
+                    ping_endpoint(candidate, &config.model)
+                })
+                .await?;
+                anyhow::Ok((url, key, connected))
+            }
+            .await;
+            match switch {
+                Ok((url, key, connected)) => {
+                    client = connected;
+                    api_key = key;
+                    println!("{} {}", "Switched endpoint:".bright_green(), url);
+                }
+                Err(e) => println!(
+                    "{} {:#} (keeping the current endpoint)",
+                    "Error:".bright_red(),
+                    e
+                ),
+            }
+            continue;
+        }
+
+        if input == "/clear" {
+            messages.clear();
+            println!("{}", "Conversation cleared.".bright_black());
+            continue;
+        }
+
+        // Preloaded context rides along with the first message
+        let mut content = Vec::new();
+        if let Some(preload) = preload.take() {
+            content.push(ContentBlock::text(preload.text));
+        }
+        content.push(ContentBlock::text(input));
+
+        messages.push(Message {
+            role: Role::User,
+            content,
+        });
+
+        if let Err(e) = agent_loop(
+            &client,
+            &config,
+            &skill_loader,
+            &stats,
+            &processes,
+            out.as_ref(),
+            &mut messages,
+        )
+        .await
+        {
+            eprintln!("{} {}", "Error:".bright_red(), e);
+            if let Some(hint) = api_version_hint(&e.to_string()) {
+                eprintln!("{}", hint.bright_yellow());
+            }
+            messages.pop();
+        }
+
+        if let Some(path) = &config.autosave_path {
+            if let Err(e) = save_history(path, &messages) {
+                eprintln!("{} {:#}", "Warning:".bright_yellow(), e);
+            }
+        }
+
+        println!();
+    }
+
+    if cli.json {
+        let mut summary = stats.to_json();
+        summary["session_id"] = json!(config.session_id);
+        println!("{}", serde_json::to_string_pretty(&summary)?);
+    } else {
+        println!("{}", "=".repeat(60).bright_black());
+        println!("{}", "Session Summary".bright_cyan().bold());
+        println!("{}", "=".repeat(60).bright_black());
+        println!("{}", stats.render());
+    }
+
+    let stopped = processes.kill_all();
+    if stopped > 0 {
+        println!(
+            "{}",
+            format!("Stopped {} background process(es)", stopped).bright_black()
+        );
+    }
+
+    if let Some(path) = &config.manifest_path {
+        let manifest = serde_json::to_string_pretty(&stats.touched_files().to_json())?;
+        fs::write(path, manifest)
+            .with_context(|| format!("Failed to write manifest {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mini_code_core::todo::TodoStatus;
+
+    /// A Config with every setting at its default, ignoring MINI_CODE_* and
+    /// `.env` files so results don't depend on the environment
+    fn test_config(workdir: &Path) -> Config {
+        Config {
+            model: mini_code_core::model::DEFAULT_MODEL.to_string(),
+            workdir: workdir.to_path_buf(),
+            skills_dir: workdir.join("skills"),
+            skills_path: parse_skills_path(workdir, None, None),
+            max_output_tokens: 160000,
+            max_truncation_retries: 3,
+            max_repeated_tool_calls: 3,
+            cassette_path: None,
+            cassette: None,
+            auto_continue: 0,
+            temperature: None,
+            top_p: None,
+            interactive_skills: false,
+            tee_path: None,
+            subagent_timeout_secs: 300,
+            project_context: None,
+            manifest_path: None,
+            confirm_edits: false,
+            idle_timeout_secs: 0,
+            persona_name: None,
+            prompt_footer: None,
+            debug_requests: false,
+            redactor: Arc::new(Redactor::new(&[], &[]).unwrap()),
+            max_history_messages: 0,
+            approval: Box::new(AllowAll),
+            reflect: false,
+            todo_style: TodoRenderStyle::default(),
+            enabled_tools: None,
+            disabled_tools: Vec::new(),
+            max_tools_per_turn: DEFAULT_MAX_TOOLS_PER_TURN,
+            trace_path: None,
+            compact_history: false,
+            ask_user_default: None,
+            interactive: false,
+            log_max_bytes: 0,
+            log_keep: DEFAULT_LOG_KEEP,
+            scratch: ScratchDir::for_session(),
+            read_concurrency: DEFAULT_READ_CONCURRENCY,
+            subagent_timeline: false,
+            editable_extensions: Vec::new(),
+            enforce_todos: false,
+            autosave_path: None,
+            format_command: None,
+            explain: false,
+            offline: false,
+            macros: BTreeMap::new(),
+            ignore: IgnoreMatcher::new(workdir, &[]).unwrap(),
+            max_turn_results: DEFAULT_MAX_TURN_RESULTS,
+            session_id: "test-session".to_string(),
+            heartbeat_secs: DEFAULT_HEARTBEAT_SECS,
+            default_read_lines: None,
+            injection_guard: None,
+        }
+    }
+
+    fn text_response(text: &str) -> MessagesResponse {
+        serde_json::from_value(json!({
+            "id": "msg_test",
+            "type": "message",
+            "role": "assistant",
+            "model": "test-model",
+            "content": [{"type": "text", "text": text}],
+            "stop_reason": "end_turn",
+            "stop_sequence": null,
+            "usage": {"input_tokens": 10, "output_tokens": 5}
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_reflection_makes_one_extra_call() {
+        let messages = vec![
+            Message {
+                role: Role::User,
+                content: vec![ContentBlock::text("What is 2 + 2?")],
+            },
+            Message {
+                role: Role::Assistant,
+                content: vec![ContentBlock::text("5")],
+            },
+        ];
+        let calls = AtomicUsize::new(0);
+        let fake_client = |reply: &'static str| {
+            let calls = &calls;
+            move |review: Vec<Message>| async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                assert_eq!(review.len(), 3);
+                Ok(text_response(reply))
+            }
+        };
+
+        let out = BufferWriter {
+            lines: Mutex::new(Vec::new()),
+        };
+
+        let off = reflect_on_answer(false, &messages, "No todos.", &out, fake_client("4")).await;
+        assert_eq!(off, None);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        let revised = reflect_on_answer(true, &messages, "No todos.", &out, fake_client("4")).await;
+        assert_eq!(revised.as_deref(), Some("4"));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let kept = reflect_on_answer(true, &messages, "No todos.", &out, fake_client("LGTM")).await;
+        assert_eq!(kept, None);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(out.lines.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_failed_reflection_keeps_answer() {
+        let messages = vec![Message {
+            role: Role::Assistant,
+            content: vec![ContentBlock::text("4")],
+        }];
+        let out = BufferWriter {
+            lines: Mutex::new(Vec::new()),
+        };
+
+        let revised = reflect_on_answer(true, &messages, "No todos.", &out, |_| async {
+            Err(anyhow::anyhow!("connection reset"))
+        })
+        .await;
+        assert_eq!(revised, None);
+        let lines = out.lines.lock().unwrap();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("Review failed, keeping the answer: connection reset"));
+    }
+
+    #[test]
+    fn test_reconcile_backfills_missing_tool_result() {
+        let tool_use = |id: &str| ContentBlock::ToolUse {
+            id: id.into(),
+            name: "bash".into(),
+            input: json!({"command": "ls"}),
+        };
+        let assistant = vec![
+            ContentBlock::text("Running two commands"),
+            tool_use("t1"),
+            tool_use("t2"),
+        ];
+        let mut results = vec![tool_result("t1".to_string(), "ok".to_string(), false)];
+
+        reconcile_tool_results(&assistant, &mut results);
+
+        let ids: Vec<&str> = results
+            .iter()
+            .filter_map(|r| match r {
+                ContentBlock::ToolResult { tool_use_id, .. } => Some(tool_use_id.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ids, vec!["t1", "t2"]);
+        assert!(matches!(
+            &results[1],
+            ContentBlock::ToolResult {
+                is_error: Some(true),
+                ..
+            }
+        ));
+
+        // Already complete: nothing added
+        reconcile_tool_results(&assistant, &mut results);
+        assert_eq!(results.len(), 2);
+    }
+
+    #[test]
+    fn test_cap_tool_calls() {
+        let calls: Vec<usize> = (1..=12).collect();
+
+        let (run, skipped) = cap_tool_calls(calls.clone(), 10);
+        assert_eq!(run, (1..=10).collect::<Vec<_>>());
+        assert_eq!(skipped, vec![11, 12]);
+
+        let (run, skipped) = cap_tool_calls(calls.clone(), 12);
+        assert_eq!(run.len(), 12);
+        assert!(skipped.is_empty());
+
+        // 0 disables the cap
+        let (run, skipped) = cap_tool_calls(calls, 0);
+        assert_eq!(run.len(), 12);
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn test_report_progress_reaches_parent() {
+        let progress = Arc::new(Mutex::new(SubagentProgress::new()));
+        let parent_view = Arc::clone(&progress);
+
+        let ok = run_report_progress(&progress, &json!({"message": "found 12 files so far"}));
+        assert_eq!(ok, "Progress reported.");
+        assert!(parent_view
+            .lock()
+            .unwrap()
+            .status()
+            .ends_with("| found 12 files so far"));
+
+        let long = "x".repeat(300);
+        run_report_progress(
+            &progress,
+            &json!({ "message": format!("{}\nsecond line", long) }),
+        );
+        let report = parent_view.lock().unwrap().last_report.clone().unwrap();
+        assert_eq!(report.len(), MAX_PROGRESS_REPORT_CHARS + 3);
+
+        assert!(run_report_progress(&progress, &json!({})).starts_with("Error:"));
+        assert_eq!(parent_view.lock().unwrap().tool_count, 0);
+    }
+
+    #[test]
+    fn test_offline_mode_drops_network_tools() {
+        let mut config = test_config(&env::temp_dir());
+        let loader = SkillLoader::new(&[]);
+        let names = |tools: Vec<Tool>| tools.into_iter().map(|t| t.name).collect::<Vec<_>>();
+
+        config.offline = false;
+        let online = names(config.tool_set(create_all_tools(&loader)));
+        assert!(online.contains(&"web_search".to_string()));
+
+        config.offline = true;
+        let offline = names(config.tool_set(create_all_tools(&loader)));
+        let sub = names(config.tool_set(get_tools_for_subagent("code", &loader)));
+        for tool in NETWORK_TOOLS {
+            assert!(!offline.contains(&tool.to_string()));
+            assert!(!sub.contains(&tool.to_string()));
+        }
+        assert_eq!(offline.len(), online.len() - 1);
+        assert!(offline.contains(&"read_file".to_string()));
+
+        // A call that slips through anyway is refused, not sent
+        let output = execute_tool(
+            &config,
+            &TodoManager::new(),
+            &loader,
+            &SessionStats::new(),
+            &ProcessRegistry::new(),
+            &CancellationToken::new(),
+            "web_search",
+            &json!({"query": "rust"}),
+        )
+        .text;
+        assert_eq!(output, OFFLINE_TOOL_CALL);
+    }
+
+    #[test]
+    fn test_default_read_lines_applies_only_without_limit() {
+        let dir = env::temp_dir().join(format!("v4_default_read_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let text: Vec<String> = (1..=10).map(|i| format!("line {}", i)).collect();
+        fs::write(dir.join("big.txt"), text.join("\n")).unwrap();
+        fs::write(dir.join("small.txt"), "one\ntwo").unwrap();
+
+        let mut config = test_config(&dir);
+        let loader = SkillLoader::new(&[]);
+        let read = |config: &Config, input: serde_json::Value| {
+            execute_tool(
+                config,
+                &TodoManager::new(),
+                &loader,
+                &SessionStats::new(),
+                &ProcessRegistry::new(),
+                &CancellationToken::new(),
+                "read_file",
+                &input,
+            )
+            .text
+        };
+
+        config.default_read_lines = None;
+        let unset = read(&config, json!({"path": "big.txt"}));
+        config.default_read_lines = Some(3);
+        let defaulted = read(&config, json!({"path": "big.txt"}));
+        let explicit = read(&config, json!({"path": "big.txt", "limit": 5}));
+        let short = read(&config, json!({"path": "small.txt"}));
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(unset, text.join("\n"));
+        assert_eq!(
+            defaulted,
+            "line 1\nline 2\nline 3\n... (7 more lines)\n\
+             (stopped at the default 3 lines; pass a larger limit to read more)"
+        );
+        assert_eq!(
+            explicit,
+            "line 1\nline 2\nline 3\nline 4\nline 5\n... (5 more lines)"
+        );
+        assert_eq!(short, "one\ntwo");
+    }
+
+    #[test]
+    fn test_filter_tools_enabled_and_disabled() {
+        let loader = SkillLoader::new(&[]);
+        let all = create_all_tools(&loader);
+        let names = |tools: &[Tool]| tools.iter().map(|t| t.name.clone()).collect::<Vec<_>>();
+
+        let offline = filter_tools(all.clone(), None, &["web_search".to_string()]);
+        assert!(names(&all).contains(&"web_search".to_string()));
+        assert!(!names(&offline).contains(&"web_search".to_string()));
+        assert_eq!(offline.len(), all.len() - 1);
+
+        let enabled = vec![
+            "bash".to_string(),
+            "read_file".to_string(),
+            "Task".to_string(),
+        ];
+        let minimal = filter_tools(all.clone(), Some(&enabled), &["Task".to_string()]);
+        assert_eq!(names(&minimal), vec!["bash", "read_file"]);
+
+        assert_eq!(
+            unknown_tool_names(&all, Some(&enabled), &["websearch".to_string()]),
+            vec!["websearch"]
+        );
+    }
+
+    #[test]
+    fn test_git_log_and_blame() {
+        let redactor = Redactor::new(&[], &[]).unwrap();
+        let dir = env::temp_dir().join(format!("v4_git_tools_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+
+        let outside = run_git_log(&dir, "lib.rs", 5, &redactor);
+        assert!(outside.contains("is not a git repository"), "{}", outside);
+
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(args)
+                .current_dir(&dir)
+                .env("GIT_AUTHOR_DATE", "2024-01-02T10:00:00Z")
+                .env("GIT_COMMITTER_DATE", "2024-01-02T10:00:00Z")
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success(), "git {:?}", args);
+        };
+        git(&["init", "-q"]);
+        git(&["config", "user.name", "Ada"]);
+        git(&["config", "user.email", "ada@example.com"]);
+        fs::write(dir.join("lib.rs"), "fn a() {}\n").unwrap();
+        git(&["add", "lib.rs"]);
+        git(&["commit", "-q", "-m", "Add a"]);
+        fs::write(dir.join("lib.rs"), "fn a() {}\nfn b() {}\n").unwrap();
+        git(&["commit", "-q", "-am", "Add b"]);
+
+        let log = run_git_log(&dir, "lib.rs", 10, &redactor);
+        let latest = run_git_log(&dir, "lib.rs", 1, &redactor);
+        let blame = run_git_blame(&dir, "lib.rs", Some(2), Some(2), &redactor);
+        let untracked = run_git_log(&dir, "other.rs", 10, &redactor);
+        fs::remove_dir_all(&dir).ok();
+
+        let subjects: Vec<&str> = log.lines().map(|l| l.split_once(": ").unwrap().1).collect();
+        assert_eq!(subjects, vec!["Add b", "Add a"]);
+        assert!(log.contains("2024-01-02 Ada"), "{}", log);
+        assert_eq!(latest.lines().count(), 1);
+        assert!(
+            blame.contains("Ada") && blame.contains("fn b() {}"),
+            "{}",
+            blame
+        );
+        assert!(!blame.contains("fn a()"), "{}", blame);
+        assert_eq!(untracked, "No git history for other.rs");
+    }
+
+    #[test]
+    fn test_rust_check_reports_diagnostics() {
+        let ok = run_rust_check(
+            "let x: u32 = 1 + 2;\nprintln!(\"{}\", x);",
+            RUST_CHECK_TIMEOUT,
+        );
+        assert_eq!(ok, "Compiles cleanly");
+
+        let bad = run_rust_check(
+            "fn main() {\n    let x: u32 = \"not a number\";\n    println!(\"{}\", x);\n}",
+            RUST_CHECK_TIMEOUT,
+        );
+        assert!(bad.starts_with("Does not compile:"), "{}", bad);
+        assert!(bad.contains("E0308"), "{}", bad);
+    }
+
+    #[tokio::test]
+    async fn test_read_files_reports_each_file_in_order() {
+        let dir = env::temp_dir().join(format!("v4_read_files_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for i in 0..5 {
+            fs::write(dir.join(format!("f{}.txt", i)), format!("file {}", i)).unwrap();
+        }
+        let paths: Vec<String> = [
+            "f0.txt",
+            "missing.txt",
+            "f1.txt",
+            "f2.txt",
+            "f3.txt",
+            "f4.txt",
+        ]
+        .iter()
+        .map(|p| p.to_string())
+        .collect();
+
+        let outputs = run_read_files(
+            &dir,
+            &paths,
+            None,
+            2,
+            &Arc::new(Redactor::new(&[], &[]).unwrap()),
+        )
+        .await;
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(outputs.len(), 6);
+        assert_eq!(outputs[0], "file 0");
+        assert!(outputs[1].starts_with("Error"), "{}", outputs[1]);
+        assert_eq!(&outputs[2..], ["file 1", "file 2", "file 3", "file 4"]);
+
+        let formatted = format_read_files(&paths[..2], &outputs[..2]);
+        assert!(formatted.starts_with("=== f0.txt ===\nfile 0\n\n=== missing.txt ===\nError"));
+    }
+
+    #[test]
+    fn test_context_overflow_detection() {
+        let message = |bytes: usize| Message {
+            role: Role::User,
+            content: vec![ContentBlock::text("x".repeat(bytes))],
+        };
+
+        assert_eq!(context_overflow(&[message(4000)], "system"), None);
+        // 4 bytes per token: 800k bytes is ~200k tokens, past window minus margin
+        assert_eq!(
+            context_overflow(&[message(400_000), message(400_000)], ""),
+            Some(200_000)
+        );
+        let at_limit = (tokens::CONTEXT_WINDOW - CONTEXT_SAFETY_MARGIN) * 4;
+        assert_eq!(context_overflow(&[message(at_limit)], ""), None);
+        assert!(context_overflow(&[message(at_limit + 4)], "").is_some());
+    }
+
+    #[test]
+    fn test_parallel_prompts_take_turns() {
+        /// Answers "y" slowly, counting how many prompts are waiting at once
+        struct SlowAnswer {
+            waiting: Arc<AtomicUsize>,
+            most: Arc<AtomicUsize>,
+        }
+
+        impl io::Read for SlowAnswer {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let now = self.waiting.fetch_add(1, Ordering::SeqCst) + 1;
+                self.most.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(50));
+                self.waiting.fetch_sub(1, Ordering::SeqCst);
+                buf[..2].copy_from_slice(b"y\n");
+                Ok(2)
+            }
+        }
+
+        let waiting = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let handles: Vec<_> = (0..4)
+            .map(|i| {
+                let mut reader = io::BufReader::new(SlowAnswer {
+                    waiting: waiting.clone(),
+                    most: most.clone(),
+                });
+                thread::spawn(move || {
+                    let input = json!({"command": format!("echo {}", i)});
+                    ask_approval(Path::new("."), "bash", &input, &mut reader)
                 })
-                .sum::<usize>()
+            })
+            .collect();
+
+        for handle in handles {
+            assert!(handle.join().unwrap());
+        }
+        assert_eq!(most.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_read_api_key_blank_keeps_current() {
+        let mut typed = io::Cursor::new(b"  sk-new-key \n".to_vec());
+        assert_eq!(read_api_key(&mut typed, "sk-old").unwrap(), "sk-new-key");
+        let mut blank = io::Cursor::new(b"\n".to_vec());
+        assert_eq!(read_api_key(&mut blank, "sk-old").unwrap(), "sk-old");
+    }
+
+    #[test]
+    fn test_ask_user_without_interactive_user() {
+        let mut unused = io::Cursor::new(b"should not be read\n".to_vec());
+        assert_eq!(
+            run_ask_user("Which database?", false, Some("postgres"), &mut unused),
+            "postgres"
+        );
+        assert!(run_ask_user("Which database?", false, None, &mut unused)
+            .starts_with("Error: no interactive user"));
+        assert_eq!(unused.position(), 0);
+
+        let mut answered = io::Cursor::new(b"sqlite\n".to_vec());
+        assert_eq!(
+            run_ask_user("Which database?", true, None, &mut answered),
+            "sqlite"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compact_history_prepends_facts() {
+        let user = |text: &str| Message {
+            role: Role::User,
+            content: vec![ContentBlock::text(text)],
+        };
+        let assistant = |text: &str| Message {
+            role: Role::Assistant,
+            content: vec![ContentBlock::text(text)],
+        };
+        let mut messages = vec![
+            user("fix the parser"),
+            assistant("fixed src/parse.rs"),
+            user("now the docs"),
+            assistant("updated README.md"),
+            user("thanks"),
+        ];
+
+        let summary = "## Summary\nFixed a parser bug.\n\n## Facts\n- Changed src/parse.rs\n- Decided to keep the old API";
+        let compacted = compact_history(&mut messages, 3, |request| async move {
+            let last = request.last().unwrap();
+            assert!(matches!(
+                &last.content[0],
+                ContentBlock::Text { text } if text.contains(FACTS_HEADER)
+            ));
+            Ok(text_response(summary))
         })
-        .sum();
+        .await
+        .unwrap();
+
+        assert_eq!(compacted, 2);
+        assert_eq!(messages.len(), 3);
+        let ContentBlock::Text { text: note } = &messages[0].content[0] else {
+            panic!("expected a text block");
+        };
+        assert_eq!(
+            note,
+            "[SYSTEM: 2 earlier messages were compacted into this summary.]\n\n\
+             ## Facts\n- Changed src/parse.rs\n- Decided to keep the old API\n\n\
+             ## Summary\nFixed a parser bug."
+        );
+        assert!(
+            matches!(&messages[0].content[1], ContentBlock::Text { text } if text == "now the docs")
+        );
+
+        // Under the window nothing is summarized
+        let untouched = compact_history(&mut messages, 3, |_| async {
+            anyhow::bail!("should not be called")
+        })
+        .await
+        .unwrap();
+        assert_eq!(untouched, 0);
+        assert!(compaction_note("no facts here", 4).contains("## Facts\n- (none recorded)"));
+    }
+
+    #[test]
+    fn test_trim_history_keeps_valid_roles() {
+        let user = |text: &str| Message {
+            role: Role::User,
+            content: vec![ContentBlock::text(text)],
+        };
+        let assistant = |text: &str| Message {
+            role: Role::Assistant,
+            content: vec![ContentBlock::text(text)],
+        };
+        let result = || Message {
+            role: Role::User,
+            content: vec![tool_result("t1".to_string(), "ok".to_string(), false)],
+        };
+
+        let mut messages = vec![
+            user("first"),
+            assistant("calling a tool"),
+            result(),
+            assistant("done"),
+            user("second"),
+            assistant("calling a tool"),
+            result(),
+            assistant("done again"),
+            user("third"),
+        ];
+
+        // 6 would start on a tool_result; the cut moves forward to "third"
+        let mut trimmed = messages.clone();
+        assert_eq!(trim_history(&mut trimmed, 3), 8);
+        assert_eq!(trimmed.len(), 1);
+
+        assert_eq!(trim_history(&mut messages, 6), 4);
+        assert_eq!(messages.len(), 5);
+        assert!(matches!(messages[0].role, Role::User));
+        assert!(!matches!(
+            messages[0].content[0],
+            ContentBlock::ToolResult { .. }
+        ));
+        for pair in messages.windows(2) {
+            assert_ne!(
+                matches!(pair[0].role, Role::User),
+                matches!(pair[1].role, Role::User)
+            );
+        }
+
+        assert_eq!(trim_history(&mut messages, 0), 0);
+        assert_eq!(trim_history(&mut messages, 20), 0);
+    }
+
+    #[test]
+    fn test_run_bash_output_is_redacted() {
+        let redactor = Redactor::new(&[], &[]).unwrap();
+        let (_, output) = run_bash_cancellable(
+            Path::new("."),
+            "echo ANTHROPIC_API_KEY=not-a-real-key-123; echo MODEL_NAME=claude",
+            0,
+            &CancellationToken::new(),
+            &str::to_string,
+        );
+
+        assert_eq!(
+            redactor.redact(&output),
+            "ANTHROPIC_API_KEY=***REDACTED***\nMODEL_NAME=claude"
+        );
+    }
+
+    #[test]
+    fn test_subagent_session_resume_reuses_history() {
+        let sessions = SubagentSessions::default();
+        let mut first = sessions.begin(None, "explore", "Find the parser").unwrap();
+        assert_eq!(first.id, "sub-1");
+        first.messages.push(Message {
+            role: Role::Assistant,
+            content: vec![ContentBlock::text("It's in src/parser.rs")],
+        });
+        sessions.store(first);
+
+        // The agent type argument is ignored on resume; the session keeps its own
+        let resumed = sessions
+            .begin(Some("sub-1"), "code", "Which function handles errors?")
+            .unwrap();
+        assert_eq!(resumed.agent_type, "explore");
+        assert_eq!(
+            resumed.messages,
+            vec![
+                Message {
+                    role: Role::User,
+                    content: vec![ContentBlock::text("Find the parser")],
+                },
+                Message {
+                    role: Role::Assistant,
+                    content: vec![ContentBlock::text("It's in src/parser.rs")],
+                },
+                Message {
+                    role: Role::User,
+                    content: vec![ContentBlock::text("Which function handles errors?")],
+                },
+            ]
+        );
+        // Taken out while running, so it can't be resumed twice at once
+        assert!(sessions.begin(Some("sub-1"), "explore", "again").is_err());
+    }
+
+    #[test]
+    fn test_subagent_sessions_are_capped() {
+        let sessions = SubagentSessions::default();
+        for _ in 0..MAX_SUBAGENT_SESSIONS + 2 {
+            let session = sessions.begin(None, "explore", "task").unwrap();
+            sessions.store(session);
+        }
+        assert!(sessions.begin(Some("sub-1"), "explore", "x").is_err());
+        assert!(sessions.begin(Some("sub-10"), "explore", "x").is_ok());
+    }
+
+    #[test]
+    fn test_search_results_dedupe_and_truncate() {
+        let result = |url: &str, snippet: String| SearchResult {
+            title: "Rust".to_string(),
+            url: url.to_string(),
+            snippet,
+        };
+        let results = dedupe_search_results(vec![
+            result("https://rust-lang.org/learn/", "a".into()),
+            result("https://rust-lang.org/learn?utm_source=ddg", "dup".into()),
+            result("https://rust-lang.org/learn#book", "dup".into()),
+            result("https://docs.rs/?q=serde&utm_medium=x", "b".repeat(3000)),
+        ]);
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(normalize_url(&results[1].url), "https://docs.rs?q=serde");
+
+        let small = format_search_results("rust", &results, 10_000);
+        assert!(small.contains(&"b".repeat(3000)));
+        assert!(!small.contains("shortened"));
+
+        let capped = format_search_results("rust", &results, 1000);
+        assert!(capped.contains("URL: https://docs.rs/?q=serde&utm_medium=x"));
+        assert!(!capped.contains(&"b".repeat(200)));
+        assert!(capped.ends_with("(1 snippets shortened to fit the 1000-byte limit)"));
+
+        // Too many results for even short snippets: every title and URL stays
+        let many: Vec<SearchResult> = (0..20)
+            .map(|i| SearchResult {
+                title: format!("Result {}", i),
+                url: format!("https://example.com/{}", i),
+                snippet: "z".repeat(500),
+            })
+            .collect();
+        let dropped = format_search_results("rust", &many, 1500);
+        for i in 0..20 {
+            assert!(dropped.contains(&format!("URL: https://example.com/{}\n", i)));
+        }
+        assert!(!dropped.contains('z'));
+        assert!(dropped.ends_with("(snippets dropped to fit the 1500-byte limit)"));
+    }
+
+    #[test]
+    fn test_format_debug_request_is_valid_json() {
+        let request = MessagesRequestBuilder::new(
+            "test-model",
+            vec![Message {
+                role: Role::User,
+                content: vec![ContentBlock::text("x".repeat(10_000))],
+            }],
+            1000,
+        )
+        .system(SystemPrompt::Text("You are a coding agent.".to_string()))
+        .tools(create_base_tools())
+        .build()
+        .unwrap();
+
+        let dump = format_debug_request(&request).unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(&dump).unwrap();
+
+        assert_eq!(parsed["model"], "test-model");
+        assert!(parsed["tools"].as_array().is_some_and(|t| !t.is_empty()));
+        let text = parsed["messages"][0]["content"][0]["text"]
+            .as_str()
+            .unwrap();
+        assert!(text.ends_with("... [8000 bytes truncated]"));
+    }
+
+    #[test]
+    fn test_system_prompt_persona_and_footer() {
+        let mut config = test_config(&env::temp_dir());
+
+        config.persona_name = None;
+        config.prompt_footer = None;
+        let plain = config.system_prompt("", "");
+        assert!(plain.starts_with("You are a coding agent at "));
+
+        config.persona_name = Some("Acme Helper".to_string());
+        config.prompt_footer = Some("Escalate outages to #oncall.".to_string());
+        let branded = config.system_prompt("", "");
+        assert!(branded.starts_with("You are Acme Helper, a coding agent, at "));
+        assert!(branded.contains("- Use TodoWrite to track multi-step work"));
+        assert!(branded.ends_with("\n\nEscalate outages to #oncall."));
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_switch_validates_before_committing() {
+        let checks = AtomicUsize::new(0);
+        let reachable = |candidate: Client| {
+            checks.fetch_add(1, Ordering::SeqCst);
+            async move { Ok(candidate) }
+        };
+        assert!(
+            connect_endpoint("http://localhost:8080", "sk-local", reachable)
+                .await
+                .is_ok()
+        );
+        assert_eq!(checks.load(Ordering::SeqCst), 1);
+
+        let unreachable = |_candidate: Client| async { anyhow::bail!("connection refused") };
+        let err = connect_endpoint("http://localhost:9", "sk-local", unreachable)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "Could not reach http://localhost:9: connection refused"
+        );
+
+        // Bad input is rejected before any request is made
+        let never = |_candidate: Client| async { panic!("should not be checked") };
+        assert!(connect_endpoint("localhost:8080", "sk-local", never)
+            .await
+            .is_err());
+        assert!(connect_endpoint("http://localhost:8080", " ", never)
+            .await
+            .is_err());
+
+        assert_eq!(
+            parse_endpoint_command("/endpoint http://localhost:8080/")
+                .unwrap()
+                .unwrap(),
+            ("http://localhost:8080".to_string(), false)
+        );
+        assert_eq!(
+            parse_endpoint_command("/endpoint https://proxy.example --key")
+                .unwrap()
+                .unwrap(),
+            ("https://proxy.example".to_string(), true)
+        );
+        assert!(parse_endpoint_command("/endpoint").unwrap().is_err());
+        assert!(parse_endpoint_command("/endpoints").is_none());
+    }
+
+    #[test]
+    fn test_turn_results_budget_cuts_largest_first() {
+        let mut results = vec![
+            tool_result("a".to_string(), "a".repeat(40_000), false),
+            tool_result("b".to_string(), "small output".to_string(), false),
+            tool_result("c".to_string(), "c".repeat(25_000), false),
+            ContentBlock::text("[format] `cargo fmt` made no changes"),
+        ];
+        let untouched = results_size(&results);
+        assert!(enforce_results_budget(&mut results, 0).is_none());
+        assert!(enforce_results_budget(&mut results, untouched).is_none());
+        assert_eq!(results_size(&results), untouched);
+
+        let note = enforce_results_budget(&mut results, 30_000).unwrap();
+        assert!(results_size(&results) <= 30_000);
+        assert!(note.contains("over the 30000-byte budget"));
+
+        let texts: Vec<String> = results
+            .iter()
+            .map(|block| match block {
+                ContentBlock::ToolResult {
+                    content: anthropic::types::ToolResultContent::Text(text),
+                    ..
+                } => text.clone(),
+                ContentBlock::Text { text } => text.clone(),
+                _ => String::new(),
+            })
+            .collect();
+        // Both large results are capped to the same length; the small one is whole
+        assert!(texts[0].ends_with(TURN_BUDGET_MARKER));
+        assert!(texts[2].ends_with(TURN_BUDGET_MARKER));
+        assert_eq!(texts[0].len(), texts[2].len());
+        assert_eq!(texts[1], "small output");
+        assert_eq!(texts[3], "[format] `cargo fmt` made no changes");
+        assert_eq!(texts[4], note);
+        // The budget is used, not just respected
+        assert!(results_size(&results) > 29_000);
+    }
+
+    #[test]
+    fn test_macro_expansion_and_run_command() {
+        assert_eq!(
+            expand_macro("Review {args} for bugs.", " src/parser.rs "),
+            "Review src/parser.rs for bugs."
+        );
+        assert_eq!(expand_macro("Review {args}", ""), "Review");
+        assert_eq!(
+            expand_macro("Run the tests and fix failures.", ""),
+            "Run the tests and fix failures."
+        );
+        assert_eq!(
+            expand_macro("Run the tests.", "only the parser ones"),
+            "Run the tests.\n\nonly the parser ones"
+        );
+
+        let path = env::temp_dir().join(format!("v4_macros_{}.toml", std::process::id()));
+        fs::write(
+            &path,
+            "[macros]\nreview = \"Review {args} and list fixes.\"\nship = \"Bump the version.\"\n",
+        )
+        .unwrap();
+        let macros = load_macros(&path).unwrap();
+        fs::write(&path, "[macros\n").unwrap();
+        let broken = load_macros(&path);
+        fs::remove_file(&path).ok();
+
+        assert!(load_macros(&path).unwrap().is_empty());
+        assert!(broken.is_err());
+        assert_eq!(
+            run_macro_command("/run review src/lib.rs docs/", &macros)
+                .unwrap()
+                .unwrap(),
+            "Review src/lib.rs docs/ and list fixes."
+        );
+        assert_eq!(
+            run_macro_command("/run ship", &macros).unwrap().unwrap(),
+            "Bump the version."
+        );
+        assert_eq!(
+            run_macro_command("/run deploy", &macros)
+                .unwrap()
+                .unwrap_err()
+                .to_string(),
+            "Unknown macro 'deploy' (macros: review, ship)"
+        );
+        assert!(run_macro_command("/run", &macros)
+            .unwrap()
+            .unwrap_err()
+            .to_string()
+            .starts_with("Usage: /run <macro> [args]"));
+        assert!(run_macro_command("/running late", &macros).is_none());
+        assert!(run_macro_command("fix the bug", &macros).is_none());
+    }
+
+    #[test]
+    fn test_explain_mode_adds_rule_and_rationales() {
+        let mut config = test_config(&env::temp_dir());
+
+        config.explain = false;
+        assert!(!config.system_prompt("", "").contains(EXPLAIN_RULE));
+        config.explain = true;
+        let prompt = config.system_prompt("", "");
+        assert!(prompt.contains(
+            "- After finishing, summarize what changed.\n- Before each tool call, write one sentence"
+        ));
+
+        let content = vec![
+            ContentBlock::text("Check the manifest for the crate name."),
+            ContentBlock::ToolUse {
+                id: "t1".to_string(),
+                name: "read_file".to_string(),
+                input: json!({"path": "Cargo.toml"}),
+            },
+            ContentBlock::ToolUse {
+                id: "t2".to_string(),
+                name: "bash".to_string(),
+                input: json!({"command": "ls"}),
+            },
+            ContentBlock::text("  Run the tests to see what fails.  "),
+            ContentBlock::ToolUse {
+                id: "t3".to_string(),
+                name: "bash".to_string(),
+                input: json!({"command": "cargo test"}),
+            },
+        ];
+        let rationales = tool_rationales(&content);
+        assert_eq!(rationales["t1"], "Check the manifest for the crate name.");
+        assert!(!rationales.contains_key("t2"));
+        assert_eq!(rationales["t3"], "Run the tests to see what fails.");
+    }
+
+    #[test]
+    fn test_skills_from_multiple_dirs_with_shadowing() {
+        let root = env::temp_dir().join(format!("v4_skills_path_{}", std::process::id()));
+        fs::remove_dir_all(&root).ok();
+        let global = root.join("global");
+        let project = root.join("project/skills");
+        create_skill_scaffold(&global, "shared").unwrap();
+        create_skill_scaffold(&global, "global-only").unwrap();
+        create_skill_scaffold(&project, "shared").unwrap();
+
+        let loader = SkillLoader::new(&[global.clone(), project.clone()]);
+        let listing = loader.list_with_sources();
+        fs::remove_dir_all(&root).ok();
+
+        assert_eq!(loader.list_skills().len(), 2);
+        assert_eq!(loader.skills["shared"].dir, project.join("shared"));
+        assert_eq!(
+            listing,
+            vec![
+                format!("global-only  ({})", global.display()),
+                format!("shared  ({})", project.display()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_skills_path() {
+        let workdir = Path::new("/work");
+        assert_eq!(
+            parse_skills_path(workdir, None, Some("/home/me")),
+            vec![
+                PathBuf::from("/home/me/.mini-code/skills"),
+                PathBuf::from("/work/skills")
+            ]
+        );
+        assert_eq!(
+            parse_skills_path(workdir, Some("/opt/skills: local :"), None),
+            vec![PathBuf::from("/opt/skills"), PathBuf::from("/work/local")]
+        );
+        // Without HOME the global default is skipped
+        assert_eq!(
+            parse_skills_path(workdir, Some(""), None),
+            vec![PathBuf::from("/work/skills")]
+        );
+    }
+
+    #[test]
+    fn test_read_with_timeout() {
+        let quick = read_with_timeout(Duration::from_secs(5), || "line".to_string());
+        let stalled = read_with_timeout(Duration::from_millis(50), || {
+            thread::sleep(Duration::from_secs(2));
+            "late".to_string()
+        });
+
+        assert_eq!(quick.as_deref(), Some("line"));
+        assert_eq!(stalled, None);
+    }
+
+    #[test]
+    fn test_approval_without_terminal_is_refused() {
+        let mut config = test_config(&env::temp_dir());
+        config.approval = Box::new(Interactive::all());
+
+        assert_eq!(
+            check_approval(&config, "bash", &json!({"command": "rm -rf build"})),
+            Some(
+                "Error: approval required but stdin is not interactive; bash was not run"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            check_approval(&config, "read_file", &json!({"path": "a.txt"})),
+            None
+        );
+    }
+
+    #[test]
+    fn test_confirm_edit_approve_and_deny() {
+        let dir = env::temp_dir().join(format!("v4_confirm_edit_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.rs"), "fn old() {}\n").unwrap();
+        let diff = edit_preview(&dir, "lib.rs", "old", "new");
+        let unmatched = edit_preview(&dir, "lib.rs", "missing", "new");
+        fs::remove_dir_all(&dir).ok();
+
+        let diff = diff.unwrap();
+        assert!(diff.contains("-fn old() {}"));
+        assert!(diff.contains("+fn new() {}"));
+        assert_eq!(unmatched, None);
+
+        assert!(confirm_edit(&diff, &mut io::Cursor::new("y\n")));
+        assert!(confirm_edit(&diff, &mut io::Cursor::new("YES\n")));
+        assert!(!confirm_edit(&diff, &mut io::Cursor::new("n\n")));
+        assert!(!confirm_edit(&diff, &mut io::Cursor::new("\n")));
+        assert!(!confirm_edit(&diff, &mut io::Cursor::new("")));
+    }
+
+    #[test]
+    fn test_confirm_edits_without_terminal_leaves_file() {
+        let dir = env::temp_dir().join(format!("v4_confirm_no_tty_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.rs"), "fn old() {}\n").unwrap();
+        // What MINI_CODE_CONFIRM_EDITS=1 sets up, under --json or piped stdin
+        let mut config = test_config(&dir);
+        config.confirm_edits = true;
+        config.approval = Box::new(Interactive::only(&["edit_file"]));
+        let loader = SkillLoader::new(&[]);
+
+        let output = dispatch_subagent_tool(
+            &config,
+            &config.tool_set(get_tools_for_subagent("code", &loader)),
+            &TodoManager::new(),
+            &loader,
+            &SessionStats::new(),
+            &ProcessRegistry::new(),
+            &CancellationToken::new(),
+            "edit_file",
+            &json!({"path": "lib.rs", "old_text": "old", "new_text": "new"}),
+        )
+        .text;
+        let content = fs::read_to_string(dir.join("lib.rs")).unwrap();
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(
+            output,
+            "Error: edit confirmation required but stdin is not interactive; the edit was not made"
+        );
+        assert_eq!(content, "fn old() {}\n");
+    }
+
+    #[test]
+    fn test_touched_files_buckets() {
+        let dir = env::temp_dir().join(format!("v4_manifest_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        fs::write(dir.join("a.txt"), "hello").unwrap();
+
+        let stats = SessionStats::new();
+        let read = run_read_with(&dir, "a.txt", None, false, None, &str::to_string);
+        stats.record_file(FileAccess::Read, &dir, "a.txt", &read);
+        let written = run_write_locked(&dir, "src/b.txt", "new", None, false);
+        stats.record_file(FileAccess::Write, &dir, "src/b.txt", &written);
+        let missing = run_read_with(&dir, "nope.txt", None, false, None, &str::to_string);
+        stats.record_file(FileAccess::Read, &dir, "nope.txt", &missing);
+        stats.record_file(FileAccess::Read, &dir, "./a.txt", &read);
+        fs::remove_dir_all(&dir).ok();
+
+        let manifest = stats.touched_files().to_json();
+        assert_eq!(manifest["read"], json!(["a.txt"]));
+        assert_eq!(manifest["written"], json!(["src/b.txt"]));
+        assert_eq!(manifest["edited"], json!([]));
+    }
+
+    #[test]
+    fn test_has_response_text() {
+        assert!(!has_response_text(&[]));
+        assert!(!has_response_text(&[ContentBlock::text("  \n ")]));
+        assert!(!has_response_text(&[ContentBlock::ToolUse {
+            id: "t1".into(),
+            name: "bash".into(),
+            input: json!({}),
+        }]));
+        assert!(has_response_text(&[
+            ContentBlock::text(""),
+            ContentBlock::text("Done.")
+        ]));
+        assert!(empty_subagent_result(4).contains("after 4 tool calls"));
+    }
+
+    #[test]
+    fn test_skill_reference_search() {
+        let dir = env::temp_dir().join(format!("v4_skill_reference_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let skill_dir = create_skill_scaffold(&dir, "pdf").unwrap();
+        let skill_dir = skill_dir.parent().unwrap();
+        fs::create_dir_all(skill_dir.join("references/forms")).unwrap();
+        fs::write(
+            skill_dir.join("references/forms/fields.md"),
+            "# Form fields\nUse AcroForm to fill fields.\nFlatten afterwards.\n",
+        )
+        .unwrap();
+        fs::write(skill_dir.join("references/api.md"), "Nothing here.\n").unwrap();
+        let loader = SkillLoader::new(std::slice::from_ref(&dir));
+
+        let found = run_skill_reference(&loader, "pdf", "acroform FIELDS");
+        let none = run_skill_reference(&loader, "pdf", "encryption");
+        let missing = run_skill_reference(&loader, "nope", "acroform");
+        fs::remove_dir_all(&dir).ok();
+
+        assert!(found.starts_with("1 match(es) for 'acroform FIELDS' in pdf:"));
+        assert!(found.contains(
+            "references/forms/fields.md:2\n# Form fields\nUse AcroForm to fill fields.\nFlatten afterwards."
+        ));
+        assert!(none.starts_with("No matches"));
+        assert!(missing.starts_with("Error:"));
+    }
+
+    #[test]
+    fn test_skill_resource_manifest() {
+        let dir = env::temp_dir().join(format!("v4_skill_manifest_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        let skill_dir = create_skill_scaffold(&dir, "tooling").unwrap();
+        let skill_dir = skill_dir.parent().unwrap();
+        fs::create_dir_all(skill_dir.join("scripts")).unwrap();
+        fs::write(skill_dir.join("scripts/setup.sh"), "#!/bin/sh").unwrap();
+        fs::write(skill_dir.join("scripts/check.py"), "print()").unwrap();
+        create_skill_scaffold(&dir, "bare").unwrap();
+        let loader = SkillLoader::new(std::slice::from_ref(&dir));
+
+        let manifest = loader.resource_manifest("tooling");
+        let info = run_skill_info(&loader, "tooling");
+        let bare = run_skill_info(&loader, "bare");
+        let missing = run_skill_info(&loader, "missing");
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(
+            manifest,
+            Some(vec!["Scripts: check.py, setup.sh".to_string()])
+        );
+        assert!(info.contains("- Scripts: check.py, setup.sh"));
+        assert!(!info.contains("# Skill:"));
+        assert!(bare.ends_with("No bundled resources."));
+        assert!(missing.starts_with("Error:"));
+    }
+
+    #[test]
+    fn test_subagent_prompt_includes_project_context() {
+        let dir = env::temp_dir().join(format!("v4_project_ctx_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("AGENTS.md"), "Use anyhow for errors.\n").unwrap();
+        let context = load_project_context(&dir, "AGENTS.md");
+        let disabled = load_project_context(&dir, "");
+        let missing = load_project_context(&dir, "NOPE.md");
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(context.as_deref(), Some("Use anyhow for errors."));
+        assert_eq!(disabled, None);
+        assert_eq!(missing, None);
+
+        let with = subagent_system_prompt(&dir, context.as_deref(), "code", "Implement.");
+        let without = subagent_system_prompt(&dir, None, "code", "Implement.");
+        assert!(with.starts_with("You were spawned by a parent agent."));
+        assert!(with.contains("Use anyhow for errors.\n\nYou are a code subagent"));
+        assert!(without.starts_with("You are a code subagent"));
+    }
+
+    #[test]
+    fn test_bash_exit_code_sets_error_flag() {
+        let dir = env::temp_dir().join(format!("v4_bash_error_flag_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = test_config(&dir);
+        let loader = SkillLoader::new(&[]);
+        let bash = |command: &str| {
+            let output = execute_tool(
+                &config,
+                &TodoManager::new(),
+                &loader,
+                &SessionStats::new(),
+                &ProcessRegistry::new(),
+                &CancellationToken::new(),
+                "bash",
+                &json!({ "command": command }),
+            );
+            let block = tool_result("t".into(), output.text.clone(), output.failed);
+            let ContentBlock::ToolResult { is_error, .. } = block else {
+                unreachable!()
+            };
+            (output.text, is_error)
+        };
+
+        let ok = bash("echo fine");
+        let failed = bash("echo broken >&2; exit 3");
+        // Printing the marker doesn't make a successful command look failed
+        let spoofed = bash("echo 'log line'; echo '[exit code: 1]'");
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(ok, ("fine".to_string(), None));
+        assert_eq!(failed, ("broken\n[exit code: 3]".to_string(), Some(true)));
+        assert_eq!(spoofed, ("log line\n[exit code: 1]".to_string(), None));
+    }
+
+    #[test]
+    fn test_context_glob_expansion() {
+        let redactor = Redactor::new(&[], &[]).unwrap();
+        let dir = env::temp_dir().join(format!("v4_context_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("src/a.rs"), "fn a() {}").unwrap();
+        fs::write(dir.join("src/b.rs"), "fn b() {}").unwrap();
+        fs::write(dir.join("notes.md"), "notes").unwrap();
+        fs::create_dir_all(dir.join("target/debug")).unwrap();
+        fs::write(dir.join("target/debug/build.rs"), "fn gen() {}").unwrap();
+        let dir = dir.canonicalize().unwrap();
+        let ignore = IgnoreMatcher::new(&dir, &[]).unwrap();
+        let forced = IgnoreMatcher::new(&dir, &["!target/".to_string()]).unwrap();
+
+        let patterns = vec!["src/*.rs".to_string(), "src/a.rs".to_string()];
+        let files = expand_context_globs(&dir, &patterns, &ignore).unwrap();
+        let preload = load_context_files(&dir, &patterns, &ignore, &redactor).unwrap();
+        let escape = expand_context_globs(&dir.join("src"), &["../*.md".to_string()], &ignore);
+        let empty = expand_context_globs(&dir, &["*.txt".to_string()], &ignore);
+        let everything = vec!["**/*.rs".to_string()];
+        let skipped = expand_context_globs(&dir, &everything, &ignore).unwrap();
+        let included = expand_context_globs(&dir, &everything, &forced).unwrap();
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(files, vec![dir.join("src/a.rs"), dir.join("src/b.rs")]);
+        // target/ is skipped by default, but a `!target/` rule brings it back
+        assert_eq!(skipped, files);
+        assert!(included.contains(&dir.join("target/debug/build.rs")));
+        assert_eq!(included.len(), 3);
+        assert_eq!(preload.files, 2);
+        assert!(preload.text.contains("=== src/a.rs ===\nfn a() {}"));
+        assert!(escape
+            .unwrap_err()
+            .to_string()
+            .contains("escapes workspace"));
+        assert!(empty.is_err());
+    }
+
+    #[test]
+    fn test_editable_extensions_allowlist() {
+        let allowed = parse_extension_list("md, .TXT");
+        assert_eq!(allowed, vec!["md", "txt"]);
+
+        assert_eq!(check_editable("docs/guide.md", &allowed), Ok(()));
+        assert_eq!(check_editable("NOTES.TXT", &allowed), Ok(()));
+        assert_eq!(
+            check_editable("src/main.rs", &allowed),
+            Err("Error: editing .rs files is not permitted".to_string())
+        );
+        assert!(check_editable("Makefile", &allowed).is_err());
+        assert_eq!(check_editable("src/main.rs", &[]), Ok(()));
+    }
+
+    #[test]
+    fn test_subagent_timeline_only_when_verbose() {
+        let mut progress = SubagentProgress::new();
+        progress.record_call(
+            "bash",
+            &json!({"command": "cargo test"}),
+            Duration::from_millis(1500),
+        );
+        progress.record_call(
+            "read_file",
+            &json!({"path": "x".repeat(100)}),
+            Duration::ZERO,
+        );
+        assert_eq!(progress.timeline.len(), 2);
+
+        let summary = "Tests pass.".to_string();
+        assert_eq!(progress.with_timeline(summary.clone(), false), summary);
+
+        let verbose = progress.with_timeline(summary, true);
+        let lines: Vec<&str> = verbose.lines().collect();
+        assert_eq!(lines[0], "Tests pass.");
+        assert_eq!(lines[2], "Tool timeline:");
+        assert_eq!(lines[3], r#"  1. bash {"command":"cargo test"} (1.5s)"#);
+        assert!(lines[4].starts_with(r#"  2. read_file {"path":"xxx"#));
+        assert!(lines[4].ends_with("... (0.0s)"));
+    }
+
+    #[test]
+    fn test_subagent_deadline_check() {
+        let mut progress = SubagentProgress::new();
+        assert!(progress.remaining(Duration::from_secs(300)).is_ok());
+
+        progress.start_time = Instant::now()
+            .checked_sub(Duration::from_secs(301))
+            .unwrap();
+        progress.tool_count = 7;
+        assert_eq!(
+            progress.remaining(Duration::from_secs(300)),
+            Err("[ERROR] Subagent exceeded time budget (300s, 7 tools)".to_string())
+        );
+    }
+
+    #[test]
+    fn test_validate_file_json() {
+        let redactor = Redactor::new(&[], &[]).unwrap();
+        let dir = env::temp_dir().join(format!("v4_validate_file_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("ok.json"), r#"{"b": [1, 2], "a": true}"#).unwrap();
+        fs::write(dir.join("bad.json"), "{\n  \"a\": 1,\n  \"b\" 2\n}\n").unwrap();
+        fs::write(dir.join("notes.txt"), "hi").unwrap();
+
+        let valid = run_validate_file(&dir, "ok.json", false, &redactor);
+        let canonical = run_validate_file(&dir, "ok.json", true, &redactor);
+        let invalid = run_validate_file(&dir, "bad.json", false, &redactor);
+        let unsupported = run_validate_file(&dir, "notes.txt", false, &redactor);
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(valid, "valid");
+        assert!(canonical.starts_with("valid\n\n{\n  \"a\": true,"));
+        assert!(
+            invalid.starts_with("invalid: line 3, column 7:"),
+            "{}",
+            invalid
+        );
+        assert!(unsupported.starts_with("Error: Unsupported file type '.txt'"));
+    }
+
+    #[test]
+    fn test_parse_config_file_yaml_and_toml_errors() {
+        let yaml = parse_config_file("yaml", "a: 1\nb: [1, 2\n").unwrap();
+        assert!(yaml.unwrap_err().starts_with("line "));
+
+        let toml = parse_config_file("toml", "[package]\nname = \n").unwrap();
+        assert!(toml.unwrap_err().starts_with("line 2, column 8:"));
+        assert_eq!(
+            parse_config_file("toml", "b = 1\na = \"x\"\n")
+                .unwrap()
+                .unwrap(),
+            "a = \"x\"\nb = 1\n"
+        );
+    }
+
+    #[test]
+    fn test_run_count_tokens() {
+        let text = "word ".repeat(200); // 1000 bytes
+        assert_eq!(
+            run_count_tokens(Path::new("."), Some(&text), None),
+            "text: ~250 tokens (1000 bytes, 1 lines)"
+        );
+
+        let dir = env::temp_dir().join(format!("v4_count_tokens_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("notes.md"), "abcd\nefgh\n").unwrap();
+        let from_file = run_count_tokens(&dir, None, Some("notes.md"));
+        let neither = run_count_tokens(&dir, None, None);
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(from_file, "notes.md: ~2 tokens (10 bytes, 2 lines)");
+        assert!(neither.starts_with("Error:"));
+    }
+
+    #[test]
+    fn test_run_skill_records_token_cost() {
+        let dir = env::temp_dir().join(format!("v4_skill_tokens_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        create_skill_scaffold(&dir, "lean").unwrap();
+        let loader = SkillLoader::new(std::slice::from_ref(&dir));
+        let stats = SessionStats::new();
+
+        let first = run_skill(&loader, &stats, "lean");
+        run_skill(&loader, &stats, "lean");
+        run_skill(&loader, &stats, "missing");
+        fs::remove_dir_all(&dir).ok();
+
+        let counters = stats.snapshot();
+        assert_eq!(
+            counters.skill_tokens.get("lean"),
+            Some(&(estimate_tokens(&first) * 2))
+        );
+        assert!(!counters.skill_tokens.contains_key("missing"));
+        assert!(stats.render().contains("Skills:     lean ~"));
+    }
+
+    struct BufferWriter {
+        lines: Mutex<Vec<String>>,
+    }
+
+    impl Writer for BufferWriter {
+        fn line(&self, text: &str) {
+            self.lines.lock().unwrap().push(text.to_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_context_ends_turn_with_valid_history() {
+        let mut config = test_config(&env::temp_dir());
+        config.compact_history = false;
+        config.max_history_messages = 0;
+        let client = build_client("test-key", Some("http://127.0.0.1:9")).unwrap();
+        let out = BufferWriter {
+            lines: Mutex::new(Vec::new()),
+        };
+
+        // Overflow noticed after a tool result was pushed, mid-turn
+        let mut messages = vec![
+            Message {
+                role: Role::User,
+                content: vec![ContentBlock::text("dump the log")],
+            },
+            Message {
+                role: Role::Assistant,
+                content: vec![ContentBlock::ToolUse {
+                    id: "t1".to_string(),
+                    name: "bash".to_string(),
+                    input: json!({"command": "cat big.log"}),
+                }],
+            },
+            Message {
+                role: Role::User,
+                content: vec![tool_result(
+                    "t1".to_string(),
+                    "x".repeat(tokens::CONTEXT_WINDOW * 4),
+                    false,
+                )],
+            },
+        ];
+        let result = agent_loop(
+            &client,
+            &config,
+            &SkillLoader::new(&[]),
+            &SessionStats::new(),
+            &ProcessRegistry::new(),
+            &out,
+            &mut messages,
+        )
+        .await;
+
+        assert!(result.is_ok());
+        assert_eq!(messages.len(), 4);
+        assert!(matches!(
+            messages[2].content[0],
+            ContentBlock::ToolResult { .. }
+        ));
+        assert_eq!(messages[3].role, Role::Assistant);
+        assert_eq!(
+            messages[3].content,
+            vec![ContentBlock::text(CONTEXT_TOO_LARGE_TURN)]
+        );
+        let lines = out.lines.lock().unwrap();
+        assert!(lines.iter().any(|l| l.contains("Context too large")));
+    }
+
+    #[test]
+    fn test_writer_captures_tool_lines() {
+        colored::control::set_override(false);
+        let out = BufferWriter {
+            lines: Mutex::new(Vec::new()),
+        };
+        display_tool_output(&out, "bash", "hello");
+        display_tool_output(&out, "read_file", "Error: Path escapes workspace: ../x");
+        display_tool_output(&out, "read_file", &"x".repeat(400));
+        display_tool_output(&out, "Task", "summary printed by run_task");
+        display_tool_output(&out, "Skill", "# pdf\nbody");
+        colored::control::unset_override();
+
+        let lines = out.lines.lock().unwrap();
+        assert_eq!(
+            *lines,
+            vec![
+                "  hello".to_string(),
+                "Error: Path escapes workspace: ../x".to_string(),
+                format!("  {}...", "x".repeat(300)),
+                "Skill loaded: # pdf".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_workdir_rejects_missing_and_files() {
+        let dir = env::temp_dir().join(format!("v4_workdir_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("notes.txt");
+        fs::write(&file, "x").unwrap();
+        let missing = dir.join("gone");
+
+        let missing_err = resolve_workdir(Some(&missing)).unwrap_err().to_string();
+        let file_err = resolve_workdir(Some(&file)).unwrap_err().to_string();
+        let ok = resolve_workdir(Some(&dir)).unwrap();
+        let canonical = dir.canonicalize().unwrap();
+        let read = run_read_with(&missing, "a.txt", None, false, None, &str::to_string);
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(
+            missing_err,
+            format!("Workspace {} does not exist", missing.display())
+        );
+        assert!(file_err.contains("is not a directory"));
+        assert_eq!(ok, canonical);
+        assert!(read.contains("no longer exists"), "{}", read);
+    }
+
+    #[test]
+    fn test_tee_writer_strips_ansi() {
+        let path = env::temp_dir().join(format!("v4_tee_{}.log", std::process::id()));
+        fs::remove_file(&path).ok();
+
+        let tee = TeeWriter::open(&path, 0, DEFAULT_LOG_KEEP).unwrap();
+        tee.line("\x1b[93m> bash\x1b[0m");
+        drop(tee);
+        let logged = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).ok();
+
+        assert_eq!(logged, "> bash\n");
+    }
+
+    #[test]
+    fn test_replace_in_files_dry_run_and_apply() {
+        let workdir = env::temp_dir().join(format!("v4_replace_{}", std::process::id()));
+        fs::remove_dir_all(&workdir).ok();
+        fs::create_dir_all(workdir.join("src/net")).unwrap();
+        fs::create_dir_all(workdir.join("target/debug")).unwrap();
+        fs::write(
+            workdir.join("src/lib.rs"),
+            "use old_name;\nfn a() { old_name(); old_name(); }\n",
+        )
+        .unwrap();
+        fs::write(workdir.join("src/net/http.rs"), "old_name::get();\n").unwrap();
+        fs::write(workdir.join("README.md"), "Call old_name to start.\n").unwrap();
+        fs::write(workdir.join("target/debug/gen.rs"), "old_name();\n").unwrap();
+        fs::write(workdir.join("src/logo.bin"), [0xff, 0xfe, 0x00]).unwrap();
+        let workdir = workdir.canonicalize().unwrap();
+        let ignore = IgnoreMatcher::new(&workdir, &[]).unwrap();
+        let replace = |glob: Option<&str>, dry_run: bool| {
+            run_replace_in_files(
+                &workdir,
+                r"\bold_(name)\b",
+                "new_$1",
+                glob,
+                dry_run,
+                &ignore,
+                &[],
+            )
+        };
+
+        let dry = replace(Some("**/*.rs"), true);
+        let untouched = fs::read_to_string(workdir.join("src/lib.rs")).unwrap();
+        let shallow = replace(Some("src/*.rs"), true);
+        let applied = replace(Some("**/*.rs"), false);
+        let lib = fs::read_to_string(workdir.join("src/lib.rs")).unwrap();
+        let http = fs::read_to_string(workdir.join("src/net/http.rs")).unwrap();
+        let readme = fs::read_to_string(workdir.join("README.md")).unwrap();
+        let generated = fs::read_to_string(workdir.join("target/debug/gen.rs")).unwrap();
+        let again = replace(None, false);
+        let bad = run_replace_in_files(&workdir, "(", "x", None, true, &ignore, &[]);
+        fs::remove_dir_all(&workdir).ok();
+
+        assert_eq!(
+            dry,
+            "Would edit src/lib.rs (3 replacements)\n\
+             Would edit src/net/http.rs (1 replacement)\n\
+             \nDry run: 2 file(s) would change, 4 replacement(s); nothing written"
+        );
+        assert!(untouched.contains("old_name"));
+        assert!(shallow.starts_with("Would edit src/lib.rs (3 replacements)\n\nDry run: 1 file"));
+
+        assert_eq!(
+            applied,
+            "Edited src/lib.rs (3 replacements)\n\
+             Edited src/net/http.rs (1 replacement)\n\
+             \n2 file(s) changed, 4 replacement(s)"
+        );
+        assert_eq!(lib, "use new_name;\nfn a() { new_name(); new_name(); }\n");
+        assert_eq!(http, "new_name::get();\n");
+        // Outside the glob, and under target/, nothing changes
+        assert_eq!(readme, "Call old_name to start.\n");
+        assert_eq!(generated, "old_name();\n");
+        assert_eq!(
+            edited_paths("replace_in_files", &json!({}), &applied),
+            vec!["src/lib.rs", "src/net/http.rs"]
+        );
+        assert!(edited_paths("replace_in_files", &json!({}), &dry).is_empty());
+
+        // Without a glob the README is the only file still matching
+        assert!(again.starts_with("Edited README.md (1 replacement)"));
+        assert!(bad.starts_with("Error: Invalid pattern"));
+    }
+
+    #[test]
+    fn test_rust_rename_tool_rewrites_file() {
+        let workdir = env::temp_dir().join(format!("v4_rust_rename_{}", std::process::id()));
+        fs::create_dir_all(&workdir).unwrap();
+        let source = "fn parse(parser: &str) -> usize {\n    // parse it\n    parser.len()\n}\n\nfn main() {\n    parse(\"parse\");\n}\n";
+        fs::write(workdir.join("lib.rs"), source).unwrap();
+        fs::write(workdir.join("broken.rs"), "fn parse( {").unwrap();
+
+        let output = run_rust_rename(&workdir, "lib.rs", "parse", "tokenize");
+        let renamed = fs::read_to_string(workdir.join("lib.rs")).unwrap();
+        let missing = run_rust_rename(&workdir, "lib.rs", "parse", "lex");
+        let broken = run_rust_rename(&workdir, "broken.rs", "parse", "lex");
+        let not_rust = run_rust_rename(&workdir, "notes.md", "parse", "lex");
+        fs::remove_dir_all(&workdir).ok();
+
+        assert_eq!(
+            output,
+            "Edited lib.rs: renamed 2 occurrence(s) of `parse` to `tokenize`"
+        );
+        assert_eq!(
+            renamed,
+            "fn tokenize(parser: &str) -> usize {\n    // parse it\n    parser.len()\n}\n\nfn main() {\n    tokenize(\"parse\");\n}\n"
+        );
+        assert_eq!(missing, "Error: No identifier `parse` in lib.rs");
+        assert!(broken.starts_with("Error: broken.rs not valid Rust"));
+        assert!(not_rust.contains("only handles .rs files"));
+        assert_eq!(
+            edited_paths("rust_rename", &json!({"path": "lib.rs"}), &output),
+            vec!["lib.rs"]
+        );
+    }
 
-    let system_tokens = system.len() / 4;
-    messages_tokens + system_tokens
-}
+    #[test]
+    fn test_tool_input_validated_against_read_file_schema() {
+        let tools = create_base_tools();
+        let validate = |input: serde_json::Value| validate_tool_input(&tools, "read_file", &input);
 
-fn calculate_max_tokens(messages: &[Message], system: &str, max_output_tokens: u32) -> u32 {
-    const MAX_CONTEXT: usize = 200000;
-    const OUTPUT_RATIO: f64 = 0.4;
+        assert_eq!(validate(json!({"path": "src/main.rs"})), None);
+        assert_eq!(validate(json!({"path": "src/main.rs", "limit": 20})), None);
 
-    let context = estimate_context_tokens(messages, system);
-    let available = MAX_CONTEXT.saturating_sub(context);
-    let max_output = (available as f64 * OUTPUT_RATIO) as u32;
+        assert_eq!(
+            validate(json!({"path": "src/main.rs", "limit": "20"})).unwrap(),
+            "Error: Invalid input for read_file:\n- /limit: \"20\" is not of type \"integer\""
+        );
+        let missing = validate(json!({"limit": 20})).unwrap();
+        assert_eq!(
+            missing,
+            "Error: Invalid input for read_file:\n- \"path\" is a required property"
+        );
+        // Every problem is listed, not just the first
+        let both = validate(json!({"path": 7, "limit": "all"})).unwrap();
+        assert_eq!(both.lines().count(), 3);
+        assert!(both.contains("- /path: 7 is not of type \"string\""));
+
+        // Unknown tools fall through to execute_tool's own handling
+        assert_eq!(
+            validate_tool_input(&tools, "no_such_tool", &json!({})),
+            None
+        );
 
-    max_output.min(max_output_tokens).max(4000)
-}
+        // A schema that fails to compile would silently skip validation
+        for tool in create_all_tools(&SkillLoader::new(&[])) {
+            assert!(
+                jsonschema::validator_for(&tool.input_schema).is_ok(),
+                "{} has an invalid input_schema",
+                tool.name
+            );
+        }
+    }
 
-// =============================================================================
-// Main Agent Loop (adapted for v4 with Skills + Task + Todo)
-// =============================================================================
+    #[test]
+    fn test_disabled_tool_call_is_refused() {
+        let dir = env::temp_dir().join(format!("v4_disabled_tool_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut config = test_config(&dir);
+        config.disabled_tools = vec!["Task".to_string(), "bash".to_string()];
+        let loader = SkillLoader::new(&[]);
+
+        let tools = config.tool_set(create_all_tools(&loader));
+        assert_eq!(
+            check_tool_available(&tools, "Task"),
+            Some("Error: tool Task is not available".to_string())
+        );
+        assert_eq!(check_tool_available(&tools, "read_file"), None);
+
+        // A subagent naming the disabled tool anyway doesn't run it
+        let sub_tools = config.tool_set(get_tools_for_subagent("code", &loader));
+        let output = dispatch_subagent_tool(
+            &config,
+            &sub_tools,
+            &TodoManager::new(),
+            &loader,
+            &SessionStats::new(),
+            &ProcessRegistry::new(),
+            &CancellationToken::new(),
+            "bash",
+            &json!({"command": "touch ran"}),
+        )
+        .text;
+        let ran = dir.join("ran").exists();
+        fs::remove_dir_all(&dir).ok();
 
-async fn agent_loop(
-    client: &Client,
-    config: &Config,
-    skill_loader: &SkillLoader,
-    messages: &mut Vec<Message>,
-) -> Result<()> {
-    let todo_manager = TodoManager::new();
+        assert_eq!(output, "Error: tool bash is not available");
+        assert!(!ran);
+    }
 
-    let skill_descriptions = skill_loader.get_descriptions();
-    let agent_descriptions = get_agent_descriptions();
-    let system = config.system_prompt(&skill_descriptions, &agent_descriptions);
+    #[test]
+    fn test_diff_save_as_is_a_checked_write() {
+        let loader = SkillLoader::new(&[]);
+        let dir = env::temp_dir().join(format!("v4_diff_save_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.rs"), "fn a() {}\n").unwrap();
+        let mut config = test_config(&dir);
+
+        // Read-only agents can diff but not save the result
+        for read_only in ["explore", "plan"] {
+            let sub_tools = get_tools_for_subagent(read_only, &loader);
+            let diff = sub_tools.iter().find(|t| t.name == "diff").unwrap();
+            assert!(diff.input_schema["properties"].get("save_as").is_none());
+
+            let dispatch = |input: serde_json::Value| {
+                dispatch_subagent_tool(
+                    &config,
+                    &sub_tools,
+                    &TodoManager::new(),
+                    &loader,
+                    &SessionStats::new(),
+                    &ProcessRegistry::new(),
+                    &CancellationToken::new(),
+                    "diff",
+                    &input,
+                )
+                .text
+            };
+            let shown = dispatch(json!({"path": "a.rs", "content": "fn b() {}\n"}));
+            assert!(shown.contains("+fn b() {}"), "{}: {}", read_only, shown);
+            assert_eq!(
+                dispatch(json!({"path": "a.rs", "content": "fn b() {}\n", "save_as": "a.patch"})),
+                "Error: save_as is not available to read-only agents; return the diff instead"
+            );
+            assert!(!dir.join("a.patch").exists());
+        }
 
-    let tools = create_all_tools(skill_loader);
+        config.editable_extensions = vec!["rs".to_string()];
+
+        let output = execute_tool(
+            &config,
+            &TodoManager::new(),
+            &loader,
+            &SessionStats::new(),
+            &ProcessRegistry::new(),
+            &CancellationToken::new(),
+            "diff",
+            &json!({"path": "a.rs", "content": "fn b() {}\n", "save_as": "a.diff"}),
+        )
+        .text;
+        let saved = dir.join("a.diff").exists();
+        fs::remove_dir_all(&dir).ok();
 
-    let mut consecutive_truncations = 0;
+        assert_eq!(output, "Error: editing .diff files is not permitted");
+        assert!(!saved);
+    }
 
-    loop {
-        let max_tokens = calculate_max_tokens(messages, &system, config.max_output_tokens);
+    #[test]
+    fn test_heartbeat_interval() {
+        let secs = Duration::from_secs;
+        assert_eq!(heartbeats_due(secs(0), 30), 0);
+        assert_eq!(heartbeats_due(secs(29), 30), 0);
+        assert_eq!(heartbeats_due(secs(30), 30), 1);
+        assert_eq!(heartbeats_due(Duration::from_millis(89_999), 30), 2);
+        assert_eq!(heartbeats_due(secs(120), 30), 4);
+        assert_eq!(heartbeats_due(secs(7), 5), 1);
+        // 0 turns the heartbeat off
+        assert_eq!(heartbeats_due(secs(600), 0), 0);
+    }
 
-        let request = MessagesRequestBuilder::new(&config.model, messages.clone(), max_tokens)
-            .system(SystemPrompt::Text(system.clone()))
-            .tools(tools.clone())
-            .build()?;
+    #[test]
+    fn test_log_entries_carry_session_id() {
+        let path = env::temp_dir().join(format!("v4_tee_session_{}.log", std::process::id()));
+        fs::remove_file(&path).ok();
+
+        let tee = TeeWriter::open(&path, 0, DEFAULT_LOG_KEEP)
+            .unwrap()
+            .tagged("3f2b6c1e");
+        tee.line("\x1b[93m> bash\x1b[0m");
+        tee.line("  ok");
+        drop(tee);
+        let logged = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).ok();
+
+        assert_eq!(logged, "[3f2b6c1e] > bash\n[3f2b6c1e]   ok\n");
+        assert_eq!(subagent_log_id("3f2b6c1e", "sub-2"), "3f2b6c1e/sub-2");
+
+        // Sessions started without MINI_CODE_SESSION_ID get distinct UUIDs
+        let dir = env::temp_dir();
+        let first = Config::from_env(Some(&dir), None).unwrap().session_id;
+        let second = Config::from_env(Some(&dir), None).unwrap().session_id;
+        if env::var("MINI_CODE_SESSION_ID").is_err() {
+            assert_ne!(first, second);
+            assert!(uuid::Uuid::parse_str(&first).is_ok());
+        }
+    }
 
-        let animation = spawn_thinking_animation();
-        let response = client.messages(request).await?;
-        drop(animation);
+    #[test]
+    fn test_tee_writer_rotates_past_max_bytes() {
+        let dir = env::temp_dir().join(format!("v4_tee_rotate_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("session.log");
+
+        // Each line is 6 bytes, so every third line starts a new file
+        let tee = TeeWriter::open(&path, 12, 2).unwrap();
+        for line in [
+            "line1", "line2", "line3", "line4", "line5", "line6", "line7",
+        ] {
+            tee.line(line);
+        }
+        drop(tee);
 
-        match response.stop_reason {
-            Some(StopReason::MaxTokens) => {
-                consecutive_truncations += 1;
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap_or_default();
+        let (current, first, second) = (
+            read("session.log"),
+            read("session.log.1"),
+            read("session.log.2"),
+        );
+        let third = dir.join("session.log.3").exists();
+        fs::remove_dir_all(&dir).ok();
 
-                println!(
-                    "{} {}",
-                    "Warning:".bright_yellow(),
-                    format!(
-                        "Response truncated (attempt {}/{})",
-                        consecutive_truncations, config.max_truncation_retries
-                    )
-                    .bright_black()
-                );
+        assert_eq!(current, "line7\n");
+        assert_eq!(first, "line5\nline6\n");
+        assert_eq!(second, "line3\nline4\n");
+        assert!(!third, "only `keep` rotated files survive");
+    }
 
-                if consecutive_truncations >= config.max_truncation_retries {
-                    anyhow::bail!(
-                        "Error: Response truncated {} times in a row. Task may be too complex.\n\n\
-                         Hint: Break the task into smaller steps, or write large outputs\n\
-                         to files using write_file.\n\n\
-                         You can also increase MINI_CODE_MAX_OUTPUT_TOKENS (current: {})",
-                        consecutive_truncations,
-                        config.max_output_tokens
-                    );
-                }
+    #[test]
+    fn test_salvage_truncated_text() {
+        let partials = vec![
+            "A long first attempt that got cut off mid".to_string(),
+            "Short summary".to_string(),
+        ];
+        assert_eq!(
+            salvage_truncated_text(&partials, 3, 8000).unwrap(),
+            "A long first attempt that got cut off mid"
+        );
 
-                messages.push(Message {
-                    role: Role::Assistant,
-                    content: response.content,
-                });
+        let err = salvage_truncated_text(&[], 3, 8000).unwrap_err();
+        assert!(err.to_string().contains("no usable text"));
+    }
 
-                messages.push(Message {
-                    role: Role::User,
-                    content: vec![ContentBlock::text(
-                        "[SYSTEM: Your response was truncated due to length. \
-                         Please provide a shorter summary of the key points \
-                         (max 3-4 sentences), or write detailed content to a file instead.]",
-                    )],
-                });
+    #[test]
+    fn test_load_env_files_reads_workdir_env() {
+        let dir = env::temp_dir().join(format!("v4_workdir_env_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(".env"), "V4_TEST_WORKDIR_ENV=from-workdir\n").unwrap();
+        fs::write(dir.join("extra.env"), "V4_TEST_EXTRA_ENV=from-env-file\n").unwrap();
+
+        let loaded = load_env_files(&dir, Some(&dir.join("extra.env")));
+        let missing = load_env_files(&dir, Some(&dir.join("missing.env")));
+        fs::remove_dir_all(&dir).ok();
+
+        assert!(loaded.is_ok());
+        assert!(missing.is_err());
+        assert_eq!(env::var("V4_TEST_WORKDIR_ENV").unwrap(), "from-workdir");
+        assert_eq!(env::var("V4_TEST_EXTRA_ENV").unwrap(), "from-env-file");
+    }
 
-                continue;
-            }
+    #[test]
+    fn test_skill_search_ranks_matches() {
+        let dir = env::temp_dir().join(format!("v4_skill_search_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        for (name, description) in [
+            (
+                "pdf",
+                "Process PDF files: extract text, merge and split documents",
+            ),
+            ("code-review", "Review code changes for bugs and style"),
+            (
+                "mcp-builder",
+                "Build MCP servers following the protocol spec",
+            ),
+            ("docx", "Create and edit Word documents"),
+        ] {
+            fs::create_dir_all(dir.join(name)).unwrap();
+            fs::write(
+                dir.join(name).join("SKILL.md"),
+                format!(
+                    "---\nname: {}\ndescription: {}\n---\n\nBody\n",
+                    name, description
+                ),
+            )
+            .unwrap();
+        }
 
-            Some(StopReason::ToolUse) => {
-                consecutive_truncations = 0;
+        let loader = SkillLoader::new(std::slice::from_ref(&dir));
+        let names = |query: &str| -> Vec<String> {
+            loader
+                .search(query, 3)
+                .iter()
+                .map(|s| s.name.clone())
+                .collect()
+        };
+        let pdf_docs = names("merge two PDF documents");
+        let review = names("review my code");
+        let none = names("bake bread");
+        fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(pdf_docs, vec!["pdf", "docx"]);
+        assert_eq!(review[0], "code-review");
+        assert!(none.is_empty());
+    }
 
-                let mut tool_calls = Vec::new();
-                for block in &response.content {
-                    if let ContentBlock::ToolUse { id, name, input } = block {
-                        tool_calls.push((id.clone(), name.clone(), input.clone()));
-                    }
-                }
+    #[test]
+    fn test_run_diff_against_content() {
+        let redactor = Redactor::new(&[], &[]).unwrap();
+        let dir = env::temp_dir().join(format!("v4_diff_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "one\ntwo\nthree\n").unwrap();
+
+        let diff = run_diff(
+            &dir,
+            "a.txt",
+            None,
+            Some("one\n2\nthree\n"),
+            Some("out/a.diff"),
+            &redactor,
+        );
+        let saved = fs::read_to_string(dir.join("out/a.diff")).unwrap_or_default();
+        let same = run_diff(
+            &dir,
+            "a.txt",
+            None,
+            Some("one\ntwo\nthree\n"),
+            None,
+            &redactor,
+        );
+        let both = run_diff(&dir, "a.txt", Some("a.txt"), Some("x"), None, &redactor);
+        fs::remove_dir_all(&dir).ok();
+
+        assert!(diff.starts_with("--- a.txt\n+++ a.txt (proposed)\n"));
+        assert!(diff.contains("-two\n+2\n"));
+        assert_eq!(saved, diff);
+        assert_eq!(same, "(no differences)");
+        assert!(both.starts_with("Error:"));
+    }
 
-                let mut results = Vec::new();
-                for (id, name, input) in tool_calls {
-                    // Display tool call
-                    let tool_display = match name.as_str() {
-                        "Task" => format!("{} {}", ">".bright_blue(), name.bright_magenta()),
-                        "Skill" => format!("{} {}", ">".bright_blue(), name.bright_green()),
-                        "TodoWrite" => format!("{} {}", ">".bright_blue(), name.bright_magenta()),
-                        "bash" => format!("{} {}", ">".bright_blue(), name.bright_yellow()),
-                        "web_search" => format!("{} {}", ">".bright_blue(), name.bright_cyan()),
-                        _ => format!("{} {}", ">".bright_blue(), name.bright_cyan()),
-                    };
-                    println!("\n{}", tool_display);
+    #[test]
+    fn test_todo_render_styles() {
+        let items = vec![
+            TodoItem {
+                content: "Read spec".to_string(),
+                status: TodoStatus::Completed,
+                active_form: "Reading spec".to_string(),
+            },
+            TodoItem {
+                content: "Write parser".to_string(),
+                status: TodoStatus::InProgress,
+                active_form: "Writing parser".to_string(),
+            },
+            TodoItem {
+                content: "Add tests".to_string(),
+                status: TodoStatus::Pending,
+                active_form: "Adding tests".to_string(),
+            },
+        ];
+        let render = |style: TodoRenderStyle| {
+            let manager = TodoManager::with_style(style);
+            manager.update(items.clone()).unwrap()
+        };
 
-                    let output = execute_tool_async(
-                        client,
-                        config,
-                        &todo_manager,
-                        skill_loader,
-                        &name,
-                        &input,
-                    )
-                    .await;
-
-                    // Display output
-                    let preview = if name == "TodoWrite"
-                        || name == "Task"
-                        || name == "Skill"
-                        || name == "web_search"
-                    {
-                        output.clone()
-                    } else if output.len() > 300 {
-                        format!("{}...", safe_truncate(&output, 300))
-                    } else {
-                        output.clone()
-                    };
+        assert_eq!(
+            render(TodoRenderStyle::Ascii),
+            "[x] Read spec\n[>] Write parser <- Writing parser\n[ ] Add tests\n\n(1/3 completed)"
+        );
+        assert_eq!(
+            render(TodoRenderStyle::Emoji),
+            "✅ Read spec\n🔄 Write parser <- Writing parser\n⬜ Add tests\n\n(1/3 completed)"
+        );
+        assert_eq!(
+            render(TodoRenderStyle::Markdown),
+            "- [x] Read spec\n- [ ] **Write parser** _(Writing parser)_\n- [ ] Add tests\n\n(1/3 completed)"
+        );
+        assert_eq!(TodoRenderStyle::default(), TodoRenderStyle::Ascii);
+        assert_eq!(
+            TodoRenderStyle::parse("Emoji").unwrap(),
+            TodoRenderStyle::Emoji
+        );
+        assert!(TodoRenderStyle::parse("fancy").is_err());
+    }
 
-                    if output.starts_with("Error:") {
-                        println!("{}", preview.bright_red());
-                    } else if name == "TodoWrite" {
-                        println!("{}", preview.bright_green());
-                    } else if name == "Task" {
-                        // Task output already printed by run_task
-                    } else if name == "Skill" {
-                        println!(
-                            "{} {}",
-                            "Skill loaded:".bright_green(),
-                            preview.lines().next().unwrap_or("").bright_black()
-                        );
-                    } else if name == "web_search" {
-                        println!("{}", preview.bright_black());
-                    } else {
-                        println!("  {}", preview.bright_black());
-                    }
+    #[test]
+    fn test_should_auto_continue_follows_todo_state() {
+        let manager = TodoManager::with_style(TodoRenderStyle::Ascii);
+        assert!(!should_auto_continue(&manager, 0, 3));
+
+        manager
+            .update(vec![
+                TodoItem {
+                    content: "Write parser".to_string(),
+                    status: TodoStatus::InProgress,
+                    active_form: "Writing parser".to_string(),
+                },
+                TodoItem {
+                    content: "Add tests".to_string(),
+                    status: TodoStatus::Pending,
+                    active_form: "Adding tests".to_string(),
+                },
+            ])
+            .unwrap();
+        assert!(should_auto_continue(&manager, 0, 3));
+        assert!(should_auto_continue(&manager, 2, 3));
+        assert!(!should_auto_continue(&manager, 3, 3));
+        assert!(!should_auto_continue(&manager, 0, 0));
+
+        manager
+            .update(vec![TodoItem {
+                content: "Write parser".to_string(),
+                status: TodoStatus::Completed,
+                active_form: "Writing parser".to_string(),
+            }])
+            .unwrap();
+        assert!(!should_auto_continue(&manager, 0, 3));
+    }
 
-                    results.push(ContentBlock::ToolResult {
-                        tool_use_id: id,
-                        is_error: None,
-                        content: anthropic::types::ToolResultContent::Text(output),
-                    });
-                }
+    #[test]
+    fn test_todo_reminder_only_for_incomplete_todos() {
+        let manager = TodoManager::with_style(TodoRenderStyle::Ascii);
+        assert_eq!(todo_reminder(&manager, true, false), None);
+
+        manager
+            .update(vec![
+                TodoItem {
+                    content: "Write parser".to_string(),
+                    status: TodoStatus::Completed,
+                    active_form: "Writing parser".to_string(),
+                },
+                TodoItem {
+                    content: "Add tests".to_string(),
+                    status: TodoStatus::Pending,
+                    active_form: "Adding tests".to_string(),
+                },
+            ])
+            .unwrap();
+        let reminder = todo_reminder(&manager, true, false).unwrap();
+        assert!(reminder.contains("- Add tests"));
+        assert!(!reminder.contains("Write parser"));
+
+        // Off unless enabled, and the model may insist after one reminder
+        assert_eq!(todo_reminder(&manager, false, false), None);
+        assert_eq!(todo_reminder(&manager, true, true), None);
+
+        manager
+            .update(vec![TodoItem {
+                content: "Add tests".to_string(),
+                status: TodoStatus::Completed,
+                active_form: "Adding tests".to_string(),
+            }])
+            .unwrap();
+        assert_eq!(todo_reminder(&manager, true, false), None);
+    }
 
-                messages.push(Message {
-                    role: Role::Assistant,
-                    content: response.content,
-                });
-                messages.push(Message {
-                    role: Role::User,
-                    content: results,
-                });
-            }
+    #[test]
+    fn test_format_command_runs_once_after_edits() {
+        let redactor = Redactor::new(&[], &[]).unwrap();
+        let workdir = env::temp_dir().join(format!("v4_format_{}", std::process::id()));
+        fs::create_dir_all(&workdir).unwrap();
+        let workdir = workdir.canonicalize().unwrap();
+        fs::write(workdir.join("a.rs"), "fn main(){}\n").unwrap();
+        let cancel = CancellationToken::new();
+
+        let edit = json!({"path": "a.rs", "old_text": "main", "new_text": "run"});
+        let output = run_edit_locked(&workdir, "a.rs", "main", "run");
+        let missing = json!({"path": "b.rs", "old_text": "x", "new_text": "y"});
+        let mut edited = Vec::new();
+        edited.extend(edited_paths("edit_file", &edit, &output));
+        edited.extend(edited_paths(
+            "edit_file",
+            &missing,
+            "Error: Text not found in b.rs",
+        ));
+        edited.extend(edited_paths("read_file", &edit, "fn run(){}"));
+        edited.extend(edited_paths("edit_file", &edit, "Edited a.rs"));
+        assert_eq!(edited, vec!["a.rs", "a.rs"]);
+
+        let formatter = "echo ran >> fmt.log; printf 'fn run() {}\\n' > a.rs";
+        let report = run_format_command(&workdir, formatter, &edited, &cancel, &redactor);
+        let log = fs::read_to_string(workdir.join("fmt.log")).unwrap();
+        let formatted = fs::read_to_string(workdir.join("a.rs")).unwrap();
+        let unchanged = run_format_command(&workdir, "true", &edited, &cancel, &redactor);
+        let failed = run_format_command(&workdir, "exit 3", &edited, &cancel, &redactor);
+        fs::remove_dir_all(&workdir).ok();
+
+        // Invoked once for the whole batch, not once per edit
+        assert_eq!(log, "ran\n");
+        assert_eq!(formatted, "fn run() {}\n");
+        assert_eq!(
+            report,
+            format!(
+                "[format] `{}` reformatted a.rs - re-read before editing again",
+                formatter
+            )
+        );
+        assert_eq!(unchanged, "[format] `true` made no changes");
+        assert!(failed.starts_with("[format] `exit 3` failed:"));
+    }
 
-            Some(StopReason::EndTurn) | Some(StopReason::StopSequence) | None => {
-                // Normal end - display text and return
-                for block in &response.content {
-                    if let ContentBlock::Text { text } = block {
-                        if !text.trim().is_empty() {
-                            println!("{}", text);
-                        }
-                    }
-                }
+    #[test]
+    fn test_reviewer_proposes_patches_without_writing() {
+        let redactor = Redactor::new(&[], &[]).unwrap();
+        let names: Vec<String> = get_tools_for_subagent("reviewer", &SkillLoader::new(&[]))
+            .into_iter()
+            .map(|t| t.name)
+            .collect();
+        for writer in ["write_file", "edit_file", "bash", "bash_background", "diff"] {
+            assert!(!names.contains(&writer.to_string()), "{}", writer);
+        }
+        assert!(names.contains(&"propose_patch".to_string()));
+        assert!(names.contains(&"read_file".to_string()));
+        let code: Vec<String> = get_tools_for_subagent("code", &SkillLoader::new(&[]))
+            .into_iter()
+            .map(|t| t.name)
+            .collect();
+        assert!(!code.contains(&"propose_patch".to_string()));
+
+        let workdir = env::temp_dir().join(format!("v4_reviewer_{}", std::process::id()));
+        fs::create_dir_all(&workdir).unwrap();
+        let workdir = workdir.canonicalize().unwrap();
+        fs::write(workdir.join("lib.rs"), "fn a() {}\nfn b() {}\n").unwrap();
+
+        let mut patches = PatchSet::default();
+        let first = patches.propose(&workdir, "lib.rs", "fn a() {}", "fn a() -> u8 { 1 }");
+        // Later proposals see earlier ones
+        let second = patches.propose(&workdir, "lib.rs", "-> u8 { 1 }", "-> u8 { 2 }");
+        let missing = patches.propose(&workdir, "lib.rs", "fn c()", "fn d()");
+        let created = patches.propose(&workdir, "NOTES.md", "", "review notes\n");
+        let on_disk = fs::read_to_string(workdir.join("lib.rs")).unwrap();
+        let notes_exist = workdir.join("NOTES.md").exists();
+        fs::remove_dir_all(&workdir).ok();
+
+        assert_eq!(
+            first,
+            "Proposed change to lib.rs (+1/-1 lines so far); nothing was written"
+        );
+        assert!(second.starts_with("Proposed change to lib.rs (+1/-1"));
+        assert_eq!(missing, "Error: Text not found in lib.rs");
+        assert!(created.starts_with("Proposed change to NOTES.md (+1/-0"));
+        assert_eq!(on_disk, "fn a() {}\nfn b() {}\n");
+        assert!(!notes_exist);
+
+        let summary = patches.append_to("Looks good overall.".to_string(), &redactor);
+        assert!(
+            summary.starts_with("Looks good overall.\n\nProposed patch (not applied):\n```diff\n")
+        );
+        assert!(summary.contains("--- a/NOTES.md\n+++ b/NOTES.md\n"));
+        assert!(summary.contains("-fn a() {}\n+fn a() -> u8 { 2 }\n"));
+        assert!(summary.ends_with("```"));
+        assert_eq!(
+            PatchSet::default().append_to("ok".to_string(), &redactor),
+            "ok"
+        );
+    }
 
-                messages.push(Message {
-                    role: Role::Assistant,
-                    content: response.content,
-                });
+    #[test]
+    fn test_reviewer_write_file_call_is_refused() {
+        let workdir = env::temp_dir().join(format!("v4_reviewer_write_{}", std::process::id()));
+        fs::create_dir_all(&workdir).unwrap();
+        fs::write(workdir.join("lib.rs"), "fn a() {}\n").unwrap();
+        let config = test_config(&workdir);
+        let loader = SkillLoader::new(&[]);
+        let sub_tools = config.tool_set(get_tools_for_subagent("reviewer", &loader));
+
+        let dispatch = |name: &str, input: serde_json::Value| {
+            dispatch_subagent_tool(
+                &config,
+                &sub_tools,
+                &TodoManager::new(),
+                &loader,
+                &SessionStats::new(),
+                &ProcessRegistry::new(),
+                &CancellationToken::new(),
+                name,
+                &input,
+            )
+            .text
+        };
+        let write = dispatch(
+            "write_file",
+            json!({"path": "lib.rs", "content": "fn b() {}\n"}),
+        );
+        let edit = dispatch(
+            "edit_file",
+            json!({"path": "lib.rs", "old_text": "fn a", "new_text": "fn c"}),
+        );
+        let read = dispatch("read_file", json!({"path": "lib.rs"}));
+        let on_disk = fs::read_to_string(workdir.join("lib.rs")).unwrap();
+        fs::remove_dir_all(&workdir).ok();
+
+        assert_eq!(write, "Error: tool write_file is not available");
+        assert_eq!(edit, "Error: tool edit_file is not available");
+        assert_eq!(read, "fn a() {}\n");
+        assert_eq!(on_disk, "fn a() {}\n");
+    }
 
-                return Ok(());
-            }
+    #[test]
+    fn test_run_project_task_runs_discovered_targets_only() {
+        let redactor = Redactor::new(&[], &[]).unwrap();
+        let workdir = env::temp_dir().join(format!("v4_project_tasks_{}", std::process::id()));
+        fs::create_dir_all(&workdir).unwrap();
+        let workdir = workdir.canonicalize().unwrap();
+        fs::write(workdir.join("Makefile"), "hello:\n\t@echo hi from make\n").unwrap();
+        let cancel = CancellationToken::new();
+
+        let ran = run_project_task(&workdir, "hello", None, &cancel, &redactor);
+        let unknown = run_project_task(&workdir, "rm -rf .", None, &cancel, &redactor);
+        let bad_source = run_project_task(&workdir, "hello", Some("cargo"), &cancel, &redactor);
+        fs::remove_dir_all(&workdir).ok();
+
+        if Command::new("make").arg("--version").output().is_ok() {
+            assert_eq!(ran, "$ make hello\nhi from make");
         }
+        assert_eq!(
+            unknown,
+            "Error: No project task named 'rm -rf .' (see list_tasks)"
+        );
+        assert!(bad_source.starts_with("Error: Unknown task source 'cargo'"));
     }
-}
 
-// =============================================================================
-// Client Initialization (from v3)
-// =============================================================================
+    #[test]
+    fn test_tail_file_follows_appended_lines() {
+        let redactor = Redactor::new(&[], &[]).unwrap();
+        let workdir = env::temp_dir().join(format!("v4_tail_{}", std::process::id()));
+        fs::create_dir_all(&workdir).unwrap();
+        let workdir = workdir.canonicalize().unwrap();
+        let log = workdir.join("app.log");
+        fs::write(&log, "one\ntwo\nthree\n").unwrap();
+        let cancel = CancellationToken::new();
+
+        let snapshot = run_tail_file(&workdir, "app.log", Some(2), None, &cancel, &redactor);
+
+        let writer = {
+            let log = log.clone();
+            thread::spawn(move || {
+                for line in ["four", "five"] {
+                    thread::sleep(Duration::from_millis(150));
+                    let mut file = fs::OpenOptions::new().append(true).open(&log).unwrap();
+                    writeln!(file, "{}", line).unwrap();
+                }
+            })
+        };
+        let followed = run_tail_file(&workdir, "app.log", Some(1), Some(1), &cancel, &redactor);
+        writer.join().unwrap();
+        let quiet = run_tail_file(&workdir, "app.log", Some(1), Some(1), &cancel, &redactor);
+        let escape = run_tail_file(
+            &workdir,
+            "../outside.log",
+            Some(1),
+            None,
+            &cancel,
+            &redactor,
+        );
+        fs::remove_dir_all(&workdir).ok();
 
-fn create_client() -> Result<Client> {
-    dotenvy::dotenv().ok();
+        assert_eq!(snapshot, "two\nthree");
+        assert_eq!(followed, "three\n--- appended during 1s ---\nfour\nfive");
+        assert_eq!(quiet, "five\n(no new lines in 1s)");
+        assert!(escape.contains("escapes workspace"));
+    }
 
-    let api_key = env::var("ANTHROPIC_API_KEY")
-        .or_else(|_| env::var("ANTHROPIC_AUTH_TOKEN"))
-        .map_err(|_| {
-            anyhow::anyhow!("Missing API key: set ANTHROPIC_API_KEY or ANTHROPIC_AUTH_TOKEN")
-        })?;
+    #[test]
+    fn test_tail_file_reads_backwards_in_chunks() {
+        let workdir = env::temp_dir().join(format!("v4_tail_chunks_{}", std::process::id()));
+        fs::create_dir_all(&workdir).unwrap();
+        // Several chunks' worth of lines, without a trailing newline
+        let text = (1..=5000)
+            .map(|i| format!("line {}", i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        fs::write(workdir.join("big.log"), &text).unwrap();
+        fs::write(workdir.join("short.log"), "a\nb\n").unwrap();
+        fs::write(workdir.join("empty.log"), "").unwrap();
+
+        let tail = |path: &str, count: usize| {
+            let mut file = fs::File::open(workdir.join(path)).unwrap();
+            let len = file.metadata().unwrap().len();
+            read_last_lines(&mut file, len, count).unwrap()
+        };
+        let last = tail("big.log", 3);
+        let across_chunks = tail("big.log", 2000);
+        let short = tail("short.log", 20);
+        let empty = tail("empty.log", 20);
+        fs::remove_dir_all(&workdir).ok();
+
+        assert_eq!(last, "line 4998\nline 4999\nline 5000");
+        let expected: Vec<String> = (3001..=5000).map(|i| format!("line {}", i)).collect();
+        assert_eq!(across_chunks, expected.join("\n"));
+        assert_eq!(short, "a\nb");
+        assert_eq!(empty, "");
+
+        // `lines` is optional; leaving it out gets DEFAULT_TAIL_LINES
+        let tools = create_all_tools(&SkillLoader::new(&[]));
+        assert_eq!(
+            validate_tool_input(&tools, "tail_file", &json!({"path": "big.log"})),
+            None
+        );
+    }
 
-    let mut builder = anthropic::client::ClientBuilder::new().api_key(api_key);
+    #[test]
+    fn test_hash_file_known_digests() {
+        let redactor = Redactor::new(&[], &[]).unwrap();
+        let workdir = env::temp_dir().join(format!("v4_hash_{}", std::process::id()));
+        fs::create_dir_all(workdir.join("out/sub")).unwrap();
+        fs::write(workdir.join("out/hello.txt"), "hello\n").unwrap();
+        fs::write(workdir.join("out/sub/empty.txt"), "").unwrap();
+        fs::create_dir_all(workdir.join("out/node_modules")).unwrap();
+        fs::write(workdir.join("out/node_modules/dep.js"), "x").unwrap();
+        let ignore = IgnoreMatcher::new(&workdir, &[]).unwrap();
+
+        let sha = run_hash_file(&workdir, "out/hello.txt", None, &ignore, &redactor);
+        let md5 = run_hash_file(&workdir, "out/hello.txt", Some("md5"), &ignore, &redactor);
+        let manifest = run_hash_file(&workdir, "out", None, &ignore, &redactor);
+        let bad_algo = run_hash_file(&workdir, "out/hello.txt", Some("crc32"), &ignore, &redactor);
+        fs::remove_dir_all(&workdir).ok();
+
+        assert_eq!(
+            sha,
+            "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03  out/hello.txt"
+        );
+        assert_eq!(md5, "b1946ac92492d2347c6235b4d2611184  out/hello.txt");
+
+        let lines: Vec<&str> = manifest.lines().collect();
+        assert_eq!(
+            lines[..2],
+            [
+                "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03  hello.txt",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  sub/empty.txt",
+            ]
+        );
+        assert!(lines[3].starts_with("2 files, manifest "));
 
-    if let Ok(base_url) = env::var("ANTHROPIC_API_BASE").or_else(|_| env::var("ANTHROPIC_BASE_URL"))
-    {
-        builder = builder.api_base(base_url);
+        assert!(bad_algo.starts_with("Error: Unsupported algo 'crc32'"));
     }
 
-    if let Ok(api_version) = env::var("ANTHROPIC_API_VERSION") {
-        builder = builder.api_version(api_version);
+    #[test]
+    fn test_autosave_writes_valid_json_each_turn() {
+        let dir = env::temp_dir().join(format!("v4_autosave_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.json");
+
+        let mut messages = vec![Message {
+            role: Role::User,
+            content: vec![ContentBlock::text("first question")],
+        }];
+        save_history(&path, &messages).unwrap();
+        messages.push(Message {
+            role: Role::Assistant,
+            content: vec![ContentBlock::text("first answer")],
+        });
+        save_history(&path, &messages).unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        let leftovers = fs::read_dir(&dir).unwrap().count();
+        let restored = load_history(&path).unwrap();
+        fs::remove_dir_all(&dir).ok();
+
+        let json: serde_json::Value = serde_json::from_str(&text).unwrap();
+        assert_eq!(json.as_array().map(Vec::len), Some(2));
+        assert_eq!(leftovers, 1, "the temp file is renamed into place");
+        assert_eq!(restored.len(), 2);
+        assert!(matches!(
+            &restored[1].content[0],
+            ContentBlock::Text { text } if text == "first answer"
+        ));
     }
 
-    builder = builder.timeout(Duration::from_secs(600));
+    #[test]
+    fn test_should_print_banner() {
+        assert!(should_print_banner(false, false, false));
+        assert!(!should_print_banner(true, false, false));
+        assert!(!should_print_banner(false, true, false));
+        // --json output stays machine-readable
+        assert!(!should_print_banner(false, false, true));
+    }
 
-    let client = builder.build()?;
-    Ok(client)
-}
+    #[test]
+    fn test_session_stats_aggregation() {
+        let stats = SessionStats::new();
+        stats.record_turn();
+        stats.record_turn();
+        stats.record_tool_call("bash");
+        stats.record_tool_call("bash");
+        stats.record_tool_call("read_file");
+        stats.record_usage(100, 20);
+        stats.record_usage(50, 5);
+        stats.record_subagent();
+
+        let counters = stats.snapshot();
+        assert_eq!(counters.turns, 2);
+        assert_eq!(counters.tool_calls.get("bash"), Some(&2));
+        assert_eq!(counters.tool_calls.get("read_file"), Some(&1));
+        assert_eq!(counters.input_tokens, 150);
+        assert_eq!(counters.output_tokens, 25);
+        assert_eq!(counters.subagents, 1);
+
+        let rendered = stats.render();
+        assert!(rendered.contains("Tool calls: 3"));
+        assert!(rendered.contains("(bash 2, read_file 1)"));
+
+        let json = stats.to_json();
+        assert_eq!(json["tool_calls"]["bash"], 2);
+        assert!(json["wall_time_secs"].is_number());
+    }
 
-// =============================================================================
-// Input Handling (from v3)
-// =============================================================================
+    #[test]
+    fn test_safe_path_allows_missing_parents() {
+        let dir = env::temp_dir()
+            .canonicalize()
+            .unwrap()
+            .join(format!("v4_safe_path_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
 
-#[cfg(feature = "readline")]
-fn prompt_user() -> Result<String> {
-    let mut rl = Editor::<(), DefaultHistory>::new()?;
+        let nested = safe_path(&dir, "a/b/c.txt");
+        let escape = safe_path(&dir, "missing/../../outside.txt");
+        fs::remove_dir_all(&dir).ok();
 
-    match rl.readline("You: ") {
-        Ok(line) => {
-            let trimmed = line.trim();
-            if trimmed.is_empty() {
-                anyhow::bail!("Empty input")
-            }
-            Ok(trimmed.to_string())
-        }
-        Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
-            println!("\nExiting...");
-            std::process::exit(0);
-        }
-        Err(e) => Err(e.into()),
+        assert_eq!(nested.unwrap(), dir.join("a/b/c.txt"));
+        assert!(escape.is_err());
     }
-}
 
-#[cfg(not(feature = "readline"))]
-fn prompt_user() -> Result<String> {
-    print!("You: ");
-    io::stdout().flush()?;
+    #[test]
+    fn test_create_skill_scaffold_parses() {
+        let dir = env::temp_dir().join(format!("v4_skill_new_{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
 
-    let stdin = io::stdin();
-    let mut line = String::new();
-    stdin.lock().read_line(&mut line)?;
+        let skill_md = create_skill_scaffold(&dir, "log-triage").unwrap();
+        let loader = SkillLoader::new(std::slice::from_ref(&dir));
+        let skill = loader.parse_skill_md(&skill_md);
+        let duplicate = create_skill_scaffold(&dir, "log-triage");
+        let has_folders = ["scripts", "references", "assets"]
+            .iter()
+            .all(|f| dir.join("log-triage").join(f).is_dir());
+        fs::remove_dir_all(&dir).ok();
+
+        let skill = skill.expect("generated SKILL.md should parse");
+        assert_eq!(skill.name, "log-triage");
+        assert!(!skill.description.is_empty());
+        assert!(has_folders);
+        assert!(duplicate.is_err());
+        assert_eq!(loader.list_skills(), vec!["log-triage".to_string()]);
+    }
 
-    let trimmed = line.trim();
-    if trimmed.is_empty() {
-        anyhow::bail!("Empty input")
+    #[test]
+    fn test_create_skill_scaffold_rejects_bad_names() {
+        let dir = env::temp_dir();
+        assert!(create_skill_scaffold(&dir, "../escape").is_err());
+        assert!(create_skill_scaffold(&dir, "").is_err());
     }
 
-    Ok(trimmed.to_string())
-}
+    #[test]
+    fn test_subagent_result_parses_json_block() {
+        let text = "Done.\n\n```json\n{\"summary\": \"Added parser\", \"files\": [\"src/parser.rs\"], \"findings\": [\"No tests existed\"]}\n```";
+        let result = SubagentResult::parse(text);
+
+        assert_eq!(result.summary, "Added parser");
+        assert_eq!(result.files, vec!["src/parser.rs"]);
+        assert_eq!(result.findings, vec!["No tests existed"]);
+        assert_eq!(
+            result.render(),
+            "Added parser\n\nFiles:\n- src/parser.rs\n\nFindings:\n- No tests existed"
+        );
+    }
 
-// =============================================================================
-// Main Entry Point
-// =============================================================================
+    #[test]
+    fn test_subagent_result_plain_text() {
+        let result = SubagentResult::parse("Found 3 TODOs in src/main.rs");
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let config = Config::from_env()?;
-    let client = create_client()?;
-    let skill_loader = SkillLoader::new(&config.skills_dir);
+        assert_eq!(result.summary, "Found 3 TODOs in src/main.rs");
+        assert!(result.files.is_empty());
+        assert!(result.findings.is_empty());
+        assert_eq!(result.render(), "Found 3 TODOs in src/main.rs");
+    }
 
-    // Display startup info
-    println!("{}", "=".repeat(60).bright_black());
-    println!(
-        "{} {} {}",
-        "Mini Claude Code".bright_cyan().bold(),
-        "v4".bright_magenta(),
-        "(Skills + Subagents + Todo)".bright_black()
-    );
-    println!("{}", "=".repeat(60).bright_black());
-    println!("{} {}", "Model:".bright_black(), config.model);
-    println!("{} {}", "Workdir:".bright_black(), config.workdir.display());
+    #[test]
+    fn test_subagent_result_uses_prose_when_summary_missing() {
+        let result =
+            SubagentResult::parse("Refactored config.\n```json\n{\"files\": [\"a.rs\"]}\n```");
 
-    let skill_count = skill_loader.list_skills().len();
-    if skill_count > 0 {
-        println!("{} {} skills loaded", "Skills:".bright_green(), skill_count);
-        for skill_name in skill_loader.list_skills() {
-            println!("  {} {}", "-".bright_black(), skill_name.bright_green());
-        }
-    } else {
-        println!(
-            "{} {}",
-            "Skills:".bright_black(),
-            "none (create skills/ folder with SKILL.md files)".bright_yellow()
-        );
+        assert_eq!(result.summary, "Refactored config.");
+        assert_eq!(result.files, vec!["a.rs"]);
     }
 
-    println!("{}", "=".repeat(60).bright_black());
-    println!();
+    #[test]
+    fn test_watchdog_warns_then_aborts() {
+        let mut watchdog = LoopWatchdog::new(3);
+        let input = json!({"command": "ls"});
 
-    let mut messages = Vec::new();
+        assert_eq!(watchdog.observe("bash", &input), WatchdogVerdict::Continue);
+        assert_eq!(watchdog.observe("bash", &input), WatchdogVerdict::Continue);
+        assert_eq!(watchdog.observe("bash", &input), WatchdogVerdict::Warn(3));
+        assert_eq!(watchdog.observe("bash", &input), WatchdogVerdict::Abort(4));
+    }
 
-    loop {
-        let input = match prompt_user() {
-            Ok(input) => input,
-            Err(_) => continue,
+    #[test]
+    fn test_looping_turn_answers_every_call() {
+        let out = BufferWriter {
+            lines: Mutex::new(Vec::new()),
         };
-
-        messages.push(Message {
+        let mut messages = vec![Message {
             role: Role::User,
-            content: vec![ContentBlock::text(input)],
-        });
+            content: vec![ContentBlock::text("list files")],
+        }];
+        let content = vec![
+            ContentBlock::text("Trying again."),
+            ContentBlock::ToolUse {
+                id: "t1".to_string(),
+                name: "bash".to_string(),
+                input: json!({"command": "ls"}),
+            },
+            ContentBlock::ToolUse {
+                id: "t2".to_string(),
+                name: "read_file".to_string(),
+                input: json!({"path": "a.txt"}),
+            },
+        ];
 
-        if let Err(e) = agent_loop(&client, &config, &skill_loader, &mut messages).await {
-            eprintln!("{} {}", "Error:".bright_red(), e);
-            messages.pop();
-        }
+        end_looping_turn(&out, &mut messages, content, "bash", 4, 3);
 
-        println!();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[1].role, Role::Assistant);
+        let ids: Vec<&str> = messages[2]
+            .content
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::ToolResult { tool_use_id, .. } => Some(tool_use_id.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ids, vec!["t1", "t2"]);
+        assert_eq!(
+            messages[3].content,
+            vec![ContentBlock::text(LOOP_ABORTED_TURN)]
+        );
+        let lines = out.lines.lock().unwrap();
+        assert!(lines[0].contains("MINI_CODE_MAX_REPEATED_TOOL_CALLS (current: 3)"));
+    }
+
+    #[test]
+    fn test_watchdog_resets_on_different_call() {
+        let mut watchdog = LoopWatchdog::new(3);
+        let ls = json!({"command": "ls"});
+        let pwd = json!({"command": "pwd"});
+
+        watchdog.observe("bash", &ls);
+        watchdog.observe("bash", &ls);
+        assert_eq!(watchdog.observe("bash", &pwd), WatchdogVerdict::Continue);
+        assert_eq!(watchdog.observe("bash", &ls), WatchdogVerdict::Continue);
+        assert_eq!(
+            watchdog.observe("read_file", &ls),
+            WatchdogVerdict::Continue
+        );
     }
 }
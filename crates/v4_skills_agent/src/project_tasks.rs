@@ -0,0 +1,313 @@
+//! Project Task Module
+//!
+//! Finds the commands a project already defines at the workspace root:
+//! `package.json` scripts, `Makefile` targets and `justfile` recipes. The
+//! `list_tasks` tool shows them so the model doesn't spend turns working
+//! out how to build and test; `run_project_task` runs one by name.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::Path;
+
+const MAKEFILES: &[&str] = &["GNUmakefile", "makefile", "Makefile"];
+const JUSTFILES: &[&str] = &["justfile", "Justfile", ".justfile"];
+
+/// Where a task is defined, and so which tool runs it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskSource {
+    Npm,
+    Make,
+    Just,
+}
+
+impl TaskSource {
+    pub fn parse(value: &str) -> Result<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "npm" | "package.json" => Ok(Self::Npm),
+            "make" | "makefile" => Ok(Self::Make),
+            "just" | "justfile" => Ok(Self::Just),
+            other => anyhow::bail!(
+                "Unknown task source '{}': expected npm, make or just",
+                other
+            ),
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Npm => "npm",
+            Self::Make => "make",
+            Self::Just => "just",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProjectTask {
+    pub source: TaskSource,
+    pub name: String,
+    /// Script body (package.json) or recipe parameters (justfile)
+    pub detail: Option<String>,
+}
+
+impl ProjectTask {
+    fn new(source: TaskSource, name: &str, detail: Option<String>) -> Self {
+        Self {
+            source,
+            name: name.to_string(),
+            detail,
+        }
+    }
+
+    /// Shell command that runs this task
+    pub fn command(&self) -> String {
+        let name = shell_quote(&self.name);
+        match self.source {
+            TaskSource::Npm => format!("npm run {}", name),
+            TaskSource::Make => format!("make {}", name),
+            TaskSource::Just => format!("just {}", name),
+        }
+    }
+}
+
+/// Single-quote `value` for `sh` unless it is plainly safe
+fn shell_quote(value: &str) -> String {
+    let plain = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_.:/@+".contains(c));
+    if plain && !value.is_empty() {
+        value.to_string()
+    } else {
+        format!("'{}'", value.replace('\'', r"'\''"))
+    }
+}
+
+/// Scripts from a package.json document
+pub fn parse_package_json(text: &str) -> Result<Vec<ProjectTask>> {
+    let json: serde_json::Value = serde_json::from_str(text).context("Invalid package.json")?;
+    let Some(scripts) = json.get("scripts").and_then(|s| s.as_object()) else {
+        return Ok(Vec::new());
+    };
+    Ok(scripts
+        .iter()
+        .map(|(name, body)| {
+            ProjectTask::new(TaskSource::Npm, name, body.as_str().map(str::to_string))
+        })
+        .collect())
+}
+
+/// Explicit targets from a Makefile.
+///
+/// Recipe lines, variable assignments, special targets (`.PHONY`) and
+/// pattern rules (`%.o`) are skipped.
+pub fn parse_makefile(text: &str) -> Vec<ProjectTask> {
+    let mut tasks: Vec<ProjectTask> = Vec::new();
+    for line in text.lines() {
+        if line.starts_with('\t') || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let Some((targets, rest)) = line.split_once(':') else {
+            continue;
+        };
+        // `VAR := x`, `VAR ::= x` and `VAR = a:b` are assignments, not rules
+        if rest.starts_with('=') || rest.starts_with(":=") || targets.contains('=') {
+            continue;
+        }
+        for target in targets.split_whitespace() {
+            let is_rule = !target.starts_with('.')
+                && !target.contains(['%', '$', '('])
+                && !tasks.iter().any(|t| t.name == target);
+            if is_rule {
+                tasks.push(ProjectTask::new(TaskSource::Make, target, None));
+            }
+        }
+    }
+    tasks
+}
+
+/// Public recipes from a justfile (names starting with `_` are private)
+pub fn parse_justfile(text: &str) -> Vec<ProjectTask> {
+    const KEYWORDS: &[&str] = &["set", "alias", "import", "export", "mod"];
+
+    let mut tasks = Vec::new();
+    for line in text.lines() {
+        if line.starts_with([' ', '\t', '#', '[']) {
+            continue;
+        }
+        let Some((header, rest)) = line.split_once(':') else {
+            continue;
+        };
+        if rest.starts_with('=') {
+            continue;
+        }
+        let mut words = header.split_whitespace();
+        let Some(name) = words.next().map(|n| n.trim_start_matches('@')) else {
+            continue;
+        };
+        let valid = name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if valid && !name.is_empty() && !name.starts_with('_') && !KEYWORDS.contains(&name) {
+            let params: Vec<&str> = words.collect();
+            let detail = (!params.is_empty()).then(|| params.join(" "));
+            tasks.push(ProjectTask::new(TaskSource::Just, name, detail));
+        }
+    }
+    tasks
+}
+
+/// Every task defined at the workspace root
+pub fn discover(workdir: &Path) -> Result<Vec<ProjectTask>> {
+    let mut tasks = Vec::new();
+    if let Ok(text) = fs::read_to_string(workdir.join("package.json")) {
+        tasks.extend(parse_package_json(&text)?);
+    }
+    if let Some(text) = MAKEFILES
+        .iter()
+        .find_map(|f| fs::read_to_string(workdir.join(f)).ok())
+    {
+        tasks.extend(parse_makefile(&text));
+    }
+    if let Some(text) = JUSTFILES
+        .iter()
+        .find_map(|f| fs::read_to_string(workdir.join(f)).ok())
+    {
+        tasks.extend(parse_justfile(&text));
+    }
+    Ok(tasks)
+}
+
+/// One line per task: the command to run it, then its script or parameters
+pub fn render(tasks: &[ProjectTask]) -> String {
+    if tasks.is_empty() {
+        return "No package.json scripts, Makefile targets or justfile recipes found".to_string();
+    }
+    tasks
+        .iter()
+        .map(|t| match &t.detail {
+            Some(detail) => format!("{}  # {}", t.command(), detail),
+            None => t.command(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The task called `name`; `source` picks one when several files define it
+pub fn find<'a>(
+    tasks: &'a [ProjectTask],
+    name: &str,
+    source: Option<TaskSource>,
+) -> Result<&'a ProjectTask> {
+    let matches: Vec<&ProjectTask> = tasks
+        .iter()
+        .filter(|t| t.name == name && source.is_none_or(|s| t.source == s))
+        .collect();
+    match matches.as_slice() {
+        [task] => Ok(task),
+        [] => anyhow::bail!("No project task named '{}' (see list_tasks)", name),
+        several => anyhow::bail!(
+            "'{}' is defined by {}; pass source to pick one",
+            name,
+            several
+                .iter()
+                .map(|t| t.source.label())
+                .collect::<Vec<_>>()
+                .join(" and ")
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(tasks: &[ProjectTask]) -> Vec<&str> {
+        tasks.iter().map(|t| t.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_parse_package_json_scripts() {
+        let tasks = parse_package_json(
+            r#"{
+                "name": "web",
+                "scripts": {
+                    "build": "vite build",
+                    "test:unit": "vitest run",
+                    "lint": "eslint ."
+                },
+                "dependencies": {"vite": "^5.0.0"}
+            }"#,
+        )
+        .unwrap();
+
+        let mut found = names(&tasks);
+        found.sort();
+        assert_eq!(found, vec!["build", "lint", "test:unit"]);
+        let test = find(&tasks, "test:unit", None).unwrap();
+        assert_eq!(test.command(), "npm run test:unit");
+        let build = find(&tasks, "build", Some(TaskSource::Npm)).unwrap();
+        assert_eq!(
+            render(std::slice::from_ref(build)),
+            "npm run build  # vite build"
+        );
+        assert!(parse_package_json(r#"{"name": "no-scripts"}"#)
+            .unwrap()
+            .is_empty());
+        assert!(parse_package_json("{not json").is_err());
+    }
+
+    #[test]
+    fn test_parse_makefile_and_justfile() {
+        let makefile = "\
+CC := gcc
+FLAGS = -O2 -Wall
+.PHONY: all test clean
+
+all: build test
+build test: deps
+\t$(CC) $(FLAGS) -o app main.c
+%.o: %.c
+\t$(CC) -c $<
+# clean: not a target
+clean:
+\trm -f app
+";
+        assert_eq!(
+            names(&parse_makefile(makefile)),
+            vec!["all", "build", "test", "clean"]
+        );
+
+        let justfile = "\
+set shell := [\"bash\", \"-c\"]
+version := \"1.0\"
+
+# Run the tests
+test filter='':
+    cargo test {{filter}}
+
+@fmt:
+    cargo fmt
+_helper:
+    echo private
+";
+        let recipes = parse_justfile(justfile);
+        assert_eq!(names(&recipes), vec!["test", "fmt"]);
+        assert_eq!(recipes[0].detail.as_deref(), Some("filter=''"));
+
+        let mut all = parse_makefile(makefile);
+        all.extend(recipes);
+        assert_eq!(find(&all, "clean", None).unwrap().command(), "make clean");
+        assert!(find(&all, "test", None)
+            .unwrap_err()
+            .to_string()
+            .contains("defined by make and just"));
+        assert_eq!(
+            find(&all, "test", Some(TaskSource::Just))
+                .unwrap()
+                .command(),
+            "just test"
+        );
+        assert!(find(&all, "deploy", None).is_err());
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+}
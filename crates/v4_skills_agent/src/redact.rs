@@ -0,0 +1,158 @@
+//! Secret Redaction Module
+//!
+//! Scrubs secrets from tool output before it reaches the conversation, the
+//! terminal, a MINI_CODE_TEE log or a MINI_CODE_SAVE_TRUNCATED_DIR file.
+//! Three kinds of match are replaced with `***REDACTED***`:
+//!
+//! - `NAME=value` where NAME looks like a key, token, secret or password
+//! - strings shaped like well-known API keys (`sk-ant-...`, `ghp_...`, ...)
+//! - the current values of named secret environment variables
+//!
+//! Extra regexes come from `MINI_CODE_REDACT_PATTERNS`, extra variable names
+//! from `MINI_CODE_SECRET_NAMES`.
+
+use anyhow::{Context, Result};
+use mini_code_core::tools::cap_output_redacted;
+use regex::Regex;
+
+pub const REDACTED: &str = "***REDACTED***";
+
+/// Secret-looking `NAME=value` assignments (also `export NAME="value"`)
+const ASSIGNMENT_PATTERN: &str =
+    r#"\b([A-Z0-9_]*(?:KEY|TOKEN|SECRET|PASSWORD|PASSWD)[A-Z0-9_]*)(\s*=\s*)["']?[^\s"']+["']?"#;
+
+/// Shapes of common API keys
+const KEY_PATTERNS: &[&str] = &[
+    r"sk-ant-[A-Za-z0-9_\-]{10,}",
+    r"sk-[A-Za-z0-9]{20,}",
+    r"gh[pousr]_[A-Za-z0-9]{20,}",
+    r"AKIA[0-9A-Z]{16}",
+    r"xox[abprs]-[A-Za-z0-9\-]{10,}",
+];
+
+/// Variables whose values are always redacted
+const DEFAULT_SECRET_NAMES: &[&str] = &["ANTHROPIC_API_KEY", "ANTHROPIC_AUTH_TOKEN"];
+
+/// Values shorter than this aren't treated as secrets (too many false hits)
+const MIN_SECRET_LEN: usize = 8;
+
+#[derive(Debug)]
+pub struct Redactor {
+    assignment: Regex,
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    /// Build from extra regexes and `(name, value)` pairs of secret variables
+    pub fn new(extra_patterns: &[String], secrets: &[(String, String)]) -> Result<Self> {
+        let mut patterns = Vec::new();
+        for pattern in KEY_PATTERNS {
+            patterns.push(Regex::new(pattern)?);
+        }
+        for pattern in extra_patterns {
+            patterns.push(Regex::new(pattern).with_context(|| {
+                format!("Invalid MINI_CODE_REDACT_PATTERNS entry: {}", pattern)
+            })?);
+        }
+        for (_, value) in secrets {
+            if value.len() >= MIN_SECRET_LEN {
+                patterns.push(Regex::new(&regex::escape(value))?);
+            }
+        }
+
+        Ok(Self {
+            assignment: Regex::new(ASSIGNMENT_PATTERN)?,
+            patterns,
+        })
+    }
+
+    /// Build from the environment: MINI_CODE_REDACT_PATTERNS holds
+    /// whitespace-separated regexes, MINI_CODE_SECRET_NAMES comma-separated
+    /// variable names (added to ANTHROPIC_API_KEY / ANTHROPIC_AUTH_TOKEN).
+    pub fn from_env() -> Result<Self> {
+        let extra_patterns: Vec<String> = std::env::var("MINI_CODE_REDACT_PATTERNS")
+            .unwrap_or_default()
+            .split_whitespace()
+            .map(str::to_string)
+            .collect();
+
+        let extra_names = std::env::var("MINI_CODE_SECRET_NAMES").unwrap_or_default();
+        let secrets: Vec<(String, String)> = DEFAULT_SECRET_NAMES
+            .iter()
+            .copied()
+            .chain(extra_names.split(',').map(str::trim))
+            .filter(|name| !name.is_empty())
+            .filter_map(|name| {
+                std::env::var(name)
+                    .ok()
+                    .map(|value| (name.to_string(), value))
+            })
+            .collect();
+
+        Self::new(&extra_patterns, &secrets)
+    }
+
+    /// Number of value patterns (key shapes, extra regexes, secret values)
+    pub fn pattern_count(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn redact(&self, text: &str) -> String {
+        let mut text = self
+            .assignment
+            .replace_all(text, format!("${{1}}${{2}}{}", REDACTED).as_str())
+            .into_owned();
+        for pattern in &self.patterns {
+            text = pattern.replace_all(&text, REDACTED).into_owned();
+        }
+        text
+    }
+
+    /// Cap tool output; whatever MINI_CODE_SAVE_TRUNCATED_DIR keeps in full
+    /// is redacted first
+    pub fn cap(&self, output: String) -> String {
+        cap_output_redacted(output, &|text| self.redact(text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacts_assignments_and_key_shapes() {
+        let redactor = Redactor::new(&[], &[]).unwrap();
+
+        assert_eq!(
+            redactor.redact("export GITHUB_TOKEN=\"abc123\"\nDEBUG=true"),
+            "export GITHUB_TOKEN=***REDACTED***\nDEBUG=true"
+        );
+        assert_eq!(
+            redactor.redact("using key sk-ant-REDACTED now"),
+            "using key ***REDACTED*** now"
+        );
+        // Ordinary code isn't touched
+        assert_eq!(
+            redactor.redact("let key = map.get(k);"),
+            "let key = map.get(k);"
+        );
+    }
+
+    #[test]
+    fn test_redacts_configured_values_and_patterns() {
+        let redactor = Redactor::new(
+            &[r"internal-[0-9]{4}".to_string()],
+            &[
+                ("DB_URL".to_string(), "postgres://u:hunter22@db".to_string()),
+                ("SHORT".to_string(), "abc".to_string()),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            redactor.redact("connect postgres://u:hunter22@db ok, ticket internal-1234, abc"),
+            "connect ***REDACTED*** ok, ticket ***REDACTED***, abc"
+        );
+        assert!(Redactor::new(&["(".to_string()], &[]).is_err());
+    }
+}
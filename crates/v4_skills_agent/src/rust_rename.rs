@@ -0,0 +1,133 @@
+//! Rust Rename Module
+//!
+//! Identifier-level renames for the `rust_rename` tool. The file is
+//! tokenized with proc-macro2, so only identifier tokens spelled exactly
+//! `from` change; comments, string literals, lifetimes and longer names
+//! that merely contain `from` are left as they were.
+
+use anyhow::{Context, Result};
+use proc_macro2::{Span, TokenStream, TokenTree};
+use std::str::FromStr;
+
+/// Replace every identifier token `from` in `source` with `to`.
+/// Returns the new source and how many tokens were renamed.
+pub fn rename_identifiers(source: &str, from: &str, to: &str) -> Result<(String, usize)> {
+    for name in [from, to] {
+        syn::parse_str::<syn::Ident>(name)
+            .map_err(|_| anyhow::anyhow!("'{}' is not a valid Rust identifier", name))?;
+    }
+    syn::parse_file(source).map_err(|e| {
+        let at = e.span().start();
+        anyhow::anyhow!(
+            "not valid Rust ({} at {}:{}); use edit_file instead",
+            e,
+            at.line,
+            at.column + 1
+        )
+    })?;
+    let tokens = TokenStream::from_str(source)
+        .map_err(|e| anyhow::anyhow!("{}", e))
+        .context("failed to tokenize")?;
+
+    let mut spans = Vec::new();
+    collect_idents(tokens, from, &mut spans);
+
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    let mut offsets: Vec<usize> = spans
+        .iter()
+        .filter_map(|span| {
+            // Lines are 1-based; columns count chars, not bytes
+            let start = span.start();
+            let line_start = *line_starts.get(start.line.checked_sub(1)?)?;
+            let offset = line_start
+                + source[line_start..]
+                    .char_indices()
+                    .nth(start.column)
+                    .map_or(0, |(i, _)| i);
+            // Doc comments become `doc` tokens spanning the comment; the
+            // text check keeps such synthesized tokens out
+            (source.get(offset..offset + from.len()) == Some(from)).then_some(offset)
+        })
+        .collect();
+    offsets.sort_unstable();
+    offsets.dedup();
+
+    let mut renamed = String::with_capacity(source.len());
+    let mut last = 0;
+    for &offset in &offsets {
+        renamed.push_str(&source[last..offset]);
+        renamed.push_str(to);
+        last = offset + from.len();
+    }
+    renamed.push_str(&source[last..]);
+    Ok((renamed, offsets.len()))
+}
+
+/// Spans of identifier tokens equal to `name`, skipping lifetimes (`'name`)
+fn collect_idents(tokens: TokenStream, name: &str, spans: &mut Vec<Span>) {
+    let mut after_quote = false;
+    for tree in tokens {
+        match &tree {
+            TokenTree::Group(group) => collect_idents(group.stream(), name, spans),
+            TokenTree::Ident(ident) if !after_quote && ident == name => spans.push(ident.span()),
+            _ => {}
+        }
+        after_quote = matches!(&tree, TokenTree::Punct(p) if p.as_char() == '\'');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renames_only_whole_identifier_tokens() {
+        let source = r#"/// Returns the count plus one
+fn count(count_all: bool) -> usize {
+    // count is recounted below
+    let recount = count_all as usize;
+    let label = "count";
+    let café = 'x';
+    recount + count_items(café) + count
+}
+
+struct Counter<'count> {
+    count: &'count usize,
+}
+"#;
+        let (renamed, changed) = rename_identifiers(source, "count", "total").unwrap();
+        assert_eq!(changed, 3);
+        assert_eq!(
+            renamed,
+            r#"/// Returns the count plus one
+fn total(count_all: bool) -> usize {
+    // count is recounted below
+    let recount = count_all as usize;
+    let label = "count";
+    let café = 'x';
+    recount + count_items(café) + total
+}
+
+struct Counter<'count> {
+    total: &'count usize,
+}
+"#
+        );
+    }
+
+    #[test]
+    fn test_substrings_and_bad_input_are_left_alone() {
+        let source = "fn handler() { let handler_map = handlers(); prehandler(); }\n";
+        let (renamed, changed) = rename_identifiers(source, "handle", "process").unwrap();
+        assert_eq!(changed, 0);
+        assert_eq!(renamed, source);
+
+        let err = rename_identifiers("fn broken( {", "broken", "fixed").unwrap_err();
+        assert!(err.to_string().starts_with("not valid Rust"));
+        assert!(err.to_string().ends_with("use edit_file instead"));
+        assert!(rename_identifiers(source, "handler", "fn").is_err());
+        assert!(rename_identifiers(source, "handler", "two words").is_err());
+    }
+}
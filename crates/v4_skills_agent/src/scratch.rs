@@ -0,0 +1,110 @@
+//! Scratch Directory Module
+//!
+//! A per-session directory under the OS temp dir for notes, intermediate
+//! output and other throwaway files the model shouldn't leave in the
+//! workspace. It is created on first write and removed when dropped.
+
+use anyhow::{Context, Result};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Largest scratch file returned by `read`
+const MAX_READ_BYTES: usize = 50000;
+
+pub struct ScratchDir {
+    root: PathBuf,
+}
+
+impl ScratchDir {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// `<temp>/mini_code_scratch_<pid>`, unique per running agent
+    pub fn for_session() -> Self {
+        Self::new(std::env::temp_dir().join(format!("mini_code_scratch_{}", std::process::id())))
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Resolve `name` inside the scratch dir; absolute paths and `..` are refused
+    fn path(&self, name: &str) -> Result<PathBuf> {
+        let relative = Path::new(name);
+        let confined = relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if name.trim().is_empty() || !confined {
+            anyhow::bail!("Invalid scratch path '{}': use a relative path", name);
+        }
+        Ok(self.root.join(relative))
+    }
+
+    pub fn write(&self, name: &str, content: &str) -> Result<String> {
+        let path = self.path(name)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        fs::write(&path, content).with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(format!(
+            "Wrote {} bytes to scratch {} ({})",
+            content.len(),
+            name,
+            path.display()
+        ))
+    }
+
+    pub fn read(&self, name: &str) -> Result<String> {
+        let path = self.path(name)?;
+        let bytes = fs::read(&path).with_context(|| format!("No scratch file '{}'", name))?;
+        let text = String::from_utf8_lossy(&bytes);
+        if text.len() > MAX_READ_BYTES {
+            let mut cut = MAX_READ_BYTES;
+            while !text.is_char_boundary(cut) {
+                cut -= 1;
+            }
+            Ok(format!("{}...", &text[..cut]))
+        } else {
+            Ok(text.into_owned())
+        }
+    }
+
+    /// Remove the directory and everything in it
+    pub fn cleanup(&self) {
+        fs::remove_dir_all(&self.root).ok();
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        self.cleanup();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scratch_files_live_in_temp_and_are_cleaned_up() {
+        let root = std::env::temp_dir().join(format!("v4_scratch_{}", std::process::id()));
+        let scratch = ScratchDir::new(root.clone());
+
+        scratch.write("notes/plan.md", "step 1").unwrap();
+        assert!(root.starts_with(std::env::temp_dir()));
+        assert_eq!(
+            fs::read_to_string(root.join("notes/plan.md")).unwrap(),
+            "step 1"
+        );
+        assert_eq!(scratch.read("notes/plan.md").unwrap(), "step 1");
+
+        assert!(scratch.write("../escape.txt", "x").is_err());
+        assert!(scratch.write("/etc/passwd", "x").is_err());
+        assert!(scratch.read("missing.txt").is_err());
+
+        drop(scratch);
+        assert!(!root.exists());
+    }
+}
@@ -0,0 +1,204 @@
+//! Span Trace Module
+//!
+//! With `--trace PATH` (or `MINI_CODE_TRACE=PATH`) agent turns, subagent runs,
+//! API calls and tool executions are recorded as nested `tracing` spans and
+//! written to PATH as a JSON tree:
+//!
+//! ```text
+//! {"spans": [{"name": "agent_loop", "fields": {}, "start_ms": 0.0,
+//!             "duration_ms": 812.4, "children": [...]}]}
+//! ```
+//!
+//! The file is rewritten whenever a top-level span closes, so it stays
+//! complete even if the process is killed mid-session.
+
+use anyhow::{Context as _, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// One finished span and the spans that closed inside it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpanNode {
+    pub name: String,
+    pub fields: BTreeMap<String, serde_json::Value>,
+    /// Offset from the start of tracing
+    pub start_ms: f64,
+    pub duration_ms: f64,
+    pub children: Vec<SpanNode>,
+}
+
+/// Span state kept in the registry's extensions until the span closes
+struct OpenSpan {
+    node: SpanNode,
+    started: Instant,
+}
+
+struct FieldVisitor<'a>(&'a mut BTreeMap<String, serde_json::Value>);
+
+impl Visit for FieldVisitor<'_> {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0
+            .insert(field.name().to_string(), format!("{:?}", value).into());
+    }
+}
+
+/// `tracing` layer that assembles closed spans into a tree on disk
+pub struct SpanTreeLayer {
+    path: PathBuf,
+    epoch: Instant,
+    roots: Arc<Mutex<Vec<SpanNode>>>,
+}
+
+impl SpanTreeLayer {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            epoch: Instant::now(),
+            roots: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    fn write(&self, roots: &[SpanNode]) {
+        let data = serde_json::json!({ "spans": roots });
+        if let Ok(text) = serde_json::to_string_pretty(&data) {
+            fs::write(&self.path, text).ok();
+        }
+    }
+}
+
+impl<S> Layer<S> for SpanTreeLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut fields = BTreeMap::new();
+        attrs.record(&mut FieldVisitor(&mut fields));
+
+        span.extensions_mut().insert(OpenSpan {
+            node: SpanNode {
+                name: span.name().to_string(),
+                fields,
+                start_ms: self.epoch.elapsed().as_secs_f64() * 1000.0,
+                duration_ms: 0.0,
+                children: Vec::new(),
+            },
+            started: Instant::now(),
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut extensions = span.extensions_mut();
+        if let Some(open) = extensions.get_mut::<OpenSpan>() {
+            values.record(&mut FieldVisitor(&mut open.node.fields));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        let Some(open) = span.extensions_mut().remove::<OpenSpan>() else {
+            return;
+        };
+        let mut node = open.node;
+        node.duration_ms = open.started.elapsed().as_secs_f64() * 1000.0;
+
+        match span.parent() {
+            Some(parent) => {
+                let mut extensions = parent.extensions_mut();
+                if let Some(parent) = extensions.get_mut::<OpenSpan>() {
+                    parent.node.children.push(node);
+                }
+            }
+            None => {
+                let mut roots = self.roots.lock().unwrap();
+                roots.push(node);
+                self.write(&roots);
+            }
+        }
+    }
+}
+
+/// Install the span-tree exporter as the global subscriber
+pub fn init(path: &Path) -> Result<()> {
+    fs::write(path, "{\"spans\": []}")
+        .with_context(|| format!("Failed to create trace file {}", path.display()))?;
+    let subscriber = tracing_subscriber::registry().with(SpanTreeLayer::new(path));
+    tracing::subscriber::set_global_default(subscriber).context("Tracing already initialized")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::field::Empty;
+
+    #[derive(Deserialize)]
+    struct TraceFile {
+        spans: Vec<SpanNode>,
+    }
+
+    #[test]
+    fn test_span_tree_file() {
+        let path = std::env::temp_dir().join(format!("v4_trace_{}.json", std::process::id()));
+        let subscriber = tracing_subscriber::registry().with(SpanTreeLayer::new(&path));
+
+        tracing::subscriber::with_default(subscriber, || {
+            let turn = tracing::info_span!("agent_loop");
+            turn.in_scope(|| {
+                let api = tracing::info_span!("api_call", input_tokens = Empty);
+                api.record("input_tokens", 120u64);
+                drop(api);
+
+                tracing::info_span!("run_task", agent_type = "explore").in_scope(|| {
+                    tracing::info_span!("tool", name = "bash").in_scope(|| {});
+                });
+            });
+        });
+
+        let trace: TraceFile = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        fs::remove_file(&path).ok();
+
+        assert_eq!(trace.spans.len(), 1);
+        let turn = &trace.spans[0];
+        assert_eq!(turn.name, "agent_loop");
+        let names: Vec<&str> = turn.children.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["api_call", "run_task"]);
+        assert_eq!(turn.children[0].fields["input_tokens"], 120);
+
+        let task = &turn.children[1];
+        assert_eq!(task.fields["agent_type"], "explore");
+        assert_eq!(task.children[0].name, "tool");
+        assert_eq!(task.children[0].fields["name"], "bash");
+        assert!(turn.duration_ms >= task.duration_ms);
+        assert!(task.start_ms >= turn.start_ms);
+    }
+}
@@ -76,10 +76,16 @@
 
 use anthropic::types::{
     ContentBlock, Message, MessagesRequestBuilder, Role, StopReason, SystemPrompt, Tool,
+    ToolResultContent,
 };
 use anthropic::Client;
 use anyhow::{Context, Result};
 use colored::Colorize;
+use mini_code_core::api::{api_version_hint, apply_api_version};
+use mini_code_core::color::configure_colors;
+use mini_code_core::todo::{TodoItem, TodoManager};
+use mini_code_core::tokens;
+use mini_code_core::tools::{run_bash, run_edit, run_read, run_write, safe_truncate};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
@@ -88,15 +94,14 @@ use std::env;
 use std::fs;
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, TryLockError};
 use std::thread;
 use std::time::{Duration, Instant};
 
 // MCP browser client module
 mod mcp_client;
-use mcp_client::McpBrowserClient;
+use mcp_client::{kill_pid, McpBrowserClient, McpServer, StartupPolicy};
 
 #[cfg(not(feature = "readline"))]
 use std::io::BufRead;
@@ -166,14 +171,15 @@ struct Config {
     skills_dir: PathBuf,
     max_output_tokens: u32,
     max_truncation_retries: usize,
+    /// How long to wait for the MCP server to answer `initialize`
+    mcp_startup_timeout_secs: u64,
 }
 
 impl Config {
     fn from_env() -> Result<Self> {
         dotenvy::dotenv().ok();
 
-        let model =
-            env::var("MODEL_NAME").unwrap_or_else(|_| "claude-sonnet-4-20250514".to_string());
+        let model = mini_code_core::model::resolve_model();
         let workdir = env::current_dir().context("Failed to get current directory")?;
         let skills_dir = workdir.join("skills");
 
@@ -189,12 +195,19 @@ impl Config {
             .unwrap_or(3)
             .clamp(1, 10);
 
+        let mcp_startup_timeout_secs = env::var("MINI_CODE_MCP_STARTUP_TIMEOUT_SECS")
+            .ok()
+            .and_then(|s| s.parse::<u64>().ok())
+            .unwrap_or(30)
+            .max(1);
+
         Ok(Self {
             model,
             workdir,
             skills_dir,
             max_output_tokens,
             max_truncation_retries,
+            mcp_startup_timeout_secs,
         })
     }
 
@@ -459,91 +472,6 @@ fn get_agent_descriptions() -> String {
         .join("\n")
 }
 
-// =============================================================================
-// TodoManager (from v2)
-// =============================================================================
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct TodoItem {
-    content: String,
-    status: TodoStatus,
-    #[serde(rename = "activeForm")]
-    active_form: String,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
-#[serde(rename_all = "snake_case")]
-enum TodoStatus {
-    Pending,
-    InProgress,
-    Completed,
-}
-
-struct TodoManager {
-    items: Arc<Mutex<Vec<TodoItem>>>,
-}
-
-impl TodoManager {
-    fn new() -> Self {
-        Self {
-            items: Arc::new(Mutex::new(Vec::new())),
-        }
-    }
-
-    fn update(&self, new_items: Vec<TodoItem>) -> Result<String> {
-        let mut in_progress_count = 0;
-
-        for (i, item) in new_items.iter().enumerate() {
-            if item.content.trim().is_empty() {
-                anyhow::bail!("Item {}: content required", i);
-            }
-            if item.active_form.trim().is_empty() {
-                anyhow::bail!("Item {}: activeForm required", i);
-            }
-            if item.status == TodoStatus::InProgress {
-                in_progress_count += 1;
-            }
-        }
-
-        if new_items.len() > 20 {
-            anyhow::bail!("Max 20 todos allowed");
-        }
-        if in_progress_count > 1 {
-            anyhow::bail!("Only one task can be in_progress at a time");
-        }
-
-        *self.items.lock().unwrap() = new_items;
-
-        Ok(self.render())
-    }
-
-    fn render(&self) -> String {
-        let items = self.items.lock().unwrap();
-
-        if items.is_empty() {
-            return "No todos.".to_string();
-        }
-
-        let mut lines = Vec::new();
-        for item in items.iter() {
-            let line = match item.status {
-                TodoStatus::Completed => format!("[x] {}", item.content),
-                TodoStatus::InProgress => format!("[>] {} <- {}", item.content, item.active_form),
-                TodoStatus::Pending => format!("[ ] {}", item.content),
-            };
-            lines.push(line);
-        }
-
-        let completed = items
-            .iter()
-            .filter(|t| t.status == TodoStatus::Completed)
-            .count();
-        lines.push(format!("\n({}/{} completed)", completed, items.len()));
-
-        lines.join("\n")
-    }
-}
-
 // =============================================================================
 // Web Search Tool (from ai-research-agent)
 // =============================================================================
@@ -841,7 +769,7 @@ fn create_base_tools() -> Vec<Tool> {
         },
         Tool {
             name: "browser_screenshot".to_string(),
-            description: "Take a screenshot of the current browser page. Returns the screenshot as base64 image data.".to_string(),
+            description: "Take a screenshot of the current browser page. The screenshot is returned as an image you can look at.".to_string(),
             input_schema: json!({
                 "type": "object",
                 "properties": {},
@@ -996,167 +924,6 @@ fn get_tools_for_subagent(agent_type: &str, skill_loader: &SkillLoader) -> Vec<T
 // Tool Implementations
 // =============================================================================
 
-fn safe_truncate(s: &str, max_bytes: usize) -> &str {
-    if s.len() <= max_bytes {
-        return s;
-    }
-
-    let mut boundary = max_bytes;
-    while boundary > 0 && !s.is_char_boundary(boundary) {
-        boundary -= 1;
-    }
-
-    &s[..boundary]
-}
-
-fn safe_path(workdir: &Path, relative_path: &str) -> Result<PathBuf> {
-    let path = workdir.join(relative_path);
-    let canonical = path.canonicalize().or_else(|_| {
-        if let Some(parent) = path.parent() {
-            if parent.exists() {
-                Ok(parent.canonicalize()?.join(
-                    path.file_name()
-                        .ok_or_else(|| anyhow::anyhow!("Invalid path"))?,
-                ))
-            } else {
-                Err(anyhow::anyhow!("Parent directory does not exist"))
-            }
-        } else {
-            Err(anyhow::anyhow!("Invalid path"))
-        }
-    })?;
-
-    if !canonical.starts_with(workdir) {
-        anyhow::bail!("Path escapes workspace: {}", relative_path);
-    }
-
-    Ok(canonical)
-}
-
-fn run_bash(workdir: &Path, command: &str) -> String {
-    let dangerous = ["rm -rf /", "sudo", "shutdown", "reboot", "> /dev/"];
-    if dangerous.iter().any(|d| command.contains(d)) {
-        return "Error: Dangerous command blocked".to_string();
-    }
-
-    let output = Command::new("sh")
-        .arg("-c")
-        .arg(command)
-        .current_dir(workdir)
-        .output();
-
-    match output {
-        Ok(output) => {
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            let stderr = String::from_utf8_lossy(&output.stderr);
-            let combined = format!("{}{}", stdout, stderr).trim().to_string();
-
-            if combined.is_empty() {
-                "(no output)".to_string()
-            } else if combined.len() > 50000 {
-                format!("{}...", safe_truncate(&combined, 50000))
-            } else {
-                combined
-            }
-        }
-        Err(e) => format!("Error: {}", e),
-    }
-}
-
-fn run_read(workdir: &Path, path: &str, limit: Option<i64>) -> String {
-    match safe_path(workdir, path) {
-        Ok(safe_path) => {
-            // Read file as raw bytes first to handle non-UTF8 content gracefully
-            match fs::read(&safe_path) {
-                Ok(bytes) => {
-                    // Check for non-UTF8 bytes
-                    let has_invalid_utf8 = bytes.iter().any(|&b| b > 0x7F && !b.is_ascii());
-
-                    let content = if has_invalid_utf8 {
-                        // Use lossy conversion to handle binary data in log files
-                        String::from_utf8_lossy(&bytes).to_string()
-                    } else {
-                        // Safe to use strict UTF-8 for pure ASCII files
-                        match String::from_utf8(bytes) {
-                            Ok(s) => s,
-                            Err(e) => {
-                                // Fallback to lossy conversion
-                                String::from_utf8_lossy(e.as_bytes()).to_string()
-                            }
-                        }
-                    };
-
-                    let lines: Vec<&str> = content.lines().collect();
-                    let total_lines = lines.len();
-
-                    let output = if let Some(limit) = limit {
-                        if limit > 0 && (limit as usize) < total_lines {
-                            let limited_lines: Vec<&str> =
-                                lines.iter().take(limit as usize).copied().collect();
-                            format!(
-                                "{}\n... ({} more lines)",
-                                limited_lines.join("\n"),
-                                total_lines - limit as usize
-                            )
-                        } else {
-                            content
-                        }
-                    } else {
-                        content
-                    };
-
-                    if output.len() > 50000 {
-                        format!("{}...", safe_truncate(&output, 50000))
-                    } else {
-                        output
-                    }
-                }
-                Err(e) => format!("Error reading file: {}", e),
-            }
-        }
-        Err(e) => format!("Error: {}", e),
-    }
-}
-
-fn run_write(workdir: &Path, path: &str, content: &str) -> String {
-    match safe_path(workdir, path) {
-        Ok(safe_path) => {
-            if let Some(parent) = safe_path.parent() {
-                if let Err(e) = fs::create_dir_all(parent) {
-                    return format!("Error creating parent directories: {}", e);
-                }
-            }
-
-            match fs::write(&safe_path, content) {
-                Ok(_) => format!("Wrote {} bytes to {}", content.len(), path),
-                Err(e) => format!("Error: {}", e),
-            }
-        }
-        Err(e) => format!("Error: {}", e),
-    }
-}
-
-fn run_edit(workdir: &Path, path: &str, old_text: &str, new_text: &str) -> String {
-    match safe_path(workdir, path) {
-        Ok(safe_path) => match fs::read_to_string(&safe_path) {
-            Ok(content) => {
-                if !content.contains(old_text) {
-                    return format!("Error: Text not found in {}", path);
-                }
-
-                let new_content = content.replacen(old_text, new_text, 1);
-
-                match fs::write(&safe_path, new_content) {
-                    Ok(_) => format!("Edited {}", path),
-                    Err(e) => format!("Error: {}", e),
-                }
-            }
-            Err(e) => format!("Error: {}", e),
-        },
-        Err(e) => format!("Error: {}", e),
-    }
-}
-
 fn run_todo(todo_manager: &TodoManager, items: Vec<TodoItem>) -> String {
     match todo_manager.update(items) {
         Ok(rendered) => rendered,
@@ -1216,11 +983,32 @@ fn run_browser_navigate(mcp_client: &Arc<Mutex<McpBrowserClient>>, url: &str) ->
     }
 }
 
-fn run_browser_screenshot(mcp_client: &Arc<Mutex<McpBrowserClient>>) -> String {
+/// Screenshots go back to the model as image blocks so it can look at them
+fn run_browser_screenshot(mcp_client: &Arc<Mutex<McpBrowserClient>>) -> ToolResultContent {
     let client = mcp_client.lock().unwrap();
     match client.screenshot() {
-        Ok(result) => result,
-        Err(e) => format!("Error taking screenshot: {}", e),
+        Ok(blocks) => ToolResultContent::Blocks(blocks),
+        Err(e) => ToolResultContent::Text(format!("Error taking screenshot: {}", e)),
+    }
+}
+
+/// Text form of a tool result for previews and text-only callers
+fn tool_result_text(content: &ToolResultContent) -> String {
+    match content {
+        ToolResultContent::Text(text) => text.clone(),
+        ToolResultContent::Blocks(blocks) => blocks
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::Text { text } => Some(text.clone()),
+                ContentBlock::Image { source } => Some(format!(
+                    "[image: {}, {} bytes base64]",
+                    source.media_type,
+                    source.data.len()
+                )),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
     }
 }
 
@@ -1517,19 +1305,23 @@ Complete the task and return a clear, concise summary."#,
                             progress_guard.current_tool = Some(tool_display);
                         }
 
-                        let output = execute_tool(
-                            config,
-                            todo_manager,
-                            skill_loader,
-                            mcp_client,
-                            name,
-                            input,
-                        );
+                        let content = if name == "browser_screenshot" {
+                            run_browser_screenshot(mcp_client)
+                        } else {
+                            ToolResultContent::Text(execute_tool(
+                                config,
+                                todo_manager,
+                                skill_loader,
+                                mcp_client,
+                                name,
+                                input,
+                            ))
+                        };
 
                         results.push(ContentBlock::ToolResult {
                             tool_use_id: id.clone(),
                             is_error: None,
-                            content: anthropic::types::ToolResultContent::Text(output),
+                            content,
                         });
 
                         {
@@ -1672,7 +1464,7 @@ fn execute_tool(
                 "Error: Missing 'url' parameter".to_string()
             }
         }
-        "browser_screenshot" => run_browser_screenshot(mcp_client),
+        "browser_screenshot" => tool_result_text(&run_browser_screenshot(mcp_client)),
         "browser_get_performance" => run_browser_get_performance(mcp_client),
         "browser_snapshot" => run_browser_snapshot(mcp_client),
         "browser_get_network" => run_browser_get_network(mcp_client),
@@ -1759,6 +1551,9 @@ async fn execute_tool_async(
 // Token Management (from v3)
 // =============================================================================
 
+/// Rough cost of one screenshot-sized image block
+const IMAGE_TOKEN_ESTIMATE: usize = 1600;
+
 fn estimate_context_tokens(messages: &[Message], system: &str) -> usize {
     let messages_tokens: usize = messages
         .iter()
@@ -1771,8 +1566,15 @@ fn estimate_context_tokens(messages: &[Message], system: &str) -> usize {
                         serde_json::to_string(input).unwrap_or_default().len() / 4
                     }
                     ContentBlock::ToolResult { content, .. } => match content {
-                        anthropic::types::ToolResultContent::Text(t) => t.len() / 4,
-                        _ => 100, // Default estimate
+                        ToolResultContent::Text(t) => t.len() / 4,
+                        // Images are billed by pixels, not base64 length
+                        ToolResultContent::Blocks(blocks) => blocks
+                            .iter()
+                            .map(|b| match b {
+                                ContentBlock::Text { text } => text.len() / 4,
+                                _ => IMAGE_TOKEN_ESTIMATE,
+                            })
+                            .sum(),
                     },
                     _ => 0,
                 })
@@ -1784,17 +1586,6 @@ fn estimate_context_tokens(messages: &[Message], system: &str) -> usize {
     messages_tokens + system_tokens
 }
 
-fn calculate_max_tokens(messages: &[Message], system: &str, max_output_tokens: u32) -> u32 {
-    const MAX_CONTEXT: usize = 200000;
-    const OUTPUT_RATIO: f64 = 0.4;
-
-    let context = estimate_context_tokens(messages, system);
-    let available = MAX_CONTEXT.saturating_sub(context);
-    let max_output = (available as f64 * OUTPUT_RATIO) as u32;
-
-    max_output.min(max_output_tokens).max(4000)
-}
-
 // =============================================================================
 // Main Agent Loop (adapted for v4 with Skills + Task + Todo)
 // =============================================================================
@@ -1817,7 +1608,10 @@ async fn agent_loop(
     let mut consecutive_truncations = 0;
 
     loop {
-        let max_tokens = calculate_max_tokens(messages, &system, config.max_output_tokens);
+        let max_tokens = tokens::calculate_max_tokens(
+            estimate_context_tokens(messages, &system),
+            config.max_output_tokens,
+        );
 
         let request = MessagesRequestBuilder::new(&config.model, messages.clone(), max_tokens)
             .system(SystemPrompt::Text(system.clone()))
@@ -1893,16 +1687,23 @@ async fn agent_loop(
                     };
                     println!("\n{}", tool_display);
 
-                    let output = execute_tool_async(
-                        client,
-                        config,
-                        &todo_manager,
-                        skill_loader,
-                        mcp_client,
-                        &name,
-                        &input,
-                    )
-                    .await;
+                    let content = if name == "browser_screenshot" {
+                        run_browser_screenshot(mcp_client)
+                    } else {
+                        ToolResultContent::Text(
+                            execute_tool_async(
+                                client,
+                                config,
+                                &todo_manager,
+                                skill_loader,
+                                mcp_client,
+                                &name,
+                                &input,
+                            )
+                            .await,
+                        )
+                    };
+                    let output = tool_result_text(&content);
 
                     // Display output
                     let preview = if name == "TodoWrite"
@@ -1938,7 +1739,7 @@ async fn agent_loop(
                     results.push(ContentBlock::ToolResult {
                         tool_use_id: id,
                         is_error: None,
-                        content: anthropic::types::ToolResultContent::Text(output),
+                        content,
                     });
                 }
 
@@ -1993,9 +1794,7 @@ fn create_client() -> Result<Client> {
         builder = builder.api_base(base_url);
     }
 
-    if let Ok(api_version) = env::var("ANTHROPIC_API_VERSION") {
-        builder = builder.api_version(api_version);
-    }
+    builder = apply_api_version(builder);
 
     builder = builder.timeout(Duration::from_secs(600));
 
@@ -2044,12 +1843,107 @@ fn prompt_user() -> Result<String> {
     Ok(trimmed.to_string())
 }
 
+// =============================================================================
+// Graceful Shutdown
+// =============================================================================
+
+/// A registered MCP server and its process id, which stays readable while
+/// the client is locked
+struct ServerHandle {
+    server: Arc<Mutex<dyn McpServer>>,
+    pid: Arc<AtomicU32>,
+}
+
+impl ServerHandle {
+    fn new(server: Arc<Mutex<dyn McpServer>>) -> Self {
+        let pid = server
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pid_handle();
+        Self { server, pid }
+    }
+}
+
+/// Stop every registered MCP server, returning how many stopped cleanly.
+///
+/// Runs on the signal task, so it never waits for a lock: a client that is
+/// busy mid-request has its process killed by id instead.
+fn shutdown_mcp_servers(servers: &[ServerHandle]) -> usize {
+    let mut stopped = 0;
+    for handle in servers {
+        let result = match handle.server.try_lock() {
+            Ok(guard) => guard.stop(),
+            // A poisoned lock still holds a live process, so stop it anyway
+            Err(TryLockError::Poisoned(e)) => e.into_inner().stop(),
+            Err(TryLockError::WouldBlock) => {
+                let pid = handle.pid.swap(0, Ordering::SeqCst);
+                if pid != 0 {
+                    kill_pid(pid);
+                }
+                Ok(())
+            }
+        };
+        match result {
+            Ok(()) => stopped += 1,
+            Err(e) => eprintln!("{} Failed to stop MCP server: {}", "⚠".bright_yellow(), e),
+        }
+    }
+    stopped
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown_signal() -> &'static str {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(_) => {
+            tokio::signal::ctrl_c().await.ok();
+            return "SIGINT";
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+        _ = sigterm.recv() => "SIGTERM",
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown_signal() -> &'static str {
+    tokio::signal::ctrl_c().await.ok();
+    "SIGINT"
+}
+
+/// Stop MCP servers and flush output on SIGINT/SIGTERM.
+///
+/// `Drop` alone is not enough: a signal kills the process without unwinding,
+/// which would leave orphaned `npx` processes behind.
+fn spawn_shutdown_handler(servers: Vec<ServerHandle>) {
+    tokio::spawn(async move {
+        let signal_name = wait_for_shutdown_signal().await;
+        eprintln!(
+            "\n{} Received {}, shutting down...",
+            "⚠".bright_yellow(),
+            signal_name
+        );
+
+        shutdown_mcp_servers(&servers);
+
+        io::stdout().flush().ok();
+        io::stderr().flush().ok();
+
+        std::process::exit(if signal_name == "SIGTERM" { 143 } else { 130 });
+    });
+}
+
 // =============================================================================
 // Main Entry Point
 // =============================================================================
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    configure_colors(env::args().any(|a| a == "--no-color"));
     let config = Config::from_env()?;
     let client = create_client()?;
     let skill_loader = SkillLoader::new(&config.skills_dir);
@@ -2062,7 +1956,9 @@ async fn main() -> Result<()> {
     if mcp_available {
         println!("{} chrome-devtools-mcp detected", "✓".bright_green());
         // Start MCP server
-        if let Err(e) = mcp_client.lock().unwrap().start() {
+        let policy =
+            StartupPolicy::with_timeout(Duration::from_secs(config.mcp_startup_timeout_secs));
+        if let Err(e) = mcp_client.lock().unwrap().start(&policy) {
             println!("{} Failed to start MCP server: {}", "⚠".bright_yellow(), e);
             println!("{} Browser tools will be unavailable", "⚠".bright_yellow());
         }
@@ -2075,6 +1971,8 @@ async fn main() -> Result<()> {
         println!("{} Browser tools will be unavailable", "⚠".bright_yellow());
     }
 
+    spawn_shutdown_handler(vec![ServerHandle::new(mcp_client.clone())]);
+
     // Display startup info
     println!("{}", "=".repeat(60).bright_black());
     println!(
@@ -2127,9 +2025,89 @@ async fn main() -> Result<()> {
             agent_loop(&client, &config, &skill_loader, &mcp_client, &mut messages).await
         {
             eprintln!("{} {}", "Error:".bright_red(), e);
+            if let Some(hint) = api_version_hint(&e.to_string()) {
+                eprintln!("{}", hint.bright_yellow());
+            }
             messages.pop();
         }
 
         println!();
     }
 }
+
+// =============================================================================
+// Tests
+// =============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    struct CountingServer {
+        stops: Arc<AtomicUsize>,
+        pid: Arc<AtomicU32>,
+    }
+
+    impl CountingServer {
+        fn handle(stops: &Arc<AtomicUsize>) -> ServerHandle {
+            ServerHandle::new(Arc::new(Mutex::new(CountingServer {
+                stops: stops.clone(),
+                pid: Arc::new(AtomicU32::new(0)),
+            })))
+        }
+    }
+
+    impl McpServer for CountingServer {
+        fn stop(&self) -> Result<()> {
+            self.stops.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn pid_handle(&self) -> Arc<AtomicU32> {
+            self.pid.clone()
+        }
+    }
+
+    #[test]
+    fn test_shutdown_stops_every_server() {
+        let stops = Arc::new(AtomicUsize::new(0));
+        let servers = vec![
+            CountingServer::handle(&stops),
+            CountingServer::handle(&stops),
+        ];
+
+        assert_eq!(shutdown_mcp_servers(&servers), 2);
+        assert_eq!(stops.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_shutdown_with_unstarted_browser_client() {
+        let servers = vec![ServerHandle::new(Arc::new(Mutex::new(
+            McpBrowserClient::new(),
+        )))];
+        assert_eq!(shutdown_mcp_servers(&servers), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_shutdown_kills_busy_server_by_pid() {
+        let mut process = std::process::Command::new("sleep")
+            .arg("30")
+            .spawn()
+            .unwrap();
+        let stops = Arc::new(AtomicUsize::new(0));
+        let servers = vec![CountingServer::handle(&stops)];
+        servers[0].pid.store(process.id(), Ordering::SeqCst);
+
+        // The client is locked mid-request; shutdown must not wait for it
+        let busy = servers[0].server.lock().unwrap();
+        assert_eq!(shutdown_mcp_servers(&servers), 1);
+        drop(busy);
+
+        let status = process.wait().unwrap();
+        assert!(!status.success());
+        assert_eq!(stops.load(Ordering::SeqCst), 0);
+        assert_eq!(servers[0].pid.load(Ordering::SeqCst), 0);
+    }
+}
@@ -2,17 +2,101 @@
 //!
 //! Provides integration with chrome-devtools-mcp for browser automation
 
+use anthropic::types::{ContentBlock, ImageSource};
 use anyhow::{Context, Result};
 use serde_json::{json, Value};
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::process::{Command, Stdio};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// How `start` waits for a freshly spawned server to answer `initialize`
+pub struct StartupPolicy {
+    /// Give up once this much time has passed since the spawn
+    pub timeout: Duration,
+    pub max_attempts: usize,
+    /// Pause after the first failed attempt; doubles each time, up to 2s
+    pub initial_backoff: Duration,
+}
+
+impl StartupPolicy {
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(250),
+        }
+    }
+}
+
+/// Longest pause between readiness attempts
+const MAX_STARTUP_BACKOFF: Duration = Duration::from_secs(2);
+
+/// Run `probe` until it succeeds, backing off between failures.
+///
+/// Returns the number of attempts used, or the last probe error once the
+/// attempts or the timeout run out. Each probe is handed the time left before
+/// the timeout and must give up by then.
+pub fn poll_until_ready<F>(policy: &StartupPolicy, mut probe: F) -> Result<usize>
+where
+    F: FnMut(Duration) -> Result<()>,
+{
+    let started = Instant::now();
+    let mut backoff = policy.initial_backoff;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let remaining = policy.timeout.saturating_sub(started.elapsed());
+        let error = match probe(remaining) {
+            Ok(()) => return Ok(attempt),
+            Err(e) => e,
+        };
+
+        let elapsed = started.elapsed();
+        if attempt >= policy.max_attempts || elapsed + backoff > policy.timeout {
+            anyhow::bail!(
+                "MCP server not ready after {} attempts ({:.1}s): {}",
+                attempt,
+                elapsed.as_secs_f64(),
+                error
+            );
+        }
+        thread::sleep(backoff);
+        backoff = (backoff * 2).min(MAX_STARTUP_BACKOFF);
+    }
+}
+
+/// An MCP client that owns an external server process and must stop it on exit
+pub trait McpServer: Send {
+    /// Stop the server process (idempotent)
+    fn stop(&self) -> Result<()>;
+
+    /// Process id of the running server, 0 when none. Shared so it can be
+    /// read while the client itself is locked mid-request.
+    fn pid_handle(&self) -> Arc<AtomicU32>;
+}
+
+/// Kill a server process by id, without going through its client
+pub fn kill_pid(pid: u32) {
+    #[cfg(unix)]
+    let killed = Command::new("kill")
+        .args(["-KILL", &pid.to_string()])
+        .stderr(Stdio::null())
+        .status();
+    #[cfg(not(unix))]
+    let killed = Command::new("taskkill")
+        .args(["/F", "/T", "/PID", &pid.to_string()])
+        .stderr(Stdio::null())
+        .status();
+    killed.ok();
+}
 
 /// MCP Browser client for controlling Chrome/Edge
 pub struct McpBrowserClient {
     process: Arc<Mutex<Option<std::process::Child>>>,
+    pid: Arc<AtomicU32>,
     request_id: Arc<Mutex<u64>>,
 }
 
@@ -21,6 +105,7 @@ impl McpBrowserClient {
     pub fn new() -> Self {
         Self {
             process: Arc::new(Mutex::new(None)),
+            pid: Arc::new(AtomicU32::new(0)),
             request_id: Arc::new(Mutex::new(0)),
         }
     }
@@ -37,8 +122,8 @@ impl McpBrowserClient {
         }
     }
 
-    /// Start the MCP server process
-    pub fn start(&self) -> Result<()> {
+    /// Start the MCP server process and wait until it answers `initialize`
+    pub fn start(&self, policy: &StartupPolicy) -> Result<()> {
         let mut process_guard = self
             .process
             .lock()
@@ -60,26 +145,79 @@ impl McpBrowserClient {
             .spawn()
             .context("Failed to start chrome-devtools-mcp. Make sure Node.js is installed")?;
 
+        self.pid.store(mcp_process.id(), Ordering::SeqCst);
         *process_guard = Some(mcp_process);
+        drop(process_guard);
 
-        // Give it time to start
-        thread::sleep(Duration::from_secs(2));
+        let ready = poll_until_ready(policy, |remaining| {
+            if let Some(status) = self.exit_status()? {
+                anyhow::bail!("server exited during startup ({})", status);
+            }
+            let request = json!({
+                "jsonrpc": "2.0",
+                "method": "initialize",
+                "params": {
+                    "protocolVersion": "2024-11-05",
+                    "capabilities": {},
+                    "clientInfo": {"name": "mini-code", "version": env!("CARGO_PKG_VERSION")}
+                },
+                "id": self.next_request_id()?
+            });
+            let response = self.execute_request_timeout(&request.to_string(), remaining)?;
+            if let Some(error) = response.get("error") {
+                anyhow::bail!("initialize failed: {}", error);
+            }
+            Ok(())
+        });
+        if let Err(e) = ready {
+            self.stop().ok();
+            return Err(e);
+        }
+        self.send_notification("notifications/initialized")?;
 
         println!("✅ chrome-devtools-mcp server started");
 
         Ok(())
     }
 
+    /// Exit status if the server process has already quit
+    fn exit_status(&self) -> Result<Option<std::process::ExitStatus>> {
+        let mut process_guard = self
+            .process
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock process: {}", e))?;
+        match process_guard.as_mut() {
+            Some(process) => Ok(process.try_wait()?),
+            None => anyhow::bail!("MCP process not started"),
+        }
+    }
+
+    /// Send a JSON-RPC notification (no id, no response expected)
+    fn send_notification(&self, method: &str) -> Result<()> {
+        let mut process_guard = self
+            .process
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock process: {}", e))?;
+        let stdin = process_guard
+            .as_mut()
+            .and_then(|p| p.stdin.as_mut())
+            .ok_or_else(|| anyhow::anyhow!("MCP process not started"))?;
+        let notification = json!({"jsonrpc": "2.0", "method": method});
+        writeln!(stdin, "{}", notification).context("Failed to write to MCP stdin")
+    }
+
+    fn next_request_id(&self) -> Result<u64> {
+        let mut id = self
+            .request_id
+            .lock()
+            .map_err(|e| anyhow::anyhow!("Failed to lock request_id: {}", e))?;
+        *id += 1;
+        Ok(*id)
+    }
+
     /// Send a JSON-RPC request to the MCP server
     fn send_request(&self, method: &str, params: Value) -> Result<Value> {
-        let request_id = {
-            let mut id = self
-                .request_id
-                .lock()
-                .map_err(|e| anyhow::anyhow!("Failed to lock request_id: {}", e))?;
-            *id += 1;
-            *id
-        };
+        let request_id = self.next_request_id()?;
 
         let request = json!({
             "jsonrpc": "2.0",
@@ -111,19 +249,55 @@ impl McpBrowserClient {
         }
 
         // Read response
-        if let Some(stdout) = process.stdout.as_mut() {
-            let reader = BufReader::new(stdout);
-            for line in reader.lines() {
-                let line = line?;
-                if line.trim().starts_with('{') {
-                    if let Ok(response) = serde_json::from_str::<Value>(&line) {
-                        return Ok(response);
+        match process.stdout.as_mut() {
+            Some(stdout) => read_response(stdout),
+            None => Err(anyhow::anyhow!("No valid response from MCP server")),
+        }
+    }
+
+    /// Execute a request, giving up after `timeout`. The response is read on
+    /// its own thread; on timeout the server is stopped, which ends that read.
+    fn execute_request_timeout(&self, request: &str, timeout: Duration) -> Result<Value> {
+        let mut stdout = {
+            let mut process_guard = self
+                .process
+                .lock()
+                .map_err(|e| anyhow::anyhow!("Failed to lock process: {}", e))?;
+            let process = process_guard
+                .as_mut()
+                .ok_or_else(|| anyhow::anyhow!("MCP process not started"))?;
+            if let Some(stdin) = process.stdin.as_mut() {
+                writeln!(stdin, "{}", request).context("Failed to write to MCP stdin")?;
+            }
+            process
+                .stdout
+                .take()
+                .ok_or_else(|| anyhow::anyhow!("No valid response from MCP server"))?
+        };
+
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let response = read_response(&mut stdout);
+            tx.send((stdout, response)).ok();
+        });
+
+        match rx.recv_timeout(timeout) {
+            Ok((stdout, response)) => {
+                if let Ok(mut process_guard) = self.process.lock() {
+                    if let Some(process) = process_guard.as_mut() {
+                        process.stdout = Some(stdout);
                     }
                 }
+                response
+            }
+            Err(_) => {
+                self.stop().ok();
+                anyhow::bail!(
+                    "no response within {:.1}s (MINI_CODE_MCP_STARTUP_TIMEOUT_SECS); server stopped",
+                    timeout.as_secs_f64()
+                )
             }
         }
-
-        Err(anyhow::anyhow!("No valid response from MCP server"))
     }
 
     /// Navigate to a URL
@@ -142,8 +316,8 @@ impl McpBrowserClient {
         extract_result_text(&response)
     }
 
-    /// Take a screenshot
-    pub fn screenshot(&self) -> Result<String> {
+    /// Take a screenshot; the image comes back as an image content block
+    pub fn screenshot(&self) -> Result<Vec<ContentBlock>> {
         let response = self.send_request(
             "tools/call",
             json!({
@@ -152,7 +326,7 @@ impl McpBrowserClient {
             }),
         )?;
 
-        extract_result_text(&response)
+        extract_result_blocks(&response)
     }
 
     /// Get performance metrics
@@ -235,6 +409,7 @@ impl McpBrowserClient {
             .map_err(|e| anyhow::anyhow!("Failed to lock process: {}", e))?;
 
         if let Some(mut process) = process_guard.take() {
+            self.pid.store(0, Ordering::SeqCst);
             process.kill().context("Failed to kill MCP process")?;
             println!("🛑 chrome-devtools-mcp server stopped");
         }
@@ -243,12 +418,35 @@ impl McpBrowserClient {
     }
 }
 
+impl McpServer for McpBrowserClient {
+    fn stop(&self) -> Result<()> {
+        McpBrowserClient::stop(self)
+    }
+
+    fn pid_handle(&self) -> Arc<AtomicU32> {
+        self.pid.clone()
+    }
+}
+
 impl Drop for McpBrowserClient {
     fn drop(&mut self) {
         let _ = self.stop();
     }
 }
 
+/// Read lines until one parses as a JSON-RPC message
+fn read_response(stdout: &mut impl Read) -> Result<Value> {
+    for line in BufReader::new(stdout).lines() {
+        let line = line?;
+        if line.trim().starts_with('{') {
+            if let Ok(response) = serde_json::from_str::<Value>(&line) {
+                return Ok(response);
+            }
+        }
+    }
+    Err(anyhow::anyhow!("No valid response from MCP server"))
+}
+
 /// Extract text content from MCP response
 fn extract_result_text(response: &Value) -> Result<String> {
     if let Some(result) = response.get("result") {
@@ -268,6 +466,52 @@ fn extract_result_text(response: &Value) -> Result<String> {
     Ok("Operation completed".to_string())
 }
 
+/// Map every item of an MCP tool result to a content block.
+///
+/// Text items stay text; base64 `image` items become image blocks so the
+/// model sees the picture instead of a placeholder.
+pub fn extract_result_blocks(response: &Value) -> Result<Vec<ContentBlock>> {
+    if let Some(error) = response.get("error") {
+        anyhow::bail!("MCP error: {}", error);
+    }
+
+    let items = response
+        .get("result")
+        .and_then(|r| r.get("content"))
+        .and_then(|c| c.as_array())
+        .map(Vec::as_slice)
+        .unwrap_or_default();
+
+    let mut blocks = Vec::new();
+    for item in items {
+        match item.get("type").and_then(|t| t.as_str()) {
+            Some("image") => {
+                let data = item.get("data").and_then(|d| d.as_str());
+                let mime = item.get("mimeType").and_then(|m| m.as_str());
+                if let (Some(data), Some(mime)) = (data, mime) {
+                    blocks.push(ContentBlock::Image {
+                        source: ImageSource {
+                            source_type: "base64".to_string(),
+                            media_type: mime.to_string(),
+                            data: data.to_string(),
+                        },
+                    });
+                }
+            }
+            _ => {
+                if let Some(text) = item.get("text").and_then(|t| t.as_str()) {
+                    blocks.push(ContentBlock::text(text));
+                }
+            }
+        }
+    }
+
+    if blocks.is_empty() {
+        blocks.push(ContentBlock::text("Operation completed"));
+    }
+    Ok(blocks)
+}
+
 /// Create browser automation tools for Claude
 #[allow(dead_code)]
 pub fn create_browser_tools() -> Vec<Value> {
@@ -312,3 +556,102 @@ pub fn create_browser_tools() -> Vec<Value> {
         }),
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fast_policy(max_attempts: usize) -> StartupPolicy {
+        StartupPolicy {
+            timeout: Duration::from_secs(5),
+            max_attempts,
+            initial_backoff: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn test_image_result_maps_to_image_block() {
+        let response = json!({
+            "jsonrpc": "2.0",
+            "id": 3,
+            "result": {
+                "content": [
+                    {"type": "text", "text": "Took a screenshot of the current page"},
+                    {"type": "image", "data": "iVBORw0KGgo=", "mimeType": "image/png"}
+                ]
+            }
+        });
+
+        let blocks = extract_result_blocks(&response).unwrap();
+        assert_eq!(blocks.len(), 2);
+        assert!(matches!(&blocks[0], ContentBlock::Text { text } if text.starts_with("Took")));
+        match &blocks[1] {
+            ContentBlock::Image { source } => {
+                assert_eq!(source.source_type, "base64");
+                assert_eq!(source.media_type, "image/png");
+                assert_eq!(source.data, "iVBORw0KGgo=");
+            }
+            other => panic!("expected an image block, got {:?}", other),
+        }
+
+        let error = json!({"error": {"code": -32000, "message": "no page"}});
+        assert!(extract_result_blocks(&error).is_err());
+    }
+
+    #[test]
+    fn test_poll_until_ready_after_a_few_attempts() {
+        // A fake server that only answers on the third probe
+        let mut probes = 0;
+        let attempts = poll_until_ready(&fast_policy(10), |_| {
+            probes += 1;
+            if probes < 3 {
+                anyhow::bail!("No valid response from MCP server")
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(attempts, 3);
+
+        let never = poll_until_ready(&fast_policy(4), |_| anyhow::bail!("connection refused"));
+        let message = never.unwrap_err().to_string();
+        assert!(message.starts_with("MCP server not ready after 4 attempts"));
+        assert!(message.ends_with("connection refused"));
+
+        let impatient = StartupPolicy {
+            timeout: Duration::from_millis(10),
+            max_attempts: 100,
+            initial_backoff: Duration::from_millis(20),
+        };
+        let timed_out = poll_until_ready(&impatient, |_| anyhow::bail!("still booting"));
+        assert!(timed_out
+            .unwrap_err()
+            .to_string()
+            .starts_with("MCP server not ready after 1 attempts"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hung_server_times_out_and_is_stopped() {
+        // Reads its input but never answers
+        let child = Command::new("sleep")
+            .arg("30")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let client = McpBrowserClient::new();
+        client.pid.store(child.id(), Ordering::SeqCst);
+        *client.process.lock().unwrap() = Some(child);
+
+        let started = Instant::now();
+        let result = client.execute_request_timeout("{}", Duration::from_millis(200));
+
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("no response within 0.2s"));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(client.pid.load(Ordering::SeqCst), 0);
+        assert!(client.process.lock().unwrap().is_none());
+    }
+}